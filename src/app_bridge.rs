@@ -0,0 +1,141 @@
+// RaftCLI: Serial to TCP bridge module
+// Rob Dobson 2024
+
+use clap::Parser;
+use serialport_fix_stop_bits::SerialPort;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::app_flash::resolve_flash_port;
+use crate::serial_monitor::open_serial_port;
+
+// Time between attempts to reopen the serial port after it is lost
+const SERIAL_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A client that doesn't accept data for this long is disconnected (so a slow client doesn't
+// hold up the others)
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Parser, Debug)]
+pub struct BridgeCmd {
+    #[clap(short = 'p', long, help = "Serial port (default the most likely port)")]
+    pub port: Option<String>,
+    #[clap(short = 'b', long, default_value = "115200", help = "Baud rate")]
+    pub baud: u32,
+    #[clap(short = 'l', long, help = "TCP port to listen on for clients")]
+    pub listen: u16,
+    #[clap(long, default_value = "0.0.0.0", help = "Address to listen on (e.g. 127.0.0.1 for local clients only)")]
+    pub bind: String,
+    #[clap(short = 'v', long, help = "Vendor ID")]
+    pub vid: Option<String>,
+}
+
+// Serial port shared by the serial reader and client threads (None while reconnecting)
+type SharedSerialPort = Arc<Mutex<Option<Box<dyn SerialPort>>>>;
+
+// Connected clients (with an id so a client can remove itself when it disconnects)
+type SharedClients = Arc<Mutex<Vec<(u64, TcpStream)>>>;
+
+// Send data received from the serial port to all the clients (dropping any that fail)
+fn send_to_clients(clients: &SharedClients, data: &[u8]) {
+    let mut clients = clients.lock().unwrap();
+    clients.retain_mut(|(id, stream)| {
+        if stream.write_all(data).is_ok() {
+            return true;
+        }
+        println!("Client {} not accepting data - disconnected", id);
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        false
+    });
+}
+
+// Read from the serial port and send to the clients, reopening the port if it is lost
+fn serial_reader(serial_port: SharedSerialPort, port_name: String, baud_rate: u32, clients: SharedClients) {
+    let mut buffer = vec![0; 1024];
+    loop {
+        let result = serial_port.lock().unwrap().as_mut().map(|serial_port| serial_port.read(&mut buffer));
+        match result {
+            Some(Ok(n)) if n > 0 => send_to_clients(&clients, &buffer[..n]),
+            Some(Ok(_)) => {}
+            Some(Err(ref e)) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Some(Err(e)) => {
+                println!("Serial port {} lost ({}) - reconnecting", port_name, e);
+                *serial_port.lock().unwrap() = None;
+            }
+            None => {
+                thread::sleep(SERIAL_RECONNECT_INTERVAL);
+                if let Ok(new_port) = open_serial_port(&port_name, baud_rate) {
+                    println!("Serial port {} reconnected", port_name);
+                    *serial_port.lock().unwrap() = Some(new_port);
+                }
+            }
+        }
+    }
+}
+
+// Read from a client and write to the serial port until the client disconnects (data received
+// while the serial port is being reconnected is dropped)
+fn client_reader(id: u64, mut stream: TcpStream, serial_port: SharedSerialPort, clients: SharedClients) {
+    let mut buffer = vec![0; 1024];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if let Some(serial_port) = serial_port.lock().unwrap().as_mut() {
+                    let _ = serial_port.write_all(&buffer[..n]);
+                }
+            }
+        }
+    }
+    let mut clients = clients.lock().unwrap();
+    if clients.iter().any(|(client_id, _)| *client_id == id) {
+        println!("Client {} disconnected", id);
+        clients.retain(|(client_id, _)| *client_id != id);
+    }
+}
+
+// Bridge a serial port to TCP - bytes received from the serial port are sent to all connected
+// clients and bytes received from any client are written to the serial port
+pub fn start_bridge(cmd: &BridgeCmd) -> Result<(), Box<dyn std::error::Error>> {
+    let port_name = resolve_flash_port(cmd.port.clone(), true, cmd.vid.clone(), 0);
+    let serial_port: SharedSerialPort = Arc::new(Mutex::new(Some(open_serial_port(&port_name, cmd.baud)?)));
+    let listener = TcpListener::bind((cmd.bind.as_str(), cmd.listen))
+        .map_err(|e| format!("Unable to listen on {}:{}: {}", cmd.bind, cmd.listen, e))?;
+    println!("Bridging serial port {} at {} baud to TCP {}:{} (Ctrl+C to stop)", port_name, cmd.baud, cmd.bind, cmd.listen);
+
+    // Serial port reader (handles reconnecting the serial port)
+    let clients: SharedClients = Arc::new(Mutex::new(Vec::new()));
+    {
+        let serial_port = Arc::clone(&serial_port);
+        let clients = Arc::clone(&clients);
+        let baud_rate = cmd.baud;
+        thread::spawn(move || serial_reader(serial_port, port_name, baud_rate, clients));
+    }
+
+    // Accept clients (each is read in its own thread)
+    let mut next_id = 1;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error accepting client: {}", e);
+                continue;
+            }
+        };
+        let id = next_id;
+        next_id += 1;
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        println!("Client {} connected from {}", id, peer);
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT));
+        let writer = stream.try_clone()?;
+        clients.lock().unwrap().push((id, writer));
+        let serial_port = Arc::clone(&serial_port);
+        let clients = Arc::clone(&clients);
+        thread::spawn(move || client_reader(id, stream, serial_port, clients));
+    }
+    Ok(())
+}
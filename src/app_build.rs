@@ -1,24 +1,152 @@
 
 use std::collections::HashMap;
-use std::process::{Command, Stdio};
+use log::debug;
 use std::fs;
 use std::io;
-use std::path::Path;
-use crate::raft_cli_utils::{default_esp_idf_version, find_matching_esp_idf, is_docker_available, is_esp_idf_env, prepare_esp_idf, utils_get_sys_type};
+use crate::raft_cli_utils::{default_esp_idf_version, find_matching_esp_idf, is_docker_available, is_esp_idf_env, prepare_esp_idf, utils_get_sys_type, get_sys_type_names};
 use crate::raft_cli_utils::check_app_folder_valid;
-use crate::raft_cli_utils::check_for_raft_artifacts_deletion;
-use crate::raft_cli_utils::execute_and_capture_output;
+use crate::project_config::{read_project_config, PROJECT_CONFIG_FILE_NAME};
+use crate::raft_cli_utils::{check_for_raft_artifacts_deletion, write_build_metadata, BuildMetadata};
+use crate::raft_cli_utils::{execute_and_stream_output, execute_interactive, parse_build_diagnostics, print_build_error_summary, OutputMode};
 use crate::raft_cli_utils::convert_path_for_docker;
-use crate::raft_cli_utils::CommandError;
+use crate::raft_cli_utils::{CommandError, CommandExitError, ToolMissingError};
 use crate::raft_cli_utils::get_esp_idf_version_from_dockerfile;
-use crate::raft_cli_utils::idf_version_ok;
+use crate::raft_cli_utils::{get_local_idf_version, idf_version_ok, idf_versions_match};
+use crate::raft_cli_utils::{compute_firmware_hash, get_build_folder_name, write_firmware_hash, BUILT_FIRMWARE_HASH_FILE};
+use crate::raft_cli_utils::is_dry_run;
+use crate::raft_cli_utils::{delete_all_build_folders, delete_build_folders};
+use crate::app_sdkconfig::prepare_sdkconfig_override;
+use crate::build_cache::{ccache_env_vars, is_ccache_available, read_ccache_stats, read_docker_ccache_stats, report_ccache_stats};
+use crate::systype_config::target_chip;
+use crate::target_settings::sys_type_settings;
+use crate::build_cache::{DOCKER_BUILD_CACHE_DIR, DOCKER_BUILD_CACHE_VOLUME, DOCKER_CCACHE_DIR, DOCKER_CCACHE_VOLUME};
+
+// Docker image used for builds (when the ESP-IDF version isn't known)
+const DEFAULT_DOCKER_IMAGE: &str = "raftbuilder";
+
+// Options controlling how a raft app is built
+#[derive(Clone, Debug)]
+pub struct BuildOptions {
+    pub clean: bool,
+    pub clean_only: bool,
+    pub force_docker: bool,
+    pub no_docker: bool,
+    pub use_local_idf_matching_dockerfile_idf: bool,
+    pub idf_path_full: Option<String>,
+    // Only show warnings and errors from the build
+    pub quiet: bool,
+    // File that the build output is also written to
+    pub build_log: Option<String>,
+    // File of extra sdkconfig settings merged into the SysType's sdkconfig.defaults
+    pub sdkconfig_extra: Option<String>,
+    // File that diagnostics parsed from the build output are written to (as JSON)
+    pub diagnostics_json: Option<String>,
+    // Don't use ccache even if it is available
+    pub no_ccache: bool,
+    // Don't keep the caches in the docker container in a docker volume
+    pub no_cache_volume: bool,
+    // Open an interactive shell in the docker container instead of building
+    pub docker_shell: bool,
+    // Docker image to build and run (default named after the ESP-IDF version in the Dockerfile)
+    pub docker_image: Option<String>,
+    // Build with the ESP-IDF that is set up even if it isn't the version the app needs
+    pub ignore_idf_version: bool,
+    // Only use a local ESP-IDF of exactly the version the app needs (not a newer patch release)
+    pub idf_exact: bool,
+}
+
+// Get the docker build preference (Some(true) to use docker, Some(false) to build with a local
+// ESP-IDF) and where it was set - in order of precedence this is the --docker/--no-docker
+// options, the RAFT_NO_DOCKER and RAFT_FORCE_DOCKER environment variables and then the
+// [build] docker setting in the app's .raftcli.toml (None if not set so docker is used if it is
+// available)
+pub fn docker_build_preference(build_options: &BuildOptions, app_folder: &str)
+                -> Result<Option<(bool, &'static str)>, std::io::Error> {
+    if build_options.no_docker {
+        return Ok(Some((false, "--no-docker")));
+    }
+    if build_options.force_docker {
+        return Ok(Some((true, "--docker")));
+    }
+    if std::env::var("RAFT_NO_DOCKER").unwrap_or("false".to_string()) == "true" {
+        return Ok(Some((false, "RAFT_NO_DOCKER")));
+    }
+    if std::env::var("RAFT_FORCE_DOCKER").unwrap_or("false".to_string()) == "true" {
+        return Ok(Some((true, "RAFT_FORCE_DOCKER")));
+    }
+    let project_config = read_project_config(app_folder).map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(project_config.build.docker.map(|docker| (docker, PROJECT_CONFIG_FILE_NAME)))
+}
+
+// Determine if docker is to be used for build
+pub fn use_docker_for_build(build_options: &BuildOptions, app_folder: &str) -> Result<bool, std::io::Error> {
+    if build_options.use_local_idf_matching_dockerfile_idf {
+        return Ok(false);
+    }
+    match docker_build_preference(build_options, app_folder)? {
+        Some((false, _)) => Ok(false),
+        Some((true, _)) if is_docker_available() => Ok(true),
+        Some((true, source)) => Err(std::io::Error::other(ToolMissingError::new(
+                    &format!("Docker is required by {} but is not available", source)))),
+        None => Ok(is_docker_available()),
+    }
+}
+
+// Get idf path which should be the path specified in the idf_path_full if it exists or, if not then it should be
+// the path specified in an environment variable IDF_PATH
+pub fn get_idf_path(build_options: &BuildOptions) -> Option<String> {
+    if build_options.idf_path_full.is_none() {
+        std::env::var("IDF_PATH").ok()
+    } else {
+        build_options.idf_path_full.clone()
+    }
+}
+
+// Warn if the SysTypes manifest gives a different chip from the one the SysType is built for (the
+// build only uses the SysType's own files)
+fn check_manifest_chip(app_folder: &str, sys_type: &str) {
+    let (Some(manifest_chip), Some(built_chip)) = (sys_type_settings(app_folder, sys_type).chip, target_chip(app_folder, sys_type)) else {
+        return;
+    };
+    if manifest_chip != built_chip {
+        println!("Warning: the SysTypes manifest gives {} for SysType {} but it is built for {}", manifest_chip, sys_type, built_chip);
+    }
+}
+
+// Check the ESP-IDF that is set up is the version the app's Dockerfile needs before building
+// with it (ESP-IDF folders given with -e are found and checked when the build is set up)
+fn check_local_idf_version(app_folder: &str, build_options: &BuildOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if build_options.idf_path_full.is_some() || !is_esp_idf_env() {
+        return Ok(());
+    }
+    let Some(idf_version) = get_local_idf_version() else {
+        return Ok(());
+    };
+    let required_esp_idf_version = get_esp_idf_version_from_dockerfile(app_folder).unwrap_or(default_esp_idf_version());
+    if idf_versions_match(&idf_version, &required_esp_idf_version) {
+        return Ok(());
+    }
+    if build_options.ignore_idf_version {
+        println!("Warning: building with ESP-IDF {} but the app needs ESP-IDF {} (--ignore-idf-version)",
+                    idf_version, required_esp_idf_version);
+        return Ok(());
+    }
+    println!("The app's Dockerfile needs ESP-IDF {} but ESP-IDF {} is set up (IDF_PATH {})", required_esp_idf_version,
+                idf_version, std::env::var("IDF_PATH").unwrap_or_default());
+    println!("  - build using Docker (--docker)");
+    println!("  - or use -e to give the path of ESP-IDF {} (or of a folder containing esp-idf-v{})",
+                required_esp_idf_version, required_esp_idf_version);
+    println!("  - or add --ignore-idf-version to build with ESP-IDF {} anyway", idf_version);
+    Err(Box::new(ToolMissingError::new(&format!("ESP-IDF {} is set up but the app needs ESP-IDF {}",
+                idf_version, required_esp_idf_version))))
+}
 
-pub fn build_raft_app(build_sys_type: &Option<String>, clean: bool, clean_only: bool, app_folder: String,
-            force_docker_arg: bool, no_docker_arg: bool, 
-            use_local_idf_matching_dockerfile_idf: bool, 
-            idf_path_full: Option<String>) 
+pub fn build_raft_app(build_sys_type: &Option<String>, app_folder: String, build_options: &BuildOptions)
                             -> Result<String, Box<dyn std::error::Error>> {
 
+    let clean = build_options.clean;
+    let clean_only = build_options.clean_only;
+
     // println!("Building the app in folder: {} clean {} clean_only {} no_docker_arg {}", app_folder, clean, clean_only, no_docker_arg);
 
     // Check the app folder is valid
@@ -27,11 +155,22 @@ pub fn build_raft_app(build_sys_type: &Option<String>, clean: bool, clean_only:
     }
 
     // Determine the Systype to build
-    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone());
-    if sys_type.is_err() {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Error determining SysType")));
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
+    check_manifest_chip(&app_folder, &sys_type);
+
+    // Check a local build will use the ESP-IDF version the app needs
+    let use_docker = use_docker_for_build(build_options, &app_folder)?;
+    if !use_docker && !clean_only {
+        check_local_idf_version(&app_folder, build_options)?;
     }
-    let sys_type = sys_type.unwrap();
+
+    // What is being built (recorded so the build_raft_artifacts folder is deleted when it changes)
+    let build_metadata = BuildMetadata {
+        sys_type: sys_type.clone(),
+        chip: sys_type_settings(&app_folder, &sys_type).chip,
+        idf_version: get_esp_idf_version_from_dockerfile(&app_folder).unwrap_or(default_esp_idf_version()),
+    };
 
     // Flags indicating the build folder and "build_raft_artifacts" folder should be deleted
     let mut delete_build_folder = false;
@@ -44,47 +183,26 @@ pub fn build_raft_app(build_sys_type: &Option<String>, clean: bool, clean_only:
         delete_build_raft_artifacts_folder = true;
     } else {
         // Check if the "build_raft_artifacts" folder needs to be deleted
-        if check_for_raft_artifacts_deletion(app_folder.clone(), sys_type.clone()) {
+        if check_for_raft_artifacts_deletion(app_folder.clone(), &build_metadata) {
             delete_build_raft_artifacts_folder = true;
         }
     }
 
-    // Determine if docker is to be used for build
-    let mut no_docker = std::env::var("RAFT_NO_DOCKER").unwrap_or("false".to_string()) == "true";
-    if no_docker_arg {
-        no_docker = true;
-    }
-
-    // Determine if docker is to be forced for build
-    let mut force_docker = std::env::var("RAFT_FORCE_DOCKER").unwrap_or("false".to_string()) == "true";
-    if force_docker_arg {
-        force_docker = true;
-    }
+    // Generate the sdkconfig defaults with any extra settings
+    let sdkconfig_override = if clean_only {
+        None
+    } else {
+        prepare_sdkconfig_override(&app_folder, &sys_type, build_options.sdkconfig_extra.as_deref())?
+    };
 
     // Handle building with or without docker
-    let build_result = if use_local_idf_matching_dockerfile_idf || no_docker || !is_docker_available() && !force_docker {
-        // Get idf path which should be the path specified in the idf_path_full if it exists or, if not then it should be
-        // the path specified in an environment variable IDF_PATH
-        let idf_path = if idf_path_full.is_none() {
-            std::env::var("IDF_PATH").ok()
-        } else {
-            idf_path_full.clone()
-        };
-
-        // Build without docker
-        build_without_docker(app_folder.clone(), sys_type.clone(), clean, clean_only,
-                    delete_build_folder, delete_build_raft_artifacts_folder, idf_path)
-    } else if is_docker_available() {
-        // Build with docker
-        build_with_docker(app_folder.clone(), sys_type.clone(), clean, clean_only,
-                    delete_build_folder, delete_build_raft_artifacts_folder)
-    } else 
-    {
-        // Either ESP IDF or docker must be available to build
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Either ESP IDF or Docker must be available to build",
-        ))
+    let build_result = if use_docker {
+        build_with_docker(app_folder.clone(), sys_type.clone(), build_options,
+                delete_build_folder, delete_build_raft_artifacts_folder, sdkconfig_override.as_deref())
+    } else {
+        build_without_docker(app_folder.clone(), sys_type.clone(), build_options,
+                delete_build_folder, delete_build_raft_artifacts_folder, get_idf_path(build_options),
+                sdkconfig_override.as_deref())
     };
 
     // If the build failed, return the error
@@ -92,47 +210,191 @@ pub fn build_raft_app(build_sys_type: &Option<String>, clean: bool, clean_only:
         return Err(Box::new(build_result.unwrap_err()));
     }
 
+    // Record a hash of the built firmware so unchanged firmware need not be flashed again
+    if !clean_only && !build_options.docker_shell && !is_dry_run() {
+        let build_folder = get_build_folder_name(sys_type.clone(), app_folder.clone());
+        match compute_firmware_hash(&build_folder) {
+            Ok(hash) => {
+                if let Err(e) = write_firmware_hash(&app_folder, BUILT_FIRMWARE_HASH_FILE, &hash) {
+                    println!("Warning: unable to record firmware hash: {}", e);
+                }
+            }
+            Err(e) => println!("Warning: unable to compute firmware hash: {}", e),
+        }
+        if let Err(e) = write_build_metadata(&app_folder, &build_metadata) {
+            println!("Warning: unable to record build metadata: {}", e);
+        }
+    }
+
     Ok(build_result.unwrap().to_string())
 }
 
+// Delete build output - either for the SysType (and the build_raft_artifacts folder) or, if all
+// is set, for every SysType
+pub fn clean_raft_app(build_sys_type: &Option<String>, app_folder: String, all: bool)
+                            -> Result<(), Box<dyn std::error::Error>> {
+
+    // Check the app folder is valid
+    if !check_app_folder_valid(app_folder.clone()) {
+        return Err(Box::new(std::io::Error::other("Invalid app folder")));
+    }
+
+    if all {
+        println!("Raft clean all SysTypes in {}", app_folder);
+        delete_all_build_folders(&app_folder)?;
+        return Ok(());
+    }
+
+    // Determine the SysType to clean
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
+    println!("Raft clean SysType {} in {}", sys_type, app_folder);
+    delete_build_folders(&app_folder, &sys_type, true, true)?;
+    Ok(())
+}
+
+// Result of building each SysType (SysType name and error message on failure)
+pub type SysTypeBuildResults = Vec<(String, Result<(), String>)>;
+
+// Build every SysType in the app folder (except Common) one at a time - they can't be built in
+// parallel as all builds share the build_raft_artifacts folder - and return the result for each
+pub fn build_all_sys_types(app_folder: String, build_options: &BuildOptions, keep_going: bool)
+                            -> Result<SysTypeBuildResults, Box<dyn std::error::Error>> {
+
+    // Check the app folder is valid
+    if !check_app_folder_valid(app_folder.clone()) {
+        return Err(Box::new(std::io::Error::other("Invalid app folder")));
+    }
+
+    // Build each SysType in turn (stopping at the first failure unless keep_going is set)
+    let mut results = Vec::new();
+    for sys_type in get_sys_type_names(&app_folder)? {
+        let result = build_raft_app(&Some(sys_type.clone()), app_folder.clone(), build_options)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+        let failed = result.is_err();
+        results.push((sys_type, result));
+        if failed && !keep_going {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+// Write the diagnostics parsed from the build output to the diagnostics file (if one is set) - file
+// paths in the docker container are changed to paths on the host
+fn write_build_diagnostics(build_options: &BuildOptions, output: &str, project_dir: &str, in_docker: bool) {
+    let Some(diagnostics_json) = &build_options.diagnostics_json else {
+        return;
+    };
+    let mut diagnostics = parse_build_diagnostics(output);
+    if in_docker {
+        if let Ok(host_project_dir) = fs::canonicalize(project_dir) {
+            for diagnostic in diagnostics.iter_mut() {
+                if let Some(rel_path) = diagnostic.file.strip_prefix("/project/") {
+                    diagnostic.file = host_project_dir.join(rel_path).to_string_lossy().to_string();
+                }
+            }
+        }
+    }
+    let result = serde_json::to_string_pretty(&diagnostics)
+        .map_err(io::Error::other)
+        .and_then(|json| fs::write(diagnostics_json, json));
+    if let Err(e) = result {
+        println!("Warning: unable to write build diagnostics to {}: {}", diagnostics_json, e);
+    }
+}
+
+// Build the Docker image (streaming output in the same way as the container run)
+// Get the name of the docker image used to build the app - this is set with --docker-image or
+// named after the ESP-IDF version in the app's Dockerfile (e.g. raftbuilder-5.3.1) so that apps
+// using different ESP-IDF versions don't replace each other's image
+pub fn docker_image_name(project_dir: &str, build_options: &BuildOptions) -> String {
+    if let Some(docker_image) = &build_options.docker_image {
+        return docker_image.clone();
+    }
+    match get_esp_idf_version_from_dockerfile(project_dir) {
+        Ok(esp_idf_version) => {
+            // Image names can only contain lower case letters, digits, '.', '_' and '-'
+            let esp_idf_version: String = esp_idf_version.to_lowercase().chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '-' })
+                .collect();
+            format!("{}-{}", DEFAULT_DOCKER_IMAGE, esp_idf_version)
+        }
+        Err(_) => DEFAULT_DOCKER_IMAGE.to_string(),
+    }
+}
+
+pub fn build_docker_image(project_dir: &str, build_options: &BuildOptions) -> Result<String, std::io::Error> {
+    let docker_image = docker_image_name(project_dir, build_options);
+    let docker_image_build_args: Vec<String> = ["build", "-t", &docker_image, "."].iter().map(|s| s.to_string()).collect();
+    match execute_and_stream_output("docker".to_string(), &docker_image_build_args,
+                    project_dir.to_string(), HashMap::new(), OutputMode::from_quiet(build_options.quiet),
+                    build_options.build_log.as_deref()) {
+        Ok(outcome) if outcome.success() => Ok(outcome.output),
+        Ok(outcome) => {
+            print_build_error_summary(&outcome.output);
+            eprintln!("Docker image build command failed");
+            Err(io::Error::other(CommandExitError::new("Docker image build command failed", outcome.exit_code)))
+        }
+        Err(e) => {
+            eprintln!("Docker image build command failed: {}", e);
+            let message = format!("Docker image build command failed: {}", e);
+            if let CommandError::CommandNotFound(_) = e {
+                return Err(io::Error::other(ToolMissingError::new(&message)));
+            }
+            Err(io::Error::other(message))
+        }
+    }
+}
+
+// Get the docker volume argument mapping the project folder to /project
+pub fn get_docker_project_volume(project_dir: &str) -> Result<String, std::io::Error> {
+    let absolute_project_dir = fs::canonicalize(project_dir)?;
+    let docker_compatible_project_dir = convert_path_for_docker(absolute_project_dir);
+    Ok(format!("{}:/project", docker_compatible_project_dir?))
+}
+
 // Build with docker and return output as a string
-fn build_with_docker(project_dir: String, systype_name: String, clean: bool, clean_only: bool,
-            delete_build_folder: bool, delete_raft_artifacts_folder: bool) -> Result<String, std::io::Error> {
+fn build_with_docker(project_dir: String, systype_name: String, build_options: &BuildOptions,
+            delete_build_folder: bool, delete_raft_artifacts_folder: bool,
+            sdkconfig_override: Option<&str>) -> Result<String, std::io::Error> {
+
+    let clean = build_options.clean;
+    let clean_only = build_options.clean_only;
+    let output_mode = OutputMode::from_quiet(build_options.quiet);
 
     // Build with docker
     println!("Raft build SysType {} in {}{}",  systype_name, project_dir.clone(),
                     if clean { " (clean first)" } else { "" });
 
     // Build the Docker image
-    let fail_docker_image_msg = format!("Docker build command failed");
-    let docker_image_build_args = vec!["build", "-t", "raftbuilder", "."];
-    let docker_image_build_status = Command::new("docker")
-        .current_dir(project_dir.clone())
-        .args(docker_image_build_args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())        
-        .status()
-        .expect(&fail_docker_image_msg);
-
-    if !docker_image_build_status.success() {
-        eprintln!("Docker image build command failed");
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Docker image build command failed"));
-    }
+    let image_build_output = build_docker_image(&project_dir, build_options)?;
+    let docker_image = docker_image_name(&project_dir, build_options);
 
     // Execute the Docker command to build the app
     let build_dir = format!("./build/{}", systype_name);
-    let absolute_project_dir = fs::canonicalize(project_dir.clone())?;
-    let docker_compatible_project_dir = convert_path_for_docker(absolute_project_dir);
-    let project_dir_full = format!("{}:/project", docker_compatible_project_dir?);
+    let project_dir_full = get_docker_project_volume(&project_dir)?;
 
     // Command sequence
     let mut command_sequence = String::new();
 
-    if delete_build_folder {
-        command_sequence += format!("rm -rf ./{}; ", build_dir).as_str();
+    // Delete build folders if required - files created in the container may not be deletable
+    // on the host (e.g. owned by root on linux) so in that case delete them in the container
+    if let Err(e) = delete_build_folders(&project_dir, &systype_name, delete_build_folder, delete_raft_artifacts_folder) {
+        println!("Unable to delete build folders ({}) - deleting them in the container instead", e);
+        if delete_build_folder {
+            command_sequence += format!("rm -rf ./{}; ", build_dir).as_str();
+        }
+        if delete_raft_artifacts_folder {
+            command_sequence += "rm -rf ./build_raft_artifacts; ";
+        }
     }
-    if delete_raft_artifacts_folder {
-        command_sequence += "rm -rf ./build_raft_artifacts; ";
+
+    // The ccache statistics in the volume are zeroed so they are just for this build
+    let use_ccache = !build_options.no_ccache && !clean_only;
+    if use_ccache {
+        command_sequence += "ccache -z > /dev/null 2>&1; ";
     }
 
     command_sequence += "idf.py -B ";
@@ -144,13 +406,48 @@ fn build_with_docker(project_dir: String, systype_name: String, clean: bool, cle
         command_sequence += " build";
     }
 
-    let docker_run_args = vec![
+    // Use the generated sdkconfig defaults if there are extra settings
+    let sdkconfig_defaults_env = sdkconfig_override.map(|path| format!("SDKCONFIG_DEFAULTS=/project/{}", path));
+    let mut docker_run_args = vec![
         "run", "--rm",
         "-v", &project_dir_full,
         "-w", "/project",
-        "raftbuilder",
-        "/bin/bash", "-c", &command_sequence,
     ];
+    if let Some(sdkconfig_defaults_env) = &sdkconfig_defaults_env {
+        docker_run_args.extend(["-e", sdkconfig_defaults_env.as_str()]);
+    }
+
+    // Keep the component manager (and other) caches in a docker volume so components aren't
+    // downloaded again for each build (the build output is still in the project folder)
+    let build_cache_volume = format!("{}:{}", DOCKER_BUILD_CACHE_VOLUME, DOCKER_BUILD_CACHE_DIR);
+    let component_cache_env = format!("IDF_COMPONENT_CACHE_PATH={}/Espressif/ComponentManager", DOCKER_BUILD_CACHE_DIR);
+    if !build_options.no_cache_volume {
+        docker_run_args.extend(["-v", build_cache_volume.as_str(), "-e", component_cache_env.as_str()]);
+    }
+
+    // Keep the ccache directory in a docker volume so the cache persists across container runs
+    let ccache_volume = format!("{}:{}", DOCKER_CCACHE_VOLUME, DOCKER_CCACHE_DIR);
+    let ccache_dir_env = format!("CCACHE_DIR={}", DOCKER_CCACHE_DIR);
+    let ccache_env: Vec<String> = ccache_env_vars().iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    if use_ccache {
+        docker_run_args.extend(["-v", ccache_volume.as_str(), "-e", ccache_dir_env.as_str()]);
+        for env_var in &ccache_env {
+            docker_run_args.extend(["-e", env_var.as_str()]);
+        }
+    }
+
+    // Open an interactive shell in the container (with the project and caches mounted as for
+    // the build) instead of building
+    if build_options.docker_shell {
+        docker_run_args.extend(["-it", docker_image.as_str(), "/bin/bash"]);
+        let docker_run_args: Vec<String> = docker_run_args.iter().map(|s| s.to_string()).collect();
+        println!("Opening a shell in the {} container (use exit to leave it)", docker_image);
+        return match execute_interactive("docker", &docker_run_args, &project_dir) {
+            Ok(_) => Ok(image_build_output),
+            Err(e) => Err(io::Error::other(format!("Unable to open a shell in the container: {:?}", e))),
+        };
+    }
+    docker_run_args.extend([docker_image.as_str(), "/bin/bash", "-c", &command_sequence]);
 
     // Convert to string vector
     let docker_run_args: Vec<String> = docker_run_args.iter().map(|s| s.to_string()).collect();
@@ -160,34 +457,94 @@ fn build_with_docker(project_dir: String, systype_name: String, clean: bool, cle
 
     // Execute the Docker command and capture its output
     let docker_command = "docker".to_string();
-    match execute_and_capture_output(docker_command.clone(), &docker_run_args, project_dir.clone(), HashMap::new()) {
-        Ok((output, success_flag)) => {
-            if success_flag {
-                // Success - return the output as a String
-                Ok(output)
+    match execute_and_stream_output(docker_command.clone(), &docker_run_args, project_dir.clone(), HashMap::new(), output_mode,
+                build_options.build_log.as_deref()) {
+        Ok(outcome) => {
+            print_build_error_summary(&outcome.output);
+            write_build_diagnostics(build_options, &outcome.output, &project_dir, true);
+            if use_ccache && outcome.success() && !is_dry_run() {
+                if let Some(stats) = read_docker_ccache_stats(&docker_image) {
+                    report_ccache_stats(&stats);
+                }
+            }
+            if outcome.success() {
+                // Success - return the output of the image build and run as a String
+                Ok(image_build_output + &outcome.output)
             } else {
-                // If the command executed but was not successful, log the output and return an error
-                eprintln!("Docker run failed but executed: {}", output);
-                Err(io::Error::new(io::ErrorKind::Other, "Docker run executed with errors"))
+                // If the command executed but was not successful (output has already been shown) return an error
+                eprintln!("Docker run failed but executed");
+                Err(io::Error::other(CommandExitError::new("Docker run executed with errors", outcome.exit_code)))
             }
         },
         Err(e) => {
             // More granular error handling based on the CommandError enum
+            let tool_missing = matches!(e, CommandError::CommandNotFound(_));
             let error_message = match e {
                 CommandError::CommandNotFound(msg) => format!("Docker command not found: {}", msg),
                 CommandError::ExecutionFailed(msg) => format!("Docker execution failed: {}", msg),
+                CommandError::Timeout(secs) => format!("Docker run timed out after {}s", secs),
                 CommandError::Other(io_err) => format!("An IO error occurred during Docker execution: {}", io_err),
             };
             eprintln!("Docker run failed: {}", error_message);
+            if tool_missing {
+                return Err(io::Error::other(ToolMissingError::new(&error_message)));
+            }
             Err(io::Error::new(io::ErrorKind::Other, error_message))
         }
     }
 }
 
+// Get the environment variables needed to use a local ESP-IDF matching the version in the Dockerfile
+// (none are needed if the current environment is already set up for it)
+pub fn get_local_idf_env_vars(project_dir: &str, idf_path: Option<String>, idf_exact: bool)
+            -> Result<HashMap<String, String>, std::io::Error> {
+
+    // Get required ESP IDF version from Dockerfile
+    let required_esp_idf_version = get_esp_idf_version_from_dockerfile(project_dir).unwrap_or(default_esp_idf_version());
+
+    // Check if we an ESP IDF environment is set and the version is correct
+    let mut idf_env_vars_to_add: HashMap<String, String> = HashMap::new();
+    let esp_idf_ok = is_esp_idf_env() && idf_version_ok(required_esp_idf_version.clone());
+    if !esp_idf_ok {
+
+        // Use the IDF path provided or the IDF_PATH environment variable
+        let idf_path: Option<String> = idf_path.or_else(|| std::env::var("IDF_PATH").ok());
+
+        // No ESP IDF found so try to find one
+        let idf_found = find_matching_esp_idf(&required_esp_idf_version, idf_path, idf_exact);
+
+        debug!("IDF found {:?}", idf_found);
+
+        // Prepare the ESP-IDF environment
+        if let Some(idf_found) = idf_found {
+            println!("Using ESP-IDF at {} ({})", idf_found.path.display(), idf_found.reason);
+            let idf_prep_result = prepare_esp_idf(idf_found.path.as_path());
+            if idf_prep_result.is_err() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "No ESP-IDF environment variables found"));
+            }
+            idf_env_vars_to_add = idf_prep_result.unwrap();
+        } else {
+            let message = if idf_exact {
+                format!("No ESP-IDF {} found", required_esp_idf_version)
+            } else {
+                format!("No ESP-IDF matching {} found", required_esp_idf_version)
+            };
+            return Err(std::io::Error::other(ToolMissingError::new(&message)));
+        }
+           
+        // return Err(std::io::Error::new(std::io::ErrorKind::Other, "ESP-IDF environment not found"));
+    }
+
+    Ok(idf_env_vars_to_add)
+}
+
 // Build without docker
-fn build_without_docker(project_dir: String, systype_name: String, clean: bool, clean_only: bool,
+fn build_without_docker(project_dir: String, systype_name: String, build_options: &BuildOptions,
     delete_build_folder: bool, delete_raft_artifacts_folder: bool,
-    idf_path: Option<String>) -> Result<String, std::io::Error> {
+    idf_path: Option<String>, sdkconfig_override: Option<&str>) -> Result<String, std::io::Error> {
+
+    let clean = build_options.clean;
+    let clean_only = build_options.clean_only;
     
     // Debug
     println!(
@@ -199,22 +556,9 @@ fn build_without_docker(project_dir: String, systype_name: String, clean: bool,
     
     // Folders
     let build_dir = format!("build/{}", systype_name);
-    let build_raft_artifacts_folder = format!("{}/build_raft_artifacts", project_dir.clone());
 
     // Delete build folders if required
-    if delete_build_folder {
-        let build_dir_full = format!("{}/{}", project_dir.clone(), build_dir);
-        if Path::new(&build_dir_full).exists() {
-            fs::remove_dir_all(&build_dir_full)?;
-        }
-    }
-
-    // Delete the "build_raft_artifacts" folder if required
-    if delete_raft_artifacts_folder {
-        if Path::new(&build_raft_artifacts_folder).exists() {
-            fs::remove_dir_all(&build_raft_artifacts_folder)?;
-        }
-    }
+    delete_build_folders(&project_dir, &systype_name, delete_build_folder, delete_raft_artifacts_folder)?;
 
     // IDF args in a vector of Strings
     let mut idf_run_args = vec!["-B".to_string(), build_dir];
@@ -225,54 +569,53 @@ fn build_without_docker(project_dir: String, systype_name: String, clean: bool,
         idf_run_args.push("build".to_string());
     }
     
-    // Get required ESP IDF version from Dockerfile
-    let required_esp_idf_version = get_esp_idf_version_from_dockerfile(&project_dir).unwrap_or(default_esp_idf_version());
-
-    // // TODO remove
-    // println!("Required ESP-IDF version: {:?} esp_idf_env_set {:?}", esp_idf_version, is_esp_idf_env());
-
-    // Check if we an ESP IDF environment is set and the version is correct
-    let mut idf_env_vars_to_add: HashMap<String, String> = HashMap::new();
-    let esp_idf_ok = is_esp_idf_env() && idf_version_ok(required_esp_idf_version.clone());
-    if !esp_idf_ok {
-
-        // Use the IDF path provided or the IDF_PATH environment variable
-        let idf_path: Option<String> = idf_path.or_else(|| std::env::var("IDF_PATH").ok());
-
-        // No ESP IDF found so try to find one
-        let idf_found_at_path = find_matching_esp_idf(required_esp_idf_version.clone(), idf_path);
-
-        // TODO remove
-        println!("IDF found {:?}", idf_found_at_path);
+    // Get the ESP-IDF environment (the one set up is used as it is if the version is ignored)
+    let use_idf_env = build_options.ignore_idf_version && build_options.idf_path_full.is_none() && is_esp_idf_env();
+    let mut idf_env_vars_to_add = if use_idf_env {
+        HashMap::new()
+    } else {
+        get_local_idf_env_vars(&project_dir, idf_path, build_options.idf_exact)?
+    };
 
-        // Prepare the ESP-IDF environment
-        if idf_found_at_path.is_some() {
-            let idf_prep_result = prepare_esp_idf(idf_found_at_path.unwrap().as_path());
-            if idf_prep_result.is_err() {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "No ESP-IDF environment variables found"));
-            }
-            idf_env_vars_to_add = idf_prep_result.unwrap();
-        } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "No matching ESP-IDF found"));
-        }
-           
-        // return Err(std::io::Error::new(std::io::ErrorKind::Other, "ESP-IDF environment not found"));
+    // Use the generated sdkconfig defaults if there are extra settings
+    if let Some(sdkconfig_override) = sdkconfig_override {
+        let override_file = fs::canonicalize(&project_dir)?.join(sdkconfig_override);
+        idf_env_vars_to_add.insert("SDKCONFIG_DEFAULTS".to_string(), override_file.to_string_lossy().to_string());
     }
 
+    // Use ccache if it is installed (the statistics before the build are kept to report the
+    // hits and misses of this build)
+    let use_ccache = !build_options.no_ccache && !clean_only && is_ccache_available();
+    let ccache_stats_before = if use_ccache {
+        idf_env_vars_to_add.extend(ccache_env_vars());
+        read_ccache_stats()
+    } else {
+        None
+    };
+
     // Execute the command and handle the output
     let idf_py_command = "idf.py".to_string();
-    match execute_and_capture_output(idf_py_command.clone(), &idf_run_args, project_dir.clone(), idf_env_vars_to_add) {
-        Ok((output, success_flag)) => {
-            if success_flag {
-                Ok(output) // Return the output directly
+    match execute_and_stream_output(idf_py_command.clone(), &idf_run_args, project_dir.clone(), idf_env_vars_to_add,
+                OutputMode::from_quiet(build_options.quiet), build_options.build_log.as_deref()) {
+        Ok(outcome) => {
+            print_build_error_summary(&outcome.output);
+            write_build_diagnostics(build_options, &outcome.output, &project_dir, false);
+            if outcome.success() && !is_dry_run() {
+                if let (Some(before), Some(after)) = (ccache_stats_before, read_ccache_stats()) {
+                    report_ccache_stats(&after.since(&before));
+                }
+            }
+            if outcome.success() {
+                Ok(outcome.output) // Return the output directly
             } else {
-                // If the command executed but failed, provide detailed feedback
-                eprintln!("idf.py build executed but failed: {}", output);
-                Err(io::Error::new(io::ErrorKind::Other, "idf.py build executed with errors"))
+                // If the command executed but failed (output has already been shown) summarise the errors
+                eprintln!("idf.py build executed but failed");
+                Err(io::Error::other(CommandExitError::new("idf.py build executed with errors", outcome.exit_code)))
             }
         },
         Err(e) => {
             // Detailed error handling based on the failure
+            let tool_missing = matches!(e, CommandError::CommandNotFound(_));
             let error_message = match e {
                 CommandError::CommandNotFound(msg) => {
                     // Check if the error is due to the idf.py command not being found
@@ -283,9 +626,13 @@ fn build_without_docker(project_dir: String, systype_name: String, clean: bool,
                     }
                 },
                 CommandError::ExecutionFailed(msg) => format!("Execution failed: {}", msg),
+                CommandError::Timeout(secs) => format!("idf.py timed out after {}s", secs),
                 CommandError::Other(io_err) => format!("An IO error occurred: {}", io_err),
             };
             eprintln!("idf.py build failed: {}", error_message);
+            if tool_missing {
+                return Err(io::Error::other(ToolMissingError::new(&error_message)));
+            }
             Err(io::Error::new(io::ErrorKind::Other, error_message))
         }
     }
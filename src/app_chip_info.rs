@@ -0,0 +1,179 @@
+// RaftCLI: Chip info module
+// Rob Dobson 2024
+
+use std::collections::HashMap;
+use std::path::Path;
+use regex::Regex;
+
+use crate::raft_cli_utils::{execute_and_stream_output, get_flash_tool_cmd, is_dry_run, utils_get_sys_type, OutputMode};
+use crate::raft_cli_utils::{is_serial_port_busy_msg, serial_port_busy_hint, CommandExitError};
+use crate::target_settings::sys_type_settings;
+
+// Information about the attached chip reported by esptool
+#[derive(Clone, Debug, Default)]
+pub struct ChipInfo {
+    pub chip: Option<String>,
+    pub mac: Option<String>,
+    pub flash_size: Option<String>,
+    pub crystal: Option<String>,
+}
+
+// Parse esptool flash_id output (e.g. "Chip is ESP32-S3 (QFN56) (revision v0.2)" from esptool v4
+// or "Chip type: ESP32-S3 (QFN56) (revision v0.2)" from v5)
+pub fn parse_chip_info(output: &str) -> ChipInfo {
+    let capture = |pattern: &str| {
+        Regex::new(pattern).unwrap()
+            .captures(output)
+            .map(|caps| caps[1].trim().to_string())
+    };
+    ChipInfo {
+        chip: capture(r"(?m)^Chip (?:is|type:)\s*(\S+)"),
+        mac: capture(r"(?m)^MAC:\s*([0-9A-Fa-f:]+)"),
+        flash_size: capture(r"(?m)^Detected flash size:\s*(\S+)"),
+        crystal: capture(r"(?m)^Crystal (?:is|frequency:)\s*(\S+)"),
+    }
+}
+
+// Chip families other than the original ESP32 (which is reported as its variant, e.g. ESP32-D0WD-V3)
+const NON_ESP32_CHIP_FAMILIES: &[&str] = &[
+    "esp32s2", "esp32s3", "esp32c2", "esp32c3", "esp32c5", "esp32c6", "esp32c61", "esp32h2", "esp32p4",
+];
+
+// Check if the chip reported by esptool (e.g. ESP32-S3) is the target chip (e.g. esp32s3)
+fn is_same_chip(detected_chip: &str, target_chip: &str) -> bool {
+    let detected_chip = detected_chip.replace('-', "").to_lowercase();
+    let target_chip = target_chip.to_lowercase();
+    if target_chip == "esp32" {
+        return detected_chip.starts_with("esp32")
+            && !NON_ESP32_CHIP_FAMILIES.iter().any(|family| detected_chip.starts_with(family));
+    }
+    detected_chip == target_chip
+}
+
+// Read and show the chip information from the device on the port and warn if the chip isn't
+// the one the SysType targets
+pub fn show_chip_info(flash_cmd: &str, port: &str, app_folder: &str, sys_type: Option<&str>)
+                -> Result<ChipInfo, Box<dyn std::error::Error>> {
+    let args = vec!["-p".to_string(), port.to_string(), "flash_id".to_string()];
+    let outcome = execute_and_stream_output(flash_cmd.to_string(), &args, app_folder.to_string(),
+                HashMap::new(), OutputMode::Nothing, None)?;
+    let output = outcome.output.as_str();
+    if is_dry_run() {
+        return Ok(ChipInfo::default());
+    }
+    if !outcome.success() {
+        println!("{}", output);
+        if is_serial_port_busy_msg(output) {
+            println!("{}", serial_port_busy_hint(port));
+        }
+        let message = format!("Unable to read chip info from {}", port);
+        return Err(Box::new(CommandExitError::new(&message, outcome.exit_code)));
+    }
+
+    let chip_info = parse_chip_info(output);
+    let unknown = "unknown".to_string();
+    println!("Chip on {}: {}", port, chip_info.chip.as_ref().unwrap_or(&unknown));
+    println!("  MAC:        {}", chip_info.mac.as_ref().unwrap_or(&unknown));
+    println!("  Flash size: {}", chip_info.flash_size.as_ref().unwrap_or(&unknown));
+    println!("  Crystal:    {}", chip_info.crystal.as_ref().unwrap_or(&unknown));
+
+    // Check the chip matches the SysType
+    if let (Some(sys_type), Some(detected_chip)) = (sys_type, &chip_info.chip) {
+        if let Some(target_chip) = sys_type_settings(app_folder, sys_type).chip {
+            if !is_same_chip(detected_chip, &target_chip) {
+                println!("Warning: the attached chip is {} but SysType {} targets {}", detected_chip, sys_type, target_chip);
+            }
+        }
+    }
+    Ok(chip_info)
+}
+
+// Show the chip information for the device (checked against the SysType if in an app folder)
+pub fn chip_info_raft_app(
+    build_sys_type: &Option<String>,
+    app_folder: &str,
+    port: &str,
+    native_serial_port: bool,
+    flash_tool: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sys_type = if Path::new(app_folder).join("systypes").exists() {
+        utils_get_sys_type(build_sys_type, app_folder.to_string()).ok()
+    } else {
+        None
+    };
+    let flash_cmd = get_flash_tool_cmd(flash_tool, native_serial_port);
+    show_chip_info(&flash_cmd, port, app_folder, sys_type.as_deref())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chip_info_esptool_v4() {
+        let output = "esptool.py v4.7.0\n\
+            Serial port /dev/ttyUSB0\n\
+            Connecting....\n\
+            Detecting chip type... ESP32-S3\n\
+            Chip is ESP32-S3 (QFN56) (revision v0.2)\n\
+            Features: WiFi, BLE, Embedded PSRAM 8MB (AP_3v3)\n\
+            Crystal is 40MHz\n\
+            MAC: f4:12:fa:4d:5e:30\n\
+            Uploading stub...\n\
+            Running stub...\n\
+            Stub running...\n\
+            Manufacturer: c8\n\
+            Device: 4018\n\
+            Detected flash size: 16MB\n\
+            Flash type set in eFuse: quad (4 data lines)\n\
+            Hard resetting via RTS pin...\n";
+        let chip_info = parse_chip_info(output);
+        assert_eq!(chip_info.chip.as_deref(), Some("ESP32-S3"));
+        assert_eq!(chip_info.mac.as_deref(), Some("f4:12:fa:4d:5e:30"));
+        assert_eq!(chip_info.flash_size.as_deref(), Some("16MB"));
+        assert_eq!(chip_info.crystal.as_deref(), Some("40MHz"));
+    }
+
+    #[test]
+    fn test_parse_chip_info_esptool_v5() {
+        let output = "esptool v5.0.0\n\
+            Connected to ESP32 on /dev/ttyUSB0:\n\
+            Chip type:          ESP32-D0WD-V3 (revision v3.0)\n\
+            Features:           Wi-Fi, BT, Dual Core + LP Core, 240MHz, Vref calibration in eFuse, Coding Scheme None\n\
+            Crystal frequency:  40MHz\n\
+            MAC:                24:0a:c4:12:34:56\n\
+            \n\
+            Stub flasher running.\n\
+            \n\
+            Flash Memory Information:\n\
+            =========================\n\
+            Manufacturer: 20\n\
+            Device: 4016\n\
+            Detected flash size: 4MB\n\
+            \n\
+            Hard resetting via RTS pin...\n";
+        let chip_info = parse_chip_info(output);
+        assert_eq!(chip_info.chip.as_deref(), Some("ESP32-D0WD-V3"));
+        assert_eq!(chip_info.mac.as_deref(), Some("24:0a:c4:12:34:56"));
+        assert_eq!(chip_info.flash_size.as_deref(), Some("4MB"));
+        assert_eq!(chip_info.crystal.as_deref(), Some("40MHz"));
+    }
+
+    #[test]
+    fn test_parse_chip_info_no_device() {
+        let chip_info = parse_chip_info("A fatal error occurred: Could not open /dev/ttyUSB0, the port doesn't exist\n");
+        assert!(chip_info.chip.is_none() && chip_info.mac.is_none());
+    }
+
+    #[test]
+    fn test_is_same_chip() {
+        assert!(is_same_chip("ESP32-S3", "esp32s3"));
+        assert!(is_same_chip("ESP32-C6", "esp32c6"));
+        assert!(!is_same_chip("ESP32-S3", "esp32"));
+        // The original ESP32 is reported as its variant
+        assert!(is_same_chip("ESP32-D0WD-V3", "esp32"));
+        assert!(is_same_chip("ESP32-PICO-D4", "esp32"));
+        assert!(!is_same_chip("ESP32-D0WD-V3", "esp32s3"));
+    }
+}
@@ -2,12 +2,14 @@
 // Rob Dobson 2024
 
 use evalexpr::{eval_boolean_with_context, HashMapContext, Value, ContextWithMutableVariables};
-use handlebars::Handlebars;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value as JsonValue};
 use regex::Regex;
 use dialoguer::Input;
 
+use crate::app_new::new_template_handlebars;
+use crate::app_update::GIT_TAG_PATTERN;
+use crate::partition_table::{parse_partition_csv, partition_table_end};
 use crate::raft_cli_utils::default_esp_idf_version;
 
 // Define the schema for the user input
@@ -70,21 +72,49 @@ fn get_schema() -> serde_json::Value {
             "message": "Target chip must be one of esp32, esp32s3, esp32c3, esp32c6",
             "error": "Invalid target chip"
         },
-        // {
-        //     "key": "use_spiram",
-        //     "prompt": "Use SPIRAM (PSRAM)",
-        //     "default": "false",
-        //     "datatype": "boolean",
-        //     "description": "Specify whether SPIRAM (PSRAM) should be used",
-        //     "pattern": "^(true|false|t|f|yes|no|y|n)$",
-        //     "message": "Input must be true or false",
-        //     "error": "Invalid SPIRAM choice"
-        // },
-        // {
-        //     "key": "add_use_spiram_to_sdkconfig",
-        //     "condition": "use_spiram",
-        //     "generator": "\n# SPIRAM\nCONFIG_SPIRAM=y\n"
-        // },
+        {
+            "key": "use_spiram",
+            "condition": "\"{{target_chip}}\" == \"esp32\" || \"{{target_chip}}\" == \"esp32s3\"",
+            "prompt": "Use SPIRAM (PSRAM)",
+            "default": "false",
+            "datatype": "boolean",
+            "description": "Specify whether SPIRAM (PSRAM) should be used (esp32 and esp32s3 only)",
+            "pattern": "^(true|false|t|f|yes|no|y|n)$",
+            "message": "Input must be true or false",
+            "error": "Invalid SPIRAM choice"
+        },
+        {
+            "key": "use_spiram",
+            "condition": "\"{{target_chip}}\" != \"esp32\" && \"{{target_chip}}\" != \"esp32s3\"",
+            "default": "false",
+            "datatype": "boolean"
+        },
+        {
+            "key": "spiram_mode",
+            "condition": "use_spiram && \"{{target_chip}}\" == \"esp32s3\"",
+            "prompt": "SPIRAM mode (quad or octal - check the module datasheet)",
+            "default": "octal",
+            "datatype": "string",
+            "description": "The SPI mode of the SPIRAM (PSRAM) on an esp32s3 module",
+            "pattern": "^(quad|octal)$",
+            "message": "SPIRAM mode must be quad or octal",
+            "error": "Invalid SPIRAM mode"
+        },
+        {
+            "key": "spiram_esp32_sdkconfig",
+            "condition": "use_spiram && \"{{target_chip}}\" == \"esp32\"",
+            "generator": "\n# SPIRAM\nCONFIG_SPIRAM=y\nCONFIG_SPIRAM_MODE_QUAD=y\nCONFIG_SPIRAM_SPEED_40M=y\nCONFIG_SPIRAM_USE_MALLOC=y\n"
+        },
+        {
+            "key": "spiram_esp32s3_sdkconfig",
+            "condition": "use_spiram && \"{{target_chip}}\" == \"esp32s3\" && \"{{spiram_mode}}\" == \"octal\"",
+            "generator": "\n# SPIRAM\nCONFIG_SPIRAM=y\nCONFIG_SPIRAM_MODE_OCT=y\nCONFIG_SPIRAM_SPEED_80M=y\nCONFIG_SPIRAM_USE_MALLOC=y\n"
+        },
+        {
+            "key": "spiram_esp32s3_quad_sdkconfig",
+            "condition": "use_spiram && \"{{target_chip}}\" == \"esp32s3\" && \"{{spiram_mode}}\" == \"quad\"",
+            "generator": "\n# SPIRAM\nCONFIG_SPIRAM=y\nCONFIG_SPIRAM_MODE_QUAD=y\nCONFIG_SPIRAM_SPEED_80M=y\nCONFIG_SPIRAM_USE_MALLOC=y\n"
+        },
         {
             "key": "flash_size_for_partition_table",
             "prompt": "Flash Size in MB (e.g. 4, 8, 16, 32)",
@@ -95,6 +125,16 @@ fn get_schema() -> serde_json::Value {
             "message": "Flash size must be one of 4, 8, 16, 32",
             "error": "Invalid flash size"
         },
+        {
+            "key": "partition_csv_file",
+            "prompt": "Custom partition table CSV file (leave blank to generate one for the flash size)",
+            "default": "",
+            "datatype": "string",
+            "description": "Path to an existing partition table CSV file to copy into the app",
+            "pattern": ".*",
+            "message": "",
+            "error": "Invalid partition table file"
+        },
         {
             "key": "flash_size_4MB",
             "condition": "{{flash_size_for_partition_table}}==4",
@@ -116,7 +156,7 @@ fn get_schema() -> serde_json::Value {
             "generator": "# Flash size\nCONFIG_ESPTOOLPY_FLASHSIZE_8MB=y"
         },
         {
-            "key": "flash_size_8MB",
+            "key": "flash_size_16MB",
             "condition": "{{flash_size_for_partition_table}}==16",
             "generator": "# Name,   Type, SubType, Offset,  Size, Flags\nnvs,      data, nvs,     0x009000,  0x015000,\notametadata,  data, ota,     0x01e000,  0x002000,\napp0,     app,  ota_0,   0x020000,  0x200000,\napp1,     app,  ota_1,   0x220000,  0x200000,\nfs,       data, 0x83,    0x420000,  0xBE0000,"
         },
@@ -185,10 +225,10 @@ fn get_schema() -> serde_json::Value {
         {
             "key": "raft_core_git_tag",
             "prompt": "Raft Core Git Tag",
-            "default": "main",
+            "default": "{{env \"RAFT_CORE_GIT_TAG\" \"main\"}}",
             "datatype": "string",
             "description": "The git tag for the Raft Core library",
-            "pattern": "^[a-zA-Z0-9_]*$",
+            "pattern": GIT_TAG_PATTERN,
             "message": "",
             "error": "Invalid git tag"
         },
@@ -205,10 +245,10 @@ fn get_schema() -> serde_json::Value {
         {
             "key": "raft_sysmods_git_tag",
             "prompt": "Raft SysMods Git Tag",
-            "default": "main",
+            "default": "{{env \"RAFT_SYSMODS_GIT_TAG\" \"main\"}}",
             "datatype": "string",
             "description": "The git tag for the Raft SysMods library",
-            "pattern": "^[a-zA-Z0-9_]*$",
+            "pattern": GIT_TAG_PATTERN,
             "message": "",
             "error": "Invalid git tag",
             "condition": "use_raft_sysmods"
@@ -231,10 +271,10 @@ fn get_schema() -> serde_json::Value {
         {
             "key": "raft_webserver_git_tag",
             "prompt": "Raft Web Server Git Tag",
-            "default": "main",
+            "default": "{{env \"RAFT_WEBSERVER_GIT_TAG\" \"main\"}}",
             "datatype": "string",
             "description": "The git tag for the Raft Web Server library",
-            "pattern": "^[a-zA-Z0-9_]*$",
+            "pattern": GIT_TAG_PATTERN,
             "message": "",
             "error": "Invalid git tag",
             "condition": "use_raft_webserver"
@@ -319,10 +359,10 @@ fn get_schema() -> serde_json::Value {
         {
             "key": "raft_i2c_git_tag",
             "prompt": "Raft I2C Git Tag",
-            "default": "main",
+            "default": "{{env \"RAFT_I2C_GIT_TAG\" \"main\"}}",
             "datatype": "string",
             "description": "The git tag for the Raft I2C library",
-            "pattern": "^[a-zA-Z0-9_]*$",
+            "pattern": GIT_TAG_PATTERN,
             "message": "",
             "error": "Invalid git tag",
             "condition": "use_raft_i2c"
@@ -405,6 +445,77 @@ fn get_schema() -> serde_json::Value {
     schema
 }
 
+// Get the schema for the user input as JSON (for tools which create apps using the same
+// questions and validation)
+pub fn get_schema_json() -> Result<String, Box<dyn std::error::Error>> {
+    let questions = serde_json::from_value::<Vec<ConfigQuestion>>(get_schema())?;
+    Ok(serde_json::to_string_pretty(&questions)?)
+}
+
+// Get the flash size chosen in MB
+fn get_flash_size_mb(responses: &Map<String, JsonValue>) -> Option<u64> {
+    responses.get("flash_size_for_partition_table")
+        .and_then(|flash_size| flash_size.as_str())
+        .and_then(|flash_size| flash_size.parse::<u64>().ok())
+}
+
+// Use a custom partition table CSV file (if one was given) instead of the partition table
+// generated for the flash size - a file that can't be read is an error (rather than quietly
+// using the generated table) so a mistyped path doesn't give the wrong flash layout
+fn apply_custom_partition_table(responses: &mut Map<String, JsonValue>) -> Result<(), String> {
+    let partition_csv_file = responses.get("partition_csv_file")
+        .and_then(|file| file.as_str())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    if partition_csv_file.is_empty() {
+        return Ok(());
+    }
+    let partition_csv = std::fs::read_to_string(&partition_csv_file)
+        .map_err(|e| format!("Unable to read partition table {}: {}", partition_csv_file, e))?;
+
+    // Check the partitions required by raft apps are present
+    let partitions = parse_partition_csv(&partition_csv)
+        .map_err(|e| format!("Invalid partition table {}: {}", partition_csv_file, e))?;
+    let required = [("nvs", "data", Some("nvs")), ("ota", "data", Some("ota")), ("app", "app", None)];
+    for (description, part_type, subtype) in required {
+        if !partitions.iter().any(|partition| partition.part_type == part_type
+                    && subtype.is_none_or(|subtype| partition.subtype == subtype)) {
+            return Err(format!("Partition table {} has no {} partition", partition_csv_file, description));
+        }
+    }
+
+    // Replace the generated partition table
+    if let Some(flash_size_mb) = get_flash_size_mb(responses) {
+        responses.remove(&format!("flash_size_{}MB", flash_size_mb));
+    }
+    responses.insert("custom_partition_table".to_string(), JsonValue::String(partition_csv));
+    Ok(())
+}
+
+// Check the partition table fits in the flash size chosen
+fn check_partition_table_fits_flash(responses: &Map<String, JsonValue>) -> Result<(), String> {
+    let Some(flash_size_mb) = get_flash_size_mb(responses) else {
+        return Ok(());
+    };
+    let partition_table_key = format!("flash_size_{}MB", flash_size_mb);
+    let Some(partition_csv) = responses.get("custom_partition_table")
+            .or_else(|| responses.get(&partition_table_key))
+            .and_then(|csv| csv.as_str()) else {
+        return Ok(());
+    };
+    let partitions = parse_partition_csv(partition_csv)
+        .map_err(|e| format!("Invalid partition table for {}MB flash: {}", flash_size_mb, e))?;
+    let flash_size = flash_size_mb * 1024 * 1024;
+    if let Some((partition, end)) = partition_table_end(&partitions) {
+        if end > flash_size {
+            return Err(format!("Partition table doesn't fit in {}MB flash (partition {} ends at 0x{:x} but flash size is 0x{:x})",
+                        flash_size_mb, partition.name, end, flash_size));
+        }
+    }
+    Ok(())
+}
+
 // Evaluate a condition using evalexpr
 fn evaluate_condition(condition: &str, context: &HashMapContext) -> bool {
     match eval_boolean_with_context(condition, context) {
@@ -416,13 +527,40 @@ fn evaluate_condition(condition: &str, context: &HashMapContext) -> bool {
     }
 }
 
-pub fn get_user_input() -> Result<String, Box<dyn std::error::Error>> {
+// Read answers to questions from a JSON file (an object of key to answer)
+pub fn read_config_answers(config_file: &str) -> Result<Map<String, JsonValue>, Box<dyn std::error::Error>> {
+    let config_str = std::fs::read_to_string(config_file)
+        .map_err(|e| format!("Unable to read config file {}: {}", config_file, e))?;
+    match serde_json::from_str::<JsonValue>(&config_str)? {
+        JsonValue::Object(answers) => Ok(answers),
+        _ => Err(format!("Config file {} must contain a JSON object of key to answer", config_file).into()),
+    }
+}
+
+// Get the answer to a question from the answers supplied (or the default if not supplied)
+fn get_supplied_answer(question: &ConfigQuestion, answers: &Map<String, JsonValue>, default_value: String,
+            re: &Regex, message: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let answer = match answers.get(&question.key) {
+        Some(JsonValue::String(answer)) => answer.clone(),
+        Some(JsonValue::Null) | None => default_value,
+        Some(answer) => answer.to_string(),
+    };
+    if !re.is_match(&answer) {
+        let message = if message.is_empty() { question.error.as_deref().unwrap_or("Invalid input") } else { message };
+        return Err(format!("Invalid value '{}' for {}: {}", answer, question.key, message).into());
+    }
+    Ok(answer)
+}
+
+// Get the configuration for a new app - questions with supplied answers aren't asked and when
+// not interactive the defaults are used for questions not answered
+pub fn get_user_input(answers: &Map<String, JsonValue>, interactive: bool) -> Result<String, Box<dyn std::error::Error>> {
     // Load and deserialize the schema
     let schema = get_schema();
     let questions = serde_json::from_value::<Vec<ConfigQuestion>>(schema)?;
 
     let mut responses = Map::new();
-    let handlebars = Handlebars::new();
+    let handlebars = new_template_handlebars();
     let mut eval_context = HashMapContext::new();
 
     // Iterate over the questions
@@ -452,23 +590,27 @@ pub fn get_user_input() -> Result<String, Box<dyn std::error::Error>> {
             let re = Regex::new(&pattern)?;
             let message = question.message.clone().unwrap_or("Invalid input".to_string());
 
-            // Prompt user for input
-            Input::new()
-                .with_prompt(prompt)
-                .default(default_value)
-                .validate_with({
-                    let re = re; // Move `re` into the closure
-                    let message = message.clone(); // Clone `message` for use in the closure
-                    move |input: &String| {
-                        if re.is_match(input) {
-                            Ok(())
-                        } else {
-                            Err(message.clone())
+            // Use the supplied answer or prompt user for input
+            if !interactive || answers.contains_key(&question.key) {
+                get_supplied_answer(&question, answers, default_value, &re, &message)?
+            } else {
+                Input::new()
+                    .with_prompt(prompt)
+                    .default(default_value)
+                    .validate_with({
+                        let re = re; // Move `re` into the closure
+                        let message = message.clone(); // Clone `message` for use in the closure
+                        move |input: &String| {
+                            if re.is_match(input) {
+                                Ok(())
+                            } else {
+                                Err(message.clone())
+                            }
                         }
-                    }
-                })
-                .interact_text()
-                .unwrap_or_default()
+                    })
+                    .interact_text()
+                    .unwrap_or_default()
+            }
         } else if let Some(generator) = &question.generator {
             handlebars.render_template(generator, &responses)?
         } else {
@@ -479,13 +621,12 @@ pub fn get_user_input() -> Result<String, Box<dyn std::error::Error>> {
         let key = question.key.clone();
         match question.datatype.as_deref() {
             Some("boolean") => {
+                // (the same value is used in the response and when evaluating conditions)
                 let value = response.to_lowercase();
-                responses.insert(
-                    key.clone(),
-                    JsonValue::Bool(value == "true" || value == "t" || value == "yes" || value == "y"),
-                );
+                let value = value == "true" || value == "t" || value == "yes" || value == "y";
+                responses.insert(key.clone(), JsonValue::Bool(value));
                 eval_context
-                    .set_value(key.clone(), Value::from(value == "true"))
+                    .set_value(key.clone(), Value::from(value))
                     .unwrap();
             }
             Some("number") => {
@@ -505,7 +646,46 @@ pub fn get_user_input() -> Result<String, Box<dyn std::error::Error>> {
         }
     }
 
+    // Check the partition table is valid for the flash size
+    apply_custom_partition_table(&mut responses)?;
+    check_partition_table_fits_flash(&responses)?;
+
     // Convert the map to a JSON string
     let config_json = serde_json::to_string_pretty(&responses)?;
     Ok(config_json)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boolean_short_answer_in_conditions() {
+        let answers = json!({ "target_chip": "esp32s3", "use_spiram": "y" });
+        let config = get_user_input(answers.as_object().unwrap(), false).unwrap();
+        let config: JsonValue = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["use_spiram"], JsonValue::Bool(true));
+        // Questions conditional on use_spiram are only answered if the condition saw it as true
+        assert!(config.get("spiram_mode").is_some());
+    }
+
+    #[test]
+    fn test_dotted_git_tag_default() {
+        // Tags such as v1.2.3 from the environment (e.g. in CI) are rendered and accepted
+        std::env::set_var("RAFT_CORE_GIT_TAG", "v1.2.3");
+        let config = get_user_input(&Map::new(), false).unwrap();
+        std::env::remove_var("RAFT_CORE_GIT_TAG");
+        let config: JsonValue = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["raft_core_git_tag"], JsonValue::String("v1.2.3".to_string()));
+        // Tags that aren't valid git refs are still rejected
+        let answers = json!({ "raft_sysmods_git_tag": "v1.2.3 beta" });
+        assert!(get_user_input(answers.as_object().unwrap(), false).is_err());
+    }
+
+    #[test]
+    fn test_missing_custom_partition_table() {
+        let answers = json!({ "partition_csv_file": "no_such_folder/partitions.csv" });
+        let err = get_user_input(answers.as_object().unwrap(), false).unwrap_err();
+        assert!(err.to_string().contains("Unable to read partition table no_such_folder/partitions.csv"), "{}", err);
+    }
+}
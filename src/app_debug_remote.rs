@@ -0,0 +1,308 @@
+// RaftCLI: Remote debug console module
+// Rob Dobson 2024
+
+use clap::Parser;
+use crossterm::event::{self, Event};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::app_discover::resolve_device_host;
+use crate::cmd_history::{CommandHistory, DEBUG_HISTORY_FILE_NAME};
+use crate::debug_transport::{connect_transport, parse_debug_server, DebugTransport, TransportKind};
+use crate::console_log::{close_log, open_log_file, write_to_log, ConsoleLogOptions, LogLineEndings};
+use crate::terminal_io::{load_command_completions, send_file_lines, TerminalAction, TerminalIO};
+
+// Longest wait between connection attempts when backing off
+const MAX_RETRY_INTERVAL_SECS: f64 = 60.0;
+
+// Idle time before TCP keepalive probes are sent (when keepalive isn't set)
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Parser, Debug)]
+pub struct DebugRemoteCmd {
+    // IP address or hostname of the device (optionally with tcp://, udp:// or ws:// and a port) -
+    // .local names are found using mDNS
+    pub address: String,
+    // Option to specify the app folder (for command history and completions)
+    pub app_folder: Option<String>,
+    #[clap(short = 'p', long, help = "Port of the device's debug server (default 8080 for tcp and udp, 80 for ws)")]
+    pub port: Option<u16>,
+    #[clap(long, value_enum, help = "Transport used to connect (default from the address scheme or tcp)")]
+    pub transport: Option<TransportKind>,
+    #[clap(long, default_value_t = 5.0, help = "Seconds to wait before reconnecting (doubled after each failed attempt up to 60s)")]
+    pub retry_interval: f64,
+    #[clap(long, help = "Exit after this many failed connection attempts in a row (default keep retrying)")]
+    pub max_retries: Option<u32>,
+    #[clap(long, help = "Send a keepalive probe when nothing has been received for this many seconds")]
+    pub keepalive: Option<f64>,
+    #[clap(long, default_value = "", help = "Keepalive probe sent (followed by a newline)")]
+    pub keepalive_probe: String,
+    #[clap(long, help = "Reconnect if nothing is received for this many seconds (default 3 x keepalive)")]
+    pub link_timeout: Option<f64>,
+    #[clap(long, help = "Command to send when connected (repeat for more than one)")]
+    pub on_connect: Vec<String>,
+    #[clap(long, default_value_t = 200, help = "Milliseconds to wait after each --on-connect command")]
+    pub on_connect_delay: u64,
+    #[clap(long, help = "Split screen with device output above and a timestamped log of commands sent below")]
+    pub split: bool,
+    #[clap(long, help = "Command history file (default .raftcli_debug_history in the app folder)")]
+    pub history_file: Option<String>,
+    #[clap(short = 'l', long, help = "Log debug console output to file")]
+    pub log: bool,
+    #[clap(short = 'g', long, default_value = "./logs", help = "Folder for log files")]
+    pub log_folder: String,
+}
+
+// Connection attempts with exponential backoff between failures
+struct Reconnect {
+    retry_interval: f64,
+    max_retries: Option<u32>,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+impl Reconnect {
+    fn new(retry_interval: f64, max_retries: Option<u32>) -> Reconnect {
+        Reconnect {
+            retry_interval: retry_interval.max(0.0),
+            max_retries,
+            failures: 0,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    // Wait before the next attempt - the retry interval is doubled for each failure in a row
+    fn backoff_secs(&self) -> f64 {
+        let exponent = self.failures.saturating_sub(1).min(16) as i32;
+        (self.retry_interval * 2f64.powi(exponent)).min(MAX_RETRY_INTERVAL_SECS)
+    }
+
+    // Record a failed attempt returning the wait before the next one (None if no more retries)
+    fn failed(&mut self) -> Option<f64> {
+        self.failures += 1;
+        if self.max_retries.is_some_and(|max_retries| self.failures >= max_retries) {
+            return None;
+        }
+        let backoff_secs = self.backoff_secs();
+        self.next_attempt = Instant::now() + Duration::from_secs_f64(backoff_secs);
+        Some(backoff_secs)
+    }
+
+    // Connected so the next disconnection starts with the configured retry interval
+    fn connected(&mut self) {
+        self.failures = 0;
+    }
+
+    // Wait the configured retry interval after being disconnected
+    fn disconnected(&mut self) {
+        self.next_attempt = Instant::now() + Duration::from_secs_f64(self.retry_interval);
+    }
+}
+
+// Mark the link as down so that the reconnect logic takes over
+fn link_down(stream: &Mutex<Option<Box<dyn DebugTransport>>>, reconnect: &mut Reconnect, terminal_out: &Mutex<TerminalIO>, reason: &str) {
+    *stream.lock().unwrap() = None;
+    reconnect.disconnected();
+    terminal_out.lock().unwrap().show_error(&format!("{} - reconnecting", reason));
+}
+
+// Send a command to the debug server
+fn send_command(stream: &Mutex<Option<Box<dyn DebugTransport>>>, command: &str) -> Result<(), std::io::Error> {
+    match stream.lock().unwrap().as_mut() {
+        Some(stream) => stream.write(format!("{}\n", command).as_bytes()),
+        None => Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "Not connected")),
+    }
+}
+
+// Send the commands for when the connection is made (waiting after each so the device can handle it)
+fn send_on_connect_commands(cmd: &DebugRemoteCmd, stream: &Mutex<Option<Box<dyn DebugTransport>>>,
+            terminal_out: &Mutex<TerminalIO>) {
+    for command in &cmd.on_connect {
+        terminal_out.lock().unwrap().log_command(command);
+        if let Err(e) = send_command(stream, command) {
+            terminal_out.lock().unwrap().show_error(&format!("Command not sent: {}", e));
+            return;
+        }
+        thread::sleep(Duration::from_millis(cmd.on_connect_delay));
+    }
+}
+
+// Console for the debug server on a device - output from the device is shown and commands
+// typed are sent to it, reconnecting (with backoff) if the connection fails
+pub fn start_debug_console(cmd: &DebugRemoteCmd, app_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+
+    // Command history (kept separately from the serial monitor history)
+    let history_file_path = cmd.history_file.clone().unwrap_or_else(|| {
+        std::path::PathBuf::from(app_folder).join(DEBUG_HISTORY_FILE_NAME).to_string_lossy().to_string()
+    });
+    let mut command_history = CommandHistory::new(&history_file_path);
+
+    // Open log file if required
+    let log_options = ConsoleLogOptions {
+        log: cmd.log,
+        log_folder: cmd.log_folder.clone(),
+        log_file: None,
+        raw: false,
+        max_size_mb: None,
+        max_age_mins: None,
+        keep: None,
+        line_endings: LogLineEndings::Keep,
+        timestamps: false,
+        compress: false,
+    };
+    let log_file = open_log_file(&log_options)?;
+
+    // Debug server (checked and .local names found before the terminal is set up)
+    let mut server = parse_debug_server(&cmd.address, cmd.transport, cmd.port)?;
+    server.host = resolve_device_host(&server.host)?;
+
+    // Terminal output
+    let terminal_out = Arc::new(Mutex::new(TerminalIO::new(cmd.split)));
+    terminal_out.lock().unwrap().init()?;
+    terminal_out.lock().unwrap().set_completions(load_command_completions(app_folder));
+    terminal_out.lock().unwrap().print("", false);
+
+    let stream: Arc<Mutex<Option<Box<dyn DebugTransport>>>> = Arc::new(Mutex::new(None));
+    let mut reconnect = Reconnect::new(cmd.retry_interval, cmd.max_retries);
+
+    // Keepalive probes are sent when the link is quiet and the link is down if nothing is
+    // received (including replies to probes) within the link timeout
+    let keepalive = cmd.keepalive.filter(|secs| *secs > 0.0).map(Duration::from_secs_f64);
+    let link_timeout = cmd.link_timeout.map(Duration::from_secs_f64)
+        .or(keepalive.map(|keepalive| keepalive * 3))
+        .filter(|timeout| !timeout.is_zero());
+    // TCP keepalive times are in whole seconds
+    let tcp_keepalive = keepalive.unwrap_or(DEFAULT_TCP_KEEPALIVE).max(Duration::from_secs(1));
+    let mut last_received = Instant::now();
+    let mut last_probe = Instant::now();
+
+    // Main loop - the terminal is restored below even if the loop ends with an error
+    let loop_result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut buffer = [0u8; 1024];
+        loop {
+            // Connect when the wait since the last attempt is over
+            let is_connected = stream.lock().unwrap().is_some();
+            if !is_connected && Instant::now() >= reconnect.next_attempt {
+                terminal_out.lock().unwrap().show_info(&format!("Connecting to {}...", server));
+                match connect_transport(&server, tcp_keepalive) {
+                    Ok(new_stream) => {
+                        *stream.lock().unwrap() = Some(new_stream);
+                        reconnect.connected();
+                        last_received = Instant::now();
+                        last_probe = Instant::now();
+                        terminal_out.lock().unwrap().show_info(&format!("Connected to {}", server));
+                        send_on_connect_commands(cmd, &stream, &terminal_out);
+                    }
+                    Err(e) => {
+                        let Some(backoff_secs) = reconnect.failed() else {
+                            return Err(format!("Unable to connect to {} after {} attempts: {}",
+                                        server, reconnect.failures, e).into());
+                        };
+                        terminal_out.lock().unwrap().show_error(&format!(
+                            "Unable to connect to {} ({}) - retrying in {:.1}s (Esc to exit)", server, e, backoff_secs));
+                    }
+                }
+            }
+
+            // Handle data received
+            let read_result = stream.lock().unwrap().as_mut().map(|stream| stream.read(&mut buffer));
+            match read_result {
+                Some(Err(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Some(Ok(0)) | Some(Err(_)) => {
+                    link_down(&stream, &mut reconnect, &terminal_out, &format!("Disconnected from {}", server));
+                }
+                Some(Ok(n)) => {
+                    last_received = Instant::now();
+                    let received = String::from_utf8_lossy(&buffer[..n]).to_string();
+                    terminal_out.lock().unwrap().show_output(&received);
+                    write_to_log(&log_file, &received);
+                }
+                None => {}
+            }
+
+            // Check the link is alive when nothing has been received for a while
+            if stream.lock().unwrap().is_some() {
+                if link_timeout.is_some_and(|timeout| last_received.elapsed() >= timeout) {
+                    link_down(&stream, &mut reconnect, &terminal_out, &format!("No data from {} for {:.1}s",
+                                server, last_received.elapsed().as_secs_f64()));
+                } else if keepalive.is_some_and(|keepalive| last_received.elapsed() >= keepalive && last_probe.elapsed() >= keepalive) {
+                    last_probe = Instant::now();
+                    if let Err(e) = send_command(&stream, &cmd.keepalive_probe) {
+                        link_down(&stream, &mut reconnect, &terminal_out, &format!("Keepalive to {} failed ({})", server, e));
+                    }
+                }
+            }
+
+            // Handle keyboard input (polling briefly so waiting to reconnect doesn't spin)
+            if event::poll(Duration::from_millis(10))? {
+                let event = event::read()?;
+                if let Event::Resize(cols, rows) = event {
+                    terminal_out.lock().unwrap().on_resize(cols, rows);
+                }
+                if let Event::Key(key_event) = event {
+                    let action = terminal_out.lock().unwrap().handle_key_event(key_event, &mut command_history);
+                    match action {
+                        TerminalAction::Exit => return Ok(()),
+                        TerminalAction::SendCommand(user_input) => {
+                            if let Err(e) = send_command(&stream, &user_input) {
+                                terminal_out.lock().unwrap().show_error(&format!("Command not sent: {}", e));
+                            }
+                        }
+                        TerminalAction::SendFile(file_path) => {
+                            let stream = Arc::clone(&stream);
+                            send_file_lines(&file_path, Arc::clone(&terminal_out), move |user_input| {
+                                let _ = send_command(&stream, &user_input);
+                            });
+                        }
+                        TerminalAction::None => {}
+                    }
+                }
+            }
+        }
+    })();
+
+    // Clean up
+    close_log(&log_file);
+    terminal_out.lock().unwrap().close()?;
+    println!("Exiting...\r");
+
+    loop_result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut reconnect = Reconnect::new(2.0, None);
+        assert_eq!(reconnect.failed(), Some(2.0));
+        assert_eq!(reconnect.failed(), Some(4.0));
+        assert_eq!(reconnect.failed(), Some(8.0));
+        for _ in 0..20 {
+            reconnect.failed();
+        }
+        assert_eq!(reconnect.backoff_secs(), MAX_RETRY_INTERVAL_SECS);
+    }
+
+    #[test]
+    fn test_stops_at_max_retries() {
+        let mut reconnect = Reconnect::new(1.0, Some(3));
+        assert!(reconnect.failed().is_some());
+        assert!(reconnect.failed().is_some());
+        assert_eq!(reconnect.failed(), None);
+    }
+
+    #[test]
+    fn test_connected_resets_failures() {
+        let mut reconnect = Reconnect::new(1.0, Some(3));
+        reconnect.failed();
+        reconnect.failed();
+        reconnect.connected();
+        // The count and backoff start again after a successful connection
+        assert_eq!(reconnect.failed(), Some(1.0));
+        assert_eq!(reconnect.failed(), Some(2.0));
+        assert_eq!(reconnect.failed(), None);
+    }
+}
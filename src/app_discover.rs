@@ -0,0 +1,167 @@
+// RaftCLI: Device discovery module
+// Rob Dobson 2024
+
+use clap::Parser;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+// mDNS service types advertised by raft devices
+const RAFT_SERVICE_TYPES: [&str; 2] = ["_raft._tcp.local.", "_http._tcp.local."];
+
+// TXT record keys that may hold a device's friendly name
+const FRIENDLY_NAME_KEYS: [&str; 3] = ["friendly_name", "friendlyName", "fn"];
+
+// Default time to browse for devices
+pub const DEFAULT_DISCOVERY_TIMEOUT_SECS: f64 = 3.0;
+
+#[derive(Clone, Parser, Debug)]
+pub struct DiscoverCmd {
+    #[clap(long, default_value_t = DEFAULT_DISCOVERY_TIMEOUT_SECS, help = "Seconds to browse for devices")]
+    pub timeout: f64,
+}
+
+// Device found by mDNS
+#[derive(Clone, Debug)]
+pub struct DiscoveredDevice {
+    // Service instance name (e.g. My Device)
+    pub name: String,
+    // Host name without the trailing dot (e.g. mydevice.local)
+    pub hostname: String,
+    // Addresses (IPv4 first)
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub service_type: String,
+    // TXT record properties
+    pub properties: Vec<(String, String)>,
+}
+
+impl DiscoveredDevice {
+    // Address to connect to (IPv4 preferred)
+    pub fn address(&self) -> Option<IpAddr> {
+        self.addresses.first().copied()
+    }
+
+    // Friendly name advertised in the TXT record
+    pub fn friendly_name(&self) -> Option<&str> {
+        FRIENDLY_NAME_KEYS.iter().find_map(|name_key| {
+            self.properties.iter()
+                .find(|(key, value)| key.eq_ignore_ascii_case(name_key) && !value.is_empty())
+                .map(|(_, value)| value.as_str())
+        })
+    }
+
+    // Check if the device has a friendly name (or service instance name) matching the query
+    fn matches_name(&self, query: &str) -> bool {
+        self.friendly_name().is_some_and(|friendly_name| friendly_name.eq_ignore_ascii_case(query))
+            || self.name.eq_ignore_ascii_case(query)
+    }
+}
+
+// Browse mDNS for raft devices for the timeout
+pub fn discover_devices(timeout: Duration) -> Result<Vec<DiscoveredDevice>, Box<dyn std::error::Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let receivers = RAFT_SERVICE_TYPES.iter()
+        .map(|service_type| daemon.browse(service_type))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut devices: Vec<DiscoveredDevice> = Vec::new();
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        for event in receivers.iter().flat_map(|receiver| receiver.try_iter()) {
+            let ServiceEvent::ServiceResolved(service) = event else {
+                continue;
+            };
+            let service_type = service.ty_domain.clone();
+            let mut addresses: Vec<IpAddr> = service.get_addresses().iter().map(|ip| ip.to_ip_addr()).collect();
+            addresses.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+            let device = DiscoveredDevice {
+                name: service.get_fullname().strip_suffix(&format!(".{}", service_type))
+                    .unwrap_or(service.get_fullname()).to_string(),
+                hostname: service.get_hostname().trim_end_matches('.').to_string(),
+                addresses,
+                port: service.get_port(),
+                service_type: service_type.trim_end_matches(".local.").to_string(),
+                properties: service.get_properties().iter()
+                    .map(|property| (property.key().to_string(), property.val_str().to_string()))
+                    .collect(),
+            };
+            // Keep the latest information for each service instance
+            devices.retain(|existing| !(existing.name == device.name && existing.service_type == device.service_type));
+            devices.push(device);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = daemon.shutdown();
+    devices.sort_by(|a, b| a.hostname.cmp(&b.hostname).then(a.service_type.cmp(&b.service_type)));
+    Ok(devices)
+}
+
+// Resolve a .local host name to an address using mDNS discovery (other hosts are unchanged)
+pub fn resolve_device_host(host: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if !host.to_lowercase().ends_with(".local") {
+        return Ok(host.to_string());
+    }
+    let devices = discover_devices(Duration::from_secs_f64(DEFAULT_DISCOVERY_TIMEOUT_SECS))?;
+    let address = devices.iter()
+        .filter(|device| device.hostname.eq_ignore_ascii_case(host))
+        .find_map(|device| device.address())
+        .ok_or_else(|| format!("Device {} not found (use raft discover to list devices)", host))?;
+    println!("Resolved {} to {}", host, address);
+    Ok(address.to_string())
+}
+
+// Find the address of a device given as an IP address or host name (.local names are found
+// using mDNS) or, if it isn't either of those, as the friendly name of a device found by mDNS -
+// the name must match exactly one device
+pub fn resolve_device_name(device_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if device_name.to_lowercase().ends_with(".local") {
+        return resolve_device_host(device_name);
+    }
+    if device_name.parse::<IpAddr>().is_ok() || (device_name, 0).to_socket_addrs().is_ok() {
+        return Ok(device_name.to_string());
+    }
+
+    // Match the friendly name (a device advertising more than one service is one match)
+    let devices = discover_devices(Duration::from_secs_f64(DEFAULT_DISCOVERY_TIMEOUT_SECS))?;
+    let mut matches: Vec<&DiscoveredDevice> = Vec::new();
+    for device in devices.iter().filter(|device| device.matches_name(device_name)) {
+        if !matches.iter().any(|matched| matched.hostname == device.hostname) {
+            matches.push(device);
+        }
+    }
+    match matches.as_slice() {
+        [] => Err(format!("{} is not an IP address, host name or device name (use raft discover to list devices)",
+                    device_name).into()),
+        [device] => {
+            let address = device.address().ok_or_else(|| format!("No address found for device {}", device_name))?;
+            println!("Device {} is {} ({})", device_name, device.hostname, address);
+            Ok(address.to_string())
+        }
+        _ => {
+            println!("Devices named {}:", device_name);
+            for device in &matches {
+                let address = device.address().map(|ip| ip.to_string()).unwrap_or_default();
+                println!("  {:<28} {}", device.hostname, address);
+            }
+            Err(format!("{} devices are named {} (use the IP address or host name)", matches.len(), device_name).into())
+        }
+    }
+}
+
+// List the raft devices found by mDNS
+pub fn discover_raft_devices(cmd: &DiscoverCmd) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Browsing for devices for {}s...", cmd.timeout);
+    let devices = discover_devices(Duration::from_secs_f64(cmd.timeout.max(0.0)))?;
+    if devices.is_empty() {
+        println!("No devices found");
+        return Ok(());
+    }
+    println!("{:<28} {:<28} {:<16} {:<6} Service", "Name", "Hostname", "IP", "Port");
+    for device in &devices {
+        let address = device.address().map(|ip| ip.to_string()).unwrap_or_default();
+        let name = device.friendly_name().unwrap_or(&device.name);
+        println!("{:<28} {:<28} {:<16} {:<6} {}", name, device.hostname, address, device.port, device.service_type);
+    }
+    Ok(())
+}
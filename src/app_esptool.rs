@@ -0,0 +1,101 @@
+// RaftCLI: esptool passthrough module
+// Rob Dobson 2024
+
+use clap::Parser;
+use regex::Regex;
+use std::process::{Command, Stdio};
+
+use crate::raft_cli_utils::get_flash_tool_cmd;
+
+#[derive(Clone, Parser, Debug)]
+pub struct EsptoolCmd {
+    #[clap(long, help = "List common esptool subcommands with examples")]
+    pub list: bool,
+    #[clap(long, help = "Native serial port when in WSL")]
+    pub native_serial_port: bool,
+    #[clap(long, help = "Flash tool (e.g. esptool)")]
+    pub flash_tool: Option<String>,
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true, help = "Arguments passed to esptool")]
+    pub args: Vec<String>,
+}
+
+// Commonly used esptool subcommands, descriptions and example invocations through raft
+const COMMON_SUBCOMMANDS: &[(&str, &str, &str)] = &[
+    ("flash_id", "Read SPI flash manufacturer and device ID", "raft esptool -p <port> flash_id"),
+    ("read_mac", "Read MAC address from OTP ROM", "raft esptool -p <port> read_mac"),
+    ("chip_id", "Read chip ID", "raft esptool -p <port> chip_id"),
+    ("erase_flash", "Perform Chip Erase on SPI flash", "raft esptool -p <port> erase_flash"),
+    ("read_flash", "Read SPI flash content", "raft esptool -p <port> read_flash 0 0x400000 flash_dump.bin"),
+];
+
+// Extract (subcommand, description) pairs from esptool's help text
+fn parse_esptool_subcommands(help_text: &str) -> Vec<(String, String)> {
+    // Subcommands are listed indented under "positional arguments:" (esptool v4) or "Commands:" (v5)
+    let subcommand_re = Regex::new(r"^\s{2,4}([a-z][a-z0-9_-]*)\s{2,}(\S.*)$").unwrap();
+    let mut in_subcommands = false;
+    let mut subcommands = Vec::new();
+    for line in help_text.lines() {
+        if line.starts_with("positional arguments") || line.starts_with("Commands") {
+            in_subcommands = true;
+            continue;
+        }
+        if in_subcommands && !line.is_empty() && !line.starts_with(' ') {
+            in_subcommands = false;
+        }
+        if in_subcommands {
+            if let Some(caps) = subcommand_re.captures(line) {
+                subcommands.push((caps[1].to_string(), caps[2].trim().to_string()));
+            }
+        }
+    }
+    subcommands
+}
+
+fn list_esptool_subcommands(flash_cmd: &str) {
+    // Get esptool's own help text (which may not be available if esptool isn't installed)
+    let help_text = match Command::new(flash_cmd).arg("-h").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+        Err(e) => {
+            println!("Unable to run {} ({}) - is esptool installed?", flash_cmd, e);
+            String::new()
+        }
+    };
+    let subcommands = parse_esptool_subcommands(&help_text);
+
+    println!("Common esptool subcommands (run through raft esptool using {}):", flash_cmd);
+    for (name, default_description, example) in COMMON_SUBCOMMANDS {
+        // Prefer the installed esptool's description (esptool v5 uses hyphens in subcommand names)
+        let description = subcommands
+            .iter()
+            .find(|(sub_name, _)| sub_name.replace('-', "_") == *name)
+            .map(|(_, description)| description.as_str())
+            .unwrap_or(default_description);
+        println!("  {:<12} {}", name, description);
+        println!("  {:<12} e.g. {}", "", example);
+    }
+
+    if !subcommands.is_empty() {
+        let names: Vec<&str> = subcommands.iter().map(|(name, _)| name.as_str()).collect();
+        println!("\nAll esptool subcommands: {}", names.join(", "));
+    }
+    println!("\nUse raft esptool <subcommand> -h for help on a specific subcommand");
+}
+
+pub fn run_esptool(cmd: &EsptoolCmd) -> Result<i32, Box<dyn std::error::Error>> {
+    let flash_cmd = get_flash_tool_cmd(cmd.flash_tool.clone(), cmd.native_serial_port);
+
+    // Intercept --list rather than passing it to esptool
+    if cmd.list {
+        list_esptool_subcommands(&flash_cmd);
+        return Ok(0);
+    }
+
+    // Pass the arguments through to esptool
+    let status = Command::new(&flash_cmd)
+        .args(&cmd.args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?;
+    Ok(status.code().unwrap_or(1))
+}
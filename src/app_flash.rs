@@ -1,73 +1,677 @@
 use std::collections::HashMap;
-use crate::app_ports::select_most_likely_port;
+use std::io::Write;
+use dialoguer::Confirm;
+use log::{debug, trace};
+use regex::Regex;
+use std::thread;
+use std::time::Duration;
+use serialport_fix_stop_bits::new;
+use crate::app_ports::{select_matching_ports, wait_for_likely_port};
 use crate::app_ports::PortsCmd;
-use crate::raft_cli_utils::build_flash_command_args;
-use crate::raft_cli_utils::get_flash_tool_cmd;
-use crate::raft_cli_utils::execute_and_capture_output;
+use crate::raft_cli_utils::{build_erase_command_args, build_flash_command_args, build_image_flash_command_args};
+use crate::raft_cli_utils::{get_flash_tool_cmd, get_tool_version};
+use crate::raft_cli_utils::{execute_and_capture_output_cb, execute_and_stream_output, CommandError, CommandExitError};
+use crate::raft_cli_utils::{CommandOutcome, OutputMode};
 use crate::raft_cli_utils::get_build_folder_name;
 use crate::raft_cli_utils::utils_get_sys_type;
+use crate::raft_cli_utils::{compute_firmware_hash, read_firmware_hash, write_firmware_hash, FLASHED_FIRMWARE_HASH_FILE};
+use crate::raft_cli_utils::{is_serial_port_busy_msg, serial_port_busy_hint};
+use crate::raft_cli_utils::{is_dry_run, PortNotFoundError, EXIT_PORT_NOT_FOUND};
+use crate::raft_cli_utils::read_flasher_args;
+use crate::target_settings::{find_sys_type_settings, sys_type_settings};
+use crate::app_chip_info::show_chip_info;
+use crate::partition_table::{partition_offsets, read_partition_table};
+use crate::raft_cli_utils::{open_in_browser, read_raft_artifact, LAST_DEVICE_ADDR_FILE};
 
-pub fn flash_raft_app(
+// Options controlling how firmware is flashed
+#[derive(Clone, Debug)]
+pub struct FlashOptions {
+    pub flash_baud: u32,
+    pub flash_tool: Option<String>,
+    // Number of times to retry after a connection-phase failure
+    pub retries: u32,
+    // Show a single summary line rather than esptool's output
+    pub summary: bool,
+    // Erase the entire flash before flashing
+    pub erase: bool,
+    // Erase without asking for confirmation
+    pub confirmed: bool,
+    // Read back the flash after writing
+    pub verify: bool,
+    // Only flash the file for this partition (by name in the SysType's partition table)
+    pub partition: Option<String>,
+    // Show the attached chip's information before flashing
+    pub chip_info: bool,
+    // Seconds to wait for a serial port to appear if none is found
+    pub wait_for_port: u64,
+}
+
+// Check if esptool failed while connecting to the chip (rather than due to bad arguments or files)
+fn is_flash_connection_error(output: &str) -> bool {
+    const CONNECTION_ERRORS: &[&str] = &[
+        "Failed to connect",
+        "No serial data received",
+        "Timed out waiting for packet",
+        "Wrong boot mode detected",
+        "Invalid head of packet",
+        "Serial data stream stopped",
+    ];
+    CONNECTION_ERRORS.iter().any(|err| output.contains(err))
+}
+
+// Lower baud rates tried (in order) if flashing fails with a sync or timeout error
+const FALLBACK_FLASH_BAUDS: [u32; 3] = [460800, 230400, 115200];
+
+// Check if esptool failed with an error which may be due to the baud rate being too high for
+// the cable (esptool connects at a low rate before changing to the flash baud rate)
+fn is_flash_baud_error(output: &str) -> bool {
+    const BAUD_ERRORS: &[&str] = &[
+        "Timed out waiting for packet",
+        "Invalid head of packet",
+        "Serial data stream stopped",
+        "Packet content transfer stopped",
+        "Corrupt data",
+        "MD5 of file does not match",
+        "returned no data",
+    ];
+    BAUD_ERRORS.iter().any(|err| output.contains(err))
+}
+
+// Check if esptool failed because the data read back from flash didn't match (with --verify
+// or, from esptool v4, the hash check done after every write)
+fn is_flash_verify_error(output: &str) -> bool {
+    output.contains("Verification failed") || output.contains("verify failed")
+        || output.contains("MD5 of file does not match data in flash")
+}
+
+// Get the major version from esptool --version output (e.g. "esptool.py v4.7.0" gives 4)
+fn parse_esptool_major_version(version: &str) -> Option<u32> {
+    Regex::new(r"v?(\d+)\.\d+").unwrap()
+        .captures(version)
+        .and_then(|caps| caps[1].parse::<u32>().ok())
+}
+
+// Check if --verify should be passed to esptool - esptool v4 and later always check a hash of
+// the data written (their --verify is deprecated in v4 and removed in v5) so it is only passed
+// to older versions
+fn use_esptool_verify_arg(flash_cmd: &str, verify: bool) -> bool {
+    if !verify {
+        return false;
+    }
+    match get_tool_version(flash_cmd).and_then(|version| parse_esptool_major_version(&version)) {
+        Some(major_version) if major_version < 4 => true,
+        _ => {
+            println!("esptool checks the flash after writing by default so --verify isn't passed to it");
+            false
+        }
+    }
+}
+
+// Get the number of files and total bytes written from esptool output
+// (e.g. "Wrote 186896 bytes (103286 compressed) at 0x00010000 in 2.5 seconds ...")
+fn get_flash_written_totals(output: &str) -> (usize, u64) {
+    let wrote_re = regex::Regex::new(r"Wrote (\d+) bytes").unwrap();
+    let sizes: Vec<u64> = wrote_re.captures_iter(output)
+        .filter_map(|caps| caps[1].parse::<u64>().ok())
+        .collect();
+    (sizes.len(), sizes.iter().sum())
+}
+
+// Format a byte count for display
+fn format_byte_count(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1}MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1}KB", bytes as f64 / 1024.0)
+    }
+}
+
+// Width of the flash progress bar in characters
+const FLASH_PROGRESS_BAR_WIDTH: usize = 30;
+
+// Shows esptool's progress lines (e.g. "Writing at 0x00010000... (45 %)" from esptool v4 or
+// "Writing at 0x00010000 [====>   ]  45.0% ..." from v5) as a progress bar for each file being
+// written, labelled with the offset the file is written at - other lines are shown unchanged so
+// output in a format that isn't recognised is passed through as it is
+struct FlashProgress {
+    progress_re: Regex,
+    // Offset of the file being written and the percentage written so far
+    current: Option<(String, f64)>,
+}
+
+impl FlashProgress {
+    fn new() -> FlashProgress {
+        FlashProgress {
+            progress_re: Regex::new(r"^Writing at (0x[0-9a-fA-F]+)\b.*?(\d+(?:\.\d+)?) ?%").unwrap(),
+            current: None,
+        }
+    }
+
+    fn show_line(&mut self, line: &str, is_stdout: bool) {
+        let Some(caps) = self.progress_re.captures(line.trim()) else {
+            self.end_bar();
+            if is_stdout {
+                println!("{}", line);
+            } else {
+                eprintln!("{}", line);
+            }
+            return;
+        };
+        let percent = caps[2].parse::<f64>().unwrap_or(0.0).min(100.0);
+
+        // The first line for a file is written at its offset (and the percentage starts again)
+        let offset = match self.current.take() {
+            Some((offset, last_percent)) if percent >= last_percent => offset,
+            Some(_) => {
+                println!();
+                caps[1].to_string()
+            }
+            None => caps[1].to_string(),
+        };
+        let filled = ((percent / 100.0) * FLASH_PROGRESS_BAR_WIDTH as f64).round() as usize;
+        print!("\rWriting at {} [{}{}] {:5.1}%", offset, "#".repeat(filled),
+                    "-".repeat(FLASH_PROGRESS_BAR_WIDTH - filled), percent);
+        let _ = std::io::stdout().flush();
+        self.current = Some((offset, percent));
+    }
+
+    // End the line of the progress bar being shown (if there is one)
+    fn end_bar(&mut self) {
+        if self.current.take().is_some() {
+            println!();
+        }
+    }
+}
+
+// Run the flash tool showing its progress as a progress bar (or showing nothing in summary
+// mode) and capturing its output
+fn run_flash_tool(flash_cmd: &str, flash_cmd_args: &Vec<String>, app_folder: &str, output_mode: OutputMode)
+                -> Result<CommandOutcome, CommandError> {
+    let mut flash_progress = FlashProgress::new();
+    let mut output = String::new();
+    let exit_code = execute_and_capture_output_cb(flash_cmd.to_string(), flash_cmd_args, app_folder.to_string(),
+                HashMap::new(), |line, is_stdout| {
+        if output_mode != OutputMode::Nothing {
+            flash_progress.show_line(line, is_stdout);
+        }
+        output.push_str(line);
+        output.push('\n');
+    });
+    flash_progress.end_bar();
+    Ok(CommandOutcome { output, exit_code: exit_code? })
+}
+
+// Reset the device by toggling DTR/RTS (as the classic ESP32 auto-reset circuit expects)
+fn reset_device_via_serial(port: &str) {
+    // This is best effort - the port may not be accessible (e.g. a Windows port from WSL)
+    if let Ok(mut serial_port) = new(port, 115200).open() {
+        let _ = serial_port.write_data_terminal_ready(false);
+        let _ = serial_port.write_request_to_send(true);
+        thread::sleep(Duration::from_millis(100));
+        let _ = serial_port.write_request_to_send(false);
+    }
+}
+
+// Get the chip the firmware in the build folder was built for
+fn built_chip(build_folder: &str) -> Option<String> {
+    let flasher_args = read_flasher_args(build_folder).ok()?;
+    flasher_args["extra_esptool_args"]["chip"].as_str().map(|chip| chip.to_string())
+}
+
+// Warn if the firmware was built for a different chip from the one the SysType targets (e.g.
+// after switching boards without a clean build)
+fn check_firmware_chip(app_folder: &str, sys_type: &str, build_folder: &str) {
+    let Some(expected_chip) = sys_type_settings(app_folder, sys_type).chip else {
+        return;
+    };
+    let Some(built_chip) = built_chip(build_folder) else {
+        return;
+    };
+    if !built_chip.eq_ignore_ascii_case(&expected_chip) {
+        println!("Warning: firmware in {} was built for {} but SysType {} targets {} - rebuild (with --clean) before flashing",
+                    build_folder, built_chip, sys_type, expected_chip);
+    }
+}
+
+// Get the offset of a partition (by name) in the SysType's partition table
+fn get_partition_offset(app_folder: &str, sys_type: &str, partition_name: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let partition_file = sys_type_settings(app_folder, sys_type).partition_file
+        .ok_or_else(|| format!("Partition table not found for SysType {}", sys_type))?;
+    let partitions = read_partition_table(&partition_file)?;
+    let offsets = partition_offsets(&partitions);
+    match offsets.iter().find(|(partition, _)| partition.name == partition_name) {
+        Some((_, offset)) => Ok(*offset),
+        None => {
+            let names: Vec<&str> = partitions.iter().map(|partition| partition.name.as_str()).collect();
+            Err(format!("Partition {} not found in {} (partitions are {})", partition_name,
+                        partition_file.display(), names.join(", ")).into())
+        }
+    }
+}
+
+// Get the serial port to flash - the port specified or the most likely port (waiting up to
+// wait_for_port seconds for one to appear)
+pub fn find_flash_port(serial_port: Option<String>, native_serial_port: bool, vid: Option<String>,
+            wait_for_port: u64) -> Result<String, PortNotFoundError> {
+    if let Some(port) = serial_port {
+        return Ok(port);
+    }
+    // Use the most likely port if no specific port is provided
+    let port_cmd = PortsCmd::new_with_vid(vid);
+    wait_for_likely_port(&port_cmd, native_serial_port, wait_for_port)
+        .map(|port| port.port_name)
+        .ok_or(PortNotFoundError)
+}
+
+// The last firmware flashed is recorded with the port it was flashed on (so flashing one board
+// doesn't make the firmware on another board look up to date)
+fn flashed_firmware_record(hash: &str, port: &str) -> String {
+    format!("{}\n{}", hash, port)
+}
+
+// Check if the built firmware is the last firmware flashed and it was flashed on the port
+pub fn is_firmware_flashed(built_hash: Option<&str>, flashed_record: Option<&str>, port: &str) -> bool {
+    match (built_hash, flashed_record.and_then(|record| record.split_once('\n'))) {
+        (Some(built_hash), Some((flashed_hash, flashed_port))) => built_hash == flashed_hash && port == flashed_port.trim(),
+        _ => false,
+    }
+}
+
+// Get the serial port to use (as for find_flash_port) exiting if no suitable port is found
+pub fn resolve_flash_port(serial_port: Option<String>, native_serial_port: bool, vid: Option<String>,
+            wait_for_port: u64) -> String {
+    match find_flash_port(serial_port, native_serial_port, vid, wait_for_port) {
+        Ok(port) => port,
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(EXIT_PORT_NOT_FOUND);
+        }
+    }
+}
+
+// Erase the entire flash or a region given as offset and size (after confirming unless already
+// confirmed) using the same flash tool, port and baud rate as flashing
+fn erase_flash(flash_cmd: &str, chip_type: Option<&str>, app_folder: &str, port: &str, flash_options: &FlashOptions,
+                region: Option<(u64, u64)>) -> Result<(), Box<dyn std::error::Error>> {
+    if !flash_options.confirmed && !is_dry_run() {
+        let prompt = match region {
+            Some((offset, size)) => format!("Erase 0x{:x} bytes of flash at 0x{:x} on the device on {}?", size, offset, port),
+            None => format!("Erase the entire flash of the device on {} (all data including NVS will be lost)?", port),
+        };
+        let confirmed = Confirm::new()
+            .with_prompt(prompt)
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err("Flash erase cancelled".into());
+        }
+    }
+    let erase_cmd_args = build_erase_command_args(chip_type, port, flash_options.flash_baud, region);
+    if !flash_options.summary {
+        println!("Erase command args: {:?}", erase_cmd_args);
+    }
+    let output_mode = if flash_options.summary && !is_dry_run() { OutputMode::Nothing } else { OutputMode::All };
+    let outcome = execute_and_stream_output(flash_cmd.to_string(), &erase_cmd_args, app_folder.to_string(),
+                HashMap::new(), output_mode, None)?;
+    if !outcome.success() {
+        if output_mode == OutputMode::Nothing {
+            println!("{}", outcome.output);
+        }
+        if is_serial_port_busy_msg(&outcome.output) {
+            println!("{}", serial_port_busy_hint(port));
+        }
+        let message = format!("Flash erase executed with errors: {}", outcome.output.trim_end());
+        return Err(Box::new(CommandExitError::new(&message, outcome.exit_code)));
+    }
+    if is_dry_run() {
+        return Ok(());
+    }
+    if flash_options.summary {
+        println!("Erased flash -> {}", port);
+    }
+
+    // The firmware on the device is no longer known so it will be flashed by the next run
+    if read_firmware_hash(app_folder, FLASHED_FIRMWARE_HASH_FILE).is_some() {
+        let _ = write_firmware_hash(app_folder, FLASHED_FIRMWARE_HASH_FILE, "");
+    }
+    Ok(())
+}
+
+// Erase the flash of the device (the chip type is taken from the SysType's build if there is one)
+pub fn erase_raft_app(
     build_sys_type: &Option<String>,
     app_folder: String,
     serial_port: Option<String>,
     native_serial_port: bool,
     vid: Option<String>,
-    flash_baud: u32,
-    flash_tool_opt: Option<String>,
+    flash_options: &FlashOptions,
+    region: Option<(u64, u64)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chip_type = find_sys_type_settings(&app_folder, build_sys_type).and_then(|settings| settings.chip
+                .or_else(|| built_chip(&get_build_folder_name(settings.name, app_folder.clone()))));
+    let flash_cmd = get_flash_tool_cmd(flash_options.flash_tool.clone(), native_serial_port);
+    let port = find_flash_port(serial_port, native_serial_port, vid, flash_options.wait_for_port)?;
+    erase_flash(&flash_cmd, chip_type.as_deref(), &app_folder, &port, flash_options, region)
+}
+
+// Run the flash tool, retrying if the connection to the chip fails and then at lower baud rates
+// if there are sync or timeout errors (the arguments are rebuilt by build_args for each baud rate)
+fn write_flash(
+    flash_cmd: &str,
+    mut flash_cmd_args: Vec<String>,
+    build_args: &dyn Fn(u32) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    app_folder: &str,
+    port: &str,
+    flash_options: &FlashOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
 
-    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone());
-    if sys_type.is_err() {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Error determining SysType")));
+    // In summary mode esptool's output is captured but not shown
+    let output_mode = if flash_options.summary && !is_dry_run() { OutputMode::Nothing } else { OutputMode::All };
+
+    // Execute the flash command
+    let start_time = std::time::Instant::now();
+    let mut attempt = 0;
+    let mut flash_baud = flash_options.flash_baud;
+    let mut fallback_bauds = FALLBACK_FLASH_BAUDS.iter().filter(|baud| **baud < flash_options.flash_baud);
+    let outcome = loop {
+        let outcome = run_flash_tool(flash_cmd, &flash_cmd_args, app_folder, output_mode)?;
+        if outcome.success() {
+            break outcome;
+        }
+        if attempt < flash_options.retries && is_flash_connection_error(&outcome.output) {
+            attempt += 1;
+            println!("Flash connection failed - resetting device and retrying ({} of {})", attempt, flash_options.retries);
+        } else if let Some(lower_baud) = fallback_bauds.next().filter(|_| is_flash_baud_error(&outcome.output)) {
+            println!("Flashing at {} baud failed - retrying at {} baud", flash_baud, lower_baud);
+            flash_baud = *lower_baud;
+            flash_cmd_args = build_args(flash_baud)?;
+        } else {
+            break outcome;
+        }
+        reset_device_via_serial(port);
+        thread::sleep(Duration::from_millis(500));
+    };
+    let output = outcome.output.as_str();
+    if !outcome.success() {
+        // Show the full output if it wasn't shown while flashing
+        if output_mode == OutputMode::Nothing {
+            println!("{}", output);
+        }
+        // Explain the most common cause of esptool failing to open the port
+        if is_serial_port_busy_msg(output) {
+            println!("{}", serial_port_busy_hint(port));
+        }
+        let err_msg = if is_flash_verify_error(output) {
+            "Flash verification failed - the data read back from flash doesn't match the firmware".to_string()
+        } else {
+            format!("Flash executed with errors: {}", output.trim_end())
+        };
+        return Err(Box::new(CommandExitError::new(&err_msg, outcome.exit_code)));
+    }
+    if is_dry_run() {
+        return Ok(());
+    }
+    if flash_baud != flash_options.flash_baud {
+        println!("Flashed at {} baud ({} baud failed) - use -f {} to always flash at this rate",
+                    flash_baud, flash_options.flash_baud, flash_baud);
+    }
+    if flash_options.summary {
+        let (num_files, total_bytes) = get_flash_written_totals(output);
+        println!("Flashed {} files, {}, in {:.1}s at {} baud -> {}", num_files, format_byte_count(total_bytes),
+                    start_time.elapsed().as_secs_f64(), flash_baud, port);
     }
-    let sys_type = sys_type.unwrap();
+    Ok(())
+}
+
+// Flash the app returning the serial port used
+pub fn flash_raft_app(
+    build_sys_type: &Option<String>,
+    app_folder: String,
+    serial_port: Option<String>,
+    native_serial_port: bool,
+    vid: Option<String>,
+    flash_options: &FlashOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
 
     // Get build folder
     let build_folder = get_build_folder_name(sys_type.clone(), app_folder.clone());
 
     // Get flash tool
-    let flash_cmd: String = get_flash_tool_cmd(flash_tool_opt, native_serial_port);
+    let flash_cmd: String = get_flash_tool_cmd(flash_options.flash_tool.clone(), native_serial_port);
 
-    // Extract port and baud rate arguments
-    let port = if let Some(port) = serial_port {
-        port
-    } else {
-        // Use select_most_likely_port if no specific port is provided
-        let port_cmd = PortsCmd::new_with_vid(vid);
-        match select_most_likely_port(&port_cmd, native_serial_port) {
-            Some(p) => p.port_name,
-            None => {
-                println!("Error: No suitable port found");
-                std::process::exit(1);
-            }
+    // Get the serial port
+    let port = find_flash_port(serial_port, native_serial_port, vid, flash_options.wait_for_port)?;
+
+    // Check the firmware matches the chip the SysType targets
+    check_firmware_chip(&app_folder, &sys_type, &build_folder);
+
+    // Show the attached chip (a failure to read it is left for flashing to report)
+    if flash_options.chip_info {
+        if let Err(e) = show_chip_info(&flash_cmd, &port, &app_folder, Some(&sys_type)) {
+            println!("Warning: {}", e);
         }
+    }
+
+    // The chip the SysType targets (or, if not known, the one the firmware was built for)
+    let chip_type = sys_type_settings(&app_folder, &sys_type).chip.or_else(|| built_chip(&build_folder));
+
+    // Erase the flash first if required
+    if flash_options.erase {
+        erase_flash(&flash_cmd, chip_type.as_deref(), &app_folder, &port, flash_options, None)?;
+    }
+
+    // Find the offset of the partition to flash (if only one partition is to be flashed)
+    let only_offset = match &flash_options.partition {
+        Some(partition_name) => Some(get_partition_offset(&app_folder, &sys_type, partition_name)?),
+        None => None,
     };
 
-    // Extract the arguments for the flash command
-    let flash_cmd_args = build_flash_command_args(build_folder.clone(), &port, flash_baud);
+    // Extract the arguments for the flash command (rebuilt if the baud rate is lowered)
+    let verify = use_esptool_verify_arg(&flash_cmd, flash_options.verify);
+    let build_args = |flash_baud: u32| build_flash_command_args(build_folder.clone(), &port, flash_baud,
+                verify, only_offset, chip_type.as_deref());
+    let flash_cmd_args = build_args(flash_options.flash_baud);
 
     // Check for errors in the flash command and arguments
-    if flash_cmd_args.is_err() {
+    if let Err(e) = flash_cmd_args {
+        if only_offset.is_some() {
+            return Err(e);
+        }
         return Err(Box::new(std::io::Error::new(
             std::io::ErrorKind::Other,
             "Error extracting flash command arguments",
         )));
     }
     let flash_cmd_args = flash_cmd_args.unwrap();
+    if let (Some(partition_name), Some(only_offset)) = (&flash_options.partition, only_offset) {
+        println!("Flashing partition {} at 0x{:x}: {}", partition_name, only_offset,
+                    flash_cmd_args.last().map(|file| file.as_str()).unwrap_or_default());
+    }
 
-    // Debug
-    println!("Flash command: {}", flash_cmd.clone());
-    println!("Flash command args: {:?}", flash_cmd_args);
-    println!("Flash command app folder: {}", app_folder.clone());
-    // println!("Flash command build folder: {}", build_folder);
+    debug!("Flash command: {}", flash_cmd);
+    debug!("Flash command args: {:?}", flash_cmd_args);
+    debug!("Flash command app folder: {}", app_folder);
+    trace!("Flash command build folder: {}", build_folder);
 
-    // Execute the flash command and check for errors
-    let (output, success_flag) = execute_and_capture_output(flash_cmd.clone(), &flash_cmd_args, app_folder.clone(), HashMap::new())?;
-    if !success_flag {
-        let err_msg = format!("Flash executed with errors: {}", output);
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, err_msg)));
+    write_flash(&flash_cmd, flash_cmd_args, &build_args, &app_folder, &port, flash_options)?;
+
+    // Record the hash of the firmware now on the device
+    if is_dry_run() {
+        return Ok(port);
+    }
+    // (not known when only one partition was flashed)
+    if only_offset.is_none() {
+        if let Ok(hash) = compute_firmware_hash(&build_folder) {
+            let _ = write_firmware_hash(&app_folder, FLASHED_FIRMWARE_HASH_FILE, &flashed_firmware_record(&hash, &port));
+        }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(port)
+}
+
+// Result of flashing each port (port name and error message on failure)
+pub type PortFlashResults = Vec<(String, Result<(), String>)>;
+
+// Flash the app to every port matching the port pattern and vendor ID one after another - a
+// failure on one port is recorded and the remaining ports are still flashed
+pub fn flash_raft_app_all_ports(
+    build_sys_type: &Option<String>,
+    app_folder: String,
+    port_pattern: Option<String>,
+    native_serial_port: bool,
+    vid: Option<String>,
+    flash_options: &FlashOptions,
+) -> Result<PortFlashResults, PortNotFoundError> {
+    let mut port_cmd = PortsCmd::new_with_vid(vid.clone());
+    port_cmd.port = port_pattern;
+    let ports = select_matching_ports(&port_cmd, native_serial_port);
+    if ports.is_empty() {
+        return Err(PortNotFoundError);
+    }
+    println!("Flashing {} ports: {}", ports.len(),
+                ports.iter().map(|port| port.port_name.as_str()).collect::<Vec<_>>().join(", "));
+    let mut results = Vec::new();
+    for (index, port) in ports.iter().enumerate() {
+        println!("Flashing port {} ({} of {})", port.port_name, index + 1, ports.len());
+        let result = flash_raft_app(build_sys_type, app_folder.clone(), Some(port.port_name.clone()),
+                    native_serial_port, vid.clone(), flash_options)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            println!("Flashing port {} failed: {}", port.port_name, e);
+        }
+        results.push((port.port_name.clone(), result));
+    }
+    Ok(results)
+}
+
+// Prebuilt image file (e.g. a merged binary) to flash without a raft project
+#[derive(Clone, Debug)]
+pub struct FlashImage {
+    pub file: String,
+    // Chip type of the device (detected by esptool if not known)
+    pub chip: Option<String>,
+    pub offset: u64,
+}
+
+// Flash a prebuilt image file returning the serial port used
+pub fn flash_image(
+    flash_image: &FlashImage,
+    app_folder: String,
+    serial_port: Option<String>,
+    native_serial_port: bool,
+    vid: Option<String>,
+    flash_options: &FlashOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let FlashImage { file: image, chip, offset } = flash_image;
+    let chip_type = chip.as_deref();
+    let flash_cmd = get_flash_tool_cmd(flash_options.flash_tool.clone(), native_serial_port);
+    let port = find_flash_port(serial_port, native_serial_port, vid, flash_options.wait_for_port)?;
+
+    // Show the attached chip (a failure to read it is left for flashing to report)
+    if flash_options.chip_info {
+        if let Err(e) = show_chip_info(&flash_cmd, &port, &app_folder, None) {
+            println!("Warning: {}", e);
+        }
+    }
+
+    // Erase the flash first if required
+    if flash_options.erase {
+        erase_flash(&flash_cmd, chip_type, &app_folder, &port, flash_options, None)?;
+    }
+
+    let verify = use_esptool_verify_arg(&flash_cmd, flash_options.verify);
+    let build_args = |flash_baud: u32| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(build_image_flash_command_args(image, chip_type, *offset, &port, flash_baud, verify))
+    };
+    let flash_cmd_args = build_args(flash_options.flash_baud)?;
+    println!("Flashing {} at 0x{:x}", image, offset);
+    debug!("Flash command: {}", flash_cmd);
+    debug!("Flash command args: {:?}", flash_cmd_args);
+
+    write_flash(&flash_cmd, flash_cmd_args, &build_args, &app_folder, &port, flash_options)?;
+
+    // The firmware on the device is no longer the app's so it will be flashed by the next run
+    if !is_dry_run() && read_firmware_hash(&app_folder, FLASHED_FIRMWARE_HASH_FILE).is_some() {
+        let _ = write_firmware_hash(&app_folder, FLASHED_FIRMWARE_HASH_FILE, "");
+    }
+    Ok(port)
+}
+
+// Open the device's web UI - the URL is either given explicitly or derived from the address
+// last used for OTA (nothing is opened if neither is available)
+pub fn open_device_web_ui(app_folder: &str, device_url: Option<String>) {
+    let url = device_url.or_else(|| {
+        read_raft_artifact(app_folder, LAST_DEVICE_ADDR_FILE).map(|addr| format!("http://{}/", addr))
+    });
+    match url {
+        Some(url) => {
+            println!("Opening {}", url);
+            if let Err(e) = open_in_browser(&url) {
+                println!("Warning: {}", e);
+            }
+        }
+        None => println!("Device URL not known (use --device-url) - not opening browser"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_firmware_flashed() {
+        let record = flashed_firmware_record("abc123", "/dev/ttyUSB0");
+        assert!(is_firmware_flashed(Some("abc123"), Some(&record), "/dev/ttyUSB0"));
+        // Flashed on another board
+        assert!(!is_firmware_flashed(Some("abc123"), Some(&record), "/dev/ttyUSB1"));
+        // Firmware rebuilt since it was flashed
+        assert!(!is_firmware_flashed(Some("def456"), Some(&record), "/dev/ttyUSB0"));
+        // Not built, never flashed, cleared (after an erase) or recorded without a port
+        assert!(!is_firmware_flashed(None, Some(&record), "/dev/ttyUSB0"));
+        assert!(!is_firmware_flashed(Some("abc123"), None, "/dev/ttyUSB0"));
+        assert!(!is_firmware_flashed(Some("abc123"), Some(""), "/dev/ttyUSB0"));
+        assert!(!is_firmware_flashed(Some("abc123"), Some("abc123"), "/dev/ttyUSB0"));
+    }
+
+    #[test]
+    fn test_parse_esptool_major_version() {
+        assert_eq!(parse_esptool_major_version("esptool.py v3.3.4"), Some(3));
+        assert_eq!(parse_esptool_major_version("esptool.py v4.7.0"), Some(4));
+        assert_eq!(parse_esptool_major_version("esptool v5.0.2"), Some(5));
+        assert_eq!(parse_esptool_major_version("4.8.1"), Some(4));
+        assert_eq!(parse_esptool_major_version("esptool"), None);
+    }
+
+    #[test]
+    fn test_get_flash_written_totals() {
+        // esptool v4
+        let output = "Flash will be erased from 0x00000000 to 0x00005fff...\n\
+            Compressed 21024 bytes to 13355...\n\
+            Writing at 0x00000000... (100 %)\n\
+            Wrote 21024 bytes (13355 compressed) at 0x00000000 in 0.4 seconds (effective 422.0 kbit/s)...\n\
+            Hash of data verified.\n\
+            Compressed 3072 bytes to 103...\n\
+            Wrote 3072 bytes (103 compressed) at 0x00008000 in 0.1 seconds (effective 363.4 kbit/s)...\n\
+            Hash of data verified.\n\
+            Wrote 1048576 bytes at 0x00010000 in 9.2 seconds (909.8 kbit/s)...\n\
+            Hash of data verified.\n\
+            \n\
+            Leaving...\n\
+            Hard resetting via RTS pin...\n";
+        assert_eq!(get_flash_written_totals(output), (3, 21024 + 3072 + 1048576));
+        // esptool v5
+        let output = "Flash will be erased from 0x00000000 to 0x00005fff...\n\
+            Compressed 21024 bytes to 13355...\n\
+            Writing at 0x00000000 [==============================] 100.0% 13355/13355 bytes...\n\
+            Wrote 21024 bytes (13355 compressed) at 0x00000000 in 0.4 seconds (422.0 kbit/s).\n\
+            Hash of data verified.\n\
+            \n\
+            Hard resetting via RTS pin...\n";
+        assert_eq!(get_flash_written_totals(output), (1, 21024));
+        assert_eq!(get_flash_written_totals("A fatal error occurred: Failed to connect to ESP32"), (0, 0));
+    }
+}
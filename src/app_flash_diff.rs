@@ -0,0 +1,108 @@
+// RaftCLI: Flash diff module
+// Rob Dobson 2024
+
+use sha2::{Digest, Sha256};
+
+use crate::partition_table::parse_partition_value;
+use crate::raft_cli_utils::read_flasher_args;
+
+// Image written to flash (e.g. bootloader, partition-table, app) as listed in flasher_args.json
+struct FlashImage {
+    name: String,
+    offset: String,
+    size: u64,
+    hash: String,
+}
+
+// Get the named images in flasher_args.json with the size and hash of each file
+fn get_flash_images(build_folder: &str) -> Result<Vec<FlashImage>, Box<dyn std::error::Error>> {
+    let flash_args = read_flasher_args(build_folder)?;
+    let mut images = Vec::new();
+    if let Some(entries) = flash_args.as_object() {
+        for (name, entry) in entries {
+            let (Some(offset), Some(file)) = (entry["offset"].as_str(), entry["file"].as_str()) else {
+                continue;
+            };
+            let contents = std::fs::read(format!("{}/{}", build_folder, file))
+                .map_err(|e| format!("Unable to read {}/{}: {}", build_folder, file, e))?;
+            let hash = Sha256::digest(&contents);
+            images.push(FlashImage {
+                name: name.clone(),
+                offset: offset.to_string(),
+                size: contents.len() as u64,
+                hash: hash.iter().map(|b| format!("{:02x}", b)).collect(),
+            });
+        }
+    }
+    images.sort_by_key(|image| parse_partition_value(&image.offset).unwrap_or(u64::MAX));
+    Ok(images)
+}
+
+// Compare flash settings (mode, size, frequency and chip)
+fn diff_flash_settings(build_a: &str, build_b: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let flash_args_a = read_flasher_args(build_a)?;
+    let flash_args_b = read_flasher_args(build_b)?;
+    let mut changed = false;
+    let settings = [
+        ("flash_settings", "flash_mode"),
+        ("flash_settings", "flash_size"),
+        ("flash_settings", "flash_freq"),
+        ("extra_esptool_args", "chip"),
+    ];
+    for (section, key) in settings {
+        let value_a = flash_args_a[section][key].as_str().unwrap_or("-");
+        let value_b = flash_args_b[section][key].as_str().unwrap_or("-");
+        if value_a != value_b {
+            println!("  {:<20} {} -> {}", key, value_a, value_b);
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+// Report what differs at the flash level between two builds and return true if anything changed
+pub fn flash_diff(build_a: &str, build_b: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let images_a = get_flash_images(build_a)?;
+    let images_b = get_flash_images(build_b)?;
+
+    println!("Comparing {} with {}", build_a, build_b);
+    let mut changed = diff_flash_settings(build_a, build_b)?;
+
+    // Images in either build (in offset order of the first build then any new images)
+    let mut names: Vec<&str> = images_a.iter().map(|image| image.name.as_str()).collect();
+    for image in &images_b {
+        if !names.contains(&image.name.as_str()) {
+            names.push(&image.name);
+        }
+    }
+
+    println!("  {:<20} {:<10} {:>10} {:>10} {:>10}  Offset", "Image", "Status", "Size A", "Size B", "Delta");
+    for name in names {
+        let image_a = images_a.iter().find(|image| image.name == name);
+        let image_b = images_b.iter().find(|image| image.name == name);
+        let (status, size_a, size_b, offset) = match (image_a, image_b) {
+            (Some(a), Some(b)) => {
+                let status = if a.hash == b.hash && a.offset == b.offset { "unchanged" } else { "changed" };
+                let offset = if a.offset == b.offset {
+                    a.offset.clone()
+                } else {
+                    format!("{} -> {} (moved)", a.offset, b.offset)
+                };
+                (status, a.size, b.size, offset)
+            }
+            (Some(a), None) => ("removed", a.size, 0, a.offset.clone()),
+            (None, Some(b)) => ("added", 0, b.size, b.offset.clone()),
+            (None, None) => continue,
+        };
+        if status != "unchanged" {
+            changed = true;
+        }
+        let delta = size_b as i64 - size_a as i64;
+        println!("  {:<20} {:<10} {:>10} {:>10} {:>+10}  {}", name, status, size_a, size_b, delta, offset);
+    }
+
+    if !changed {
+        println!("Builds are identical at the flash level");
+    }
+    Ok(changed)
+}
@@ -0,0 +1,51 @@
+// RaftCLI: Merged binary module
+// Rob Dobson 2024
+
+use clap::Parser;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::raft_cli_utils::{build_merge_bin_command_args, execute_and_stream_output, get_build_folder_name,
+            get_flash_tool_cmd, is_dry_run, utils_get_sys_type, CommandExitError, OutputMode};
+
+// Name of the merged image (in the SysType's build folder unless --output is given)
+const DEFAULT_MERGED_BIN_FILE_NAME: &str = "factory.bin";
+
+#[derive(Clone, Parser, Debug)]
+pub struct MergeBinCmd {
+    // Option to specify the app folder
+    pub app_folder: Option<String>,
+    #[clap(short = 's', long, help = "System type")]
+    pub sys_type: Option<String>,
+    #[clap(short = 'o', long, help = "Merged image file (default factory.bin in the SysType's build folder)")]
+    pub output: Option<String>,
+    #[clap(short = 'n', long, help = "Native serial port when in WSL (selects the esptool used)")]
+    pub native_serial_port: bool,
+    #[clap(short = 't', long, help = "Flash tool (e.g. esptool)")]
+    pub flash_tool: Option<String>,
+}
+
+// Merge the bootloader, partition table, app and other images of a built SysType into a single
+// image to be flashed at offset 0
+pub fn merge_bin_raft_app(cmd: &MergeBinCmd, app_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let sys_type = utils_get_sys_type(&cmd.sys_type, app_folder.to_string())?;
+    let build_folder = get_build_folder_name(sys_type.clone(), app_folder.to_string());
+    let output = match &cmd.output {
+        // (esptool runs in the app folder so a relative path is made absolute)
+        Some(output) => std::env::current_dir()?.join(output).to_string_lossy().to_string(),
+        None => PathBuf::from(&build_folder).join(DEFAULT_MERGED_BIN_FILE_NAME).to_string_lossy().to_string(),
+    };
+    let merge_args = build_merge_bin_command_args(&build_folder, &output)
+        .map_err(|e| format!("Unable to read the build of SysType {} (build it first): {}", sys_type, e))?;
+    let flash_cmd = get_flash_tool_cmd(cmd.flash_tool.clone(), cmd.native_serial_port);
+    let outcome = execute_and_stream_output(flash_cmd, &merge_args, app_folder.to_string(),
+                HashMap::new(), OutputMode::All, None)?;
+    if !outcome.success() {
+        let message = format!("Merging images failed: {}", outcome.output.trim_end());
+        return Err(Box::new(CommandExitError::new(&message, outcome.exit_code)));
+    }
+    if !is_dry_run() {
+        println!("Merged image for SysType {} written to {} (flash at offset 0x0)", sys_type, output);
+    }
+    Ok(())
+}
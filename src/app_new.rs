@@ -2,77 +2,246 @@
 // Rob Dobson 2024
 
 use std::fs;
+use std::path::{Path, PathBuf};
 use include_dir::{include_dir, Dir};
-use handlebars::Handlebars;
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
 
 // Define the embedded directory of templates
 static RAFT_TEMPLATES_DIR: Dir = include_dir!("./raft_templates");
 
+// Handlebars helper to get the value of an environment variable - {{env "VAR" "fallback"}}
+// gives the fallback (or an empty string if there is none) when the variable isn't set
+fn env_helper(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let fallback = h.param(1).and_then(|param| param.value().as_str()).unwrap_or_default();
+    let value = h.param(0)
+        .and_then(|param| param.value().as_str())
+        .and_then(|name| std::env::var(name).ok())
+        .unwrap_or_else(|| fallback.to_string());
+    out.write(&value)?;
+    Ok(())
+}
+
+// Create a handlebars instance with the helpers available to templates and questions
+pub fn new_template_handlebars() -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_helper("env", Box::new(env_helper));
+    handlebars
+}
+
+// Remove // and /* */ comments from JSON (outside strings) so that it can be parsed
+fn strip_json_comments(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            stripped.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    stripped.push(escaped);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                // Skip to the end of the line (keeping the newline)
+                while chars.peek().is_some_and(|&next| next != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                for next in chars.by_ref() {
+                    if prev == '*' && next == '/' {
+                        break;
+                    }
+                    prev = next;
+                }
+            }
+            _ => {
+                in_string = c == '"';
+                stripped.push(c);
+            }
+        }
+    }
+    stripped
+}
+
+// Check a generated JSON (or JSON with comments) file is valid so that a value rendered into
+// it can't leave the app with a broken config file
+fn check_generated_json(dest_path: &str, content: &str) -> Result<(), String> {
+    let json = if dest_path.ends_with(".jsonc") {
+        strip_json_comments(content)
+    } else if dest_path.ends_with(".json") {
+        content.to_string()
+    } else {
+        return Ok(());
+    };
+    serde_json::from_str::<serde_json::Value>(&json)
+        .map(|_| ())
+        .map_err(|e| format!("Generated file {} is not valid JSON: {}", dest_path, e))
+}
+
+// Generate a file in the new app from a template file (the path relative to the templates
+// folder and the content may both contain handlebars)
+fn process_template_file(handlebars: &mut Handlebars, template_path: &str, content: &str, target_folder: &str,
+                            context: &serde_json::Value, created_paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    // Check if the path contains handlebars
+    let path = if template_path.contains("{{") && template_path.contains("}}") {
+        // Use handlebars to modify the path according to template rules
+        handlebars.register_template_string("path", template_path)?;
+        handlebars.render_template(template_path, context)?
+    } else {
+        template_path.to_string()
+    };
+
+    // Generate the destination path in the target folder
+    let dest_path = format!("{}/{}", target_folder, path);
+
+    // Create any folders required to copy the file (recording those created)
+    let dest_dir = std::path::Path::new(&dest_path).parent().unwrap();
+    let mut new_dirs: Vec<PathBuf> = dest_dir.ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(|dir| dir.to_path_buf())
+        .collect();
+    new_dirs.reverse();
+    fs::create_dir_all(dest_dir)?;
+    created_paths.extend(new_dirs);
+
+    // Decide to render or copy file based on its content or extension
+    if content.contains("{{") && content.contains("}}") {
+
+        // println!("Rendering file from {} to: {}", path, dest_path);
+
+        // File likely contains Handlebars syntax; attempt to register it and then render it
+        handlebars.register_template_string(path.as_str(), content)?;
+        let rendered = handlebars.render_template(content, context)?;
+        check_generated_json(&dest_path, &rendered)?;
+        fs::write(&dest_path, rendered)?;
+        created_paths.push(PathBuf::from(dest_path));
+
+    } else {
+
+        // println!("Copying file from {} to: {}", path, dest_path);
+
+        // File does not contain Handlebars syntax; copy as is
+        check_generated_json(&dest_path, content)?;
+        fs::write(&dest_path, content)?;
+        created_paths.push(PathBuf::from(dest_path));
+    }
+    Ok(())
+}
+
 // Process a template directory and use its contents to generate a new app
-fn process_dir(handlebars: &mut Handlebars, in_dir: &Dir, target_folder: &str, context: &serde_json::Value) -> 
-                            Result<(), Box<dyn std::error::Error>> {
+fn process_dir(handlebars: &mut Handlebars, in_dir: &Dir, target_folder: &str, context: &serde_json::Value,
+                            created_paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
     // Iterate through the embedded folders
     for folder in in_dir.dirs() {
         // println!("Folder: {}", folder.path().display());
-        process_dir(handlebars, folder, target_folder, context)?;
+        process_dir(handlebars, folder, target_folder, context, created_paths)?;
     }
 
     // Iterate through the embedded files
     for file in in_dir.files() {
         // println!("File: {}", file.path().display());
-        let path: std::string::String;
         if let Some(found_path) = file.path().to_str() {
-
-            // Check if the path contains handlebars
-            if found_path.contains("{{") && found_path.contains("}}") {
-                // Use handlebars to modify the path according to template rules
-                handlebars.register_template_string("path", found_path)?;
-                path = handlebars.render_template(&found_path, context)?;
-            } else {
-                path = found_path.to_string();
-            }
-
-            // Generate the destination path in the target folder
-            let dest_path = format!("{}/{}", target_folder, path);
-
-            // Create any folders required to copy the file
-            let dest_dir = std::path::Path::new(&dest_path).parent().unwrap();
-            fs::create_dir_all(dest_dir)?;
-
             // Read the template content as a string
             let content = std::str::from_utf8(file.contents())?;
+            process_template_file(handlebars, found_path, content, target_folder, context, created_paths)
+                .map_err(|e| format!("{}: {}", found_path, e))?;
+        }
+    }
 
-            // Decide to render or copy file based on its content or extension
-            if content.contains("{{") && content.contains("}}") {
-
-                // println!("Rendering file from {} to: {}", path, dest_path);
+    Ok(())
+}
 
-                // File likely contains Handlebars syntax; attempt to register it and then render it
-                handlebars.register_template_string(path.as_str(), content)?;
-                let rendered = handlebars.render_template(&content, context)?;
-                fs::write(&dest_path, rendered)?;
+// Process a template directory on the filesystem (paths are relative to the templates root)
+fn process_fs_dir(handlebars: &mut Handlebars, templates_root: &Path, in_dir: &Path, target_folder: &str,
+                            context: &serde_json::Value, created_paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries: Vec<_> = fs::read_dir(in_dir)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            process_fs_dir(handlebars, templates_root, &entry, target_folder, context, created_paths)?;
+            continue;
+        }
+        let relative_path = entry.strip_prefix(templates_root)?;
+        if let Some(found_path) = relative_path.to_str() {
+            // Use forward slashes as in the embedded templates (so handlebars paths are the same)
+            let found_path = found_path.replace('\\', "/");
+            let content = fs::read_to_string(&entry)
+                .map_err(|e| format!("Unable to read template {}: {}", entry.display(), e))?;
+            process_template_file(handlebars, &found_path, &content, target_folder, context, created_paths)
+                .map_err(|e| format!("{}: {}", found_path, e))?;
+        }
+    }
+    Ok(())
+}
 
-            } else {
+// Remove the files and folders created (most recent first) - folders are only removed if empty
+fn remove_created_paths(created_paths: &[PathBuf]) {
+    for path in created_paths.iter().rev() {
+        let _ = if path.is_dir() { fs::remove_dir(path) } else { fs::remove_file(path) };
+    }
+}
 
-                // println!("Copying file from {} to: {}", path, dest_path);
+// Generate a new app (from the templates in template_dir if specified or the embedded templates)
+// - if generation fails the files created are removed unless keep_partial is set
+pub fn generate_new_app(target_folder: &str, context: serde_json::Value, template_dir: Option<&str>, keep_partial: bool)
+                            -> Result<(), Box<dyn std::error::Error>> {
 
-                // File does not contain Handlebars syntax; copy as is
-                fs::write(dest_path, content)?;
+    // Create an instance of Handlebars
+    let mut handlebars = new_template_handlebars();
+    let mut created_paths = Vec::new();
+    let result = match template_dir {
+        Some(template_dir) => {
+            let templates_root = Path::new(template_dir);
+            if !templates_root.is_dir() {
+                return Err(format!("Template folder not found: {}", template_dir).into());
             }
+            process_fs_dir(&mut handlebars, templates_root, templates_root, target_folder, &context, &mut created_paths)
+        }
+        None => process_dir(&mut handlebars, &RAFT_TEMPLATES_DIR, target_folder, &context, &mut created_paths),
+    };
+    if let Err(e) = result {
+        if keep_partial {
+            println!("Keeping {} partially generated files and folders in {}", created_paths.len(), target_folder);
+        } else {
+            remove_created_paths(&created_paths);
+            println!("Removed {} partially generated files and folders from {}", created_paths.len(), target_folder);
         }
+        return Err(e);
     }
 
+    // Success
+    println!("Successfully generated a new raft app in: {}", target_folder);
     Ok(())
 }
 
-// Generate a new app
-pub fn generate_new_app(target_folder: &str, context: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Create an instance of Handlebars
-    let mut handlebars = Handlebars::new();
-    process_dir(&mut handlebars, &RAFT_TEMPLATES_DIR, &target_folder, &context)?;
+    #[test]
+    fn test_strip_json_comments() {
+        let content = "{\n  // line comment\n  \"a\": 1, /* block\n comment */ \"b\": 2 // trailing\n}";
+        let value: serde_json::Value = serde_json::from_str(&strip_json_comments(content)).unwrap();
+        assert_eq!(value, serde_json::json!({ "a": 1, "b": 2 }));
+    }
 
-    // Success
-    println!("Successfully generated a new raft app in: {}", target_folder);
-    Ok(())
+    #[test]
+    fn test_strip_json_comments_in_strings() {
+        // Comment markers inside strings are kept
+        let content = r#"{"url": "http://example.com/*x*/", "path": "a//b"} // comment"#;
+        assert_eq!(strip_json_comments(content), r#"{"url": "http://example.com/*x*/", "path": "a//b"} "#);
+        // Escaped quotes and backslashes don't end the string
+        let content = r#"{"q": "say \"//hi\"", "dir": "c:\\", "n": 1 /* c */}"#;
+        assert_eq!(strip_json_comments(content), r#"{"q": "say \"//hi\"", "dir": "c:\\", "n": 1 }"#);
+        let value: serde_json::Value = serde_json::from_str(&strip_json_comments(content)).unwrap();
+        assert_eq!(value["q"], "say \"//hi\"");
+    }
 }
@@ -1,4 +1,8 @@
+use crate::app_discover::resolve_device_name;
 use crate::raft_cli_utils::utils_get_sys_type;
+use crate::raft_cli_utils::{write_raft_artifact, LAST_DEVICE_ADDR_FILE};
+use crate::partition_table::{read_partition_table, smallest_ota_app_partition};
+use crate::target_settings::sys_type_settings;
 use std::fs::File;
 use std::io::{self, BufReader, Read, Write};
 use std::net::TcpStream;
@@ -7,6 +11,9 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::collections::VecDeque;
 
+// Default size of the chunks the firmware image is sent in
+pub const DEFAULT_OTA_CHUNK_SIZE: usize = 8192;
+
 // Struct to track data rate over a period (e.g., 5 seconds)
 struct DataRateTracker {
     interval_duration: Duration,          // The duration over which we want to track the rate (e.g., 5 seconds)
@@ -80,9 +87,8 @@ impl<R: Read> ProgressReader<R> {
             }
             self.total_read += n as u64;
 
-            // Write the chunk to the stream and flush it immediately
+            // Write the chunk to the stream (flushed once all chunks are written)
             stream.write_all(&buf[..n])?;
-            stream.flush()?;
 
             // Update total bytes sent
             self.total_sent += n as u64;
@@ -97,6 +103,7 @@ impl<R: Read> ProgressReader<R> {
             //     n, self.total_sent
             // );
         }
+        stream.flush()?;
         Ok(())
     }
 }
@@ -152,6 +159,7 @@ fn perform_ota_flash_basic_http_with_streaming(
     fw_image_name: &str,
     ip_addr: &str,
     port: u16,
+    chunk_size: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Check if the firmware file exists
     if !Path::new(fw_image_path).exists() {
@@ -171,7 +179,7 @@ fn perform_ota_flash_basic_http_with_streaming(
 
     // Create a ProgressReader that owns the file and wrap it in a BufReader for better I/O performance
     let file_reader = BufReader::new(file);
-    let mut progress_reader = ProgressReader::new(file_reader, 1024, progress_tracker.clone());
+    let mut progress_reader = ProgressReader::new(file_reader, chunk_size.max(1), progress_tracker.clone());
 
     // Connect to the server
     let addr = format!("{}:{}", ip_addr, port);
@@ -235,34 +243,115 @@ fn perform_ota_flash_basic_http_with_streaming(
     Ok(())
 }
 
+// Check the firmware image fits in the OTA app partition - this avoids a partially written
+// update when the image has grown beyond the partition size - if a slot (e.g. ota_1 or the
+// partition name) is specified then the image is checked against that partition (this doesn't
+// change the slot the device writes the firmware to)
+fn check_fw_image_fits_partition(app_folder: &str, sys_type: &str, fw_image_path: &str, slot: &Option<String>)
+                -> Result<(), Box<dyn std::error::Error>> {
+
+    // The check is skipped (with a warning) if the partition table can't be used unless a
+    // slot has been requested
+    let partitions = match sys_type_settings(app_folder, sys_type).partition_file {
+        Some(partition_file) => read_partition_table(&partition_file),
+        None => Err(format!("partition table not found for SysType {}", sys_type)),
+    };
+    let partitions = match partitions {
+        Ok(partitions) => partitions,
+        Err(e) if slot.is_none() => {
+            println!("Warning: unable to check firmware size: {}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(Box::new(io::Error::other(e))),
+    };
+
+    // Find the OTA partition to check against
+    let ota_partition = match slot {
+        Some(slot) => {
+            let ota_partition = partitions.iter()
+                .filter(|partition| partition.is_ota_app())
+                .find(|partition| &partition.subtype == slot || &partition.name == slot);
+            if ota_partition.is_none() {
+                let slots: Vec<String> = partitions.iter()
+                    .filter(|partition| partition.is_ota_app())
+                    .map(|partition| format!("{} ({})", partition.subtype, partition.name))
+                    .collect();
+                return Err(Box::new(io::Error::other(format!(
+                    "OTA slot {} not found - available slots: {}", slot,
+                    if slots.is_empty() { "none".to_string() } else { slots.join(", ") }))));
+            }
+            ota_partition
+        }
+        None => smallest_ota_app_partition(&partitions),
+    };
+    let Some(ota_partition) = ota_partition else {
+        println!("Warning: no OTA app partition found in partition table");
+        return Ok(());
+    };
+
+    // Compare with the firmware image size
+    let fw_image_size = std::fs::metadata(fw_image_path)?.len();
+    if fw_image_size > ota_partition.size {
+        return Err(Box::new(io::Error::other(format!(
+            "Firmware image is too large for OTA ({} bytes but partition {} is {} bytes)",
+            fw_image_size, ota_partition.name, ota_partition.size))));
+    }
+    if slot.is_some() {
+        println!("Firmware image ({} bytes) fits OTA slot {} ({} bytes at partition {})",
+                    fw_image_size, ota_partition.subtype, ota_partition.size, ota_partition.name);
+    }
+    Ok(())
+}
+
+// Remember the device address so that later commands (e.g. flash --open-browser) can use it
+fn record_device_addr(app_folder: &str, ip_addr: &str, ip_port: u16) {
+    let device_addr = if ip_port == 80 { ip_addr.to_string() } else { format!("{}:{}", ip_addr, ip_port) };
+    let _ = write_raft_artifact(app_folder, LAST_DEVICE_ADDR_FILE, &device_addr);
+}
+
+// OTA update the app returning the address of the device updated
 pub fn ota_raft_app(
     build_sys_type: &Option<String>,
     app_folder: String,
     ip_addr: String,
     ip_port: Option<u16>,
     use_curl: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    slot: &Option<String>,
+    chunk_size: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
 
     // Get the system type
-    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone());
-    if sys_type.is_err() {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Error determining SysType")));
-    }
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
 
-    // Unwrap the sys_type, ip_addr, and ip_port
-    let sys_type = sys_type.unwrap();
+    // Unwrap the ip_port
     let ip_port = ip_port.unwrap_or(80);
     let fw_image_name = format!("{}.bin", sys_type);
     let fw_image_path = format!("{}/build/{}/{}", app_folder, sys_type, fw_image_name);
 
+    // Check the firmware image exists and will fit in the OTA partition
+    if !Path::new(&fw_image_path).exists() {
+        return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Firmware image not found: {}", fw_image_path))));
+    }
+    check_fw_image_fits_partition(&app_folder, &sys_type, &fw_image_path, slot)?;
+
+    // Find the address of a .local or friendly device name
+    let ip_addr = resolve_device_name(&ip_addr)?;
+
     // Check if not using curl
     if !use_curl {
         println!("Flashing {} FW image is {}", sys_type, fw_image_path);
 
         // Call the synchronous version of perform_ota_flash with progress tracking
-        match perform_ota_flash_basic_http_with_streaming(&fw_image_path, &fw_image_name, &ip_addr, ip_port) {
-            Ok(_) => println!("OTA flash successful"),
-            Err(e) => println!("OTA flash failed: {:?}", e),
+        match perform_ota_flash_basic_http_with_streaming(&fw_image_path, &fw_image_name, &ip_addr, ip_port, chunk_size) {
+            Ok(_) => {
+                println!("OTA flash successful");
+                record_device_addr(&app_folder, &ip_addr, ip_port);
+            }
+            Err(e) => {
+                println!("OTA flash failed: {:?}", e);
+                return Err(e);
+            }
         }
 
     } else {
@@ -277,7 +366,8 @@ pub fn ota_raft_app(
         if let Ok(output) = ota_result {
             if output.status.success() {
                 println!("OTA flash successful");
-                return Ok(());
+                record_device_addr(&app_folder, &ip_addr, ip_port);
+                return Ok(ip_addr);
             } else {
                 println!("OTA flash failed: {}", String::from_utf8_lossy(&output.stderr));
                 return Err("Failed to execute curl command".to_string().into());
@@ -287,5 +377,5 @@ pub fn ota_raft_app(
         }
     }
 
-    Ok(())
+    Ok(ip_addr)
 }
\ No newline at end of file
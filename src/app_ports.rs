@@ -1,9 +1,13 @@
 use serialport_fix_stop_bits::{available_ports, SerialPortType, SerialPortInfo, UsbPortInfo};
 use clap::Parser;
+use serde::{Deserialize, Serialize};
 use wildmatch::WildMatch;
+use log::{debug, log, trace, warn, Level};
 use std::error::Error;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
-use crate::raft_cli_utils::is_wsl;
+use crate::raft_cli_utils::{is_wsl, EXIT_PORT_NOT_FOUND};
 
 #[derive(Clone, Parser, Debug)]
 pub struct PortsCmd {
@@ -23,8 +27,17 @@ pub struct PortsCmd {
     pub index: Option<usize>,
     #[clap(short = 'D', long, help = "Debug mode")]
     pub debug: bool,
-    #[clap(long, help = "Preferred VIDs (comma separated list)")]
+    #[clap(long, help = "Preferred VIDs or VID:PID pairs (comma separated list, e.g. 303a:1001,0403)")]
     pub preferred_vids: Option<String>,
+    #[clap(long, help = "Output the ports as JSON")]
+    pub json: bool,
+    #[clap(long, help = "Only output the name of the most likely port (exit code 4 if none found)")]
+    pub first: bool,
+    // Set from the global --quiet option (no message is output when no ports are found)
+    #[clap(skip)]
+    pub quiet: bool,
+    #[clap(short = 'n', long, help = "Native serial port when in WSL (as for flash and monitor, used with --first)")]
+    pub native_serial_port: bool,
 }
 
 impl PortsCmd {
@@ -39,6 +52,10 @@ impl PortsCmd {
             index: None,
             debug: false,
             preferred_vids: None,
+            json: false,
+            first: false,
+            quiet: false,
+            native_serial_port: false,
         }
     }
 }
@@ -52,13 +69,39 @@ const DEFAULT_PREFERRED_VIDS: &[&str] = &[
     "239a", // Adafruit
 ];
 
+// Port details in JSON output
+#[derive(Debug, Serialize, Deserialize)]
+struct PortJson {
+    port_name: String,
+    vid: Option<String>,
+    pid: Option<String>,
+    manufacturer: Option<String>,
+    serial: Option<String>,
+    product: Option<String>,
+    is_preferred: bool,
+}
+
 pub fn manage_ports(cmd: &PortsCmd) {
+    // Just the most likely port name (for use in scripts) - chosen in the same way as for flash
+    // and monitor (so in WSL the windows ports are used unless the native serial port is requested)
+    if cmd.first {
+        match select_most_likely_port(cmd, cmd.native_serial_port) {
+            Some(port) => println!("{}", port.port_name),
+            None => std::process::exit(EXIT_PORT_NOT_FOUND),
+        }
+        return;
+    }
     if let Err(e) = list_ports(cmd) {
         println!("Error listing ports: {}", e);
         std::process::exit(1);
     }
 }
 
+// Port matching is logged at trace level unless debug mode (-D) is on
+fn port_match_log_level(debug: bool) -> Level {
+    if debug { Level::Info } else { Level::Trace }
+}
+
 fn matches(str: &str, pattern: Option<String>, debug: bool) -> bool {
     let result = match pattern {
         Some(ref pattern) => {
@@ -70,9 +113,7 @@ fn matches(str: &str, pattern: Option<String>, debug: bool) -> bool {
         }
         None => true,
     };
-    if debug {
-        println!("matches(str:{:?}, pattern:{:?}) -> {:?}", str, pattern, result);
-    }
+    log!(port_match_log_level(debug), "matches(str:{:?}, pattern:{:?}) -> {:?}", str, pattern, result);
     result
 }
 
@@ -81,9 +122,7 @@ fn matches_opt(str: Option<String>, pattern: Option<String>, debug: bool) -> boo
         matches(&str, pattern, debug)
     } else {
         let result = pattern.is_none();
-        if debug {
-            println!("matches_opt(str:{:?}, pattern:{:?}) -> {:?}", str, pattern, result);
-        }
+        log!(port_match_log_level(debug), "matches_opt(str:{:?}, pattern:{:?}) -> {:?}", str, pattern, result);
         result
     }
 }
@@ -103,22 +142,40 @@ fn usb_port_matches(port: &SerialPortInfo, cmd: &PortsCmd) -> bool {
     false
 }
 
-fn sort_ports(mut ports: Vec<SerialPortInfo>, cmd: &PortsCmd) -> Vec<SerialPortInfo> {
-    let preferred_vids: Vec<&str> = cmd.preferred_vids.as_ref()
+fn preferred_vids(cmd: &PortsCmd) -> Vec<&str> {
+    cmd.preferred_vids.as_ref()
         .map(|vids| vids.split(',').collect())
-        .unwrap_or_else(|| DEFAULT_PREFERRED_VIDS.to_vec());
+        .unwrap_or_else(|| DEFAULT_PREFERRED_VIDS.to_vec())
+}
 
-    ports.sort_by_key(|port| {
-        if let SerialPortType::UsbPort(info) = &port.port_type {
-            if preferred_vids.contains(&format!("{:04x}", info.vid).as_str()) {
-                0
-            } else {
-                1
-            }
-        } else {
-            1
+// Rank of a port for sorting - 0 if it matches a preferred vid:pid pair, 1 if it matches a
+// preferred VID and 2 otherwise
+fn preferred_rank(port: &SerialPortInfo, preferred_vids: &[&str]) -> u8 {
+    let SerialPortType::UsbPort(info) = &port.port_type else {
+        return 2;
+    };
+    let vid = format!("{:04x}", info.vid);
+    let vid_pid = format!("{}:{:04x}", vid, info.pid);
+    let mut rank = 2;
+    for preferred in preferred_vids {
+        let preferred = preferred.trim().to_lowercase();
+        if preferred == vid_pid {
+            return 0;
+        }
+        if preferred == vid {
+            rank = 1;
         }
-    });
+    }
+    rank
+}
+
+fn is_preferred_port(port: &SerialPortInfo, preferred_vids: &[&str]) -> bool {
+    preferred_rank(port, preferred_vids) < 2
+}
+
+fn sort_ports(mut ports: Vec<SerialPortInfo>, cmd: &PortsCmd) -> Vec<SerialPortInfo> {
+    let preferred_vids = preferred_vids(cmd);
+    ports.sort_by_key(|port| preferred_rank(port, &preferred_vids));
     ports
 }
 
@@ -163,80 +220,149 @@ fn extra_usb_info(info: &UsbPortInfo) -> String {
     output
 }
 
-fn list_ports(cmd: &PortsCmd) -> Result<(), Box<dyn Error>> {
-    let ports_list = filtered_ports(cmd)?;
+fn render_ports_text(ports_list: &[SerialPortInfo]) -> String {
     if ports_list.is_empty() {
-        println!("No ports found");
-    } else {
-        for port in ports_list {
-            if let SerialPortType::UsbPort(info) = &port.port_type {
-                println!(
-                    "{} USB {}",
-                    port.port_name,
-                    extra_usb_info(&info)
-                );
-            } else {
-                println!("{} Serial Device", port.port_name);
-            }
+        return "No ports found".to_string();
+    }
+    let lines: Vec<String> = ports_list.iter().map(|port| {
+        if let SerialPortType::UsbPort(info) = &port.port_type {
+            format!("{} USB {}", port.port_name, extra_usb_info(info))
+        } else {
+            format!("{} Serial Device", port.port_name)
         }
+    }).collect();
+    lines.join("\n")
+}
+
+fn render_ports_json(ports_list: &[SerialPortInfo], cmd: &PortsCmd) -> Result<String, Box<dyn Error>> {
+    let preferred_vids = preferred_vids(cmd);
+    let ports_json: Vec<PortJson> = ports_list.iter().map(|port| {
+        let usb_info = match &port.port_type {
+            SerialPortType::UsbPort(info) => Some(info),
+            _ => None,
+        };
+        PortJson {
+            port_name: port.port_name.clone(),
+            vid: usb_info.map(|info| format!("{:04x}", info.vid)),
+            pid: usb_info.map(|info| format!("{:04x}", info.pid)),
+            manufacturer: usb_info.and_then(|info| info.manufacturer.clone()),
+            serial: usb_info.and_then(|info| info.serial_number.clone()),
+            product: usb_info.and_then(|info| info.product.clone()),
+            is_preferred: is_preferred_port(port, &preferred_vids),
+        }
+    }).collect();
+    Ok(serde_json::to_string_pretty(&ports_json)?)
+}
+
+fn list_ports(cmd: &PortsCmd) -> Result<(), Box<dyn Error>> {
+    let ports_list = filtered_ports(cmd)?;
+    if cmd.json {
+        println!("{}", render_ports_json(&ports_list, cmd)?);
+    } else if !(cmd.quiet && ports_list.is_empty()) {
+        println!("{}", render_ports_text(&ports_list));
     }
     Ok(())
 }
 
+// Get the ports from the Windows version of raft (when running in WSL) using JSON output - the
+// ports are sorted here so the preferred VIDs apply as for native ports (no ports are listed if
+// raft.exe can't be run)
+fn list_ports_via_windows_raft(cmd: &PortsCmd) -> Vec<SerialPortInfo> {
+    let mut args = vec!["ports", "--json"];
+    if let Some(vid) = &cmd.vid {
+        args.push("-v");
+        args.push(vid);
+    }
+    let output = match std::process::Command::new("raft.exe").args(args).output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Unable to run raft.exe to list the Windows serial ports (is it on the PATH?): {}", e);
+            return Vec::new();
+        }
+    };
+    let output = String::from_utf8_lossy(&output.stdout);
+    let ports_json: Vec<PortJson> = serde_json::from_str(&output).unwrap_or_default();
+    let ports = ports_json.into_iter().map(|port| {
+        let vid = port.vid.as_deref().and_then(|vid| u16::from_str_radix(vid, 16).ok());
+        let pid = port.pid.as_deref().and_then(|pid| u16::from_str_radix(pid, 16).ok());
+        let port_type = match vid {
+            Some(vid) => SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid: pid.unwrap_or(0),
+                manufacturer: port.manufacturer,
+                serial_number: port.serial,
+                product: port.product,
+            }),
+            None => SerialPortType::Unknown,
+        };
+        SerialPortInfo {
+            port_name: port.port_name,
+            port_type,
+        }
+    }).collect();
+    sort_ports(ports, cmd)
+}
+
 pub fn select_most_likely_port(cmd: &PortsCmd, native_serial_port: bool) -> Option<SerialPortInfo> {
-    // println!("select_most_likely_port cmd: {:?} native_serial_port: {:?}", cmd, native_serial_port);
+    trace!("select_most_likely_port cmd: {:?} native_serial_port: {:?}", cmd, native_serial_port);
     if is_wsl() && !native_serial_port {
-        // println!("WSL detected, looking for windows serial ports");
+        debug!("WSL detected, looking for windows serial ports");
         
-        // Use raft.exe ports <-v vid> to get the list of ports
-        let mut args = vec!["ports"];
-        if let Some(vid) = &cmd.vid {
-            args.push("-v");
-            args.push(vid);
-        }
-        let output = std::process::Command::new("raft.exe")
-            .args(args)
-            .output()
-            .expect("Failed to execute raft.exe ports");
-        let output = String::from_utf8_lossy(&output.stdout);
-        // println!("select_most_likely_port output: {:?}", output);
-        
-        // Check for "No ports" message (no ports found)
-        let no_ports_msg_pattern = "No ports";
-        if output.contains(no_ports_msg_pattern) {
-            // println!("No suitable serial ports found");
-            return None;
-        }
-        let lines: Vec<&str> = output.lines().collect();
-        let mut ports: Vec<SerialPortInfo> = Vec::new();
-        for line in lines {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() > 1 {
-                let port_name = parts[0].to_string();
-                let port_type = SerialPortType::UsbPort(UsbPortInfo {
-                    vid: 0x0403,
-                    pid: 0x0000,
-                    manufacturer: Some("FTDI".to_string()),
-                    serial_number: None,
-                    product: None,
-                });
-                ports.push(SerialPortInfo {
-                    port_name,
-                    port_type,
-                });
-            }
-        }
+        // Use raft.exe ports --json <-v vid> to get the list of ports
+        let ports = list_ports_via_windows_raft(cmd);
         if !ports.is_empty() {
-            // println!("select_most_likely_port found ports {:?}", ports);
+            trace!("select_most_likely_port found ports {:?}", ports);
             return Some(ports[0].clone());
         }
     }
     if let Ok(ports) = filtered_ports(cmd) {
         if !ports.is_empty() {
-            // println!("select_most_likely_port found ports {:?}", ports);
+            trace!("select_most_likely_port found ports {:?}", ports);
             return Some(ports[0].clone());
         }
     }
-    // println!("No ports found");
+    debug!("No ports found");
     None
 }
+
+// Select all the ports matching the filter (most likely first) e.g. to flash several boards
+pub fn select_matching_ports(cmd: &PortsCmd, native_serial_port: bool) -> Vec<SerialPortInfo> {
+    if is_wsl() && !native_serial_port {
+        let ports = list_ports_via_windows_raft(cmd);
+        if !ports.is_empty() {
+            return ports;
+        }
+    }
+    filtered_ports(cmd).unwrap_or_default()
+}
+
+// Interval between checks for a port appearing
+const PORT_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Select the most likely port, waiting up to wait_secs for one to appear (e.g. while a board that
+// has just been plugged in is enumerated) with a waiting indicator
+pub fn wait_for_likely_port(cmd: &PortsCmd, native_serial_port: bool, wait_secs: u64) -> Option<SerialPortInfo> {
+    if let Some(port) = select_most_likely_port(cmd, native_serial_port) {
+        return Some(port);
+    }
+    if wait_secs == 0 {
+        return None;
+    }
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+    let deadline = Instant::now() + Duration::from_secs(wait_secs);
+    let mut found = None;
+    let mut tick = 0;
+    while found.is_none() && Instant::now() < deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        print!("\rWaiting for a serial port {} {}s ", SPINNER[tick % SPINNER.len()], remaining.as_secs() + 1);
+        let _ = std::io::stdout().flush();
+        std::thread::sleep(PORT_WAIT_POLL_INTERVAL.min(remaining));
+        tick += 1;
+        found = select_most_likely_port(cmd, native_serial_port);
+    }
+    match &found {
+        Some(port) => println!("\rFound serial port {}            ", port.port_name),
+        None => println!("\rNo serial port appeared within {}s            ", wait_secs),
+    }
+    found
+}
@@ -0,0 +1,140 @@
+// RaftCLI: SysType sdkconfig editing module
+// Rob Dobson 2024
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::flat_key_values::FlatKeyValues;
+use crate::raft_cli_utils::{is_dry_run, utils_get_sys_type, BUILT_FIRMWARE_HASH_FILE};
+
+#[derive(Clone, Parser, Debug)]
+pub struct SdkconfigCmd {
+    #[clap(subcommand)]
+    pub action: SdkconfigAction,
+    #[clap(short = 's', long, global = true, help = "System type whose sdkconfig.defaults is used")]
+    pub sys_type: Option<String>,
+}
+
+#[derive(Clone, Subcommand, Debug)]
+pub enum SdkconfigAction {
+    #[clap(about = "Get the value of a key in the SysType's sdkconfig.defaults")]
+    Get {
+        key: String,
+        app_folder: Option<String>,
+    },
+    #[clap(about = "Set the value of a key in the SysType's sdkconfig.defaults")]
+    Set {
+        key: String,
+        value: String,
+        app_folder: Option<String>,
+    },
+}
+
+impl SdkconfigCmd {
+    pub fn app_folder(&self) -> Option<String> {
+        match &self.action {
+            SdkconfigAction::Get { app_folder, .. } | SdkconfigAction::Set { app_folder, .. } => app_folder.clone(),
+        }
+    }
+}
+
+// Get the path of the SysType's sdkconfig.defaults file
+fn sdkconfig_defaults_path(app_folder: &str, sys_type: &str) -> PathBuf {
+    PathBuf::from(app_folder).join("systypes").join(sys_type).join("sdkconfig.defaults")
+}
+
+// ESP-IDF only applies sdkconfig.defaults when sdkconfig is created so remove the generated
+// sdkconfig (and the built firmware hash so the next run flashes the new build)
+fn mark_sdkconfig_stale(app_folder: &str, sys_type: &str) -> Result<(), std::io::Error> {
+    let stale_files = [
+        PathBuf::from(app_folder).join("build").join(sys_type).join("sdkconfig"),
+        PathBuf::from(app_folder).join("build_raft_artifacts").join("sdkconfig"),
+        PathBuf::from(app_folder).join("build_raft_artifacts").join(BUILT_FIRMWARE_HASH_FILE),
+    ];
+    for stale_file in stale_files.iter().filter(|file| file.exists()) {
+        std::fs::remove_file(stale_file)?;
+    }
+    Ok(())
+}
+
+// Get the path (relative to the app folder) of the sdkconfig defaults generated for a build
+// with extra settings - this is outside build/<SysType> so that cleaning doesn't remove it
+pub fn sdkconfig_override_path(sys_type: &str) -> String {
+    format!("build/{}.sdkconfig.override", sys_type)
+}
+
+// Merge the settings in the extra file into the SysType's sdkconfig.defaults (overriding keys
+// that are in both) and write the result to the override file which is returned so it can be
+// used in place of sdkconfig.defaults - if the merged settings have changed (or extra settings
+// are no longer used) the generated sdkconfig is removed so the new settings are applied
+pub fn prepare_sdkconfig_override(app_folder: &str, sys_type: &str, sdkconfig_extra: Option<&str>)
+                -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let override_path = sdkconfig_override_path(sys_type);
+    let override_file = PathBuf::from(app_folder).join(&override_path);
+    let Some(sdkconfig_extra) = sdkconfig_extra else {
+        if override_file.exists() && !is_dry_run() {
+            std::fs::remove_file(&override_file)?;
+            mark_sdkconfig_stale(app_folder, sys_type)?;
+        }
+        return Ok(None);
+    };
+
+    // Merge the extra settings
+    let sdkconfig_path = sdkconfig_defaults_path(app_folder, sys_type);
+    let mut key_values = FlatKeyValues::load_from_file(&sdkconfig_path)
+        .map_err(|e| format!("Unable to read {}: {}", sdkconfig_path.display(), e))?;
+    let extra_key_values = FlatKeyValues::load_from_file(std::path::Path::new(sdkconfig_extra))
+        .map_err(|e| format!("Unable to read sdkconfig extra file {}: {}", sdkconfig_extra, e))?;
+    for (key, value) in extra_key_values.entries() {
+        key_values.insert(key, value);
+    }
+
+    // Only rewrite the override file if it has changed
+    let contents = key_values.to_string();
+    if std::fs::read_to_string(&override_file).ok().as_deref() == Some(contents.as_str()) {
+        return Ok(Some(override_path));
+    }
+    if is_dry_run() {
+        println!("Would write {} with extra settings from {}", override_file.display(), sdkconfig_extra);
+        return Ok(Some(override_path));
+    }
+    if let Some(parent) = override_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    key_values.save_to_file(&override_file)?;
+    mark_sdkconfig_stale(app_folder, sys_type)?;
+    println!("Using extra sdkconfig settings from {} ({})", sdkconfig_extra, override_file.display());
+    Ok(Some(override_path))
+}
+
+pub fn sdkconfig_raft_app(cmd: &SdkconfigCmd, app_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let sys_type = utils_get_sys_type(&cmd.sys_type, app_folder.to_string())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
+    let sdkconfig_path = sdkconfig_defaults_path(app_folder, &sys_type);
+    let mut key_values = FlatKeyValues::load_from_file(&sdkconfig_path)
+        .map_err(|e| format!("Unable to read {}: {}", sdkconfig_path.display(), e))?;
+
+    match &cmd.action {
+        SdkconfigAction::Get { key, .. } => {
+            match key_values.get(key) {
+                Some(value) => println!("{}", value),
+                None => return Err(format!("{} not set in {}", key, sdkconfig_path.display()).into()),
+            }
+        }
+        SdkconfigAction::Set { key, value, .. } => {
+            if key_values.get(key) == Some(value.as_str()) {
+                println!("{} is already {} in {}", key, value, sdkconfig_path.display());
+                return Ok(());
+            }
+            if is_dry_run() {
+                println!("Would set {}={} in {}", key, value, sdkconfig_path.display());
+                return Ok(());
+            }
+            key_values.insert(key, value);
+            key_values.save_to_file(&sdkconfig_path)?;
+            mark_sdkconfig_stale(app_folder, &sys_type)?;
+            println!("Set {}={} in {} (applied on the next build)", key, value, sdkconfig_path.display());
+        }
+    }
+    Ok(())
+}
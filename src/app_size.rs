@@ -0,0 +1,91 @@
+// RaftCLI: Firmware size module
+// Rob Dobson 2024
+
+use crossterm::{execute, style::{Color, ResetColor, SetForegroundColor}};
+use std::collections::HashMap;
+
+use crate::app_build::{build_docker_image, docker_image_name, get_docker_project_volume, get_idf_path, get_local_idf_env_vars, use_docker_for_build, BuildOptions};
+use crate::partition_table::{app_partition, read_partition_table};
+use crate::raft_cli_utils::{check_app_folder_valid, execute_and_capture_output, get_build_folder_name, read_flasher_args, utils_get_sys_type};
+use crate::target_settings::sys_type_settings;
+
+// Partitions above this percentage full are shown as a warning
+const APP_PARTITION_WARN_PERCENT: f64 = 90.0;
+
+// Run idf.py size for the SysType (in docker or using a local ESP-IDF as for build)
+fn run_idf_size(app_folder: &str, sys_type: &str, build_options: &BuildOptions) -> Result<bool, Box<dyn std::error::Error>> {
+    let (command, args, env_vars) = if use_docker_for_build(build_options, app_folder)? {
+        build_docker_image(app_folder, build_options)?;
+        let project_dir_full = get_docker_project_volume(app_folder)?;
+        let docker_image = docker_image_name(app_folder, build_options);
+        let command_sequence = format!("idf.py -B ./build/{} size", sys_type);
+        let args = ["run", "--rm", "-v", &project_dir_full, "-w", "/project", &docker_image, "/bin/bash", "-c", &command_sequence]
+            .iter().map(|s| s.to_string()).collect();
+        ("docker".to_string(), args, HashMap::new())
+    } else {
+        let env_vars = get_local_idf_env_vars(app_folder, get_idf_path(build_options), build_options.idf_exact)?;
+        let args = vec!["-B".to_string(), format!("build/{}", sys_type), "size".to_string()];
+        ("idf.py".to_string(), args, env_vars)
+    };
+    let outcome = execute_and_capture_output(command, &args, app_folder.to_string(), env_vars)?;
+    Ok(outcome.success())
+}
+
+// Show the app image size against the size of the app partition
+fn show_app_partition_usage(app_folder: &str, sys_type: &str, build_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let flash_args = read_flasher_args(build_folder)?;
+    let app_file = flash_args["app"]["file"].as_str()
+        .ok_or_else(|| std::io::Error::other("App image not found in flasher_args.json"))?;
+    let app_size = std::fs::metadata(format!("{}/{}", build_folder, app_file))?.len();
+
+    // Get the app partition from the partition table (which may be set in the SysTypes manifest)
+    let partition_file = sys_type_settings(app_folder, sys_type).partition_file
+        .ok_or_else(|| std::io::Error::other(format!("Partition table not found for SysType {}", sys_type)))?;
+    let partitions = read_partition_table(&partition_file)?;
+    let partition = app_partition(&partitions)
+        .ok_or_else(|| std::io::Error::other(format!("No app partition found in {}", partition_file.display())))?;
+
+    // Show usage - in red if the partition is nearly full
+    let percent_used = app_size as f64 * 100.0 / partition.size as f64;
+    let over_limit = percent_used > APP_PARTITION_WARN_PERCENT;
+    if over_limit {
+        execute!(std::io::stdout(), SetForegroundColor(Color::Red))?;
+    }
+    println!("App image {} is {} bytes - partition {} is {} bytes ({} bytes free, {:.1}% used)",
+                app_file, app_size, partition.name, partition.size,
+                partition.size as i64 - app_size as i64, percent_used);
+    if over_limit {
+        println!("Warning: app partition is more than {:.0}% full", APP_PARTITION_WARN_PERCENT);
+        execute!(std::io::stdout(), ResetColor)?;
+    }
+    Ok(())
+}
+
+// Report the memory usage of the built firmware for the SysType
+pub fn size_raft_app(build_sys_type: &Option<String>, app_folder: String, build_options: &BuildOptions)
+                -> Result<(), Box<dyn std::error::Error>> {
+
+    // Check the app folder is valid
+    if !check_app_folder_valid(app_folder.clone()) {
+        return Err(Box::new(std::io::Error::other("Invalid app folder")));
+    }
+
+    // Determine the SysType
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.clone())
+        .map_err(|e| format!("Error determining SysType: {}", e))?;
+
+    // The firmware must have been built
+    let build_folder = get_build_folder_name(sys_type.clone(), app_folder.clone());
+    if !std::path::Path::new(&format!("{}/flasher_args.json", build_folder)).exists() {
+        return Err(Box::new(std::io::Error::other(format!("SysType {} has not been built", sys_type))));
+    }
+
+    // Show the breakdown of flash/IRAM/DRAM usage from idf.py size
+    println!("Raft size SysType {} in {}", sys_type, app_folder);
+    if !run_idf_size(&app_folder, &sys_type, build_options)? {
+        println!("Warning: idf.py size failed");
+    }
+
+    // Show free space in the app partition
+    show_app_partition_usage(&app_folder, &sys_type, &build_folder)
+}
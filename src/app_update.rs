@@ -0,0 +1,239 @@
+// RaftCLI: Update raft library versions module
+// Rob Dobson 2024
+
+use clap::Parser;
+use dialoguer::Input;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::raft_cli_utils::is_dry_run;
+
+#[derive(Clone, Parser, Debug)]
+pub struct UpdateCmd {
+    // Option to specify the app folder
+    pub app_folder: Option<String>,
+    #[clap(long = "tag", help = "New git tag for a library as name=tag (e.g. core=v1.2.3 or RaftSysMods=main) - may be repeated")]
+    pub tags: Vec<String>,
+    #[clap(long, help = "Only show the git tags currently used")]
+    pub list: bool,
+}
+
+// Git tags must be valid git refs (also used to validate the tags for a new app)
+pub const GIT_TAG_PATTERN: &str = r"^[A-Za-z0-9_.\-/]+$";
+
+// A raft library git tag found in a project file
+#[derive(Clone, Debug)]
+struct LibraryTag {
+    // Library name (e.g. RaftCore)
+    library: String,
+    tag: String,
+    file: PathBuf,
+}
+
+// Find the raft library git tags in the contents of a project file - RaftCore is fetched in
+// the top level CMakeLists.txt and other libraries are listed as Name@tag in features.cmake
+fn find_library_tags(contents: &str, file: &Path) -> Vec<LibraryTag> {
+    let core_re = Regex::new(r"/(RaftCore)\.git\s+GIT_TAG\s+(\S+)").unwrap();
+    let component_re = Regex::new(r"\b(Raft[A-Za-z0-9_]+)@([A-Za-z0-9_.\-/]+)").unwrap();
+    core_re.captures_iter(contents)
+        .chain(component_re.captures_iter(contents))
+        .map(|caps| LibraryTag {
+            library: caps[1].to_string(),
+            tag: caps[2].to_string(),
+            file: file.to_path_buf(),
+        })
+        .collect()
+}
+
+// Replace the git tag of a library in the contents of a project file
+fn replace_library_tag(contents: &str, library: &str, new_tag: &str) -> String {
+    let core_re = Regex::new(&format!(r"(/{}\.git\s+GIT_TAG\s+)\S+", regex::escape(library))).unwrap();
+    let component_re = Regex::new(&format!(r"\b({}@)[A-Za-z0-9_.\-/]+", regex::escape(library))).unwrap();
+    let contents = core_re.replace_all(contents, format!("${{1}}{}", new_tag));
+    component_re.replace_all(&contents, format!("${{1}}{}", new_tag)).to_string()
+}
+
+// Project files that contain raft library git tags
+fn project_dependency_files(app_folder: &str) -> Vec<PathBuf> {
+    let mut files = vec![Path::new(app_folder).join("CMakeLists.txt")];
+    if let Ok(entries) = std::fs::read_dir(Path::new(app_folder).join("systypes")) {
+        let mut features_files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().join("features.cmake"))
+            .collect();
+        features_files.sort();
+        files.extend(features_files);
+    }
+    files.into_iter().filter(|file| file.exists()).collect()
+}
+
+// Match a library name given on the command line (e.g. core, sysmods or RaftSysMods)
+fn matches_library(name: &str, library: &str) -> bool {
+    let name = name.to_lowercase();
+    let library = library.to_lowercase();
+    name == library || format!("raft{}", name) == library
+}
+
+// Get the new tags from --tag arguments (or by asking for each library)
+fn get_new_tags(cmd: &UpdateCmd, libraries: &[(String, String)]) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let tag_re = Regex::new(GIT_TAG_PATTERN)?;
+    let mut new_tags = Vec::new();
+    if cmd.tags.is_empty() {
+        for (library, current_tag) in libraries {
+            let new_tag: String = Input::new()
+                .with_prompt(format!("{} git tag", library))
+                .default(current_tag.clone())
+                .validate_with(|input: &String| {
+                    if tag_re.is_match(input) { Ok(()) } else { Err("Invalid git tag") }
+                })
+                .interact_text()?;
+            new_tags.push((library.clone(), new_tag));
+        }
+        return Ok(new_tags);
+    }
+    for tag_arg in &cmd.tags {
+        let Some((name, new_tag)) = tag_arg.split_once('=') else {
+            return Err(format!("Invalid --tag {} (expected name=tag)", tag_arg).into());
+        };
+        if !tag_re.is_match(new_tag) {
+            return Err(format!("Invalid git tag {} for {}", new_tag, name).into());
+        }
+        let Some((library, _)) = libraries.iter().find(|(library, _)| matches_library(name, library)) else {
+            let names: Vec<&str> = libraries.iter().map(|(library, _)| library.as_str()).collect();
+            return Err(format!("Library {} not found in project (found {})", name, names.join(", ")).into());
+        };
+        new_tags.push((library.clone(), new_tag.to_string()));
+    }
+    Ok(new_tags)
+}
+
+// Show and update the git tags of the raft libraries used by an app
+pub fn update_raft_app(cmd: &UpdateCmd, app_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+
+    // Find the tags currently used
+    let files = project_dependency_files(app_folder);
+    let mut current_tags = Vec::new();
+    for file in &files {
+        let contents = std::fs::read_to_string(file)?;
+        current_tags.extend(find_library_tags(&contents, file));
+    }
+    if current_tags.is_empty() {
+        return Err(format!("No raft library git tags found in {}", app_folder).into());
+    }
+    println!("Current git tags:");
+    for library_tag in &current_tags {
+        let file = library_tag.file.strip_prefix(app_folder).unwrap_or(&library_tag.file);
+        println!("  {:<16} {:<16} ({})", library_tag.library, library_tag.tag, file.display());
+    }
+    if cmd.list {
+        return Ok(());
+    }
+
+    // Libraries (with the first tag found for each) in the order found
+    let mut libraries: Vec<(String, String)> = Vec::new();
+    for library_tag in &current_tags {
+        if !libraries.iter().any(|(library, _)| library == &library_tag.library) {
+            libraries.push((library_tag.library.clone(), library_tag.tag.clone()));
+        }
+    }
+    let new_tags = get_new_tags(cmd, &libraries)?;
+
+    // Update each file that uses a changed tag
+    for file in &files {
+        let contents = std::fs::read_to_string(file)?;
+        let mut new_contents = contents.clone();
+        for (library, new_tag) in &new_tags {
+            new_contents = replace_library_tag(&new_contents, library, new_tag);
+        }
+        if new_contents == contents {
+            continue;
+        }
+
+        // Check the updated file still has the same libraries with the new tags
+        let old_libraries: Vec<String> = find_library_tags(&contents, file).into_iter().map(|tag| tag.library).collect();
+        let updated_tags = find_library_tags(&new_contents, file);
+        let updated_ok = updated_tags.len() == old_libraries.len()
+            && updated_tags.iter().all(|updated| new_tags.iter()
+                .find(|(library, _)| library == &updated.library)
+                .is_none_or(|(_, new_tag)| new_tag == &updated.tag));
+        if !updated_ok {
+            return Err(format!("Unable to update {} (file not changed)", file.display()).into());
+        }
+
+        if is_dry_run() {
+            println!("Would update {}", file.display());
+            continue;
+        }
+
+        // Keep a backup so the edit can be reverted
+        let backup_file = PathBuf::from(format!("{}.bak", file.display()));
+        std::fs::copy(file, &backup_file)?;
+        std::fs::write(file, new_contents)?;
+        println!("Updated {} (backup in {})", file.display(), backup_file.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CMAKE_LISTS: &str = "# Fetch the RaftCore library\n\
+        FetchContent_Declare(\n\
+        \x20   raftcore\n\
+        \x20   SOURCE_DIR RaftCore\n\
+        \x20   GIT_REPOSITORY https://github.com/robdobsn/RaftCore.git\n\
+        \x20   GIT_TAG        v1.2.3\n\
+        )\n\
+        FetchContent_Populate(raftcore)\n";
+
+    const FEATURES_CMAKE: &str = "# Raft components\n\
+        set(RAFT_COMPONENTS\n\
+        \x20   RaftSysMods@main\n\
+        \x20   RaftSysModsExtra@v0.1\n\
+        \x20   RaftWebServer@release/1.x\n\
+        )\n";
+
+    fn tags(contents: &str) -> Vec<(String, String)> {
+        find_library_tags(contents, Path::new("test.cmake")).into_iter()
+            .map(|tag| (tag.library, tag.tag))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_library_tags() {
+        assert_eq!(tags(CMAKE_LISTS), vec![("RaftCore".to_string(), "v1.2.3".to_string())]);
+        assert_eq!(tags(FEATURES_CMAKE), vec![
+            ("RaftSysMods".to_string(), "main".to_string()),
+            ("RaftSysModsExtra".to_string(), "v0.1".to_string()),
+            ("RaftWebServer".to_string(), "release/1.x".to_string()),
+        ]);
+        assert!(tags("set(RAFT_COMPONENTS\n)\n").is_empty());
+    }
+
+    #[test]
+    fn test_replace_library_tag_cmake_lists() {
+        let updated = replace_library_tag(CMAKE_LISTS, "RaftCore", "v1.3.0");
+        // Only the tag changes
+        assert_eq!(updated, CMAKE_LISTS.replace("v1.2.3", "v1.3.0"));
+        // Other libraries aren't in this file so nothing changes
+        assert_eq!(replace_library_tag(CMAKE_LISTS, "RaftSysMods", "v2.0.0"), CMAKE_LISTS);
+    }
+
+    #[test]
+    fn test_replace_library_tag_features_cmake() {
+        let updated = replace_library_tag(FEATURES_CMAKE, "RaftSysMods", "v1.4.2");
+        // A library whose name starts with the same text is left unchanged
+        assert_eq!(updated, FEATURES_CMAKE.replace("RaftSysMods@main", "RaftSysMods@v1.4.2"));
+        let updated = replace_library_tag(FEATURES_CMAKE, "RaftWebServer", "v2.0.0");
+        assert_eq!(updated, FEATURES_CMAKE.replace("RaftWebServer@release/1.x", "RaftWebServer@v2.0.0"));
+    }
+
+    #[test]
+    fn test_matches_library() {
+        assert!(matches_library("core", "RaftCore"));
+        assert!(matches_library("RaftSysMods", "RaftSysMods"));
+        assert!(matches_library("webserver", "RaftWebServer"));
+        assert!(!matches_library("sysmods", "RaftSysModsExtra"));
+    }
+}
@@ -0,0 +1,62 @@
+// RaftCLI: Tool versions module
+// Rob Dobson 2024
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::raft_cli_utils::{find_executable, get_esp_idf_version_from_dockerfile, get_flash_tool_cmd,
+            get_tool_version, parse_idf_version};
+
+#[derive(Clone, Parser, Debug)]
+pub struct VersionsCmd {
+    // Option to specify the app folder (for the ESP-IDF version required by its Dockerfile)
+    pub app_folder: Option<String>,
+    #[clap(long, help = "Output the versions as JSON")]
+    pub json: bool,
+    #[clap(long, help = "Native serial port when in WSL (selects the esptool used)")]
+    pub native_serial_port: bool,
+    #[clap(long, help = "Flash tool (e.g. esptool)")]
+    pub flash_tool: Option<String>,
+}
+
+// Version of raftcli or one of the tools it uses (None if not found)
+#[derive(Serialize, Debug)]
+struct ToolVersion {
+    name: String,
+    version: Option<String>,
+}
+
+// Get the versions of raftcli and the external tools it uses
+fn get_versions(cmd: &VersionsCmd, app_folder: &str) -> Vec<ToolVersion> {
+    let mut versions = vec![ToolVersion {
+        name: "raftcli".to_string(),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }];
+    versions.push(ToolVersion { name: "docker".to_string(), version: get_tool_version("docker") });
+    versions.push(ToolVersion {
+        name: "idf.py".to_string(),
+        version: get_tool_version("idf.py").map(|output| parse_idf_version(&output).to_string()),
+    });
+    let flash_cmd = get_flash_tool_cmd(cmd.flash_tool.clone(), cmd.native_serial_port);
+    versions.push(ToolVersion { name: flash_cmd.clone(), version: get_tool_version(&flash_cmd) });
+    let python_cmd = find_executable(&["python3", "python"]).unwrap_or("python3".to_string());
+    versions.push(ToolVersion { name: python_cmd.clone(), version: get_tool_version(&python_cmd) });
+    versions.push(ToolVersion {
+        name: "ESP-IDF (Dockerfile)".to_string(),
+        version: get_esp_idf_version_from_dockerfile(app_folder).ok(),
+    });
+    versions
+}
+
+// Show the versions of raftcli and the external tools it uses
+pub fn show_versions(cmd: &VersionsCmd, app_folder: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let versions = get_versions(cmd, app_folder);
+    if cmd.json {
+        println!("{}", serde_json::to_string_pretty(&versions)?);
+        return Ok(());
+    }
+    for tool_version in &versions {
+        println!("{:<24} {}", tool_version.name, tool_version.version.as_deref().unwrap_or("not found"));
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+// RaftCLI: Build cache module
+// Rob Dobson 2024
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::process::Command;
+
+use crate::raft_cli_utils::get_tool_version;
+
+// Docker volume holding the ccache directory so the cache persists across container runs
+pub const DOCKER_CCACHE_VOLUME: &str = "raftbuilder-ccache";
+
+// Location of the ccache directory in the container
+pub const DOCKER_CCACHE_DIR: &str = "/ccache";
+
+// Docker volume for the caches kept in the container's home folder (e.g. the ESP-IDF component
+// manager and pip caches) - docker creates it the first time it is used
+pub const DOCKER_BUILD_CACHE_VOLUME: &str = "raftbuilder-cache";
+
+// Location of the caches in the container (the builder runs as root)
+pub const DOCKER_BUILD_CACHE_DIR: &str = "/root/.cache";
+
+// Compiler cache statistics (from ccache -s)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CcacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CcacheStats {
+    // Statistics for the calls made since the earlier statistics were read
+    pub fn since(&self, earlier: &CcacheStats) -> CcacheStats {
+        CcacheStats {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+        }
+    }
+}
+
+// Check if ccache is installed
+pub fn is_ccache_available() -> bool {
+    get_tool_version("ccache").is_some()
+}
+
+// Environment variables enabling ccache in idf.py builds
+pub fn ccache_env_vars() -> HashMap<String, String> {
+    HashMap::from([("IDF_CCACHE_ENABLE".to_string(), "1".to_string())])
+}
+
+// Get the hits and misses from ccache -s output - ccache 4 reports "Hits: 10 / 12 (83.33%)"
+// and "Misses: 2 / 12" for cacheable calls (repeated for each storage in verbose output) while
+// ccache 3 reports "cache hit (direct) 10", "cache hit (preprocessed) 0" and "cache miss 2"
+pub fn parse_ccache_stats(output: &str) -> Option<CcacheStats> {
+    let count = |pattern: &str| -> Option<u64> {
+        Regex::new(pattern).unwrap().captures(output).and_then(|caps| caps[1].parse().ok())
+    };
+    if let (Some(hits), Some(misses)) = (count(r"(?m)^\s*Hits:\s+(\d+)"), count(r"(?m)^\s*Misses:\s+(\d+)")) {
+        return Some(CcacheStats { hits, misses });
+    }
+    let hits_direct = count(r"(?m)^cache hit \(direct\)\s+(\d+)");
+    let hits_preprocessed = count(r"(?m)^cache hit \(preprocessed\)\s+(\d+)");
+    let misses = count(r"(?m)^cache miss\s+(\d+)")?;
+    Some(CcacheStats { hits: hits_direct.unwrap_or(0) + hits_preprocessed.unwrap_or(0), misses })
+}
+
+// Read the statistics of the local ccache
+pub fn read_ccache_stats() -> Option<CcacheStats> {
+    let output = Command::new("ccache").arg("-s").output().ok()?;
+    parse_ccache_stats(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Read the statistics of the ccache in the docker volume (using the docker build image)
+pub fn read_docker_ccache_stats(docker_image: &str) -> Option<CcacheStats> {
+    let output = Command::new("docker")
+        .args(["run", "--rm", "-v", &format!("{}:{}", DOCKER_CCACHE_VOLUME, DOCKER_CCACHE_DIR),
+                "-e", &format!("CCACHE_DIR={}", DOCKER_CCACHE_DIR), docker_image, "ccache", "-s"])
+        .output().ok()?;
+    parse_ccache_stats(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Show the cache hits and misses for a build
+pub fn report_ccache_stats(stats: &CcacheStats) {
+    let calls = stats.hits + stats.misses;
+    if calls == 0 {
+        println!("ccache: no cacheable compilations");
+        return;
+    }
+    println!("ccache: {} hits, {} misses ({:.1}% hit rate)",
+                stats.hits, stats.misses, stats.hits as f64 * 100.0 / calls as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ccache_stats_v3() {
+        let output = "cache directory                     /home/user/.ccache\n\
+            primary config                      /home/user/.ccache/ccache.conf\n\
+            secondary config      (readonly)    /etc/ccache.conf\n\
+            stats updated                       Mon Jan 10 10:00:00 2022\n\
+            cache hit (direct)                    95\n\
+            cache hit (preprocessed)               2\n\
+            cache miss                            25\n\
+            cache hit rate                     79.51 %\n\
+            called for link                        3\n\
+            files in cache                       150\n\
+            cache size                           1.2 MB\n\
+            max cache size                       5.0 GB\n";
+        assert_eq!(parse_ccache_stats(output), Some(CcacheStats { hits: 97, misses: 25 }));
+    }
+
+    #[test]
+    fn test_parse_ccache_stats_v4() {
+        let output = "Cacheable calls:   122 / 128 (95.31%)\n\
+            \x20 Hits:             97 / 122 (79.51%)\n\
+            \x20   Direct:         95 /  97 (97.94%)\n\
+            \x20   Preprocessed:    2 /  97 ( 2.06%)\n\
+            \x20 Misses:           25 / 122 (20.49%)\n\
+            Uncacheable calls:   6 / 128 ( 4.69%)\n\
+            Local storage:\n\
+            \x20 Cache size (GB): 0.1 / 5.0 ( 1.20%)\n\
+            \x20 Hits:             97 / 122 (79.51%)\n\
+            \x20 Misses:           25 / 122 (20.49%)\n";
+        assert_eq!(parse_ccache_stats(output), Some(CcacheStats { hits: 97, misses: 25 }));
+        // An empty cache
+        let output = "Local storage:\n  Cache size (GB): 0.0 / 5.0 ( 0.00%)\n";
+        assert_eq!(parse_ccache_stats(output), None);
+    }
+
+    #[test]
+    fn test_ccache_stats_since() {
+        let before = CcacheStats { hits: 97, misses: 25 };
+        let after = CcacheStats { hits: 150, misses: 27 };
+        assert_eq!(after.since(&before), CcacheStats { hits: 53, misses: 2 });
+        // Statistics zeroed (ccache -z) during the build
+        assert_eq!(CcacheStats::default().since(&before), CcacheStats::default());
+    }
+}
@@ -5,34 +5,95 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 
+// Default name of the serial monitor's command history file in the app folder
+pub const MONITOR_HISTORY_FILE_NAME: &str = ".raftcli_monitor_history";
+
+// Default name of the debug console's command history file in the app folder
+pub const DEBUG_HISTORY_FILE_NAME: &str = ".raftcli_debug_history";
+
+// Default maximum number of commands kept in the history file (RAFT_HISTORY_MAX overrides)
+const DEFAULT_HISTORY_MAX_ENTRIES: usize = 1000;
+
 pub struct CommandHistory {
     history: Vec<String>,
     position: usize,
     history_file_path: String,
+    max_entries: usize,
+    // Remove earlier copies of a command when it is used again
+    dedup: bool,
 }
 
 impl CommandHistory {
     pub fn new(history_file_path: &str) -> CommandHistory {
+        // History size and deduplication can be set with environment variables
+        let max_entries = std::env::var("RAFT_HISTORY_MAX").ok()
+            .and_then(|max_entries| max_entries.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_HISTORY_MAX_ENTRIES);
+        let dedup = std::env::var("RAFT_HISTORY_DEDUP").unwrap_or("false".to_string()) == "true";
+        CommandHistory::new_with_options(history_file_path, max_entries, dedup)
+    }
+
+    pub fn new_with_options(history_file_path: &str, max_entries: usize, dedup: bool) -> CommandHistory {
         let mut history = Vec::new();
 
+        // Create the folder for the history file if needed (otherwise history isn't saved)
+        if let Some(parent) = Path::new(history_file_path).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+        }
+
         // Load history from the file if it exists
         if Path::new(history_file_path).exists() {
             if let Ok(file) = File::open(history_file_path) {
                 let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    if let Ok(command) = line {
-                        history.push(command);
-                    }
+                for command in reader.lines().map_while(Result::ok) {
+                    history.push(command);
                 }
             }
         }
 
-        let position = history.len();
-
-        CommandHistory {
+        let mut command_history = CommandHistory {
             history,
-            position,
+            position: 0,
             history_file_path: history_file_path.to_string(),
+            max_entries: max_entries.max(1),
+            dedup,
+        };
+
+        // Apply the limits to the loaded history and rewrite the file if they changed it
+        if command_history.apply_limits() {
+            command_history.rewrite_file();
+        }
+        command_history.position = command_history.history.len();
+        command_history
+    }
+
+    // Remove duplicates (if enabled) keeping the most recent copy and then the oldest commands
+    // beyond the maximum number of entries - returns true if anything was removed
+    fn apply_limits(&mut self) -> bool {
+        let original_len = self.history.len();
+        if self.dedup {
+            let mut seen = std::collections::HashSet::new();
+            let mut deduped: Vec<String> = self.history.drain(..).rev()
+                .filter(|command| seen.insert(command.clone()))
+                .collect();
+            deduped.reverse();
+            self.history = deduped;
+        }
+        if self.history.len() > self.max_entries {
+            let num_to_remove = self.history.len() - self.max_entries;
+            self.history.drain(..num_to_remove);
+        }
+        self.history.len() != original_len
+    }
+
+    // Write the whole history to the file
+    fn rewrite_file(&self) {
+        if let Ok(mut file) = File::create(&self.history_file_path) {
+            for command in &self.history {
+                let _ = writeln!(file, "{}", command);
+            }
         }
     }
 
@@ -41,10 +102,12 @@ impl CommandHistory {
             // Avoid duplicate consecutive entries
             if self.history.is_empty() || self.history.last().unwrap() != command {
                 self.history.push(command.to_string());
-                self.position = self.history.len();
 
-                // Append command to history file
-                if let Ok(mut file) = OpenOptions::new()
+                // Rewrite the file if the limits removed older commands or otherwise append
+                // the command to the history file
+                if self.apply_limits() {
+                    self.rewrite_file();
+                } else if let Ok(mut file) = OpenOptions::new()
                     .create(true)
                     .append(true)
                     .open(&self.history_file_path)
@@ -52,6 +115,7 @@ impl CommandHistory {
                     writeln!(file, "{}", command).unwrap();
                 }
             }
+            self.position = self.history.len();
         }
     }
 
@@ -75,6 +139,17 @@ impl CommandHistory {
             None
         }
     }
+
+    // Find the most recent command before index from (use usize::MAX to search all history)
+    // that contains the query and return its index and the command
+    pub fn search_backward(&self, query: &str, from: usize) -> Option<(usize, &str)> {
+        let from = from.min(self.history.len());
+        self.history[..from].iter()
+            .enumerate()
+            .rev()
+            .find(|(_, command)| command.contains(query))
+            .map(|(index, command)| (index, command.as_str()))
+    }
 }
 
 #[cfg(test)]
@@ -106,4 +181,50 @@ mod tests {
         // Cleanup
         let _ = fs::remove_file(test_history_path);
     }
+
+    #[test]
+    fn test_history_limits() {
+        let test_history_path = "test_raftcli_history_limits.txt";
+        let _ = fs::remove_file(test_history_path);
+
+        // Oldest commands are removed beyond the maximum and earlier duplicates are removed
+        let mut command_history = CommandHistory::new_with_options(test_history_path, 3, true);
+        command_history.add_command("a");
+        command_history.add_command("b");
+        command_history.add_command("a");
+        command_history.add_command("c");
+        command_history.add_command("d");
+        assert_eq!(fs::read_to_string(test_history_path).unwrap(), "a\nc\nd\n");
+
+        // Limits are also applied when loading
+        let mut command_history = CommandHistory::new_with_options(test_history_path, 2, false);
+        assert_eq!(command_history.get_previous(), Some("d"));
+        assert_eq!(command_history.get_previous(), Some("c"));
+        assert_eq!(command_history.get_previous(), None);
+        assert_eq!(fs::read_to_string(test_history_path).unwrap(), "c\nd\n");
+
+        // Cleanup
+        let _ = fs::remove_file(test_history_path);
+    }
+
+    #[test]
+    fn test_search_backward() {
+        let test_history_path = "test_raftcli_history_search.txt";
+        let _ = fs::remove_file(test_history_path);
+
+        let mut command_history = CommandHistory::new(test_history_path);
+
+        command_history.add_command("wifi set ssid");
+        command_history.add_command("ble scan");
+        command_history.add_command("wifi status");
+
+        assert_eq!(command_history.search_backward("wifi", usize::MAX), Some((2, "wifi status")));
+        assert_eq!(command_history.search_backward("wifi", 2), Some((0, "wifi set ssid")));
+        assert_eq!(command_history.search_backward("wifi", 0), None);
+        assert_eq!(command_history.search_backward("scan", usize::MAX), Some((1, "ble scan")));
+        assert_eq!(command_history.search_backward("missing", usize::MAX), None);
+
+        // Cleanup
+        let _ = fs::remove_file(test_history_path);
+    }
 }
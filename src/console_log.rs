@@ -0,0 +1,470 @@
+// RaftCLI: Console logging module
+// Rob Dobson 2024
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use regex::Regex;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub struct LogFileInfo {
+    file: std::fs::File,
+    path: PathBuf,
+    // Threads compressing rotated log files (waited for when logging ends)
+    compress_threads: Vec<JoinHandle<()>>,
+    last_write: Instant,
+    created_at: Instant,
+    bytes_written: u64,
+    options: ConsoleLogOptions,
+    // Text received since the last line ending (when normalizing line endings)
+    partial_line: String,
+    // Last character received was \r (so a following \n is part of the same line ending)
+    pending_cr: bool,
+    // Last character written ended a line (so the next line gets a timestamp)
+    at_line_start: bool,
+    // Wall clock and monotonic times when logging started (timestamps are the wall clock time
+    // plus the monotonic time since so they aren't affected by changes to the system clock)
+    clock_start_wall: chrono::DateTime<chrono::Local>,
+    clock_start: Instant,
+}
+pub type SharedLogFile = Arc<Mutex<Option<LogFileInfo>>>;
+
+// Line endings used in text logs
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogLineEndings {
+    // Write line endings exactly as received
+    Keep,
+    Lf,
+    Crlf,
+}
+
+// Options controlling logging of console data to file
+#[derive(Clone, Debug)]
+pub struct ConsoleLogOptions {
+    pub log: bool,
+    pub log_folder: String,
+    // Append to this file (rather than creating a new timestamped file in the log folder)
+    pub log_file: Option<String>,
+    pub raw: bool,
+    // Rotation - start a new file when either limit is exceeded
+    pub max_size_mb: Option<u64>,
+    pub max_age_mins: Option<u64>,
+    // Number of log files to keep in the log folder when rotating
+    pub keep: Option<usize>,
+    // Line endings for text logs (lines are only written once complete unless kept as received)
+    pub line_endings: LogLineEndings,
+    // Prefix each line in text logs with the time it started to be received
+    pub timestamps: bool,
+    // Gzip each log file once it is complete (rotated or logging has ended)
+    pub compress: bool,
+}
+
+impl ConsoleLogOptions {
+    // Raw logs hold the exact bytes received so use a .bin extension
+    fn extension(&self) -> &'static str {
+        if self.raw { "bin" } else { "log" }
+    }
+}
+
+// Create a new timestamped log file in the log folder
+fn create_log_file(log_options: &ConsoleLogOptions) -> Result<(std::fs::File, PathBuf), std::io::Error> {
+    let log_folder = &log_options.log_folder;
+    std::fs::create_dir_all(log_folder)?;
+
+    // Rotation can happen more than once a second so avoid reusing a name
+    let name = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut log_file_name = format!("{}/{}.{}", log_folder, name, log_options.extension());
+    let mut suffix = 1;
+    while Path::new(&log_file_name).exists() {
+        log_file_name = format!("{}/{}-{}.{}", log_folder, name, suffix, log_options.extension());
+        suffix += 1;
+    }
+
+    // Open the log file
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_file_name)?;
+    Ok((file, PathBuf::from(log_file_name)))
+}
+
+// Open the named log file for appending - text logs get a separator line at the start of each
+// session so sessions can be told apart (raw logs only contain the bytes received)
+fn append_to_log_file(log_file_name: &str, raw: bool) -> Result<(std::fs::File, PathBuf), std::io::Error> {
+    if let Some(parent) = Path::new(log_file_name).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file_name)?;
+    if !raw {
+        let starts_new_line = file.metadata()?.len() == 0;
+        writeln!(file, "{}===== Session started {} =====", if starts_new_line { "" } else { "\n" },
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+    }
+    Ok((file, PathBuf::from(log_file_name)))
+}
+
+// Logging to file
+pub fn open_log_file(log_options: &ConsoleLogOptions) -> Result<SharedLogFile, std::io::Error> {
+    let log_folder = &log_options.log_folder;
+    let file = if let Some(log_file_name) = &log_options.log_file {
+        Some(append_to_log_file(log_file_name, log_options.raw)?)
+    } else if log_options.log && !log_folder.is_empty() && log_folder != "none" {
+        Some(create_log_file(log_options)?)
+    } else {
+        None
+    };
+    Ok(Arc::new(Mutex::new(file.map(|(file, path)| LogFileInfo {
+        file,
+        path,
+        compress_threads: Vec::new(),
+        last_write: Instant::now(),
+        created_at: Instant::now(),
+        bytes_written: 0,
+        options: log_options.clone(),
+        partial_line: String::new(),
+        pending_cr: false,
+        at_line_start: true,
+        clock_start_wall: chrono::Local::now(),
+        clock_start: Instant::now(),
+    }))))
+}
+
+// Check if the current log file has reached a rotation limit
+fn rotation_due(log_file_info: &LogFileInfo, bytes_to_write: usize) -> bool {
+    // Never rotate an empty file as a single large write would otherwise rotate forever (and
+    // a named log file is always appended to)
+    if log_file_info.bytes_written == 0 || log_file_info.options.log_file.is_some() {
+        return false;
+    }
+    if let Some(max_size_mb) = log_file_info.options.max_size_mb {
+        if log_file_info.bytes_written + bytes_to_write as u64 > max_size_mb * 1024 * 1024 {
+            return true;
+        }
+    }
+    if let Some(max_age_mins) = log_file_info.options.max_age_mins {
+        if log_file_info.created_at.elapsed() >= Duration::from_secs(max_age_mins * 60) {
+            return true;
+        }
+    }
+    false
+}
+
+// Delete the oldest log files so that at most keep files remain - only files named as raft
+// names log files (see create_log_file) are counted so other files in the folder are kept
+fn delete_old_log_files(log_options: &ConsoleLogOptions, keep: usize) -> Result<(), std::io::Error> {
+    // (including log files that have been compressed)
+    let log_file_name_re = Regex::new(&format!(r"^\d{{8}}-\d{{6}}(-\d+)?\.{}(\.gz)?$",
+                regex::escape(log_options.extension()))).map_err(std::io::Error::other)?;
+    let mut log_files: Vec<PathBuf> = std::fs::read_dir(&log_options.log_folder)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| log_file_name_re.is_match(&name.to_string_lossy()))
+        })
+        .collect();
+
+    // Oldest first (by modification time as rotated names within the same second get a suffix)
+    log_files.sort_by_key(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    });
+    if log_files.len() > keep {
+        let num_to_delete = log_files.len() - keep;
+        for path in log_files.iter().take(num_to_delete) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Gzip a complete log file (to <file>.gz) and delete it - a .part file is used while compressing
+// so an interrupted compression doesn't leave a truncated .gz file
+fn compress_log_file(path: &Path) -> Result<(), std::io::Error> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let part_path = PathBuf::from(format!("{}.part", gz_path.display()));
+    let mut input = std::fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(std::fs::File::create(&part_path)?, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::rename(&part_path, &gz_path)?;
+    std::fs::remove_file(path)
+}
+
+// Close the current log file and continue logging in a new one
+fn rotate_log_file(log_file_info: &mut LogFileInfo) -> Result<(), std::io::Error> {
+    log_file_info.file.flush()?;
+    let (file, path) = create_log_file(&log_file_info.options)?;
+    log_file_info.file = file;
+    let completed_path = std::mem::replace(&mut log_file_info.path, path);
+    log_file_info.created_at = Instant::now();
+    log_file_info.bytes_written = 0;
+    if let Some(keep) = log_file_info.options.keep {
+        delete_old_log_files(&log_file_info.options, keep.max(1))?;
+    }
+
+    // Compress the completed file in another thread so received data isn't held up (unless
+    // it has just been deleted to keep the number of log files down)
+    if log_file_info.options.compress && completed_path.exists() {
+        log_file_info.compress_threads.retain(|thread| !thread.is_finished());
+        log_file_info.compress_threads.push(std::thread::spawn(move || {
+            let _ = compress_log_file(&completed_path);
+        }));
+    }
+    Ok(())
+}
+
+impl LogFileInfo {
+    // ISO-8601 timestamp (with a trailing space) to prefix a logged line with
+    fn timestamp(&self) -> String {
+        let elapsed = chrono::Duration::from_std(self.clock_start.elapsed()).unwrap_or_default();
+        (self.clock_start_wall + elapsed).format("%Y-%m-%dT%H:%M:%S%.3f%:z ").to_string()
+    }
+
+    // Prefix each line with a timestamp when its first character is received (when the line
+    // endings are kept lines are written as received)
+    fn timestamp_lines(&mut self, msg: &str) -> String {
+        if !self.options.timestamps {
+            return msg.to_string();
+        }
+        let mut lines = String::new();
+        for c in msg.chars() {
+            let is_line_ending = c == '\r' || c == '\n';
+            if self.at_line_start && !is_line_ending {
+                lines.push_str(&self.timestamp());
+            }
+            lines.push(c);
+            self.at_line_start = is_line_ending;
+        }
+        lines
+    }
+
+    // Split received text into lines (ending with \n, \r\n or a bare \r) and return the complete
+    // lines with the configured line ending - any incomplete line is held until it is ended
+    fn normalize_line_endings(&mut self, msg: &str) -> String {
+        let line_ending = match self.options.line_endings {
+            LogLineEndings::Keep => return self.timestamp_lines(msg),
+            LogLineEndings::Lf => "\n",
+            LogLineEndings::Crlf => "\r\n",
+        };
+        let mut lines = String::new();
+        for c in msg.chars() {
+            match c {
+                '\n' if self.pending_cr => {
+                    self.pending_cr = false;
+                }
+                '\r' | '\n' => {
+                    lines.push_str(&self.partial_line);
+                    lines.push_str(line_ending);
+                    self.partial_line.clear();
+                    self.pending_cr = c == '\r';
+                }
+                _ => {
+                    if self.partial_line.is_empty() && self.options.timestamps {
+                        let timestamp = self.timestamp();
+                        self.partial_line.push_str(&timestamp);
+                    }
+                    self.partial_line.push(c);
+                    self.pending_cr = false;
+                }
+            }
+        }
+        lines
+    }
+
+    fn write_data(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        // Rotation happens with the lock held so no data is lost at the boundary - if a
+        // new file can't be created then logging continues in the current one
+        if rotation_due(self, data.len()) {
+            let _ = rotate_log_file(self);
+        }
+        self.file.write_all(data).unwrap();
+        self.bytes_written += data.len() as u64;
+        self.last_write = Instant::now();
+    }
+}
+
+impl Drop for LogFileInfo {
+    // Don't lose an incomplete last line
+    fn drop(&mut self) {
+        if !self.partial_line.is_empty() {
+            let _ = self.file.write_all(self.partial_line.as_bytes());
+        }
+    }
+}
+
+// Write decoded text to the log file
+pub fn write_to_log(log_file: &SharedLogFile, msg: &str) {
+    if let Ok(mut log_file) = log_file.lock() {
+        if let Some(log_file_info) = log_file.as_mut() {
+            let lines = log_file_info.normalize_line_endings(msg);
+            log_file_info.write_data(lines.as_bytes());
+        }
+    }
+}
+
+// Write the exact bytes received to the log file
+pub fn write_bytes_to_log(log_file: &SharedLogFile, data: &[u8]) {
+    if let Ok(mut log_file) = log_file.lock() {
+        if let Some(log_file_info) = log_file.as_mut() {
+            log_file_info.write_data(data);
+        }
+    }
+}
+
+// Close the log file (writing any incomplete line) and compress it if required
+pub fn close_log(log_file: &SharedLogFile) {
+    let log_file_info = match log_file.lock() {
+        Ok(mut log_file) => log_file.take(),
+        Err(_) => None,
+    };
+    if let Some(mut log_file_info) = log_file_info {
+        let compress_threads = std::mem::take(&mut log_file_info.compress_threads);
+        let compress_path = log_file_info.options.compress.then(|| log_file_info.path.clone());
+        drop(log_file_info);
+        for thread in compress_threads {
+            let _ = thread.join();
+        }
+        if let Some(path) = compress_path {
+            let _ = compress_log_file(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn log_options(log_folder: &Path) -> ConsoleLogOptions {
+        ConsoleLogOptions {
+            log: true,
+            log_folder: log_folder.to_string_lossy().to_string(),
+            log_file: None,
+            raw: false,
+            max_size_mb: None,
+            max_age_mins: None,
+            keep: None,
+            line_endings: LogLineEndings::Keep,
+            timestamps: false,
+            compress: false,
+        }
+    }
+
+    fn log_path(log_file: &SharedLogFile) -> PathBuf {
+        log_file.lock().unwrap().as_ref().unwrap().path.clone()
+    }
+
+    fn folder_file_names(folder: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(folder).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rotation_size_boundary() {
+        let folder = tempfile::tempdir().unwrap();
+        let options = ConsoleLogOptions { raw: true, max_size_mb: Some(1), ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        let first_path = log_path(&log_file);
+
+        // Filling the file up to the limit doesn't rotate it
+        write_bytes_to_log(&log_file, &vec![b'a'; 1024 * 1024 - 10]);
+        write_bytes_to_log(&log_file, &[b'b'; 10]);
+        assert_eq!(log_path(&log_file), first_path);
+
+        // The next byte goes in a new file
+        write_bytes_to_log(&log_file, b"c");
+        let second_path = log_path(&log_file);
+        close_log(&log_file);
+        assert_ne!(second_path, first_path);
+        assert_eq!(std::fs::metadata(&first_path).unwrap().len(), 1024 * 1024);
+        assert_eq!(std::fs::read(&second_path).unwrap(), b"c");
+    }
+
+    #[test]
+    fn test_rotation_keeps_other_files() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::write(folder.path().join("notes.log"), "mine").unwrap();
+        let options = ConsoleLogOptions { raw: true, max_size_mb: Some(1), keep: Some(2), ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        for _ in 0..4 {
+            write_bytes_to_log(&log_file, &vec![b'a'; 1024 * 1024]);
+        }
+        close_log(&log_file);
+        let names = folder_file_names(folder.path());
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert!(names.contains(&"notes.log".to_string()));
+    }
+
+    #[test]
+    fn test_line_endings_lf() {
+        let folder = tempfile::tempdir().unwrap();
+        let options = ConsoleLogOptions { line_endings: LogLineEndings::Lf, ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        let path = log_path(&log_file);
+        // CRLF, bare CR and LF - including a CRLF split across writes
+        write_to_log(&log_file, "one\r\ntwo\rthree\nfour\r");
+        write_to_log(&log_file, "\nfive");
+        close_log(&log_file);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\nfour\nfive");
+    }
+
+    #[test]
+    fn test_line_endings_crlf() {
+        let folder = tempfile::tempdir().unwrap();
+        let options = ConsoleLogOptions { line_endings: LogLineEndings::Crlf, ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        let path = log_path(&log_file);
+        write_to_log(&log_file, "one\ntwo\r");
+        write_to_log(&log_file, "\nthree\r\r\n");
+        close_log(&log_file);
+        // A CR on its own ends a line so CR CR LF is two line endings
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\r\ntwo\r\nthree\r\n\r\n");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let folder = tempfile::tempdir().unwrap();
+        let options = ConsoleLogOptions { line_endings: LogLineEndings::Lf, timestamps: true, ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        let path = log_path(&log_file);
+        write_to_log(&log_file, "first li");
+        write_to_log(&log_file, "ne\r\nsecond line\n");
+        close_log(&log_file);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let line_re = Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}[+-]\d{2}:\d{2} (.*)$").unwrap();
+        let lines: Vec<String> = contents.lines()
+            .map(|line| line_re.captures(line).expect(line)[1].to_string())
+            .collect();
+        assert_eq!(lines, vec!["first line", "second line"]);
+    }
+
+    #[test]
+    fn test_compress_on_close() {
+        let folder = tempfile::tempdir().unwrap();
+        let options = ConsoleLogOptions { compress: true, ..log_options(folder.path()) };
+        let log_file = open_log_file(&options).unwrap();
+        let path = log_path(&log_file);
+        write_to_log(&log_file, "compressed line\n");
+        close_log(&log_file);
+        assert!(!path.exists());
+        let mut contents = String::new();
+        GzDecoder::new(std::fs::File::open(format!("{}.gz", path.display())).unwrap())
+            .read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "compressed line\n");
+    }
+}
@@ -0,0 +1,299 @@
+// RaftCLI: Debug console transport module
+// Rob Dobson 2024
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+// Time allowed for each connection attempt
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Path of the debug console on a device's web server
+pub const DEFAULT_WEBSOCKET_PATH: &str = "/ws/debug";
+
+// Transport used to reach the debug server on a device
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    Udp,
+    Ws,
+}
+
+impl TransportKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Udp => "udp",
+            TransportKind::Ws => "ws",
+        }
+    }
+
+    // Default port of the debug server for the transport
+    pub fn default_port(&self) -> u16 {
+        match self {
+            TransportKind::Tcp | TransportKind::Udp => 8080,
+            TransportKind::Ws => 80,
+        }
+    }
+}
+
+// Where to connect to the debug server
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugServer {
+    pub kind: TransportKind,
+    pub host: String,
+    pub port: u16,
+    // Path of the websocket (only used for websockets)
+    pub path: String,
+}
+
+impl DebugServer {
+    // Host and port as used in an address (IPv6 addresses are bracketed)
+    fn host_port(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl std::fmt::Display for DebugServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            TransportKind::Tcp => write!(f, "{}", self.host_port()),
+            TransportKind::Udp => write!(f, "udp://{}", self.host_port()),
+            TransportKind::Ws => write!(f, "ws://{}{}", self.host_port(), self.path),
+        }
+    }
+}
+
+// Get the debug server from an address which may include a scheme (tcp://, udp:// or ws://),
+// port and (for websockets) a path - the transport and port are used if not in the address
+pub fn parse_debug_server(address: &str, transport: Option<TransportKind>, port: Option<u16>)
+                -> Result<DebugServer, String> {
+    let (scheme_kind, rest) = match address.split_once("://") {
+        Some(("tcp", rest)) => (Some(TransportKind::Tcp), rest),
+        Some(("udp", rest)) => (Some(TransportKind::Udp), rest),
+        Some(("ws", rest)) => (Some(TransportKind::Ws), rest),
+        Some((scheme, _)) => return Err(format!("Unsupported debug console scheme {}:// (use tcp, udp or ws)", scheme)),
+        None => (None, address),
+    };
+    if let (Some(scheme_kind), Some(transport)) = (scheme_kind, transport) {
+        if scheme_kind != transport {
+            return Err(format!("Address {} doesn't match --transport {}", address, transport.name()));
+        }
+    }
+    let kind = scheme_kind.or(transport).unwrap_or(TransportKind::Tcp);
+    let (host_port, path) = match rest.find('/') {
+        Some(path_idx) => (&rest[..path_idx], &rest[path_idx..]),
+        None => (rest, ""),
+    };
+    let (host, address_port) = match host_port.rsplit_once(':') {
+        Some((host, port_str)) if !host.contains(':') || host.ends_with(']') => {
+            let address_port = port_str.parse::<u16>().map_err(|_| format!("Invalid port in {}", address))?;
+            (host, Some(address_port))
+        }
+        _ => (host_port, None),
+    };
+    if host.is_empty() {
+        return Err(format!("No host in {}", address));
+    }
+    Ok(DebugServer {
+        kind,
+        host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+        port: address_port.or(port).unwrap_or(kind.default_port()),
+        path: if path.is_empty() { DEFAULT_WEBSOCKET_PATH.to_string() } else { path.to_string() },
+    })
+}
+
+// Connection to the debug server - reads don't block (WouldBlock is returned if there is no
+// data) and a read of 0 bytes means the connection has closed
+pub trait DebugTransport: Send {
+    fn connect(server: &DebugServer, tcp_keepalive: Duration) -> Result<Self, std::io::Error> where Self: Sized;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error>;
+    fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error>;
+}
+
+// Connect to the debug server using its transport
+pub fn connect_transport(server: &DebugServer, tcp_keepalive: Duration) -> Result<Box<dyn DebugTransport>, std::io::Error> {
+    Ok(match server.kind {
+        TransportKind::Tcp => Box::new(TcpTransport::connect(server, tcp_keepalive)?),
+        TransportKind::Udp => Box::new(UdpTransport::connect(server, tcp_keepalive)?),
+        TransportKind::Ws => Box::new(WebSocketTransport::connect(server, tcp_keepalive)?),
+    })
+}
+
+// Connect a TCP stream (trying each address the host resolves to) with TCP keepalive enabled
+// so a connection that has silently died is detected by the OS
+fn connect_tcp_stream(server: &DebugServer, tcp_keepalive: Duration) -> Result<TcpStream, std::io::Error> {
+    let socket_addrs: Vec<SocketAddr> = (server.host.as_str(), server.port).to_socket_addrs()?.collect();
+    let mut last_error = std::io::Error::other(format!("No address found for {}", server.host));
+    for socket_addr in socket_addrs {
+        match TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(tcp_keepalive))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Raw TCP
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl DebugTransport for TcpTransport {
+    fn connect(server: &DebugServer, tcp_keepalive: Duration) -> Result<Self, std::io::Error> {
+        let stream = connect_tcp_stream(server, tcp_keepalive)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpTransport { stream })
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.stream.read(buffer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.stream.write_all(data)
+    }
+}
+
+// UDP datagrams - there is no connection so the link is only known to be down if sending
+// fails (or by keepalive)
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl DebugTransport for UdpTransport {
+    fn connect(server: &DebugServer, _tcp_keepalive: Duration) -> Result<Self, std::io::Error> {
+        let socket_addr = (server.host.as_str(), server.port).to_socket_addrs()?.next()
+            .ok_or_else(|| std::io::Error::other(format!("No address found for {}", server.host)))?;
+        let local_addr = if socket_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(socket_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self.socket.recv(buffer)? {
+            // An empty datagram doesn't mean the link has closed
+            0 => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.socket.send(data).map(|_| ())
+    }
+}
+
+// WebSocket (text or binary messages) - data from a message that doesn't fit in the read
+// buffer is returned by the following reads
+pub struct WebSocketTransport {
+    socket: WebSocket<TcpStream>,
+    pending: Vec<u8>,
+}
+
+// Convert a websocket error to an IO error (so WouldBlock is kept)
+fn websocket_io_error(e: tungstenite::Error) -> std::io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => std::io::Error::other(e.to_string()),
+    }
+}
+
+impl DebugTransport for WebSocketTransport {
+    fn connect(server: &DebugServer, tcp_keepalive: Duration) -> Result<Self, std::io::Error> {
+        let stream = connect_tcp_stream(server, tcp_keepalive)?;
+        // The handshake blocks (with a timeout) and then the stream is non-blocking
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let url = format!("ws://{}{}", server.host_port(), server.path);
+        let (socket, _response) = tungstenite::client(url.as_str(), stream)
+            .map_err(|e| std::io::Error::other(format!("WebSocket handshake failed: {}", e)))?;
+        socket.get_ref().set_nonblocking(true)?;
+        Ok(WebSocketTransport { socket, pending: Vec::new() })
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.pending.is_empty() {
+            match self.socket.read() {
+                Ok(Message::Text(text)) => self.pending.extend_from_slice(text.as_bytes()),
+                Ok(Message::Binary(data)) => self.pending.extend_from_slice(&data),
+                Ok(Message::Close(_)) => return Ok(0),
+                // Pings are answered by tungstenite
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => return Ok(0),
+                Err(e) => return Err(websocket_io_error(e)),
+            }
+        }
+        let n = buffer.len().min(self.pending.len());
+        buffer[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        let text = String::from_utf8_lossy(data).to_string();
+        match self.socket.send(Message::Text(text.into())) {
+            // The message is queued and sent on a later read or write
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result.map_err(websocket_io_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(kind: TransportKind, host: &str, port: u16, path: &str) -> DebugServer {
+        DebugServer { kind, host: host.to_string(), port, path: path.to_string() }
+    }
+
+    #[test]
+    fn test_parse_debug_server_schemes() {
+        assert_eq!(parse_debug_server("192.168.1.50", None, None),
+                    Ok(server(TransportKind::Tcp, "192.168.1.50", 8080, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("tcp://mydevice.local:9000", None, None),
+                    Ok(server(TransportKind::Tcp, "mydevice.local", 9000, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("udp://192.168.1.50", None, None),
+                    Ok(server(TransportKind::Udp, "192.168.1.50", 8080, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("ws://192.168.1.50", None, None),
+                    Ok(server(TransportKind::Ws, "192.168.1.50", 80, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("ws://192.168.1.50:8081/api/ws", None, None),
+                    Ok(server(TransportKind::Ws, "192.168.1.50", 8081, "/api/ws")));
+        // The transport is used when there is no scheme
+        assert_eq!(parse_debug_server("192.168.1.50", Some(TransportKind::Ws), None).map(|s| s.kind), Ok(TransportKind::Ws));
+        assert!(parse_debug_server("udp://192.168.1.50", Some(TransportKind::Tcp), None).is_err());
+        assert!(parse_debug_server("http://192.168.1.50", None, None).is_err());
+        assert!(parse_debug_server("tcp://:8080", None, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_debug_server_ports() {
+        // A port in the address is used in preference to --port
+        assert_eq!(parse_debug_server("192.168.1.50:9000", None, Some(7000)).map(|s| s.port), Ok(9000));
+        assert_eq!(parse_debug_server("192.168.1.50", None, Some(7000)).map(|s| s.port), Ok(7000));
+        assert_eq!(parse_debug_server("ws://192.168.1.50/ws/debug", None, Some(7000)).map(|s| s.port), Ok(7000));
+        assert!(parse_debug_server("192.168.1.50:port", None, None).is_err());
+        assert!(parse_debug_server("192.168.1.50:70000", None, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_debug_server_ipv6() {
+        assert_eq!(parse_debug_server("[fe80::1]:9000", None, None),
+                    Ok(server(TransportKind::Tcp, "fe80::1", 9000, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("[::1]", None, Some(7000)),
+                    Ok(server(TransportKind::Tcp, "::1", 7000, DEFAULT_WEBSOCKET_PATH)));
+        assert_eq!(parse_debug_server("fe80::1", None, None).map(|s| (s.host, s.port)), Ok(("fe80::1".to_string(), 8080)));
+        let ws_server = parse_debug_server("ws://[fe80::1]/ws/debug", None, None).unwrap();
+        assert_eq!(ws_server.to_string(), "ws://[fe80::1]:80/ws/debug");
+    }
+}
@@ -0,0 +1,166 @@
+// RaftCLI: Flat key-value file module
+// Rob Dobson 2024
+
+use std::path::Path;
+
+// Line in a key-value file (e.g. sdkconfig.defaults) - lines other than key=value (comments,
+// blank lines, etc) are kept as they are so that saving the file preserves its format
+#[derive(Clone, Debug)]
+enum FlatLine {
+    Other(String),
+    KeyValue { key: String, value: String, line: String },
+}
+
+// Key-value file which can be edited without changing its comments, ordering or line endings
+#[derive(Clone, Debug)]
+pub struct FlatKeyValues {
+    lines: Vec<FlatLine>,
+    line_ending: &'static str,
+    trailing_line_ending: bool,
+}
+
+// Get the value from the text after the = in a line - a quoted value (which may contain # and
+// = and escaped quotes) is kept with its quotes and anything after a # outside quotes is an
+// inline comment
+fn parse_value(text: &str) -> String {
+    let text = text.trim_start();
+    if text.starts_with('"') {
+        let mut escaped = false;
+        for (char_idx, c) in text.char_indices().skip(1) {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => return text[..=char_idx].to_string(),
+                _ => {}
+            }
+        }
+        // Unterminated quote so use the whole value
+        return text.trim_end().to_string();
+    }
+    match text.split_once('#') {
+        Some((value, _comment)) => value.trim_end().to_string(),
+        None => text.trim_end().to_string(),
+    }
+}
+
+impl FlatKeyValues {
+    pub fn parse(contents: &str) -> FlatKeyValues {
+        let line_ending = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+        let lines = contents.lines().map(|line| {
+            match line.split_once('=') {
+                Some((key, value)) if !line.trim_start().starts_with('#') && !key.trim().is_empty() => FlatLine::KeyValue {
+                    key: key.trim().to_string(),
+                    value: parse_value(value),
+                    line: line.to_string(),
+                },
+                _ => FlatLine::Other(line.to_string()),
+            }
+        }).collect();
+        FlatKeyValues {
+            lines,
+            line_ending,
+            trailing_line_ending: contents.is_empty() || contents.ends_with('\n'),
+        }
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<FlatKeyValues, std::io::Error> {
+        Ok(FlatKeyValues::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_string())
+    }
+
+    // Get the value of a key (the last one if the key is repeated as that is the one used)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().rev().find_map(|line| match line {
+            FlatLine::KeyValue { key: line_key, value, .. } if line_key == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    // Get the keys and values in the order they appear
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            FlatLine::KeyValue { key, value, .. } => Some((key.as_str(), value.as_str())),
+            FlatLine::Other(_) => None,
+        })
+    }
+
+    // Set the value of a key - an existing line for the key (or a "# KEY is not set" comment)
+    // is replaced (unless the value is the same apart from whitespace) and otherwise the key
+    // is added at the end
+    pub fn insert(&mut self, key: &str, value: &str) {
+        let value = value.trim();
+        if self.get(key) == Some(value) {
+            return;
+        }
+        let new_line = FlatLine::KeyValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            line: format!("{}={}", key, value),
+        };
+        let not_set_comment = format!("# {} is not set", key);
+        let existing = self.lines.iter().rposition(|line| match line {
+            FlatLine::KeyValue { key: line_key, .. } => line_key == key,
+            FlatLine::Other(line) => line.trim() == not_set_comment,
+        });
+        match existing {
+            Some(line_idx) => self.lines[line_idx] = new_line,
+            None => self.lines.push(new_line),
+        }
+    }
+}
+
+impl std::fmt::Display for FlatKeyValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let lines: Vec<&str> = self.lines.iter().map(|line| match line {
+            FlatLine::Other(line) => line.as_str(),
+            FlatLine::KeyValue { line, .. } => line.as_str(),
+        }).collect();
+        write!(f, "{}", lines.join(self.line_ending))?;
+        if self.trailing_line_ending && !lines.is_empty() {
+            write!(f, "{}", self.line_ending)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_comments() {
+        let mut key_values = FlatKeyValues::parse("# Comment\nCONFIG_FOO=y  # note\nCONFIG_BAR=123#x\n");
+        assert_eq!(key_values.get("CONFIG_FOO"), Some("y"));
+        assert_eq!(key_values.get("CONFIG_BAR"), Some("123"));
+
+        // The original line (including its comment) is kept if the value doesn't change
+        key_values.insert("CONFIG_FOO", " y ");
+        key_values.insert("CONFIG_BAR", "456");
+        assert_eq!(key_values.to_string(), "# Comment\nCONFIG_FOO=y  # note\nCONFIG_BAR=456\n");
+    }
+
+    #[test]
+    fn test_quoted_values() {
+        let contents = "CONFIG_NAME=\"a=b # c\" # comment\nCONFIG_ESC=\"say \\\"hi\\\" #1\"\n";
+        let mut key_values = FlatKeyValues::parse(contents);
+        assert_eq!(key_values.get("CONFIG_NAME"), Some("\"a=b # c\""));
+        assert_eq!(key_values.get("CONFIG_ESC"), Some("\"say \\\"hi\\\" #1\""));
+        assert_eq!(key_values.to_string(), contents);
+
+        key_values.insert("CONFIG_NEW", "\"x=#y\"");
+        let key_values = FlatKeyValues::parse(&key_values.to_string());
+        assert_eq!(key_values.get("CONFIG_NEW"), Some("\"x=#y\""));
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        let mut key_values = FlatKeyValues::parse("CONFIG_A=1\r\n# CONFIG_B is not set\r\n");
+        assert_eq!(key_values.get("CONFIG_A"), Some("1"));
+        key_values.insert("CONFIG_B", "y");
+        key_values.insert("CONFIG_C", "2");
+        assert_eq!(key_values.to_string(), "CONFIG_A=1\r\nCONFIG_B=y\r\nCONFIG_C=2\r\n");
+    }
+}
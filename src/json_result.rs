@@ -0,0 +1,108 @@
+// RaftCLI: JSON result module
+// Rob Dobson 2024
+
+use gag::Redirect;
+use serde::Serialize;
+use std::io::Write;
+use std::time::Instant;
+
+use crate::raft_cli_utils::{get_build_folder_name, read_flasher_args};
+
+// Result of a build, flash or OTA operation for CI and scripts
+#[derive(Serialize, Debug)]
+pub struct JsonResult {
+    pub operation: String,
+    pub success: bool,
+    pub duration_secs: f64,
+    pub sys_type: Option<String>,
+    // Firmware files built (or flashed)
+    pub artifacts: Vec<String>,
+    // Size of the app firmware image in bytes
+    pub firmware_size: Option<u64>,
+    pub port: Option<String>,
+    pub ip_addr: Option<String>,
+    pub error: Option<String>,
+}
+
+impl JsonResult {
+    pub fn new(operation: &str, start_time: Instant, sys_type: Option<String>, error: Option<String>) -> JsonResult {
+        JsonResult {
+            operation: operation.to_string(),
+            success: error.is_none(),
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            sys_type,
+            artifacts: Vec::new(),
+            firmware_size: None,
+            port: None,
+            ip_addr: None,
+            error,
+        }
+    }
+
+    // Add the firmware files in the SysType's build folder (listed in flasher_args.json) and
+    // the size of the app image
+    pub fn with_build_artifacts(mut self, app_folder: &str) -> JsonResult {
+        let Some(sys_type) = self.sys_type.clone() else {
+            return self;
+        };
+        let build_folder = get_build_folder_name(sys_type, app_folder.to_string());
+        let Ok(flash_args) = read_flasher_args(&build_folder) else {
+            return self;
+        };
+        if let Some(flash_files) = flash_args["flash_files"].as_object() {
+            self.artifacts = flash_files.values()
+                .filter_map(|file| file.as_str())
+                .map(|file| format!("{}/{}", build_folder, file))
+                .collect();
+        }
+        self.firmware_size = flash_args["app"]["file"].as_str()
+            .and_then(|file| std::fs::metadata(format!("{}/{}", build_folder, file)).ok())
+            .map(|metadata| metadata.len());
+        self
+    }
+}
+
+// Where the JSON result is written - while the result is to be written to stdout other output
+// is sent to stderr so stdout only contains the JSON
+pub struct JsonResultOutput {
+    to_stdout: bool,
+    result_file: Option<String>,
+    stdout_redirect: Option<Redirect<std::io::Stderr>>,
+}
+
+impl JsonResultOutput {
+    pub fn new(to_stdout: bool, result_file: Option<String>) -> JsonResultOutput {
+        let stdout_redirect = if to_stdout {
+            let _ = std::io::stdout().flush();
+            Redirect::stdout(std::io::stderr()).ok()
+        } else {
+            None
+        };
+        JsonResultOutput { to_stdout, result_file, stdout_redirect }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.to_stdout || self.result_file.is_some()
+    }
+
+    // Write the result (stdout is restored first)
+    pub fn write(&mut self, result: &JsonResult) {
+        let _ = std::io::stdout().flush();
+        self.stdout_redirect = None;
+        let json = match serde_json::to_string_pretty(result) {
+            Ok(json) => json,
+            Err(e) => {
+                eprintln!("Unable to serialize result: {}", e);
+                return;
+            }
+        };
+        if let Some(result_file) = &self.result_file {
+            if let Err(e) = std::fs::write(result_file, format!("{}\n", json)) {
+                eprintln!("Unable to write result file {}: {}", result_file, e);
+            }
+        }
+        if self.to_stdout {
+            println!("{}", json);
+        }
+    }
+}
@@ -4,23 +4,70 @@
 // Rob Dobson 2024
 
 use clap::Parser;
+use log::debug;
 mod app_new;
 use app_new::generate_new_app;
 mod app_config;
-use app_config::get_user_input;
+use app_config::{get_schema_json, get_user_input, read_config_answers};
 mod serial_monitor;
+use serial_monitor::{MonitorBaud, MonitorOptions};
+mod serial_rx_handler;
 mod app_build;
-use app_build::build_raft_app;
+use app_build::{build_raft_app, build_all_sys_types, clean_raft_app, BuildOptions};
 mod app_flash;
-use app_flash::flash_raft_app;
+use app_flash::{erase_raft_app, flash_image, flash_raft_app, flash_raft_app_all_ports, FlashImage, open_device_web_ui,
+            find_flash_port, is_firmware_flashed, resolve_flash_port, FlashOptions, PortFlashResults};
+mod app_chip_info;
+use app_chip_info::chip_info_raft_app;
 mod app_ota;
-use app_ota::ota_raft_app;
+use app_ota::{ota_raft_app, DEFAULT_OTA_CHUNK_SIZE};
 mod raft_cli_utils;
 use raft_cli_utils::is_wsl;
 use raft_cli_utils::check_target_folder_valid;
+use raft_cli_utils::resolve_app_folder;
+use raft_cli_utils::{init_logging, kill_running_commands, set_command_timeout, set_dry_run};
+use raft_cli_utils::{error_exit_code, EXIT_BUILD_FAILED, EXIT_FLASH_FAILED, EXIT_GENERIC_FAILURE,
+            EXIT_INVALID_ARGS, EXIT_OTA_FAILED, EXIT_PORT_NOT_FOUND};
+use raft_cli_utils::utils_get_sys_type;
+use raft_cli_utils::{read_firmware_hash, BUILT_FIRMWARE_HASH_FILE, FLASHED_FIRMWARE_HASH_FILE};
 mod app_ports;
 use app_ports::{PortsCmd, manage_ports};
+mod app_size;
+use app_size::size_raft_app;
+mod app_flash_diff;
+use app_flash_diff::flash_diff;
+mod app_esptool;
+use app_esptool::{EsptoolCmd, run_esptool};
+mod app_update;
+use app_update::{UpdateCmd, update_raft_app};
+mod app_sdkconfig;
+use app_sdkconfig::{SdkconfigCmd, sdkconfig_raft_app};
+mod app_versions;
+use app_versions::{VersionsCmd, show_versions};
+mod app_merge_bin;
+use app_merge_bin::{MergeBinCmd, merge_bin_raft_app};
+mod app_bridge;
+use app_bridge::{BridgeCmd, start_bridge};
+mod app_debug_remote;
+mod app_discover;
+use app_discover::{DiscoverCmd, discover_raft_devices};
+mod debug_transport;
+use app_debug_remote::{DebugRemoteCmd, start_debug_console};
+mod json_result;
+use json_result::{JsonResult, JsonResultOutput};
+mod flat_key_values;
 mod cmd_history;
+mod console_log;
+mod terminal_io;
+use terminal_io::restore_terminal;
+mod partition_table;
+use partition_table::parse_partition_value;
+mod systype_config;
+mod target_settings;
+use target_settings::find_sys_type_settings;
+mod project_config;
+mod build_cache;
+use console_log::{ConsoleLogOptions, LogLineEndings};
 
 #[derive(Clone, Parser, Debug)]
 enum Action {
@@ -28,16 +75,42 @@ enum Action {
     New(NewCmd),
     #[clap(name = "build", about = "Build a raft app", alias = "b")]
     Build(BuildCmd),    
+    #[clap(name = "clean", about = "Delete build output of a raft app", alias = "c")]
+    Clean(CleanCmd),
     #[clap(name = "monitor", about = "Monitor a serial port", alias = "m")]
     Monitor(MonitorCmd),
+    #[clap(name = "debug", about = "Console for the debug server of a device (over TCP)", alias = "d")]
+    Debug(DebugRemoteCmd),
+    #[clap(name = "bridge", about = "Share a serial port with TCP clients")]
+    Bridge(BridgeCmd),
     #[clap(name = "run", about = "Build, flash and monitor a raft app", alias = "r")]
     Run(RunCmd),
     #[clap(name = "flash", about = "Flash firmware to the device", alias = "f")]
     Flash(FlashCmd),
+    #[clap(name = "erase", about = "Erase the flash of the device")]
+    Erase(EraseCmd),
+    #[clap(name = "chip-info", about = "Show information about the chip on the device")]
+    ChipInfo(ChipInfoCmd),
+    #[clap(name = "size", about = "Show the memory usage of a built raft app")]
+    Size(SizeCmd),
+    #[clap(name = "flash-diff", about = "Compare the flash images of two builds")]
+    FlashDiff(FlashDiffCmd),
+    #[clap(name = "merge-bin", about = "Merge the flash images of a build into one image for factory programming")]
+    MergeBin(MergeBinCmd),
     #[clap(name = "ota", about = "Over-the-air update", alias = "o")]
     Ota(OtaCmd),
+    #[clap(name = "discover", about = "List raft devices found on the network (using mDNS)")]
+    Discover(DiscoverCmd),
     #[clap(name = "ports", about = "Manage serial ports", alias = "p")]
     Ports(PortsCmd),
+    #[clap(name = "esptool", about = "Run esptool with the given arguments")]
+    Esptool(EsptoolCmd),
+    #[clap(name = "update", about = "Show or update the git tags of raft libraries used by a raft app")]
+    Update(UpdateCmd),
+    #[clap(name = "sdkconfig", about = "Get or set values in a SysType's sdkconfig.defaults")]
+    Sdkconfig(SdkconfigCmd),
+    #[clap(name = "versions", about = "Show the versions of raftcli and the tools it uses")]
+    Versions(VersionsCmd),
 }
 
 // Define arguments specific to the `new` subcommand
@@ -46,6 +119,24 @@ struct NewCmd {
     base_folder: Option<String>,
     #[clap(short = 'c', long, help = "Clean the target folder")]
     clean: bool,
+    // Option to supply answers to the questions from a JSON file (instead of asking them)
+    #[clap(long, help = "JSON file of answers to the questions (others use defaults, nothing is asked)")]
+    config: Option<String>,
+    // Option to use the default answers to all questions
+    #[clap(long, help = "Use the default answers to the questions (nothing is asked)")]
+    defaults: bool,
+    // Option to output the questions asked (including defaults and validation) as JSON
+    #[clap(long, hide = true, help = "Print the questions schema as JSON and exit")]
+    print_schema: bool,
+    // Option to use templates from a folder instead of those built into raft
+    #[clap(long, help = "Folder containing the templates to generate the app from (default built-in templates)")]
+    template_dir: Option<String>,
+    // Option to use an existing partition table
+    #[clap(long, help = "Partition table CSV file to use instead of generating one for the flash size")]
+    partition_csv: Option<String>,
+    // Option to keep the files generated if generation fails (for debugging templates)
+    #[clap(long, help = "Keep the files generated if generation fails (they are removed by default)")]
+    keep_partial: bool,
 }
 
 // Define arguments specific to the `build` subcommand
@@ -68,12 +159,61 @@ struct BuildCmd {
     // Option to disable docker
     #[clap(long, help = "Do not use docker for build")]
     no_docker: bool,
+    // Option to build without ccache
+    #[clap(long, help = "Do not use ccache for build (it is used if available)")]
+    no_ccache: bool,
+    // Option to build without the docker cache volume
+    #[clap(long, help = "Do not keep caches from docker builds in the raftbuilder-cache volume")]
+    no_cache_volume: bool,
+    // Option to choose the docker image
+    #[clap(long, help = "Docker image to build and use (default raftbuilder-<ESP-IDF version in Dockerfile>)")]
+    docker_image: Option<String>,
+    // Option to open a shell in the docker build container instead of building
+    #[clap(long, conflicts_with_all = ["no_docker", "idf_local_build", "all_systypes"], help = "Open an interactive shell in the docker build container (instead of building)")]
+    docker_shell: bool,
     // Option to find matching esp idf and source it ready to build locally
     #[clap(short = 'i', long, help = "Find and use local ESP IDF matching Dockerfile version")]
     idf_local_build: bool,    
     // Option to specify path to ESP IDF folder
     #[clap(short = 'e', long, help = "Full path to ESP IDF folder for local build (when not using docker)")]
     esp_idf_path: Option<String>,
+    // Option to build with the local ESP IDF even if it isn't the version in the Dockerfile
+    #[clap(long, help = "Build with the ESP IDF set up even if it doesn't match the Dockerfile version")]
+    ignore_idf_version: bool,
+    // Option to only use a local ESP IDF of exactly the Dockerfile version
+    #[clap(long, help = "Only use a local ESP IDF of exactly the Dockerfile version (not the newest with the same major.minor)")]
+    idf_exact: bool,
+    // Option to only show build warnings and errors
+    #[clap(long, help = "Only show build warnings and errors")]
+    quiet_build: bool,
+    // Option to write the build output to a file
+    #[clap(long, help = "Also write the full build output to this file")]
+    build_log: Option<String>,
+    // Option to merge extra sdkconfig settings into the SysType's sdkconfig.defaults for this build
+    #[clap(long, help = "File of extra sdkconfig settings to apply to this build (sdkconfig.defaults is not changed)")]
+    sdkconfig_extra: Option<String>,
+    // Option to write build diagnostics for editors
+    #[clap(long, help = "Write the errors and warnings from the build to this file as JSON")]
+    diagnostics_json: Option<String>,
+    // Option to build all SysTypes
+    #[clap(long, help = "Build all SysTypes (except Common)")]
+    all_systypes: bool,
+    // Option to continue building other SysTypes after a failure
+    #[clap(long, help = "Continue building other SysTypes if one fails (with --all-systypes)")]
+    keep_going: bool,
+}
+
+// Define arguments specific to the `clean` subcommand
+#[derive(Clone, Parser, Debug)]
+struct CleanCmd {
+    // Add an option to specify the app folder
+    app_folder: Option<String>,
+    // Add an option to specify the system type
+    #[clap(short = 's', long, help = "System type to clean")]
+    sys_type: Option<String>,
+    // Option to clean all SysTypes
+    #[clap(long, help = "Delete the build folders of all SysTypes")]
+    all: bool,
 }
 
 // Define arguments specific to the `monitor` subcommand
@@ -85,8 +225,8 @@ struct MonitorCmd {
     #[clap(short = 'p', long, help = "Serial port")]
     port: Option<String>,
     // Option to specify the monitor baud rate
-    #[clap(short = 'b', long, help = "Baud rate")]
-    monitor_baud: Option<u32>,
+    #[clap(short = 'b', long, alias = "baud", help = "Baud rate (or auto to detect it)")]
+    monitor_baud: Option<MonitorBaud>,
     // Option to disable serial port reconnection when monitoring
     #[clap(short = 'r', long, help = "Disable serial port reconnection when monitoring")]
     no_reconnect: bool,
@@ -98,6 +238,51 @@ struct MonitorCmd {
     log: bool,
     #[arg(short = 'g', long, default_value = "./logs", help = "Folder for log files")]
     log_folder: Option<String>,
+    #[arg(long, visible_alias = "log-binary", help = "Log the exact bytes received to a .bin file (no text decoding)")]
+    log_raw: bool,
+    #[arg(long, help = "Append logged data to this file (with a separator for each session) instead of a new timestamped file")]
+    log_file: Option<String>,
+    #[arg(long, help = "Start a new log file when the current one exceeds this size in MB")]
+    log_max_size: Option<u64>,
+    #[arg(long, help = "Start a new log file when the current one is older than this many minutes")]
+    log_max_age: Option<u64>,
+    #[arg(long, help = "Number of log files to keep when rotating (older files are deleted)")]
+    log_keep: Option<usize>,
+    #[arg(long, value_enum, default_value = "keep", help = "Line endings in text log files (keep as received, lf or crlf)")]
+    log_line_endings: LogLineEndings,
+    #[arg(long, conflicts_with = "log_raw", help = "Prefix each line in the log file with the time it was received (ISO-8601)")]
+    log_timestamps: bool,
+    #[arg(long, conflicts_with = "log_file", help = "Gzip each log file when it is rotated or logging ends")]
+    log_compress: bool,
+    // Option to show device output and the commands sent in separate panes
+    #[clap(long, help = "Split screen with device output above and a timestamped log of commands sent below")]
+    split: bool,
+    // Option to specify the command history file
+    #[clap(long, help = "Command history file (default .raftcli_monitor_history in the app folder)")]
+    history_file: Option<String>,
+    // Option to remove colours from the device output
+    #[clap(long, help = "Remove colours and other escape sequences from device output (when shown and logged)")]
+    no_color: bool,
+    // Option to show the received bytes as a hex dump
+    #[clap(long, help = "Show (and log) received bytes as a hex dump with offsets and ASCII (for binary protocols)")]
+    hexdump: bool,
+    // Commands to send when the serial port is opened
+    #[clap(long, help = "Command to send when the serial port is opened (repeat for more than one)")]
+    on_connect: Vec<String>,
+    #[clap(long, default_value_t = 200, help = "Milliseconds to wait after each --on-connect command")]
+    on_connect_delay: u64,
+    // Option to slow down sending typed commands
+    #[clap(long, default_value_t = 0, help = "Milliseconds to wait after each byte of a typed command (for devices that drop characters)")]
+    tx_delay: u64,
+    // Option to specify the system type (used to find the ELF file for decoding backtraces)
+    #[clap(short = 's', long, help = "System type whose ELF file is used to decode panic backtraces")]
+    sys_type: Option<String>,
+    // Option to save core dumps received from the device
+    #[clap(long, help = "Folder to save core dumps received from the device (decoded if espcoredump is available)")]
+    coredump_dir: Option<String>,
+    // Option to wait for a serial port to appear
+    #[clap(long, alias = "port-timeout", default_value = "0", help = "Seconds to wait for a serial port to appear if none is found (e.g. a board just plugged in)")]
+    wait_for_port: u64,
     // Option to specify vendor ID
     #[clap(short = 'v', long, help = "Vendor ID")]
     vid: Option<String>,
@@ -120,12 +305,33 @@ struct RunCmd {
     // Option to disable docker
     #[clap(long, help = "Do not use docker for build")]
     no_docker: bool,
+    // Option to build without ccache
+    #[clap(long, help = "Do not use ccache for build (it is used if available)")]
+    no_ccache: bool,
+    // Option to build without the docker cache volume
+    #[clap(long, help = "Do not keep caches from docker builds in the raftbuilder-cache volume")]
+    no_cache_volume: bool,
+    // Option to choose the docker image
+    #[clap(long, help = "Docker image to build and use (default raftbuilder-<ESP-IDF version in Dockerfile>)")]
+    docker_image: Option<String>,
     // Option to find matching esp idf and source it ready to build locally
     #[clap(short = 'i', long, help = "Find and use local ESP IDF matching Dockerfile version")]
     idf_local_build: bool,    
     // Option to specify path to ESP IDF folder
     #[clap(short = 'e', long, help = "Full path to ESP IDF folder for local build (when not using docker)")]
     esp_idf_path: Option<String>,
+    // Option to build with the local ESP IDF even if it isn't the version in the Dockerfile
+    #[clap(long, help = "Build with the ESP IDF set up even if it doesn't match the Dockerfile version")]
+    ignore_idf_version: bool,
+    // Option to only use a local ESP IDF of exactly the Dockerfile version
+    #[clap(long, help = "Only use a local ESP IDF of exactly the Dockerfile version (not the newest with the same major.minor)")]
+    idf_exact: bool,
+    // Option to only show build warnings and errors
+    #[clap(long, help = "Only show build warnings and errors")]
+    quiet_build: bool,
+    // Option to merge extra sdkconfig settings into the SysType's sdkconfig.defaults for this build
+    #[clap(long, help = "File of extra sdkconfig settings to apply to this build (sdkconfig.defaults is not changed)")]
+    sdkconfig_extra: Option<String>,
     // Add an option to specify the serial port
     #[clap(short = 'p', long, help = "Serial port")]
     port: Option<String>,
@@ -133,8 +339,8 @@ struct RunCmd {
     #[clap(short = 'o', long, help = "IP address or hostname for OTA flashing")]
     ip_addr: Option<String>,    
     // Option to specify the monitor baud rate
-    #[clap(short = 'b', long, help = "Monitor baud rate")]
-    monitor_baud: Option<u32>,
+    #[clap(short = 'b', long, alias = "baud", help = "Monitor baud rate (or auto to detect it)")]
+    monitor_baud: Option<MonitorBaud>,
     // Option to disable serial port reconnection when monitoring
     #[clap(short = 'r', long, help = "Disable serial port reconnection when monitoring")]
     no_reconnect: bool,  
@@ -147,11 +353,73 @@ struct RunCmd {
     // Option to specify flashing tool
     #[clap(short = 't', long, help = "Flash tool (e.g. esptool)")]
     flash_tool: Option<String>,
+    // Option to retry flashing when connecting to the chip fails
+    #[clap(long, default_value = "0", help = "Number of times to reset the device and retry if connecting to it fails")]
+    retry: u32,
+    // Option to flash even if the firmware is unchanged since it was last flashed
+    #[clap(long, help = "Flash even if the firmware is unchanged since the last flash")]
+    force_flash: bool,
+    // Option to erase the whole flash before flashing
+    #[clap(long, help = "Erase the entire flash (including NVS and other data partitions) before flashing")]
+    erase: bool,
+    // Option to skip the confirmation before erasing
+    #[clap(short = 'y', long, help = "Don't ask for confirmation before erasing flash")]
+    yes: bool,
+    // Options to read back and verify the flash after writing
+    #[clap(long, overrides_with = "no_verify", help = "Read back the flash after writing and fail if it doesn't match (esptool v4 and later always check the flash)")]
+    verify: bool,
+    #[clap(long, overrides_with = "verify", help = "Don't pass --verify to esptool (the default)")]
+    no_verify: bool,
     // Logging options
     #[arg(short = 'l', long, help = "Log serial data to file")]
     log: bool,
     #[arg(short = 'g', long, default_value = "./logs", help = "Folder for log files")]
     log_folder: Option<String>,
+    #[arg(long, visible_alias = "log-binary", help = "Log the exact bytes received to a .bin file (no text decoding)")]
+    log_raw: bool,
+    #[arg(long, help = "Append logged data to this file (with a separator for each session) instead of a new timestamped file")]
+    log_file: Option<String>,
+    #[arg(long, help = "Start a new log file when the current one exceeds this size in MB")]
+    log_max_size: Option<u64>,
+    #[arg(long, help = "Start a new log file when the current one is older than this many minutes")]
+    log_max_age: Option<u64>,
+    #[arg(long, help = "Number of log files to keep when rotating (older files are deleted)")]
+    log_keep: Option<usize>,
+    #[arg(long, value_enum, default_value = "keep", help = "Line endings in text log files (keep as received, lf or crlf)")]
+    log_line_endings: LogLineEndings,
+    #[arg(long, conflicts_with = "log_raw", help = "Prefix each line in the log file with the time it was received (ISO-8601)")]
+    log_timestamps: bool,
+    #[arg(long, conflicts_with = "log_file", help = "Gzip each log file when it is rotated or logging ends")]
+    log_compress: bool,
+    // Option to show device output and the commands sent in separate panes
+    #[clap(long, help = "Split screen with device output above and a timestamped log of commands sent below")]
+    split: bool,
+    // Option to specify the command history file
+    #[clap(long, help = "Command history file (default .raftcli_monitor_history in the app folder)")]
+    history_file: Option<String>,
+    // Option to remove colours from the device output
+    #[clap(long, help = "Remove colours and other escape sequences from device output (when shown and logged)")]
+    no_color: bool,
+    // Option to show the received bytes as a hex dump
+    #[clap(long, help = "Show (and log) received bytes as a hex dump with offsets and ASCII (for binary protocols)")]
+    hexdump: bool,
+    // Commands to send when the serial port is opened
+    #[clap(long, help = "Command to send when the serial port is opened (repeat for more than one)")]
+    on_connect: Vec<String>,
+    #[clap(long, default_value_t = 200, help = "Milliseconds to wait after each --on-connect command")]
+    on_connect_delay: u64,
+    // Option to slow down sending typed commands
+    #[clap(long, default_value_t = 0, help = "Milliseconds to wait after each byte of a typed command (for devices that drop characters)")]
+    tx_delay: u64,
+    // Option to save core dumps received from the device
+    #[clap(long, help = "Folder to save core dumps received from the device (decoded if espcoredump is available)")]
+    coredump_dir: Option<String>,
+    // Option to wait for a serial port to appear
+    #[clap(long, alias = "port-timeout", default_value = "0", help = "Seconds to wait for a serial port to appear if none is found (e.g. a board just plugged in)")]
+    wait_for_port: u64,
+    // Option to flash every matching port
+    #[clap(long, help = "Flash every port matching the vendor ID (and -p as a pattern e.g. /dev/ttyACM*) in turn and show a summary (no serial monitor)")]
+    all_ports: bool,
     // Option to specify vendor ID
     #[clap(short = 'v', long, help = "Vendor ID")]
     vid: Option<String>,
@@ -177,15 +445,153 @@ struct FlashCmd {
     // Option to specify flashing tool
     #[clap(short = 't', long, help = "Flash tool (e.g. esptool)")]
     flash_tool: Option<String>,
+    // Option to retry flashing when connecting to the chip fails
+    #[clap(long, default_value = "0", help = "Number of times to reset the device and retry if connecting to it fails")]
+    retry: u32,
+    // Option to show a single line summary instead of the flash tool output
+    #[clap(long, help = "Show a one line summary instead of the flash tool output (full output is shown on failure)")]
+    summary: bool,
+    // Option to show the chip information before flashing
+    #[clap(long, help = "Show the attached chip's type, MAC, flash size and crystal before flashing")]
+    chip_info: bool,
+    // Option to flash a single partition
+    #[clap(long, help = "Only flash the image for this partition (name in the SysType's partition table, e.g. fs)")]
+    partition: Option<String>,
+    // Option to erase the whole flash before flashing
+    #[clap(long, help = "Erase the entire flash (including NVS and other data partitions) before flashing")]
+    erase: bool,
+    // Option to skip the confirmation before erasing
+    #[clap(short = 'y', long, help = "Don't ask for confirmation before erasing flash")]
+    yes: bool,
+    // Options to read back and verify the flash after writing
+    #[clap(long, overrides_with = "no_verify", help = "Read back the flash after writing and fail if it doesn't match (esptool v4 and later always check the flash)")]
+    verify: bool,
+    #[clap(long, overrides_with = "verify", help = "Don't pass --verify to esptool (the default)")]
+    no_verify: bool,
+    // Options to flash a prebuilt image file without a raft project
+    #[clap(long, conflicts_with_all = ["sys_type", "partition", "open_browser"], help = "Flash this image file (e.g. a merged .bin) instead of the app's build (no raft project needed)")]
+    image: Option<String>,
+    #[clap(long, requires = "image", help = "Chip type of the device for --image (e.g. esp32s3, detected if not given)")]
+    chip: Option<String>,
+    #[clap(long, requires = "image", help = "Flash offset for --image (default 0x0)")]
+    offset: Option<String>,
+    // Option to open the device web UI after flashing
+    #[clap(long, help = "Open the device web UI in the browser after flashing")]
+    open_browser: bool,
+    // Option to specify the device web UI URL
+    #[clap(long, help = "Device web UI URL (defaults to the address last used for OTA)")]
+    device_url: Option<String>,
+    // Option to wait for a serial port to appear
+    #[clap(long, alias = "port-timeout", default_value = "0", help = "Seconds to wait for a serial port to appear if none is found (e.g. a board just plugged in)")]
+    wait_for_port: u64,
+    // Option to flash every matching port
+    #[clap(long, conflicts_with_all = ["image", "open_browser"], help = "Flash every port matching the vendor ID (and -p as a pattern e.g. /dev/ttyACM*) in turn and show a summary")]
+    all_ports: bool,
+    // Option to start the serial monitor after flashing
+    #[clap(short = 'M', long, conflicts_with = "all_ports", help = "Start the serial monitor on the flashed port after flashing succeeds")]
+    monitor: bool,
+    // Option to specify the monitor baud rate
+    #[clap(short = 'b', long, requires = "monitor", help = "Monitor baud rate with --monitor (or auto to detect it)")]
+    monitor_baud: Option<MonitorBaud>,
     // Option to specify vendor ID
     #[clap(short = 'v', long, help = "Vendor ID")]
     vid: Option<String>,
 }
 
+// Define arguments for the 'erase' subcommand
+#[derive(Clone, Parser, Debug)]
+struct EraseCmd {
+    // Option to specify the app folder
+    app_folder: Option<String>,
+    // Option to specify the system type (used to find the chip type)
+    #[clap(short = 's', long, help = "System type (the chip type is taken from its build if there is one)")]
+    sys_type: Option<String>,
+    // Option to specify a serial port
+    #[clap(short = 'p', long, help = "Serial port")]
+    port: Option<String>,
+    // Option to force native serial port when in WSL
+    #[clap(short = 'n', long, help = "Native serial port when in WSL")]
+    native_serial_port: bool,
+    // Option to specify flash baud rate
+    #[clap(short = 'f', long, help = "Flash baud rate")]
+    flash_baud: Option<u32>,
+    // Option to specify flashing tool
+    #[clap(short = 't', long, help = "Flash tool (e.g. esptool)")]
+    flash_tool: Option<String>,
+    // Options to erase a region rather than the entire flash
+    #[clap(long, requires_all = ["offset", "size"], help = "Erase only the region given by --offset and --size")]
+    region: bool,
+    #[clap(long, requires = "region", help = "Start of the region to erase (e.g. 0x9000)")]
+    offset: Option<String>,
+    #[clap(long, requires = "region", help = "Size of the region to erase (e.g. 0x6000 or 24K)")]
+    size: Option<String>,
+    // Option to skip the confirmation
+    #[clap(short = 'y', long, help = "Don't ask for confirmation before erasing")]
+    yes: bool,
+    // Option to specify vendor ID
+    #[clap(short = 'v', long, help = "Vendor ID")]
+    vid: Option<String>,
+}
+
+// Define arguments for the 'chip-info' subcommand
+#[derive(Clone, Parser, Debug)]
+struct ChipInfoCmd {
+    // Option to specify the app folder
+    app_folder: Option<String>,
+    // Option to specify the system type (the chip is checked against its target)
+    #[clap(short = 's', long, help = "System type to check the chip against")]
+    sys_type: Option<String>,
+    // Option to specify a serial port
+    #[clap(short = 'p', long, help = "Serial port")]
+    port: Option<String>,
+    // Option to force native serial port when in WSL
+    #[clap(short = 'n', long, help = "Native serial port when in WSL")]
+    native_serial_port: bool,
+    // Option to specify flashing tool
+    #[clap(short = 't', long, help = "Flash tool (e.g. esptool)")]
+    flash_tool: Option<String>,
+    // Option to specify vendor ID
+    #[clap(short = 'v', long, help = "Vendor ID")]
+    vid: Option<String>,
+}
+
+// Define arguments for the 'size' subcommand
+#[derive(Clone, Parser, Debug)]
+struct SizeCmd {
+    // Option to specify the app folder
+    app_folder: Option<String>,
+    // Option to specify the system type
+    #[clap(short = 's', long, help = "System type")]
+    sys_type: Option<String>,
+    // Option to enable docker
+    #[clap(long, help = "Use docker")]
+    docker: bool,
+    // Option to disable docker
+    #[clap(long, help = "Do not use docker")]
+    no_docker: bool,
+    // Option to find matching esp idf and source it
+    #[clap(short = 'i', long, help = "Find and use local ESP IDF matching Dockerfile version")]
+    idf_local_build: bool,
+    // Option to specify path to ESP IDF folder
+    #[clap(short = 'e', long, help = "Full path to ESP IDF folder (when not using docker)")]
+    esp_idf_path: Option<String>,
+    // Option to only use a local ESP IDF of exactly the Dockerfile version
+    #[clap(long, help = "Only use a local ESP IDF of exactly the Dockerfile version (not the newest with the same major.minor)")]
+    idf_exact: bool,
+}
+
+// Define arguments for the 'flash-diff' subcommand
+#[derive(Clone, Parser, Debug)]
+struct FlashDiffCmd {
+    // Build folders (containing flasher_args.json) to compare
+    build_a: String,
+    build_b: String,
+}
+
 // Define arguments for the 'ota' subcommand
 #[derive(Clone, Parser, Debug)]
 struct OtaCmd {
-    // IP address/hostname for OTA
+    // IP address/hostname for OTA (.local names and device friendly names are found using mDNS)
     ip_addr: String,
     // Option to specify the app folder
     app_folder: Option<String>,
@@ -198,6 +604,13 @@ struct OtaCmd {
     // Option to use curl for OTA
     #[clap(short = 'c', long, help = "Use curl for OTA")]
     use_curl: bool,
+    // Option to specify the OTA slot to check the firmware size against (the device chooses the
+    // slot the firmware is written to)
+    #[clap(long, help = "OTA slot (e.g. ota_0 or ota_1) to check the firmware fits before uploading - the device chooses the slot written")]
+    slot: Option<String>,
+    // Option to specify the size of the chunks the firmware is sent in
+    #[clap(long, default_value_t = DEFAULT_OTA_CHUNK_SIZE, help = "Size in bytes of the chunks the firmware is sent in")]
+    chunk_size: usize,
 }
 
 // Main CLI struct that includes the subcommands
@@ -206,6 +619,85 @@ struct OtaCmd {
 struct Cli {
     #[clap(subcommand)]
     action: Action,
+    // Options to set the log level - the short forms (-v and -q) can only be given before the
+    // subcommand as subcommands use them for other things
+    #[clap(long, global = true, action = clap::ArgAction::Count, help = "Show more detail (-v or --verbose for debug, -vv or twice for trace output)")]
+    verbose: u8,
+    #[clap(short = 'v', hide = true, action = clap::ArgAction::Count)]
+    verbose_short: u8,
+    #[clap(long, global = true, conflicts_with = "verbose", help = "Only show warnings and errors from raft's diagnostics (-q before the subcommand)")]
+    quiet: bool,
+    #[clap(short = 'q', hide = true, conflicts_with_all = ["verbose", "verbose_short"])]
+    quiet_short: bool,
+    // Option to show the commands that would be run without running them
+    #[clap(long, global = true, help = "Print the build and flash commands without executing them")]
+    dry_run: bool,
+    // Option to limit how long external commands (docker, idf.py, esptool) can run
+    #[clap(long, global = true, value_name = "SECS", help = "Kill external commands (e.g. docker, idf.py, esptool) that run for longer than this")]
+    command_timeout: Option<u64>,
+    // Options to output a JSON result for CI and scripts
+    #[clap(long, global = true, help = "Output a JSON result of build, flash or ota on stdout (other output goes to stderr)")]
+    json_result: bool,
+    #[clap(long, global = true, value_name = "FILE", help = "Write a JSON result of build, flash or ota to a file")]
+    result_file: Option<String>,
+}
+
+// Resolve the app folder argument (defaulting to the current folder) to an absolute path
+// exiting with an error if it isn't valid
+fn get_app_folder(app_folder: &str, require_project: bool) -> String {
+    match resolve_app_folder(app_folder, require_project) {
+        Ok(resolved) => {
+            debug!("App folder: {}", resolved);
+            resolved
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            std::process::exit(EXIT_INVALID_ARGS);
+        }
+    }
+}
+
+// Show an error that stops an operation before it has a result and exit - a failed result is
+// written first if a JSON result is required (so scripts don't get empty output)
+fn exit_with_json_failure(json_output: &mut JsonResultOutput, operation: &str, start_time: std::time::Instant,
+            error: &str, exit_code: i32) -> ! {
+    println!("Error: {}", error);
+    if json_output.is_enabled() {
+        json_output.write(&JsonResult::new(operation, start_time, None, Some(error.to_string())));
+    }
+    std::process::exit(exit_code);
+}
+
+// Start the serial monitor (using the windows serial monitor in WSL unless the native serial port
+// is used) and exit when it ends
+fn start_serial_monitor(app_folder_arg: String, app_folder: String, native_serial_port: bool,
+            monitor_options: MonitorOptions, log_options: ConsoleLogOptions) -> ! {
+    let result = if !native_serial_port && is_wsl() {
+        serial_monitor::start_non_native(app_folder_arg, monitor_options, log_options)
+    } else {
+        serial_monitor::start_native(app_folder, monitor_options, log_options)
+    };
+    match result {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            println!("Serial monitor error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Show the result of flashing each port returning true if all succeeded
+fn report_port_flash_results(results: &PortFlashResults) -> bool {
+    println!("Flash summary:");
+    for (port, result) in results {
+        match result {
+            Ok(()) => println!("  {}: OK", port),
+            Err(e) => println!("  {}: FAILED ({})", port, e),
+        }
+    }
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    println!("{} of {} ports flashed", results.len() - failed, results.len());
+    failed == 0
 }
 
 // Main function
@@ -213,178 +705,723 @@ fn main() {
     // Parse the command line arguments
     let args = Cli::parse();
     // println!("{:?}", args);
+    let quiet = args.quiet || args.quiet_short;
+    let log_level = match (quiet, args.verbose + args.verbose_short) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    init_logging(log_level);
+    set_dry_run(args.dry_run);
+    set_command_timeout(args.command_timeout);
+
+    // On Ctrl+C restore the terminal and stop any external command (e.g. docker or esptool)
+    // before exiting
+    let _ = ctrlc::set_handler(|| {
+        restore_terminal();
+        kill_running_commands();
+        println!("Interrupted");
+        std::process::exit(130);
+    });
 
     // Call the function to test the templates
     match args.action {
         Action::New(cmd) => {
 
-            // Validate target folder (before user input to avoid unnecessary input)
+            // Print the questions schema without creating an app
+            if cmd.print_schema {
+                match get_schema_json() {
+                    Ok(schema) => println!("{}", schema),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+                std::process::exit(0);
+            }
+
+            // Validate template and target folders (before user input to avoid unnecessary input)
+            if let Some(template_dir) = &cmd.template_dir {
+                if !std::path::Path::new(template_dir).is_dir() {
+                    println!("Error: template folder not found: {}", template_dir);
+                    std::process::exit(EXIT_INVALID_ARGS);
+                }
+            }
             let base_folder = cmd.base_folder.unwrap_or(".".to_string());
+            let base_folder_created = !std::path::Path::new(&base_folder).exists();
             let folder_valid = check_target_folder_valid(&base_folder, cmd.clean);
             if !folder_valid {
                 println!("Error: target folder is not valid");
-                std::process::exit(1);
+                std::process::exit(EXIT_INVALID_ARGS);
             }
             
+            // Get answers supplied in a config file (questions aren't asked if a config file is
+            // given or defaults are requested)
+            let mut answers = if let Some(config_file) = &cmd.config {
+                match read_config_answers(config_file) {
+                    Ok(answers) => answers,
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        std::process::exit(EXIT_INVALID_ARGS);
+                    }
+                }
+            } else {
+                serde_json::Map::new()
+            };
+            let interactive = cmd.config.is_none() && !cmd.defaults;
+            if let Some(partition_csv) = &cmd.partition_csv {
+                answers.insert("partition_csv_file".to_string(), serde_json::Value::String(partition_csv.clone()));
+            }
+
             // Get configuration
-            let json_config_str = get_user_input();
-            let json_config = serde_json::from_str(&json_config_str.unwrap()).unwrap();
+            let json_config_str = match get_user_input(&answers, interactive) {
+                Ok(json_config_str) => json_config_str,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    if base_folder_created {
+                        let _ = std::fs::remove_dir(&base_folder);
+                    }
+                    std::process::exit(1);
+                }
+            };
+            let json_config = serde_json::from_str(&json_config_str).unwrap();
 
             // Generate a new app
-            let _result = generate_new_app(&base_folder, json_config).unwrap();
-            // println!("{:?}", _result);
+            if let Err(e) = generate_new_app(&base_folder, json_config, cmd.template_dir.as_deref(), cmd.keep_partial) {
+                println!("Error generating app: {}", e);
+                if base_folder_created && !cmd.keep_partial {
+                    let _ = std::fs::remove_dir(&base_folder);
+                }
+                std::process::exit(1);
+            }
 
         }
 
         Action::Build(cmd) => {
+            let start_time = std::time::Instant::now();
+            let mut json_output = JsonResultOutput::new(args.json_result, args.result_file.clone());
+
             // Get the app folder (or default to current folder)
-            let app_folder = cmd.app_folder.unwrap_or(".".to_string());
-            let result = build_raft_app(&cmd.sys_type, cmd.clean, 
-                        cmd.clean_only, app_folder, cmd.docker, cmd.no_docker, 
-                        cmd.idf_local_build, cmd.esp_idf_path);
+            let app_folder = resolve_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), true).unwrap_or_else(|e| {
+                exit_with_json_failure(&mut json_output, "build", start_time, &e.to_string(), EXIT_INVALID_ARGS)
+            });
+            let build_options = BuildOptions {
+                clean: cmd.clean,
+                clean_only: cmd.clean_only,
+                force_docker: cmd.docker || cmd.docker_shell,
+                no_docker: cmd.no_docker,
+                use_local_idf_matching_dockerfile_idf: cmd.idf_local_build,
+                idf_path_full: cmd.esp_idf_path,
+                quiet: cmd.quiet_build,
+                build_log: cmd.build_log.clone(),
+                sdkconfig_extra: cmd.sdkconfig_extra.clone(),
+                diagnostics_json: cmd.diagnostics_json.clone(),
+                no_ccache: cmd.no_ccache,
+                no_cache_volume: cmd.no_cache_volume,
+                docker_shell: cmd.docker_shell,
+                docker_image: cmd.docker_image.clone(),
+                ignore_idf_version: cmd.ignore_idf_version,
+                idf_exact: cmd.idf_exact,
+            };
+
+            // Start a new build log (the output of each build step is appended to it)
+            if let Some(build_log) = &cmd.build_log {
+                if let Err(e) = std::fs::File::create(build_log) {
+                    exit_with_json_failure(&mut json_output, "build", start_time,
+                                &format!("unable to create build log {}: {}", build_log, e), EXIT_GENERIC_FAILURE);
+                }
+            }
+
+            // Build all SysTypes if required
+            if cmd.all_systypes {
+                let results = build_all_sys_types(app_folder, &build_options, cmd.keep_going);
+                let results = match results {
+                    Ok(results) => results,
+                    Err(e) => {
+                        println!("Build failed {:?}", e);
+                        exit_with_json_failure(&mut json_output, "build", start_time, &e.to_string(),
+                                    error_exit_code(e.as_ref(), EXIT_BUILD_FAILED));
+                    }
+                };
+                println!("Build summary:");
+                for (sys_type, result) in &results {
+                    match result {
+                        Ok(()) => println!("  {}: OK", sys_type),
+                        Err(e) => println!("  {}: FAILED ({})", sys_type, e),
+                    }
+                }
+                let failed: Vec<&str> = results.iter()
+                    .filter(|(_, result)| result.is_err())
+                    .map(|(sys_type, _)| sys_type.as_str())
+                    .collect();
+                if json_output.is_enabled() {
+                    let error = (!failed.is_empty()).then(|| format!("SysTypes failed to build: {}", failed.join(", ")));
+                    json_output.write(&JsonResult::new("build", start_time, None, error));
+                }
+                if !failed.is_empty() {
+                    std::process::exit(EXIT_BUILD_FAILED);
+                }
+                std::process::exit(0);
+            }
+
+            let result = build_raft_app(&cmd.sys_type, app_folder.clone(), &build_options);
             // println!("{:?}", result);
 
             // Check for build error
+            if let Err(e) = &result {
+                println!("Build failed {:?}", e);
+            }
+            if json_output.is_enabled() {
+                let sys_type = utils_get_sys_type(&cmd.sys_type, app_folder.clone()).ok();
+                let json_result = JsonResult::new("build", start_time, sys_type, result.as_ref().err().map(|e| e.to_string()))
+                    .with_build_artifacts(&app_folder);
+                json_output.write(&json_result);
+            }
+            if let Err(e) = &result {
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_BUILD_FAILED));
+            }
+        }
+        
+        Action::Clean(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), true);
+            let result = clean_raft_app(&cmd.sys_type, app_folder, cmd.all);
             if result.is_err() {
-                println!("Build failed {:?}", result);
+                println!("Clean failed {:?}", result);
                 std::process::exit(1);
             }
         }
-        
+
         Action::Monitor(cmd) => {
 
-            let app_folder = cmd.app_folder.unwrap_or(".".to_string());
-            let monitor_baud = cmd.monitor_baud.unwrap_or(115200);
+            // The app folder doesn't need to contain a project when only monitoring - the unresolved
+            // folder is passed to the windows serial monitor in WSL as linux absolute paths aren't valid there
+            let app_folder_arg = cmd.app_folder.unwrap_or(".".to_string());
+            let app_folder = get_app_folder(&app_folder_arg, false);
+            // (the SysType's default baud rate is used if none is specified)
+            let monitor_baud = cmd.monitor_baud
+                .or_else(|| find_sys_type_settings(&app_folder, &cmd.sys_type)?.baud.map(MonitorBaud::Rate))
+                .unwrap_or(MonitorBaud::Rate(115200));
             let log = cmd.log;
             let mut log_folder = cmd.log_folder.unwrap_or("./logs".to_string());
             // If the log_folder is relative then apply the app_folder as a prefix to it using path::join
             if !log_folder.starts_with("/") {
-                let mut log_folder_path = std::path::PathBuf::from(&app_folder);
+                let mut log_folder_path = std::path::PathBuf::from(&app_folder_arg);
                 log_folder_path.push(log_folder);
                 log_folder = log_folder_path.to_str().unwrap().to_string();
             }
 
-            // Start the serial monitor
-            if !cmd.native_serial_port && is_wsl() {
-                let result = serial_monitor::start_non_native(app_folder, 
-                            cmd.port, monitor_baud, cmd.no_reconnect, log, log_folder, cmd.vid);
-                match result {
-                    Ok(()) => std::process::exit(0),
-                    Err(e) => {
-                        println!("Serial monitor error: {}", e);
-                        std::process::exit(1);
-                    }
-                }
-            }
+            let log_options = ConsoleLogOptions {
+                log,
+                log_folder,
+                log_file: cmd.log_file.clone(),
+                raw: cmd.log_raw,
+                max_size_mb: cmd.log_max_size,
+                max_age_mins: cmd.log_max_age,
+                keep: cmd.log_keep,
+                line_endings: cmd.log_line_endings,
+                timestamps: cmd.log_timestamps,
+                compress: cmd.log_compress,
+            };
 
-            let result = serial_monitor::start_native(app_folder, 
-                            cmd.port, monitor_baud, cmd.no_reconnect, log, log_folder, cmd.vid);
-            match result {
-                Ok(()) => std::process::exit(0),
-                Err(e) => {
-                    println!("Serial monitor error: {}", e);
-                    std::process::exit(1);
-                }
-            }
+            // Extract monitor options
+            let monitor_options = MonitorOptions {
+                port: cmd.port,
+                baud_rate: monitor_baud,
+                no_reconnect: cmd.no_reconnect,
+                vid: cmd.vid,
+                split_screen: cmd.split,
+                history_file: cmd.history_file,
+                no_color: cmd.no_color,
+                sys_type: cmd.sys_type.clone(),
+                coredump_dir: cmd.coredump_dir.clone(),
+                wait_for_port: cmd.wait_for_port,
+                hexdump: cmd.hexdump,
+                on_connect: cmd.on_connect.clone(),
+                on_connect_delay_ms: cmd.on_connect_delay,
+                tx_delay_ms: cmd.tx_delay,
+            };
+
+            // Start the serial monitor
+            start_serial_monitor(app_folder_arg, app_folder, cmd.native_serial_port, monitor_options, log_options);
         }
 
         Action::Run(cmd) => {
 
-            // Get the app folder (or default to current folder)
-            let app_folder = cmd.app_folder.unwrap_or(".".to_string());
+            // Get the app folder (or default to current folder) - the unresolved folder is passed
+            // to the windows serial monitor in WSL as linux absolute paths aren't valid there
+            let app_folder_arg = cmd.app_folder.unwrap_or(".".to_string());
+            let app_folder = get_app_folder(&app_folder_arg, true);
 
             // Build the app
-            let result = build_raft_app(&cmd.sys_type, cmd.clean, false,
-                        app_folder.clone(), cmd.docker, cmd.no_docker,
-                        cmd.idf_local_build, 
-                        cmd.esp_idf_path);
+            let build_options = BuildOptions {
+                clean: cmd.clean,
+                clean_only: false,
+                force_docker: cmd.docker,
+                no_docker: cmd.no_docker,
+                use_local_idf_matching_dockerfile_idf: cmd.idf_local_build,
+                idf_path_full: cmd.esp_idf_path,
+                quiet: cmd.quiet_build,
+                build_log: None,
+                sdkconfig_extra: cmd.sdkconfig_extra.clone(),
+                diagnostics_json: None,
+                no_ccache: cmd.no_ccache,
+                no_cache_volume: cmd.no_cache_volume,
+                docker_shell: false,
+                docker_image: cmd.docker_image.clone(),
+                ignore_idf_version: cmd.ignore_idf_version,
+                idf_exact: cmd.idf_exact,
+            };
+            let result = build_raft_app(&cmd.sys_type, app_folder.clone(), &build_options);
 
             // Check for build error
-            if result.is_err() {
-                println!("Build failed {:?}", result);
-                std::process::exit(1);
+            if let Err(e) = &result {
+                println!("Build failed {:?}", e);
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_BUILD_FAILED));
             }
             
-            // Flash the app
-            let result = flash_raft_app(&cmd.sys_type,
-                        app_folder.clone(), 
-                        cmd.port.clone(),
-                        cmd.native_serial_port,
-                        cmd.vid.clone(),
-                        cmd.flash_baud.unwrap_or(1000000),
-                        cmd.flash_tool);
-            if result.is_err() {
-                println!("Flash operation failed {:?}", result);
-                std::process::exit(1);
+            // Flash every matching port (there's no single port to monitor afterwards)
+            if cmd.all_ports {
+                let flash_options = FlashOptions {
+                    flash_baud: cmd.flash_baud.unwrap_or(1000000),
+                    flash_tool: cmd.flash_tool,
+                    retries: cmd.retry,
+                    summary: false,
+                    erase: cmd.erase,
+                    confirmed: cmd.yes,
+                    verify: cmd.verify && !cmd.no_verify,
+                    partition: None,
+                    chip_info: false,
+                    wait_for_port: 0,
+                };
+                let results = flash_raft_app_all_ports(&cmd.sys_type, app_folder, cmd.port.clone(),
+                            cmd.native_serial_port, cmd.vid.clone(), &flash_options);
+                let results = results.unwrap_or_else(|e| {
+                    println!("Error: {}", e);
+                    std::process::exit(EXIT_PORT_NOT_FOUND);
+                });
+                if !report_port_flash_results(&results) {
+                    std::process::exit(EXIT_FLASH_FAILED);
+                }
+                std::process::exit(0);
+            }
+
+            // Flash the app (unless the firmware is unchanged since it was last flashed) - the port
+            // flashed is then monitored so a different board isn't chosen when several are attached
+            let mut monitor_port = cmd.port.clone();
+            let built_hash = read_firmware_hash(&app_folder, BUILT_FIRMWARE_HASH_FILE);
+            let flashed_record = read_firmware_hash(&app_folder, FLASHED_FIRMWARE_HASH_FILE);
+            let mut skip_flash = false;
+            if !cmd.force_flash && !cmd.erase && !args.dry_run && built_hash.is_some() {
+                // The firmware must also have been flashed on the same port
+                if let Ok(port) = find_flash_port(cmd.port.clone(), cmd.native_serial_port, cmd.vid.clone(), cmd.wait_for_port) {
+                    skip_flash = is_firmware_flashed(built_hash.as_deref(), flashed_record.as_deref(), &port);
+                    monitor_port = Some(port);
+                }
+            }
+            if skip_flash {
+                println!("Firmware unchanged, skipping flash (use --force-flash to flash anyway)");
+            } else {
+                let flash_options = FlashOptions {
+                    flash_baud: cmd.flash_baud.unwrap_or(1000000),
+                    flash_tool: cmd.flash_tool,
+                    retries: cmd.retry,
+                    summary: false,
+                    erase: cmd.erase,
+                    confirmed: cmd.yes,
+                    verify: cmd.verify && !cmd.no_verify,
+                    partition: None,
+                    chip_info: false,
+                    wait_for_port: cmd.wait_for_port,
+                };
+                // (the port already found is used so it isn't looked for again)
+                let result = flash_raft_app(&cmd.sys_type,
+                            app_folder.clone(), 
+                            monitor_port.clone(),
+                            cmd.native_serial_port,
+                            cmd.vid.clone(),
+                            &flash_options);
+                match result {
+                    Ok(port) => monitor_port = Some(port),
+                    Err(e) => {
+                        println!("Flash operation failed {:?}", e);
+                        std::process::exit(error_exit_code(e.as_ref(), EXIT_FLASH_FAILED));
+                    }
+                }
+            }
+
+            // Nothing to monitor in a dry run
+            if args.dry_run {
+                println!("Dry run: not starting the serial monitor");
+                std::process::exit(0);
             }
 
             // Extract logging options
-            let log = cmd.log;
-            let log_folder = cmd.log_folder.unwrap_or("./logs".to_string());
+            let log_options = ConsoleLogOptions {
+                log: cmd.log,
+                log_folder: cmd.log_folder.unwrap_or("./logs".to_string()),
+                log_file: cmd.log_file.clone(),
+                raw: cmd.log_raw,
+                max_size_mb: cmd.log_max_size,
+                max_age_mins: cmd.log_max_age,
+                keep: cmd.log_keep,
+                line_endings: cmd.log_line_endings,
+                timestamps: cmd.log_timestamps,
+                compress: cmd.log_compress,
+            };
 
-            // Extract monitor baud rate
-            let monitor_baud = cmd.monitor_baud.unwrap_or(115200);
+            // Extract monitor baud rate (the SysType's default baud rate is used if none is specified)
+            let monitor_baud = cmd.monitor_baud
+                .or_else(|| find_sys_type_settings(&app_folder, &cmd.sys_type)?.baud.map(MonitorBaud::Rate))
+                .unwrap_or(MonitorBaud::Rate(115200));
+
+            // Extract monitor options
+            let monitor_options = MonitorOptions {
+                port: monitor_port,
+                baud_rate: monitor_baud,
+                no_reconnect: cmd.no_reconnect,
+                vid: cmd.vid,
+                split_screen: cmd.split,
+                history_file: cmd.history_file,
+                no_color: cmd.no_color,
+                sys_type: cmd.sys_type.clone(),
+                coredump_dir: cmd.coredump_dir.clone(),
+                wait_for_port: cmd.wait_for_port,
+                hexdump: cmd.hexdump,
+                on_connect: cmd.on_connect.clone(),
+                on_connect_delay_ms: cmd.on_connect_delay,
+                tx_delay_ms: cmd.tx_delay,
+            };
 
             // Start the serial monitor
-            if !cmd.native_serial_port && is_wsl() {
-                let result = serial_monitor::start_non_native(app_folder, 
-                            cmd.port.clone(), monitor_baud, cmd.no_reconnect, log, log_folder, cmd.vid.clone());
-                match result {
-                    Ok(()) => std::process::exit(0),
-                    Err(e) => {
-                        println!("Serial monitor error: {}", e);
-                        std::process::exit(1);
-                    }
+            start_serial_monitor(app_folder_arg, app_folder, cmd.native_serial_port, monitor_options, log_options);
+        }
+        Action::Flash(cmd) => {
+            let start_time = std::time::Instant::now();
+            let mut json_output = JsonResultOutput::new(args.json_result, args.result_file.clone());
+
+            // Get the app folder (or default to current folder) - it doesn't need to contain a
+            // project when flashing an image file
+            let app_folder_arg = cmd.app_folder.unwrap_or(".".to_string());
+            let app_folder = resolve_app_folder(&app_folder_arg, cmd.image.is_none()).unwrap_or_else(|e| {
+                exit_with_json_failure(&mut json_output, "flash", start_time, &e.to_string(), EXIT_INVALID_ARGS)
+            });
+
+            // Check the image file to flash (if any) and get the offset to flash it at
+            let image = cmd.image.as_ref().map(|file| {
+                if !std::path::Path::new(file).is_file() {
+                    exit_with_json_failure(&mut json_output, "flash", start_time,
+                                &format!("image file not found: {}", file), EXIT_INVALID_ARGS);
                 }
+                let offset = cmd.offset.as_deref().unwrap_or("0x0");
+                let Some(offset) = parse_partition_value(offset) else {
+                    exit_with_json_failure(&mut json_output, "flash", start_time,
+                                &format!("invalid image offset {}", offset), EXIT_INVALID_ARGS);
+                };
+                FlashImage { file: file.clone(), chip: cmd.chip.clone(), offset }
+            });
+
+            // Flash the app (or image file)
+            let flash_options = FlashOptions {
+                flash_baud: cmd.flash_baud.unwrap_or(1000000),
+                flash_tool: cmd.flash_tool,
+                retries: cmd.retry,
+                summary: cmd.summary,
+                erase: cmd.erase,
+                confirmed: cmd.yes,
+                verify: cmd.verify && !cmd.no_verify,
+                partition: cmd.partition.clone(),
+                chip_info: cmd.chip_info,
+                wait_for_port: cmd.wait_for_port,
+            };
+
+            // Flash every matching port if required
+            if cmd.all_ports {
+                let results = flash_raft_app_all_ports(&cmd.sys_type, app_folder.clone(), cmd.port.clone(),
+                            cmd.native_serial_port, cmd.vid.clone(), &flash_options);
+                let results = results.unwrap_or_else(|e| {
+                    exit_with_json_failure(&mut json_output, "flash", start_time, &e.to_string(), EXIT_PORT_NOT_FOUND)
+                });
+                let all_ok = report_port_flash_results(&results);
+                if json_output.is_enabled() {
+                    let failed: Vec<String> = results.iter()
+                        .filter_map(|(port, result)| result.as_ref().err().map(|e| format!("{}: {}", port, e)))
+                        .collect();
+                    let error = (!failed.is_empty()).then(|| failed.join("; "));
+                    let sys_type = utils_get_sys_type(&cmd.sys_type, app_folder.clone()).ok();
+                    let mut json_result = JsonResult::new("flash", start_time, sys_type, error)
+                        .with_build_artifacts(&app_folder);
+                    json_result.port = Some(results.iter().map(|(port, _)| port.as_str()).collect::<Vec<_>>().join(", "));
+                    json_output.write(&json_result);
+                }
+                if !all_ok {
+                    std::process::exit(EXIT_FLASH_FAILED);
+                }
+                std::process::exit(0);
             }
 
-            let result = serial_monitor::start_native(app_folder, 
-                            cmd.port, monitor_baud, cmd.no_reconnect, log, log_folder,cmd.vid);
-            match result {
-                Ok(()) => std::process::exit(0),
-                Err(e) => {
-                    println!("Serial monitor error: {}", e);
-                    std::process::exit(1);
+            let result = match &image {
+                Some(image) => flash_image(image,
+                    app_folder.clone(),
+                    cmd.port.clone(),
+                    cmd.native_serial_port,
+                    cmd.vid.clone(),
+                    &flash_options),
+                None => flash_raft_app(&cmd.sys_type,
+                    app_folder.clone(), 
+                    cmd.port.clone(),
+                    cmd.native_serial_port,
+                    cmd.vid.clone(),
+                    &flash_options),
+            };
+            if let Err(e) = &result {
+                println!("Flash operation failed {:?}", e);
+            }
+
+            // Open the device web UI if required
+            if result.is_ok() && cmd.open_browser && !args.dry_run {
+                open_device_web_ui(&app_folder, cmd.device_url);
+            }
+
+            if json_output.is_enabled() {
+                let sys_type = match &cmd.image {
+                    Some(_) => None,
+                    None => utils_get_sys_type(&cmd.sys_type, app_folder.clone()).ok(),
+                };
+                let mut json_result = JsonResult::new("flash", start_time, sys_type, result.as_ref().err().map(|e| e.to_string()))
+                    .with_build_artifacts(&app_folder);
+                if let Some(image) = &cmd.image {
+                    json_result.artifacts = vec![image.clone()];
+                }
+                json_result.port = result.as_ref().ok().cloned();
+                json_output.write(&json_result);
+            }
+            let port = match result {
+                Ok(port) => port,
+                Err(e) => std::process::exit(error_exit_code(e.as_ref(), EXIT_FLASH_FAILED)),
+            };
+
+            // Start the serial monitor on the port just flashed (so it isn't chosen again) if required
+            if cmd.monitor {
+                if args.dry_run {
+                    println!("Dry run: not starting the serial monitor");
+                    std::process::exit(0);
                 }
+                let sys_type = if cmd.image.is_none() { cmd.sys_type.clone() } else { None };
+                let monitor_baud = cmd.monitor_baud
+                    .or_else(|| find_sys_type_settings(&app_folder, &sys_type)?.baud.map(MonitorBaud::Rate))
+                    .unwrap_or(MonitorBaud::Rate(115200));
+                let log_options = ConsoleLogOptions {
+                    log: false,
+                    log_folder: "./logs".to_string(),
+                    log_file: None,
+                    raw: false,
+                    max_size_mb: None,
+                    max_age_mins: None,
+                    keep: None,
+                    line_endings: LogLineEndings::Keep,
+                    timestamps: false,
+                    compress: false,
+                };
+                let monitor_options = MonitorOptions {
+                    port: Some(port),
+                    baud_rate: monitor_baud,
+                    no_reconnect: false,
+                    vid: cmd.vid,
+                    split_screen: false,
+                    history_file: None,
+                    no_color: false,
+                    sys_type,
+                    coredump_dir: None,
+                    wait_for_port: 0,
+                    hexdump: false,
+                    on_connect: Vec::new(),
+                    on_connect_delay_ms: 0,
+                    tx_delay_ms: 0,
+                };
+                start_serial_monitor(app_folder_arg, app_folder, cmd.native_serial_port, monitor_options, log_options);
             }
         }
-        Action::Flash(cmd) => {
+        Action::Erase(cmd) => {
 
             // Get the app folder (or default to current folder)
-            let app_folder = cmd.app_folder.unwrap_or(".".to_string());
+            let app_folder = get_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), false);
 
-            // Flash the app
-            let result = flash_raft_app(&cmd.sys_type,
-                app_folder.clone(), 
-                cmd.port.clone(),
-                cmd.native_serial_port,
-                cmd.vid.clone(),
-                cmd.flash_baud.unwrap_or(1000000),
-                cmd.flash_tool);
-            if result.is_err() {
-                println!("Flash operation failed {:?}", result);
+            // Get the region to erase
+            let region = match (&cmd.offset, &cmd.size) {
+                (Some(offset), Some(size)) if cmd.region => {
+                    match (parse_partition_value(offset), parse_partition_value(size)) {
+                        (Some(offset), Some(size)) => Some((offset, size)),
+                        _ => {
+                            println!("Error: invalid erase region offset {} size {}", offset, size);
+                            std::process::exit(EXIT_INVALID_ARGS);
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            let flash_options = FlashOptions {
+                flash_baud: cmd.flash_baud.unwrap_or(1000000),
+                flash_tool: cmd.flash_tool,
+                retries: 0,
+                summary: false,
+                erase: true,
+                confirmed: cmd.yes,
+                verify: false,
+                partition: None,
+                chip_info: false,
+                wait_for_port: 0,
+            };
+            let result = erase_raft_app(&cmd.sys_type, app_folder, cmd.port.clone(), cmd.native_serial_port,
+                        cmd.vid.clone(), &flash_options, region);
+            if let Err(e) = result {
+                println!("Erase failed: {}", e);
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_FLASH_FAILED));
+            }
+        }
+        Action::ChipInfo(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), false);
+            let port = resolve_flash_port(cmd.port.clone(), cmd.native_serial_port, cmd.vid.clone(), 0);
+            let result = chip_info_raft_app(&cmd.sys_type, &app_folder, &port, cmd.native_serial_port, cmd.flash_tool);
+            if let Err(e) = result {
+                println!("Chip info failed: {}", e);
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_GENERIC_FAILURE));
+            }
+        }
+        Action::Size(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), true);
+            let build_options = BuildOptions {
+                clean: false,
+                clean_only: false,
+                force_docker: cmd.docker,
+                no_docker: cmd.no_docker,
+                use_local_idf_matching_dockerfile_idf: cmd.idf_local_build,
+                idf_path_full: cmd.esp_idf_path,
+                quiet: true,
+                build_log: None,
+                sdkconfig_extra: None,
+                diagnostics_json: None,
+                no_ccache: false,
+                no_cache_volume: false,
+                docker_shell: false,
+                docker_image: None,
+                ignore_idf_version: false,
+                idf_exact: cmd.idf_exact,
+            };
+            let result = size_raft_app(&cmd.sys_type, app_folder, &build_options);
+            if let Err(e) = result {
+                println!("Size failed: {}", e);
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_GENERIC_FAILURE));
+            }
+        }
+        Action::FlashDiff(cmd) => {
+            let result = flash_diff(&cmd.build_a, &cmd.build_b);
+            if let Err(e) = result {
+                println!("Flash diff failed: {}", e);
                 std::process::exit(1);
             }
         }
+        Action::MergeBin(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder.clone().unwrap_or(".".to_string()), true);
+            if let Err(e) = merge_bin_raft_app(&cmd, &app_folder) {
+                println!("Merge failed: {}", e);
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_GENERIC_FAILURE));
+            }
+        }
         Action::Ota(cmd) => {
+            let start_time = std::time::Instant::now();
+            let mut json_output = JsonResultOutput::new(args.json_result, args.result_file.clone());
 
             // Get the app folder (or default to current folder)
-            let app_folder = cmd.app_folder.unwrap_or(".".to_string());
+            let app_folder = resolve_app_folder(&cmd.app_folder.unwrap_or(".".to_string()), true).unwrap_or_else(|e| {
+                exit_with_json_failure(&mut json_output, "ota", start_time, &e.to_string(), EXIT_INVALID_ARGS)
+            });
 
             // OTA the app
             let result = ota_raft_app(&cmd.sys_type,
                 app_folder.clone(), 
                 cmd.ip_addr.clone(),
                 cmd.ip_port.clone(),
-                cmd.use_curl);
+                cmd.use_curl,
+                &cmd.slot,
+                cmd.chunk_size);
             if result.is_err() {
                 println!("OTA operation failed {:?}", result);
+            }
+            if json_output.is_enabled() {
+                let sys_type = utils_get_sys_type(&cmd.sys_type, app_folder.clone()).ok();
+                let mut json_result = JsonResult::new("ota", start_time, sys_type, result.as_ref().err().map(|e| e.to_string()))
+                    .with_build_artifacts(&app_folder);
+                json_result.ip_addr = result.as_ref().ok().cloned();
+                json_output.write(&json_result);
+            }
+            if let Err(e) = &result {
+                std::process::exit(error_exit_code(e.as_ref(), EXIT_OTA_FAILED));
+            }
+        }
+        Action::Discover(cmd) => {
+            if let Err(e) = discover_raft_devices(&cmd) {
+                println!("Discovery failed: {}", e);
                 std::process::exit(1);
             }
         }
-        Action::Ports(cmd) => {
+        Action::Ports(mut cmd) => {
+            // Quiet also stops the message when no ports are found
+            cmd.quiet = quiet;
             manage_ports(&cmd);
         }
+        Action::Esptool(cmd) => {
+            match run_esptool(&cmd) {
+                Ok(exit_code) => std::process::exit(exit_code),
+                Err(e) => {
+                    println!("esptool failed {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Action::Update(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder.clone().unwrap_or(".".to_string()), true);
+            if let Err(e) = update_raft_app(&cmd, &app_folder) {
+                println!("Update failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Action::Sdkconfig(cmd) => {
+            // Get the app folder (or default to current folder)
+            let app_folder = get_app_folder(&cmd.app_folder().unwrap_or(".".to_string()), true);
+            if let Err(e) = sdkconfig_raft_app(&cmd, &app_folder) {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Action::Debug(cmd) => {
+            // The app folder is only used for the command history and completions
+            let app_folder = get_app_folder(&cmd.app_folder.clone().unwrap_or(".".to_string()), false);
+            if let Err(e) = start_debug_console(&cmd, &app_folder) {
+                println!("Debug console error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Action::Bridge(cmd) => {
+            if let Err(e) = start_bridge(&cmd) {
+                println!("Bridge error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Action::Versions(cmd) => {
+            // The app folder is only used for the ESP-IDF version in its Dockerfile
+            let app_folder = get_app_folder(&cmd.app_folder.clone().unwrap_or(".".to_string()), false);
+            if let Err(e) = show_versions(&cmd, &app_folder) {
+                println!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
     }
     std::process::exit(0);
 }
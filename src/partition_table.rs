@@ -0,0 +1,161 @@
+// RaftCLI: Partition table module
+// Rob Dobson 2024
+
+use std::path::Path;
+
+// Entry in an ESP-IDF partition table CSV file
+#[derive(Clone, Debug)]
+pub struct PartitionEntry {
+    pub name: String,
+    pub part_type: String,
+    pub subtype: String,
+    // Offset isn't required in the CSV (ESP-IDF places the partition at the next free offset)
+    pub offset: Option<u64>,
+    pub size: u64,
+}
+
+impl PartitionEntry {
+    // App partitions used for OTA updates (ota_0, ota_1, ...)
+    pub fn is_ota_app(&self) -> bool {
+        self.part_type == "app" && self.subtype.starts_with("ota_")
+    }
+}
+
+// Parse a size or offset value as used in partition tables (e.g. 0x1b0000, 4096, 64K, 2M)
+pub fn parse_partition_value(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = if let Some(number) = value.strip_suffix(['K', 'k']) {
+        (number, 1024)
+    } else if let Some(number) = value.strip_suffix(['M', 'm']) {
+        (number, 1024 * 1024)
+    } else {
+        (value, 1)
+    };
+    let number = if let Some(hex) = number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()?
+    } else {
+        number.parse::<u64>().ok()?
+    };
+    number.checked_mul(multiplier)
+}
+
+// Parse the contents of a partition table CSV file
+pub fn parse_partition_csv(csv: &str) -> Result<Vec<PartitionEntry>, String> {
+    let mut partitions = Vec::new();
+    for (line_idx, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Fields are name, type, subtype, offset, size, flags
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() < 5 {
+            return Err(format!("line {}: expected at least 5 fields but found {}", line_idx + 1, fields.len()));
+        }
+        // Offset is optional (the next free offset is used by ESP-IDF)
+        let offset = if fields[3].is_empty() {
+            None
+        } else {
+            Some(parse_partition_value(fields[3])
+                .ok_or_else(|| format!("line {}: invalid offset {}", line_idx + 1, fields[3]))?)
+        };
+        let size = parse_partition_value(fields[4])
+            .ok_or_else(|| format!("line {}: invalid size {}", line_idx + 1, fields[4]))?;
+        partitions.push(PartitionEntry {
+            name: fields[0].to_string(),
+            part_type: fields[1].to_string(),
+            subtype: fields[2].to_string(),
+            offset,
+            size,
+        });
+    }
+    Ok(partitions)
+}
+
+// Read and parse a partition table CSV file
+pub fn read_partition_table(path: &Path) -> Result<Vec<PartitionEntry>, String> {
+    let csv = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    parse_partition_csv(&csv).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Get the offset of each partition - partitions without an offset follow the previous one
+// (app partitions are aligned to 64K) as placed by ESP-IDF
+pub fn partition_offsets(partitions: &[PartitionEntry]) -> Vec<(&PartitionEntry, u64)> {
+    // Partitions start after the partition table (at 0x8000) by default
+    let mut next_offset: u64 = 0x9000;
+    partitions.iter().map(|partition| {
+        let offset = partition.offset.unwrap_or_else(|| {
+            if partition.part_type == "app" { next_offset.next_multiple_of(0x10000) } else { next_offset }
+        });
+        next_offset = offset + partition.size;
+        (partition, offset)
+    }).collect()
+}
+
+// Get the partition that ends last and the offset it ends at
+pub fn partition_table_end(partitions: &[PartitionEntry]) -> Option<(&PartitionEntry, u64)> {
+    partition_offsets(partitions).into_iter()
+        .map(|(partition, offset)| (partition, offset + partition.size))
+        .max_by_key(|(_, end)| *end)
+}
+
+// Get the smallest OTA app partition (an OTA image must fit in whichever slot is next)
+pub fn smallest_ota_app_partition(partitions: &[PartitionEntry]) -> Option<&PartitionEntry> {
+    partitions.iter()
+        .filter(|partition| partition.is_ota_app())
+        .min_by_key(|partition| partition.size)
+}
+
+// Get the partition the app is flashed to - the smallest OTA app partition if there are any
+// or otherwise the first app partition (e.g. factory)
+pub fn app_partition(partitions: &[PartitionEntry]) -> Option<&PartitionEntry> {
+    smallest_ota_app_partition(partitions)
+        .or_else(|| partitions.iter().find(|partition| partition.part_type == "app"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_partition_value() {
+        assert_eq!(parse_partition_value("0x1b0000"), Some(0x1b0000));
+        assert_eq!(parse_partition_value("0X9000"), Some(0x9000));
+        assert_eq!(parse_partition_value("4096"), Some(4096));
+        assert_eq!(parse_partition_value(" 64K "), Some(64 * 1024));
+        assert_eq!(parse_partition_value("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_partition_value("0x10K"), Some(16 * 1024));
+        assert_eq!(parse_partition_value("0xZZ"), None);
+        assert_eq!(parse_partition_value("12Q"), None);
+        assert_eq!(parse_partition_value(""), None);
+    }
+
+    #[test]
+    fn test_parse_partition_csv() {
+        let csv = "# Name, Type, SubType, Offset, Size, Flags\n\
+            nvs, data, nvs, 0x9000, 0x6000,\n\
+            otadata, data, ota, , 8K,\n\
+            ota_0, app, ota_0, , 1M,\n";
+        let partitions = parse_partition_csv(csv).unwrap();
+        assert_eq!(partitions.len(), 3);
+        assert_eq!(partitions[0].name, "nvs");
+        assert_eq!(partitions[0].offset, Some(0x9000));
+        assert_eq!(partitions[0].size, 0x6000);
+        assert_eq!(partitions[1].offset, None);
+        assert_eq!(partitions[1].size, 8 * 1024);
+        assert!(partitions[2].is_ota_app());
+        // Partitions without an offset follow on, with app partitions aligned to 64K
+        let offsets: Vec<u64> = partition_offsets(&partitions).iter().map(|(_, offset)| *offset).collect();
+        assert_eq!(offsets, vec![0x9000, 0xf000, 0x20000]);
+    }
+
+    #[test]
+    fn test_parse_partition_csv_malformed_row() {
+        let err = parse_partition_csv("nvs, data, nvs, 0x9000, 0x6000,\nphy_init, data\n").unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+        let err = parse_partition_csv("factory, app, factory, 0x10000, big,\n").unwrap_err();
+        assert!(err.contains("invalid size big"), "{}", err);
+    }
+}
@@ -0,0 +1,32 @@
+// RaftCLI: Project configuration module
+// Rob Dobson 2024
+
+use serde::Deserialize;
+use std::path::PathBuf;
+
+// Project configuration file in the app folder
+pub const PROJECT_CONFIG_FILE_NAME: &str = ".raftcli.toml";
+
+// Settings from the project configuration file (all optional)
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub build: BuildConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct BuildConfig {
+    // Build using docker (true) or a local ESP-IDF (false) - found automatically if not set
+    pub docker: Option<bool>,
+}
+
+// Read the project configuration from the app folder (the defaults are used if there isn't one)
+pub fn read_project_config(app_folder: &str) -> Result<ProjectConfig, Box<dyn std::error::Error>> {
+    let config_path = PathBuf::from(app_folder).join(PROJECT_CONFIG_FILE_NAME);
+    if !config_path.exists() {
+        return Ok(ProjectConfig::default());
+    }
+    let contents = std::fs::read_to_string(&config_path)?;
+    toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", config_path.display(), e).into())
+}
@@ -5,12 +5,18 @@ use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::fs;
 use std::error::Error;
-// use regex::Regex;
+use regex::Regex;
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
-use std::io::{self, BufRead, BufReader};
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use remove_dir_all::remove_dir_contents;
 use crossbeam::thread;
+use sha2::{Digest, Sha256};
+use crate::partition_table::parse_partition_value;
 
 pub fn default_esp_idf_version() -> String {
     // Default ESP-IDF version
@@ -21,10 +27,18 @@ pub fn utils_get_sys_type(
     build_sys_type: &Option<String>, 
     app_folder: String
 ) -> Result<String, Box<dyn std::error::Error>> {
-    // Determine the Systype to build - this is either the SysType passed in or
-    // the first SysType found in the systypes folder (excluding Common)
+    // Determine the Systype to build - this is either the SysType passed in (which must be
+    // one of the SysTypes in the systypes folder) or the first SysType found in the systypes
+    // folder (excluding Common)
     let mut sys_type: String = String::new();
     if let Some(build_sys_type) = build_sys_type {
+        // (not checked if the systypes folder can't be read)
+        if let Ok(sys_type_names) = get_sys_type_names(&app_folder) {
+            if !sys_type_names.contains(build_sys_type) {
+                return Err(format!("unknown SysType '{}', available: [{}]", build_sys_type,
+                            sys_type_names.join(", ")).into());
+            }
+        }
         sys_type = build_sys_type.to_string();
     } else {
         let sys_types = fs::read_dir(
@@ -40,7 +54,12 @@ pub fn utils_get_sys_type(
                 println!("Error reading the systypes folder: {}", sys_type_dir.err().unwrap());
                 return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Error reading the systypes folder")));
             }
-            let sys_type_name = sys_type_dir.unwrap().file_name().into_string().unwrap();
+            // (files such as the SysTypes manifest are skipped)
+            let sys_type_dir = sys_type_dir.unwrap();
+            if !sys_type_dir.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                continue;
+            }
+            let sys_type_name = sys_type_dir.file_name().into_string().unwrap();
             if sys_type_name != "Common" {
                 sys_type = sys_type_name;
                 break;
@@ -51,6 +70,44 @@ pub fn utils_get_sys_type(
     Ok(sys_type)
 }
 
+// Resolve the app folder to an absolute path, checking it exists and, if required, that it
+// contains a raft project
+pub fn resolve_app_folder(app_folder: &str, require_project: bool) -> Result<String, String> {
+    let resolved = fs::canonicalize(app_folder)
+        .map_err(|e| format!("app folder {} cannot be resolved: {}", app_folder, e))?;
+    let resolved = resolved.to_string_lossy().to_string();
+
+    // Remove the '\\?\' prefix if present (Windows extended-length path) as it doesn't allow
+    // the forward-slash separators used when building paths from the app folder
+    let resolved = match resolved.strip_prefix("\\\\?\\") {
+        Some(stripped) => stripped.to_string(),
+        None => resolved,
+    };
+
+    if require_project && !check_app_folder_valid(resolved.clone()) {
+        return Err(format!("{} is not a raft app folder (run from the app folder or specify it)", resolved));
+    }
+    Ok(resolved)
+}
+
+// Get the names of all SysTypes in the app folder (excluding Common)
+pub fn get_sys_type_names(app_folder: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let sys_types_folder = format!("{}/{}", app_folder, get_systypes_folder_name());
+    let mut sys_type_names = Vec::new();
+    for entry in fs::read_dir(&sys_types_folder)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let sys_type_name = entry.file_name().to_string_lossy().to_string();
+        if sys_type_name != "Common" {
+            sys_type_names.push(sys_type_name);
+        }
+    }
+    sys_type_names.sort();
+    Ok(sys_type_names)
+}
+
 // Check the app folder is valid
 pub fn check_app_folder_valid(app_folder: String) -> bool {
     // The app folder is valid if it exists and contains a CMakeLists.txt file
@@ -70,12 +127,55 @@ pub fn check_app_folder_valid(app_folder: String) -> bool {
     }
 }
 
-pub fn check_for_raft_artifacts_deletion(app_folder: String, sys_type: String) -> bool {
-    // Check if the "build_raft_artifacts" folder exists inside the app folder
-    // and if so extract the contents of the "cursystype.txt" file to determine
-    // the SysType of the last build - then check if this is the same as the
-    // sys_type to build and if not, delete the "build_raft_artifacts" folder
+// What the build_raft_artifacts folder was last built for (recorded after each build)
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct BuildMetadata {
+    pub sys_type: String,
+    pub chip: Option<String>,
+    pub idf_version: String,
+}
+
+// Build metadata file in the build_raft_artifacts folder
+pub const BUILD_METADATA_FILE: &str = "buildmeta.json";
+
+pub fn write_build_metadata(app_folder: &str, build_metadata: &BuildMetadata) -> Result<(), Box<dyn std::error::Error>> {
+    write_raft_artifact(app_folder, BUILD_METADATA_FILE, &serde_json::to_string_pretty(build_metadata)?)?;
+    Ok(())
+}
+
+pub fn check_for_raft_artifacts_deletion(app_folder: String, build_metadata: &BuildMetadata) -> bool {
+    // Check if the "build_raft_artifacts" folder exists inside the app folder and if so
+    // compare the SysType, chip and ESP-IDF version of the last build (from the build metadata)
+    // with the build to be done - if any has changed delete the "build_raft_artifacts" folder
     let build_raft_artifacts_folder = format!("{}/build_raft_artifacts", app_folder);
+    let sys_type = &build_metadata.sys_type;
+    if let Some(last_build) = read_raft_artifact(&app_folder, BUILD_METADATA_FILE) {
+        let last_build = match serde_json::from_str::<BuildMetadata>(&last_build) {
+            Ok(last_build) => last_build,
+            Err(e) => {
+                println!("Delete the build_raft_artifacts folder as the {} file is invalid: {}", BUILD_METADATA_FILE, e);
+                return true;
+            }
+        };
+        if last_build.sys_type != build_metadata.sys_type {
+            println!("Delete the build_raft_artifacts folder as the SysType to build has changed");
+            return true;
+        }
+        if last_build.chip != build_metadata.chip {
+            println!("Warning: the chip for SysType {} has changed from {} to {} - deleting the build_raft_artifacts folder",
+                        sys_type, last_build.chip.as_deref().unwrap_or("unknown"), build_metadata.chip.as_deref().unwrap_or("unknown"));
+            return true;
+        }
+        if last_build.idf_version != build_metadata.idf_version {
+            println!("Warning: the ESP-IDF version has changed from {} to {} - deleting the build_raft_artifacts folder",
+                        last_build.idf_version, build_metadata.idf_version);
+            return true;
+        }
+        return false;
+    }
+
+    // Builds from before the build metadata was recorded only have the SysType (in the
+    // "cursystype.txt" file written by the build)
     if Path::new(&build_raft_artifacts_folder).exists() {
         let cursystype_file = format!("{}/cursystype.txt", build_raft_artifacts_folder);
         if Path::new(&cursystype_file).exists() {
@@ -84,7 +184,7 @@ pub fn check_for_raft_artifacts_deletion(app_folder: String, sys_type: String) -
                 println!("Error reading the cursystype.txt file: {}", cursystype.err().unwrap());
                 return true;
             }
-            if cursystype.unwrap().trim() != sys_type {
+            if cursystype.unwrap().trim() != sys_type.as_str() {
                 println!("Delete the build_raft_artifacts folder as the SysType to build has changed");
                 return true;
             }
@@ -109,8 +209,7 @@ pub fn convert_path_for_docker(path: PathBuf) -> Result<String, std::io::Error>
     // Replace backslashes with forward slashes
     let docker_path = trimmed_path.replace("\\", "/");
 
-    // Debug
-    println!("Converted path: {} to: {}", path_str, docker_path);
+    debug!("Converted path: {} to: {}", path_str, docker_path);
 
     Ok(docker_path)
 }
@@ -120,27 +219,315 @@ pub fn convert_path_for_docker(path: PathBuf) -> Result<String, std::io::Error>
 pub enum CommandError {
     CommandNotFound(String),
     ExecutionFailed(String),
+    // The command was killed as it didn't finish within the timeout (in seconds)
+    Timeout(u64),
     Other(io::Error),
 }
 
 impl Display for CommandError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        // Implementation details here, for example:
-        write!(f, "{:?}", self) // Simple placeholder implementation
+        match self {
+            CommandError::Timeout(secs) => write!(f, "Command timed out after {}s and was killed", secs),
+            // Implementation details here, for example:
+            _ => write!(f, "{:?}", self), // Simple placeholder implementation
+        }
     }
 }
 
 impl Error for CommandError {}
 
-pub fn execute_and_capture_output(command: String, args: &Vec<String>, cur_dir: String, env_vars_to_add: HashMap<String, String>) -> Result<(String, bool), CommandError> {
-    
-    let process = Command::new(command.clone())
+// Outcome of a command that was executed - the output captured and the command's exit code
+#[derive(Clone, Debug)]
+pub struct CommandOutcome {
+    pub output: String,
+    pub exit_code: i32,
+}
+
+impl CommandOutcome {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+// Exit codes used by raft (documented in the README) so that scripts and CI can tell failures
+// apart - 0 is success and 1 is any other failure
+pub const EXIT_GENERIC_FAILURE: i32 = 1;
+pub const EXIT_INVALID_ARGS: i32 = 2;
+pub const EXIT_TOOL_MISSING: i32 = 3;
+pub const EXIT_PORT_NOT_FOUND: i32 = 4;
+pub const EXIT_BUILD_FAILED: i32 = 5;
+pub const EXIT_FLASH_FAILED: i32 = 6;
+pub const EXIT_OTA_FAILED: i32 = 7;
+
+// Error when a command ran but failed (the message includes the command's exit code)
+#[derive(Debug)]
+pub struct CommandExitError {
+    pub message: String,
+    pub exit_code: i32,
+}
+
+impl CommandExitError {
+    pub fn new(message: &str, exit_code: i32) -> CommandExitError {
+        CommandExitError { message: message.to_string(), exit_code }
+    }
+}
+
+impl Display for CommandExitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (exit code {})", self.message, self.exit_code)
+    }
+}
+
+impl Error for CommandExitError {}
+
+// Error when a tool needed (e.g. docker, idf.py, esptool or a matching ESP-IDF) isn't installed
+#[derive(Debug)]
+pub struct ToolMissingError {
+    pub message: String,
+}
+
+impl ToolMissingError {
+    pub fn new(message: &str) -> ToolMissingError {
+        ToolMissingError { message: message.to_string() }
+    }
+}
+
+impl Display for ToolMissingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ToolMissingError {}
+
+// Error when no serial port was given and no suitable port was found
+#[derive(Debug)]
+pub struct PortNotFoundError;
+
+impl Display for PortNotFoundError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "No suitable port found")
+    }
+}
+
+impl Error for PortNotFoundError {}
+
+// Get the exit code for raft to use for an error - EXIT_TOOL_MISSING if a tool needed wasn't
+// found, EXIT_PORT_NOT_FOUND if no serial port was found (the error may be wrapped in an
+// io::Error) and otherwise the failure exit code given
+pub fn error_exit_code(error: &(dyn Error + 'static), failure_exit_code: i32) -> i32 {
+    let inner_error = error.downcast_ref::<io::Error>()
+        .and_then(|io_error| io_error.get_ref())
+        .map(|inner| inner as &(dyn Error + 'static));
+    let errors = [Some(error), inner_error];
+    let tool_missing = errors.into_iter().flatten().any(|error| {
+        error.is::<ToolMissingError>()
+            || matches!(error.downcast_ref::<CommandError>(), Some(CommandError::CommandNotFound(_)))
+    });
+    if tool_missing {
+        EXIT_TOOL_MISSING
+    } else if errors.into_iter().flatten().any(|error| error.is::<PortNotFoundError>()) {
+        EXIT_PORT_NOT_FOUND
+    } else {
+        failure_exit_code
+    }
+}
+
+// Dry run mode - commands are printed rather than executed
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+// Set up logging of raft's diagnostics (to stderr) at the level chosen - info messages are
+// shown as they are and other levels are prefixed with the level (other crates only log
+// warnings and errors)
+pub fn init_logging(level: log::LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("raft", level)
+        .format(|buf, record| {
+            if record.level() == log::Level::Info {
+                writeln!(buf, "{}", record.args())
+            } else {
+                writeln!(buf, "{}: {}", record.level().as_str().to_lowercase(), record.args())
+            }
+        })
+        .init();
+}
+
+// Timeout (in seconds) for external commands - 0 means no timeout
+static COMMAND_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_command_timeout(timeout_secs: Option<u64>) {
+    COMMAND_TIMEOUT_SECS.store(timeout_secs.unwrap_or(0), Ordering::SeqCst);
+}
+
+fn get_command_timeout() -> Option<Duration> {
+    match COMMAND_TIMEOUT_SECS.load(Ordering::SeqCst) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+// External commands currently running (process ID and whether the command has its own process
+// group) so they can be stopped if raft is interrupted
+static RUNNING_COMMANDS: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+
+// Stop any external commands that are running (those in raft's process group also get Ctrl+C
+// from the terminal but those in their own group don't)
+pub fn kill_running_commands() {
+    let running_commands = RUNNING_COMMANDS.lock().map(|commands| commands.clone()).unwrap_or_default();
+    for (pid, own_group) in running_commands {
+        if cfg!(target_os = "windows") {
+            let _ = Command::new("taskkill").args(["/PID", &pid.to_string(), "/T", "/F"]).status();
+        } else {
+            let target = if own_group { format!("-{}", pid) } else { pid.to_string() };
+            let _ = Command::new("kill").args(["-TERM", "--", &target]).status();
+        }
+    }
+}
+
+// Kill a command that has timed out - on unix the command is started in its own process group
+// (when there is a timeout) so that the whole group (e.g. idf.py and the compilers it runs) is killed
+fn kill_timed_out_process(process: &mut std::process::Child) {
+    #[cfg(unix)]
+    {
+        let _ = Command::new("kill").args(["-KILL", "--", &format!("-{}", process.id())]).status();
+    }
+    let _ = process.kill();
+}
+
+// Format a command and its arguments so that it can be copied and pasted into a shell
+pub fn format_command_line(command: &str, args: &[String]) -> String {
+    let mut command_line = command.to_string();
+    for arg in args {
+        command_line.push(' ');
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'$;&|<>*?()".contains(c)) {
+            command_line.push_str(&format!("'{}'", arg.replace('\'', "'\\''")));
+        } else {
+            command_line.push_str(arg);
+        }
+    }
+    command_line
+}
+
+// Print the command that would be executed in dry run mode
+fn print_dry_run_command(command: &str, args: &[String], cur_dir: &str, env_vars_to_add: &HashMap<String, String>) {
+    println!("Dry run (in {}):", cur_dir);
+    let mut env_vars: Vec<_> = env_vars_to_add.iter().collect();
+    env_vars.sort();
+    for (key, value) in env_vars {
+        println!("  env {}={}", key, value);
+    }
+    println!("  {}", format_command_line(command, args));
+}
+
+pub fn execute_and_capture_output(command: String, args: &Vec<String>, cur_dir: String, env_vars_to_add: HashMap<String, String>) -> Result<CommandOutcome, CommandError> {
+    execute_and_stream_output(command, args, cur_dir, env_vars_to_add, OutputMode::All, None)
+}
+
+// Which lines of a command's output are shown on the console as it runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputMode {
+    All,
+    WarningsAndErrors,
+    Nothing,
+}
+
+impl OutputMode {
+    pub fn from_quiet(quiet: bool) -> OutputMode {
+        if quiet { OutputMode::WarningsAndErrors } else { OutputMode::All }
+    }
+
+    fn show_line(&self, line: &str) -> bool {
+        match self {
+            OutputMode::All => true,
+            OutputMode::WarningsAndErrors => is_build_warning_or_error(line),
+            OutputMode::Nothing => false,
+        }
+    }
+}
+
+// Check if a line of build output is a warning or error (shown even in quiet mode)
+pub fn is_build_warning_or_error(line: &str) -> bool {
+    let line_lower = line.to_lowercase();
+    line_lower.contains("warning:") || line_lower.contains("error:") || line_lower.contains("error ")
+        || line_lower.contains("failed")
+}
+
+// Get the compile error lines (those containing "error:") from build output
+pub fn get_build_error_lines(output: &str) -> Vec<&str> {
+    output.lines().filter(|line| line.contains("error:")).collect()
+}
+
+// Diagnostic (error, warning or note) from a gcc/clang style line of build output
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub severity: String,
+    pub message: String,
+}
+
+// Parse the gcc/clang style diagnostics (file:line[:column]: severity: message) in build output
+pub fn parse_build_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let diagnostic_re = Regex::new(r"^(.+?):(\d+):(?:(\d+):)?\s*(fatal error|error|warning|note):\s*(.*)$").unwrap();
+    output.lines().filter_map(|line| {
+        let caps = diagnostic_re.captures(line.trim_end())?;
+        Some(Diagnostic {
+            file: caps[1].trim().to_string(),
+            line: caps[2].parse().ok()?,
+            column: caps.get(3).and_then(|column| column.as_str().parse().ok()),
+            severity: if &caps[4] == "fatal error" { "error".to_string() } else { caps[4].to_string() },
+            message: caps[5].to_string(),
+        })
+    }).collect()
+}
+
+// Print a summary of compile errors so failures are easy to spot at the end of the build output
+pub fn print_build_error_summary(output: &str) {
+    let error_lines = get_build_error_lines(output);
+    if error_lines.is_empty() {
+        return;
+    }
+    eprintln!("\nBuild errors ({}):", error_lines.len());
+    for line in error_lines {
+        eprintln!("  {}", line.trim());
+    }
+}
+
+// Execute a command calling on_line with each line of its output as it is produced (the flag is
+// true for stdout and false for stderr) and return the command's exit code
+pub fn execute_and_capture_output_cb(command: String, args: &Vec<String>, cur_dir: String, env_vars_to_add: HashMap<String, String>,
+                on_line: impl FnMut(&str, bool) + Send) -> Result<i32, CommandError> {
+
+    // In dry run mode just show the command and report success
+    if is_dry_run() {
+        print_dry_run_command(&command, args, &cur_dir, &env_vars_to_add);
+        return Ok(0);
+    }
+
+    let mut process_command = Command::new(command.clone());
+    process_command
         .current_dir(cur_dir)
         .args(args)
         .envs(env_vars_to_add.iter())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn();
+        .stderr(Stdio::piped());
+    let timeout = get_command_timeout();
+    #[cfg(unix)]
+    if timeout.is_some() {
+        use std::os::unix::process::CommandExt;
+        process_command.process_group(0);
+    }
+    let process = process_command.spawn();
 
     // Match on the result
     let mut process = match process {
@@ -154,6 +541,9 @@ pub fn execute_and_capture_output(command: String, args: &Vec<String>, cur_dir:
         }
     };
 
+    let pid = process.id();
+    RUNNING_COMMANDS.lock().unwrap().push((pid, cfg!(unix) && timeout.is_some()));
+
     // Capture the output
     let stdout = process.stdout.take().unwrap();
     let stderr = process.stderr.take().unwrap();
@@ -161,50 +551,110 @@ pub fn execute_and_capture_output(command: String, args: &Vec<String>, cur_dir:
     let stdout_reader = BufReader::new(stdout);
     let stderr_reader = BufReader::new(stderr);
 
-    let captured_output = Arc::new(Mutex::new(String::new()));
+    // The callback is shared by the stdout and stderr threads
+    let on_line = Mutex::new(on_line);
 
-    // Using crossbeam to handle threads
+    // Using crossbeam to handle threads - the output threads end when the process does (and
+    // are joined at the end of the scope so no output is lost)
+    let start_time = Instant::now();
+    let mut timed_out = false;
     let thread_result = thread::scope(|s| {
-        let captured = Arc::clone(&captured_output);
+        let on_line = &on_line;
         s.spawn(move |_| {
             for line in stdout_reader.lines() {
                 match line {
-                    Ok(line) => {
-                        println!("{}", line); // Print to console
-                        let mut captured = captured.lock().unwrap();
-                        captured.push_str(&line);
-                        captured.push('\n');
-                    }
+                    Ok(line) => (on_line.lock().unwrap())(&line, true),
                     Err(_) => break,
                 }
             }
         });
 
-        let captured = Arc::clone(&captured_output);
         s.spawn(move |_| {
             for line in stderr_reader.lines() {
                 match line {
-                    Ok(line) => {
-                        eprintln!("{}", line); // Print to console
-                        let mut captured = captured.lock().unwrap();
-                        captured.push_str(&line);
-                        captured.push('\n');
-                    }
+                    Ok(line) => (on_line.lock().unwrap())(&line, false),
                     Err(_) => break,
                 }
             }
         });
+
+        // Kill the process if it doesn't finish within the timeout
+        if let Some(timeout) = timeout {
+            while let Ok(None) = process.try_wait() {
+                if start_time.elapsed() >= timeout {
+                    kill_timed_out_process(&mut process);
+                    timed_out = true;
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
     });
 
     // Handle thread problems
     if thread_result.is_err() {
+        RUNNING_COMMANDS.lock().unwrap().retain(|(running_pid, _)| *running_pid != pid);
         return Err(CommandError::ExecutionFailed("Failed to execute threads".into()));
     }
 
-    // Wait for the process to finish
-    let output = captured_output.lock().unwrap().clone();
-    let success_flag = process.wait().unwrap().success();
-    Ok((output, success_flag))
+    // Wait for the process to finish (a process ended by a signal has no exit code)
+    let status = process.wait();
+    RUNNING_COMMANDS.lock().unwrap().retain(|(running_pid, _)| *running_pid != pid);
+    let status = status.map_err(CommandError::Other)?;
+    if timed_out {
+        return Err(CommandError::Timeout(timeout.unwrap_or_default().as_secs()));
+    }
+    Ok(status.code().unwrap_or(if status.success() { 0 } else { 1 }))
+}
+
+// Execute a command streaming its output to the console line by line as it is produced and
+// return the captured output - the output mode controls which lines are shown and all output is
+// appended to the output log file if one is specified
+pub fn execute_and_stream_output(command: String, args: &Vec<String>, cur_dir: String, env_vars_to_add: HashMap<String, String>,
+                output_mode: OutputMode, output_log_path: Option<&str>) -> Result<CommandOutcome, CommandError> {
+
+    // Open the output log file (not needed in dry run mode)
+    let mut output_log = match output_log_path {
+        Some(path) if !is_dry_run() => {
+            Some(fs::OpenOptions::new().create(true).append(true).open(path).map_err(CommandError::Other)?)
+        }
+        _ => None,
+    };
+
+    let mut captured_output = String::new();
+    let exit_code = execute_and_capture_output_cb(command, args, cur_dir, env_vars_to_add, |line, is_stdout| {
+        if output_mode.show_line(line) {
+            // Print to console
+            if is_stdout {
+                println!("{}", line);
+            } else {
+                eprintln!("{}", line);
+            }
+        }
+        if let Some(output_log) = output_log.as_mut() {
+            let _ = writeln!(output_log, "{}", line);
+        }
+        captured_output.push_str(line);
+        captured_output.push('\n');
+    })?;
+    Ok(CommandOutcome { output: captured_output, exit_code })
+}
+
+// Execute a command interactively (using the console for its input and output) returning its
+// exit code - there is no timeout as the user decides when the command ends
+pub fn execute_interactive(command: &str, args: &[String], cur_dir: &str) -> Result<i32, CommandError> {
+    if is_dry_run() {
+        print_dry_run_command(command, args, cur_dir, &HashMap::new());
+        return Ok(0);
+    }
+    let status = Command::new(command).current_dir(cur_dir).args(args).status().map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            CommandError::CommandNotFound(format!("{}: No such file or directory", command))
+        } else {
+            CommandError::Other(e)
+        }
+    })?;
+    Ok(status.code().unwrap_or(if status.success() { 0 } else { 1 }))
 }
 
 fn get_systypes_folder_name() -> &'static str {
@@ -238,6 +688,66 @@ pub fn is_wsl() -> bool {
     }
 }
 
+// Check if an error message indicates a serial port is busy or access was denied
+pub fn is_serial_port_busy_msg(error_msg: &str) -> bool {
+    let error_msg = error_msg.to_lowercase();
+    error_msg.contains("busy")
+        || error_msg.contains("permission denied")
+        || error_msg.contains("access is denied")
+        || error_msg.contains("errno 13")
+        || error_msg.contains("errno 16")
+}
+
+// Explain the likely causes of a serial port being busy or inaccessible
+pub fn serial_port_busy_hint(port: &str) -> String {
+    let mut hint = format!(
+        "Serial port {} is busy or access was denied - it is probably open in another program \
+        (e.g. another raft monitor, Arduino IDE, PlatformIO, VS Code serial monitor, screen or minicom) \
+        - close that program and try again",
+        port
+    );
+
+    // On linux the user must also be a member of the group owning serial devices
+    #[cfg(target_os = "linux")]
+    {
+        let groups = Command::new("id").arg("-nG").output();
+        let in_serial_group = match groups {
+            Ok(output) => String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .any(|group| group == "dialout" || group == "uucp"),
+            Err(_) => false,
+        };
+        if !in_serial_group {
+            hint += "\nYour user does not appear to be in the dialout group - add it with \
+                'sudo usermod -a -G dialout $USER' then log out and back in";
+        }
+    }
+
+    hint
+}
+
+// Folders tools may be installed in that might not be in the PATH (e.g. when not started from a
+// login shell) - on macOS these are the Homebrew folders (Apple silicon and Intel) and the pipx
+// folder
+fn get_extra_tool_folders() -> Vec<PathBuf> {
+    if !cfg!(target_os = "macos") {
+        return Vec::new();
+    }
+    vec![
+        PathBuf::from("/opt/homebrew/bin"),
+        PathBuf::from("/usr/local/bin"),
+        dirs::home_dir().unwrap_or_default().join(".local").join("bin"),
+    ]
+}
+
+// Find an executable in the folders given (returning its full path)
+fn find_executable_in_folders(folders: &[PathBuf], executables: &[&str]) -> Option<String> {
+    folders.iter()
+        .flat_map(|folder| executables.iter().map(move |exe| folder.join(exe)))
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().to_string())
+}
+
 pub fn find_executable(executables: &[&str]) -> Option<String> {
     // println!("executables: {:?}", executables);
     for &exe in executables {
@@ -267,6 +777,8 @@ pub fn get_flash_tool_cmd(flash_tool_opt: Option<String>, native_serial_port: bo
 
             if let Some(exe) = find_executable(&possible_executables) {
                 exe
+            } else if let Some(exe) = find_executable_in_folders(&get_extra_tool_folders(), &possible_executables) {
+                exe
             } else {
                 // Fallback to default if not found
                 if cfg!(target_os = "windows") {
@@ -284,6 +796,44 @@ pub fn get_build_folder_name(sys_type: String, app_folder: String) -> String {
     build_folder_name
 }
 
+// Delete a folder (if it exists) - in dry run mode just show what would be deleted
+fn delete_folder(folder: &str) -> Result<(), std::io::Error> {
+    if Path::new(folder).exists() {
+        if is_dry_run() {
+            println!("Dry run: would delete {}", folder);
+        } else {
+            fs::remove_dir_all(folder)?;
+        }
+    }
+    Ok(())
+}
+
+// Delete the build folder for a SysType and/or the "build_raft_artifacts" folder
+pub fn delete_build_folders(app_folder: &str, sys_type: &str, delete_build_folder: bool,
+            delete_raft_artifacts_folder: bool) -> Result<(), std::io::Error> {
+    if delete_build_folder {
+        delete_folder(&get_build_folder_name(sys_type.to_string(), app_folder.to_string()))?;
+    }
+    if delete_raft_artifacts_folder {
+        delete_folder(&format!("{}/build_raft_artifacts", app_folder))?;
+    }
+    Ok(())
+}
+
+// Delete the build folders for all SysTypes and the "build_raft_artifacts" folder
+pub fn delete_all_build_folders(app_folder: &str) -> Result<(), std::io::Error> {
+    let build_folder = format!("{}/build", app_folder);
+    if Path::new(&build_folder).exists() {
+        for entry in fs::read_dir(&build_folder)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                delete_folder(&path.to_string_lossy())?;
+            }
+        }
+    }
+    delete_folder(&format!("{}/build_raft_artifacts", app_folder))
+}
+
 // pub fn get_device_type(sys_type: String, app_folder: String) -> String {
 //     // Get build folder
 //     let build_folder = get_build_folder_name(sys_type, app_folder);
@@ -312,28 +862,37 @@ pub fn get_build_folder_name(sys_type: String, app_folder: String) -> String {
 //     device_type.unwrap()[1].to_string()
 // }
 
+// Read and parse the flasher_args.json file that ESP-IDF generates in the build folder
+pub fn read_flasher_args(build_folder: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let flasher_args_file = format!("{}/flasher_args.json", build_folder);
+    let flash_args = fs::read_to_string(&flasher_args_file)
+        .map_err(|e| format!("Unable to read {}: {}", flasher_args_file, e))?;
+    serde_json::from_str(&flash_args)
+        .map_err(|e| format!("Invalid {}: {}", flasher_args_file, e).into())
+}
+
+// Build the esptool arguments to flash the build - the chip is the one given or, if not given,
+// the one in flasher_args.json
 pub fn build_flash_command_args(
     build_folder: String,
     port: &str,
     flash_baud: u32,
+    verify: bool,
+    only_offset: Option<u64>,
+    chip: Option<&str>,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    // Flash arguments file
-    let flash_args_file = format!("{}/flasher_args.json", build_folder);
-
-    // Read the flash arguments json file
-    let flash_args = fs::read_to_string(&flash_args_file)?;
-
-    // Extract the flash arguments
-    let flash_args: serde_json::Value = serde_json::from_str(&flash_args)?;
+    // Read the flash arguments
+    let flash_args = read_flasher_args(&build_folder)?;
 
     // Flash baud string
     let flash_baud = format!("{}", flash_baud);
 
-    // Extract flash settings
-    let flash_mode = flash_args["flash_settings"]["flash_mode"].as_str().unwrap();
-    let flash_size = flash_args["flash_settings"]["flash_size"].as_str().unwrap();
-    let flash_freq = flash_args["flash_settings"]["flash_freq"].as_str().unwrap();
-    let chip_type = flash_args["extra_esptool_args"]["chip"].as_str().unwrap();
+    // Extract the chip type
+    let chip_type = match chip {
+        Some(chip) => chip,
+        None => flash_args["extra_esptool_args"]["chip"].as_str()
+            .ok_or_else(|| format!("Chip not found in {}/flasher_args.json", build_folder))?,
+    };
 
     // Create initial esptool arguments
     let mut esptool_args = vec![
@@ -348,26 +907,218 @@ pub fn build_flash_command_args(
         "--chip".to_string(),
         chip_type.to_string(),
         "write_flash".to_string(),
-        "--flash_mode".to_string(),
-        flash_mode.to_string(),
-        "--flash_size".to_string(),
-        flash_size.to_string(),
-        "--flash_freq".to_string(),
-        flash_freq.to_string(),
     ];
+    esptool_args.extend(flash_settings_args(&flash_args, &build_folder)?);
+
+    // Read back the flash after writing
+    if verify {
+        esptool_args.push("--verify".to_string());
+    }
 
-    // Extract and append flash files and their offsets
+    // Extract and append flash files and their offsets (just the file at only_offset if specified)
+    let mut num_files = 0;
     if let Some(flash_files) = flash_args["flash_files"].as_object() {
         for (offset, file_path) in flash_files {
-            let full_path = format!("{}/{}", build_folder, file_path.as_str().unwrap());
+            if only_offset.is_some_and(|only_offset| parse_partition_value(offset) != Some(only_offset)) {
+                continue;
+            }
+            let full_path = format!("{}/{}", build_folder, flash_file_path(file_path, &build_folder)?);
             esptool_args.push(offset.clone());
             esptool_args.push(full_path);
+            num_files += 1;
         }
     }
+    if let Some(only_offset) = only_offset {
+        if num_files == 0 {
+            return Err(format!("No file to flash at offset 0x{:x} in {}/flasher_args.json", only_offset, build_folder).into());
+        }
+    }
+
+    Ok(esptool_args)
+}
+
+// Get the esptool flash mode, size and frequency arguments from flasher_args.json
+fn flash_settings_args(flash_args: &serde_json::Value, build_folder: &str) -> Result<Vec<String>, String> {
+    let flash_setting = |name: &str| {
+        flash_args["flash_settings"][name].as_str()
+            .map(|value| value.to_string())
+            .ok_or_else(|| format!("Flash setting {} not found in {}/flasher_args.json", name, build_folder))
+    };
+    Ok(vec![
+        "--flash_mode".to_string(),
+        flash_setting("flash_mode")?,
+        "--flash_size".to_string(),
+        flash_setting("flash_size")?,
+        "--flash_freq".to_string(),
+        flash_setting("flash_freq")?,
+    ])
+}
 
+// Get the path of a file to flash from flasher_args.json
+fn flash_file_path<'a>(file_path: &'a serde_json::Value, build_folder: &str) -> Result<&'a str, String> {
+    file_path.as_str()
+        .ok_or_else(|| format!("Invalid flash file {} in {}/flasher_args.json", file_path, build_folder))
+}
+
+// Build the esptool arguments to merge all the files in flasher_args.json into a single image
+// to be flashed at offset 0 (e.g. for factory programming)
+pub fn build_merge_bin_command_args(
+    build_folder: &str,
+    output: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let flash_args = read_flasher_args(build_folder)?;
+    let chip_type = flash_args["extra_esptool_args"]["chip"].as_str()
+        .ok_or_else(|| format!("Chip not found in {}/flasher_args.json", build_folder))?;
+    let mut esptool_args = vec![
+        "--chip".to_string(),
+        chip_type.to_string(),
+        "merge_bin".to_string(),
+        "-o".to_string(),
+        output.to_string(),
+    ];
+    esptool_args.extend(flash_settings_args(&flash_args, build_folder)?);
+    let Some(flash_files) = flash_args["flash_files"].as_object().filter(|files| !files.is_empty()) else {
+        return Err(format!("No files to merge in {}/flasher_args.json", build_folder).into());
+    };
+    for (offset, file_path) in flash_files {
+        esptool_args.push(offset.clone());
+        esptool_args.push(format!("{}/{}", build_folder, flash_file_path(file_path, build_folder)?));
+    }
     Ok(esptool_args)
 }
 
+// Build the esptool arguments to erase the entire flash (or just a region given as offset and
+// size) - the chip type is detected by esptool if it isn't known
+pub fn build_erase_command_args(
+    chip_type: Option<&str>,
+    port: &str,
+    flash_baud: u32,
+    region: Option<(u64, u64)>,
+) -> Vec<String> {
+    let mut esptool_args = vec![
+        "-p".to_string(),
+        port.to_string(),
+        "-b".to_string(),
+        flash_baud.to_string(),
+        "--before".to_string(),
+        "default_reset".to_string(),
+        "--after".to_string(),
+        "no_reset".to_string(),
+    ];
+    if let Some(chip_type) = chip_type {
+        esptool_args.push("--chip".to_string());
+        esptool_args.push(chip_type.to_string());
+    }
+    match region {
+        Some((offset, size)) => {
+            esptool_args.push("erase_region".to_string());
+            esptool_args.push(format!("0x{:x}", offset));
+            esptool_args.push(format!("0x{:x}", size));
+        }
+        None => esptool_args.push("erase_flash".to_string()),
+    }
+    esptool_args
+}
+
+// Build the esptool arguments to flash a single image file at an offset (without a build folder)
+// - the chip type is detected by esptool if it isn't given and the flash settings in the image
+// are kept
+pub fn build_image_flash_command_args(
+    image: &str,
+    chip_type: Option<&str>,
+    offset: u64,
+    port: &str,
+    flash_baud: u32,
+    verify: bool,
+) -> Vec<String> {
+    let mut esptool_args = vec![
+        "-p".to_string(),
+        port.to_string(),
+        "-b".to_string(),
+        flash_baud.to_string(),
+        "--before".to_string(),
+        "default_reset".to_string(),
+        "--after".to_string(),
+        "hard_reset".to_string(),
+    ];
+    if let Some(chip_type) = chip_type {
+        esptool_args.push("--chip".to_string());
+        esptool_args.push(chip_type.to_string());
+    }
+    esptool_args.push("write_flash".to_string());
+    if verify {
+        esptool_args.push("--verify".to_string());
+    }
+    esptool_args.push(format!("0x{:x}", offset));
+    esptool_args.push(image.to_string());
+    esptool_args
+}
+
+// Compute a hash over the firmware files (and their offsets) listed in flasher_args.json
+pub fn compute_firmware_hash(build_folder: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let flash_args = read_flasher_args(build_folder)?;
+    let mut hasher = Sha256::new();
+    if let Some(flash_files) = flash_args["flash_files"].as_object() {
+        for (offset, file_path) in flash_files {
+            let file_path = file_path.as_str().unwrap_or_default();
+            hasher.update(offset.as_bytes());
+            hasher.update(file_path.as_bytes());
+            hasher.update(fs::read(format!("{}/{}", build_folder, file_path))?);
+        }
+    }
+    let hash = hasher.finalize();
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Firmware hash files stored alongside cursystype.txt in the build_raft_artifacts folder
+pub const BUILT_FIRMWARE_HASH_FILE: &str = "curfwhash.txt";
+pub const FLASHED_FIRMWARE_HASH_FILE: &str = "lastflashedhash.txt";
+
+// Last device address used for OTA (used to find the device's web UI)
+pub const LAST_DEVICE_ADDR_FILE: &str = "lastdeviceaddr.txt";
+
+// Small values (e.g. firmware hashes) are stored in files in the build_raft_artifacts folder
+pub fn write_raft_artifact(app_folder: &str, file_name: &str, value: &str) -> Result<(), std::io::Error> {
+    let build_raft_artifacts_folder = format!("{}/build_raft_artifacts", app_folder);
+    fs::create_dir_all(&build_raft_artifacts_folder)?;
+    fs::write(format!("{}/{}", build_raft_artifacts_folder, file_name), value)
+}
+
+pub fn read_raft_artifact(app_folder: &str, file_name: &str) -> Option<String> {
+    fs::read_to_string(format!("{}/build_raft_artifacts/{}", app_folder, file_name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+pub fn write_firmware_hash(app_folder: &str, hash_file_name: &str, hash: &str) -> Result<(), std::io::Error> {
+    write_raft_artifact(app_folder, hash_file_name, hash)
+}
+
+pub fn read_firmware_hash(app_folder: &str, hash_file_name: &str) -> Option<String> {
+    read_raft_artifact(app_folder, hash_file_name)
+}
+
+// Open a URL in the default browser
+pub fn open_in_browser(url: &str) -> Result<(), std::io::Error> {
+    let mut command = if cfg!(target_os = "windows") || is_wsl() {
+        let mut command = Command::new(if cfg!(target_os = "windows") { "cmd" } else { "cmd.exe" });
+        command.args(["/C", "start", "", url]);
+        command
+    } else if cfg!(target_os = "macos") {
+        let mut command = Command::new("open");
+        command.arg(url);
+        command
+    } else {
+        let mut command = Command::new("xdg-open");
+        command.arg(url);
+        command
+    };
+    let status = command.stdout(Stdio::null()).stderr(Stdio::null()).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("Unable to open browser for {}", url)));
+    }
+    Ok(())
+}
 
 // Check the target folder is valid
 pub fn check_target_folder_valid(target_folder: &str, clean: bool) -> bool {
@@ -408,62 +1159,90 @@ pub fn is_esp_idf_env() -> bool {
     env::var("IDF_PATH").is_ok()
 }
 
-// Check if the ESP IDF version is correct
-pub fn idf_version_ok(required_esp_idf_version: String) -> bool {
-    // Run the idf.py --version command
-    let idf_output = Command::new("idf.py")
-        .arg("--version")
-        .output()
-        .expect("Failed to run idf.py --version");
+// Get the version from idf.py --version output (e.g. "ESP-IDF v5.3.1-dirty" gives 5.3.1)
+pub fn parse_idf_version(idf_version_output: &str) -> &str {
+    idf_version_output
+        .split_whitespace() // Split by whitespace
+        .nth(1)             // Get the second token (e.g., "v5.3.1-dirty")
+        .unwrap_or("")      // Fallback to an empty string if parsing fails
+        .trim_start_matches('v') // Remove the leading 'v' if present
+        .split('-')         // Split by '-' to ignore any suffix like '-dirty'
+        .next()             // Take the first part (e.g., "5.3.1")
+        .unwrap_or("")
+}
 
-    // TODO remove
-    println!("idf_version returned from idf.py: {:?}", idf_output);
+// Get the version of the ESP-IDF that is set up (from idf.py --version)
+pub fn get_local_idf_version() -> Option<String> {
+    // Run the idf.py --version command
+    let idf_output = match Command::new("idf.py").arg("--version").output() {
+        Ok(idf_output) => idf_output,
+        Err(e) => {
+            debug!("Unable to run idf.py --version: {}", e);
+            return None;
+        }
+    };
+    trace!("idf_version returned from idf.py: {:?}", idf_output);
 
     // Check if the command was successful
     if !idf_output.status.success() {
-        println!("Failed to run idf.py --version");
-        return false;
+        debug!("Failed to run idf.py --version");
+        return None;
     }
 
     // Extract the version string from the output
     let idf_version_output = String::from_utf8_lossy(&idf_output.stdout);
-    let idf_version = idf_version_output
-        .split_whitespace() // Split by whitespace
-        .nth(1)             // Get the second token (e.g., "v5.3.1-dirty")
-        .unwrap_or("")      // Fallback to an empty string if parsing fails
-        .trim_start_matches('v') // Remove the leading 'v' if present
-        .split('-')         // Split by '-' to ignore any suffix like '-dirty'
-        .next()             // Take the first part (e.g., "5.3.1")
-        .unwrap_or("");
+    let idf_version = parse_idf_version(&idf_version_output);
+    if idf_version.is_empty() {
+        return None;
+    }
+    Some(idf_version.to_string())
+}
 
-    // Normalize both versions to major.minor.patch format
+// Check ESP-IDF versions are the same (compared in major.minor.patch format)
+pub fn idf_versions_match(idf_version: &str, required_esp_idf_version: &str) -> bool {
     let idf_version_normalized = idf_version.split('.').take(3).collect::<Vec<&str>>().join(".");
     let required_version_normalized = required_esp_idf_version.split('.').take(3).collect::<Vec<&str>>().join(".");
 
-    // Debugging: Print normalized versions
-    println!(
+    debug!(
         "idf_version_normalized: {:?}, required_version_normalized: {:?}",
         idf_version_normalized, required_version_normalized
     );
+    idf_version_normalized == required_version_normalized
+}
 
-    // Compare the normalized versions
-    if idf_version_normalized != required_version_normalized {
-        println!(
-            "Error: ESP-IDF version mismatch: Required: {}, Found: {}",
-            required_version_normalized, idf_version_normalized
+// Check if the ESP IDF version is correct
+pub fn idf_version_ok(required_esp_idf_version: String) -> bool {
+    let Some(idf_version) = get_local_idf_version() else {
+        return false;
+    };
+    if !idf_versions_match(&idf_version, &required_esp_idf_version) {
+        info!(
+            "ESP-IDF {} is set up but the app needs {} - looking for a matching ESP-IDF",
+            idf_version, required_esp_idf_version
         );
         return false;
     }
-
     true
 }
 
+// Get the first line a tool outputs for --version (None if it can't be run)
+pub fn get_tool_version(tool: &str) -> Option<String> {
+    let output = Command::new(tool).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // Some tools (e.g. older pythons) print their version to stderr
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    stdout.lines().chain(stderr.lines())
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .map(|line| line.to_string())
+}
+
 // Function to check if Docker is available
 pub fn is_docker_available() -> bool {
-    Command::new("docker")
-        .arg("--version")
-        .output()
-        .map_or(false, |output| output.status.success())
+    get_tool_version("docker").is_some()
 }
 
 pub fn get_esp_idf_version_from_dockerfile(dockerfile_path: &str) -> Result<String, Box<dyn std::error::Error>> {
@@ -485,57 +1264,175 @@ pub fn get_esp_idf_version_from_dockerfile(dockerfile_path: &str) -> Result<Stri
     )))
 }
 
-pub fn find_matching_esp_idf(target_version: String, user_path: Option<String>) -> Option<PathBuf> {
+// ESP-IDF version numbers (major, minor and, if known, patch)
+type IdfVersion = (u32, u32, Option<u32>);
+
+// ESP-IDF installation chosen for a local build and why it was chosen
+#[derive(Debug)]
+pub struct EspIdfMatch {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+// Get the version numbers from a version or folder name (e.g. 5.5.1, esp-idf-v5.5.1, release-v5.5)
+fn parse_idf_version_numbers(version: &str) -> Option<IdfVersion> {
+    let caps = Regex::new(r"(\d+)\.(\d+)(?:\.(\d+))?").unwrap().captures(version)?;
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?, caps.get(3).and_then(|patch| patch.as_str().parse().ok())))
+}
+
+fn format_idf_version(version: &IdfVersion) -> String {
+    match version.2 {
+        Some(patch) => format!("{}.{}.{}", version.0, version.1, patch),
+        None => format!("{}.{}", version.0, version.1),
+    }
+}
+
+// Get the version of an ESP-IDF folder - from tools/cmake/version.cmake if possible (so folders
+// such as esp-idf or release-v5.5 have a full version) and otherwise from the folder name or the
+// name of the folder containing it (e.g. v5.5.1/esp-idf)
+fn esp_idf_folder_version(idf_folder: &Path) -> Option<IdfVersion> {
+    if let Ok(version_cmake) = fs::read_to_string(idf_folder.join("tools").join("cmake").join("version.cmake")) {
+        let component = |name: &str| -> Option<u32> {
+            Regex::new(&format!(r"set\s*\(\s*IDF_VERSION_{}\s+(\d+)\s*\)", name)).unwrap()
+                .captures(&version_cmake)
+                .and_then(|caps| caps[1].parse().ok())
+        };
+        if let (Some(major), Some(minor)) = (component("MAJOR"), component("MINOR")) {
+            return Some((major, minor, component("PATCH")));
+        }
+    }
+    parse_idf_version_numbers(&idf_folder.file_name()?.to_string_lossy())
+        .or_else(|| parse_idf_version_numbers(&idf_folder.parent()?.file_name()?.to_string_lossy()))
+}
+
+// Get the version of a folder if it is an ESP-IDF folder (one containing tools/idf.py and the
+// export script used to set up the environment)
+fn installed_esp_idf(path: PathBuf) -> Option<(PathBuf, IdfVersion)> {
+    let export_script = if cfg!(target_os = "windows") { "export.bat" } else { "export.sh" };
+    if !path.join("tools").join("idf.py").is_file() || !path.join(export_script).is_file() {
+        return None;
+    }
+    let version = esp_idf_folder_version(&path)?;
+    Some((path, version))
+}
+
+// Get the ESP-IDF folders in a folder along with their versions (including those in an esp-idf
+// folder one level down such as v5.5.1/esp-idf)
+fn find_installed_esp_idfs(folder: &Path) -> Vec<(PathBuf, IdfVersion)> {
+    let Ok(entries) = folder.read_dir() else {
+        return Vec::new();
+    };
+    entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| installed_esp_idf(entry.path()).or_else(|| installed_esp_idf(entry.path().join("esp-idf"))))
+        .collect()
+}
+
+// Get the ESP-IDF folders listed by the ESP-IDF installers and idf_tools.py - these are in the
+// idfInstalled entries of esp_idf.json or idf-env.json in the ESP-IDF tools folder
+fn get_registered_esp_idf_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for tools_path in get_esp_idf_tools_paths() {
+        for file_name in ["esp_idf.json", "idf-env.json"] {
+            let Ok(contents) = fs::read_to_string(tools_path.join(file_name)) else {
+                continue;
+            };
+            let idf_env: serde_json::Value = match serde_json::from_str(&contents) {
+                Ok(idf_env) => idf_env,
+                Err(e) => {
+                    debug!("Unable to read {:?}: {}", tools_path.join(file_name), e);
+                    continue;
+                }
+            };
+            let installed: Vec<&serde_json::Value> = match &idf_env["idfInstalled"] {
+                serde_json::Value::Object(installed) => installed.values().collect(),
+                serde_json::Value::Array(installed) => installed.iter().collect(),
+                _ => Vec::new(),
+            };
+            paths.extend(installed.iter().filter_map(|idf| idf["path"].as_str()).map(PathBuf::from));
+        }
+    }
+    paths
+}
+
+// Choose the ESP-IDF to use - an exact match for the version needed or, unless exact is set, the
+// newest with the same major.minor version (any version can be used for latest)
+fn choose_esp_idf(target_version: &str, installed: Vec<(PathBuf, IdfVersion)>, exact: bool) -> Option<EspIdfMatch> {
+    let newest = |candidates: Vec<(PathBuf, IdfVersion)>| {
+        candidates.into_iter().max_by_key(|(_, version)| (version.0, version.1, version.2.unwrap_or(0)))
+    };
+    if target_version == "latest" {
+        let (path, version) = newest(installed)?;
+        return Some(EspIdfMatch { path, reason: format!("{} is the newest installed and the app uses latest",
+                    format_idf_version(&version)) });
+    }
+    let required = parse_idf_version_numbers(target_version)?;
+    let same_minor: Vec<(PathBuf, IdfVersion)> = installed.into_iter()
+        .filter(|(_, version)| version.0 == required.0 && version.1 == required.1)
+        .collect();
+    if let Some(patch) = required.2 {
+        if let Some((path, version)) = same_minor.iter().find(|(_, version)| version.2 == Some(patch)) {
+            return Some(EspIdfMatch { path: path.clone(), reason: format!("exact match for {}", format_idf_version(version)) });
+        }
+        if exact {
+            return None;
+        }
+    }
+    let (path, version) = newest(same_minor)?;
+    Some(EspIdfMatch { path, reason: format!("{} is the newest {}.{}.x installed and {} wasn't found",
+                format_idf_version(&version), required.0, required.1, target_version) })
+}
+
+// Find a local ESP-IDF for the version needed - an ESP-IDF folder given is used as it is,
+// otherwise the ESP-IDFs in the folder given and in the default install locations are searched
+pub fn find_matching_esp_idf(target_version: &str, user_path: Option<String>, exact: bool) -> Option<EspIdfMatch> {
+    let mut search_folders = Vec::new();
+
     // 1. Check user-specified path
     if let Some(path) = user_path {
-        let user_dir = Path::new(&path);
-        if user_dir.is_dir() {
-            // Check if the folder is an ESP-IDF folder by checking if it contains a file named export.sh
-            if user_dir.join("export.sh").is_file() {
-                // TODO remove
-                println!("Found required ESP IDF folder {:?}", user_dir);
-                return Some(user_dir.to_path_buf());
-            }
-            // If it's a directory, look for subfolders named esp-idf-vx.y.z
-            if let Some(matching_path) = user_dir
-                .read_dir()
-                .ok()?
-                .filter_map(|entry| entry.ok())
-                .map(|entry| entry.path())
-                .find(|p| p.file_name().map_or(false, |name| name.to_string_lossy().ends_with(&target_version)))
-            {
-                // TODO remove
-                println!("Found matching path: {:?}", matching_path);
-                return Some(matching_path);
-            }
+        let user_dir = PathBuf::from(&path);
+        // Check if the folder is an ESP-IDF folder by checking if it contains a file named export.sh
+        if user_dir.join("export.sh").is_file() {
+            debug!("Found required ESP IDF folder {:?}", user_dir);
+            return Some(EspIdfMatch { path: user_dir, reason: "ESP-IDF folder given".to_string() });
         }
+        search_folders.push(user_dir);
     }
 
     // 2. Default paths based on the platform
-    let default_paths = get_default_esp_idf_paths();
-
-    // TODO remove
-    println!("Searching default paths: {:?}", default_paths);
-
-    for path in default_paths {
-        if path.is_dir() {
-            if let Some(matching_path) = path
-                .read_dir()
-                .ok()?
-                .filter_map(|entry| entry.ok())
-                .map(|entry| entry.path())
-                .find(|p| p.file_name().map_or(false, |name| name.to_string_lossy().ends_with(&target_version)))
-            {
-                // TODO remove
-                println!("Found matching path: {:?}", matching_path);
-                return Some(matching_path);
-            }
+    search_folders.extend(get_default_esp_idf_paths());
+    debug!("Searching for ESP-IDF {} in {:?}", target_version, search_folders);
+    let mut installed: Vec<(PathBuf, IdfVersion)> = search_folders.iter()
+        .flat_map(|folder| find_installed_esp_idfs(folder))
+        .collect();
+
+    // 3. ESP-IDFs listed by the installers (which may be anywhere)
+    for registered in get_registered_esp_idf_paths().into_iter().filter_map(installed_esp_idf) {
+        if !installed.iter().any(|(path, _)| *path == registered.0) {
+            installed.push(registered);
         }
     }
+    debug!("ESP-IDFs found {:?}", installed);
 
-    // TODO remove
-    println!("No matching ESP-IDF found for {:?}", target_version);
-    None
+    let idf_match = choose_esp_idf(target_version, installed, exact);
+    if idf_match.is_none() {
+        debug!("No matching ESP-IDF found for {:?}", target_version);
+    }
+    idf_match
+}
+
+// Get the folders the ESP-IDF tools are installed in (IDF_TOOLS_PATH if it is set and then the
+// installer's default)
+fn get_esp_idf_tools_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = env::var("IDF_TOOLS_PATH").ok().map(PathBuf::from).into_iter().collect();
+
+    #[cfg(target_os = "windows")]
+    paths.push(PathBuf::from("C:\\Espressif"));
+
+    #[cfg(target_os = "macos")]
+    paths.push(dirs::home_dir().unwrap_or_default().join(".espressif"));
+
+    paths.dedup();
+    paths
 }
 
 // Helper function to get default paths based on OS
@@ -545,11 +1442,27 @@ fn get_default_esp_idf_paths() -> Vec<PathBuf> {
     #[cfg(target_os = "linux")]
     paths.push(dirs::home_dir().unwrap_or_default().join("esp"));
 
+    // The frameworks folder in IDF_TOOLS_PATH, the esp folder in the user's profile (used by the
+    // VS Code extension and manual installs) and then the installer's default
     #[cfg(target_os = "windows")]
-    paths.push(PathBuf::from("C:\\Espressif\\frameworks"));
+    {
+        if let Ok(idf_tools_path) = env::var("IDF_TOOLS_PATH") {
+            paths.push(PathBuf::from(idf_tools_path).join("frameworks"));
+        }
+        paths.push(dirs::home_dir().unwrap_or_default().join("esp"));
+        paths.push(PathBuf::from("C:\\Espressif\\frameworks"));
+    }
 
+    // The esp folder, the ESP-IDF tools folders and their frameworks folders (the VS Code
+    // extension and the installation manager install ESP-IDF in ~/.espressif)
     #[cfg(target_os = "macos")]
-    paths.push(dirs::home_dir().unwrap_or_default().join("esp"));
+    {
+        paths.push(dirs::home_dir().unwrap_or_default().join("esp"));
+        for tools_path in get_esp_idf_tools_paths() {
+            paths.push(tools_path.join("frameworks"));
+            paths.push(tools_path);
+        }
+    }
 
     paths
 }
@@ -1,11 +1,8 @@
 // RaftCLI: Serial monitor module
 // Rob Dobson 2024
 
-use crossterm::{
-    cursor, event::{self, Event, KeyCode, KeyEventKind, KeyModifiers}, execute, style::{Color, ResetColor, SetForegroundColor}, terminal,
-};
+use crossterm::event::{self, Event};
 use serialport_fix_stop_bits::{new, SerialPort};
-use std::io::Write;
 use std::process::{Command, Stdio};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -14,208 +11,198 @@ use std::sync::{
 use std::thread;
 use std::time::Duration;
 
-use crate::{app_ports::{select_most_likely_port, PortsCmd}, cmd_history::CommandHistory};
+use crate::{app_ports::{wait_for_likely_port, PortsCmd}, cmd_history::{CommandHistory, MONITOR_HISTORY_FILE_NAME}};
+use crate::raft_cli_utils::{is_serial_port_busy_msg, serial_port_busy_hint, EXIT_PORT_NOT_FOUND};
+use crate::console_log::{close_log, open_log_file, write_bytes_to_log, write_to_log, ConsoleLogOptions, LogLineEndings};
+use crate::terminal_io::{load_command_completions, send_file_lines, AnsiStripper, TerminalAction, TerminalIO};
+use crate::serial_rx_handler::{find_app_elf, SerialRxHandler};
 
-struct LogFileInfo {
-    file: std::fs::File,
-    last_write: std::time::Instant,
-}
-type SharedLogFile = Arc<Mutex<Option<LogFileInfo>>>;
-
-struct TerminalOut {
-    command_buffer: String,
-    cursor_col: u16,
-    cursor_row: u16,
-    cols: u16,
-    rows: u16,
-    is_error: bool,
+struct CommandAndTime {
+    user_input: String,
+    _time: std::time::Instant,
+    // Typed commands are written with the inter-byte delay (other commands have their own pacing)
+    paced: bool,
 }
 
-impl TerminalOut {
-    fn new() -> TerminalOut {
-        TerminalOut {
-            command_buffer: String::new(),
-            cursor_col: 0,
-            cursor_row: 0,
-            cols: 0,
-            rows: 0,
-            is_error: false,
-        }
-    }
-
-    fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let (_cols, rows) = terminal::size()?;
-        self.cols = _cols;
-        self.rows = rows;
-        // Setup terminal for raw mode
-        terminal::enable_raw_mode()?;
-        execute!(
-            std::io::stdout(),
-            terminal::Clear(terminal::ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
-        Ok(())
-    }
-
-    fn print(&mut self, data: &str, force_show: bool) {
-
-        if !force_show && self.is_error {
-            return;
-        }
-
-        // Clear error flag
-        self.is_error = false;
+// Baud rates tried (in order) when detecting the baud rate
+const AUTO_BAUD_RATES: [u32; 9] = [115200, 230400, 460800, 921600, 74880, 57600, 38400, 19200, 9600];
 
-        // Clear the last line of the terminal (command buffer)
-        execute!(
-            std::io::stdout(),
-            cursor::MoveTo(0, self.rows - 1),
-            terminal::Clear(terminal::ClearType::CurrentLine)
-        ).unwrap();
+// Time spent receiving at each baud rate when detecting the baud rate
+const AUTO_BAUD_WINDOW: Duration = Duration::from_millis(500);
 
-        // Move the cursor to the position of the last output
-        execute!(
-            std::io::stdout(),
-            cursor::MoveTo(self.cursor_col, self.cursor_row)
-        ).unwrap();
+// Data received needed (and the score) to accept a baud rate without trying the others
+const AUTO_BAUD_MIN_BYTES: usize = 64;
+const AUTO_BAUD_GOOD_SCORE: f64 = 0.98;
 
-        // Display the received data
-        self.display_serial_data(&data);
-
-        // Get the cursor position
-        let (cursor_col, mut cursor_row) = cursor::position().unwrap();
+// Monitor baud rate (or detect the baud rate from the data received)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MonitorBaud {
+    Auto,
+    Rate(u32),
+}
 
-        // If the cursor is not at the first column then add a newline
-        if cursor_col != 0 && cursor_row == self.rows - 1 {
-            print!("\n");
-            cursor_row -= 1;
+impl std::str::FromStr for MonitorBaud {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(MonitorBaud::Auto);
         }
-
-        // Save the cursor position
-        self.cursor_col = cursor_col;
-        self.cursor_row = cursor_row;
-
-        // Move the cursor to the bottom line and clear it
-        execute!(
-            std::io::stdout(),
-            cursor::MoveTo(0, self.rows - 1),
-            terminal::Clear(terminal::ClearType::CurrentLine),
-            SetForegroundColor(Color::Yellow),
-        ).unwrap();
-
-        // Display the command buffer
-        print!("> {}", self.command_buffer);
-
-        // Reset the text color
-        execute!(std::io::stdout(), ResetColor).unwrap();
-
-        // Flush the output
-        std::io::stdout().flush().unwrap();
-    }
-
-    fn show_error(&mut self, error_msg: &str) {
-
-        // Move the cursor to the bottom line and clear it
-        execute!(
-            std::io::stdout(),
-            cursor::MoveTo(0, self.rows - 1),
-            terminal::Clear(terminal::ClearType::CurrentLine),
-            SetForegroundColor(Color::Red),
-        ).unwrap();
-
-        // Display the error message
-        print!("! {}", error_msg);
-
-        // Reset the text color
-        execute!(std::io::stdout(), ResetColor).unwrap();
-
-        // Flush the output
-        std::io::stdout().flush().unwrap();
-
-        // Set the error flag
-        self.is_error = true;
+        s.parse::<u32>().map(MonitorBaud::Rate).map_err(|_| format!("Invalid baud rate {} (use a number or auto)", s))
     }
+}
 
-    fn display_serial_data(&mut self, data: &str) {
-        print!("{}", data);
-        std::io::stdout().flush().unwrap();
+impl std::fmt::Display for MonitorBaud {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MonitorBaud::Auto => write!(f, "auto"),
+            MonitorBaud::Rate(rate) => write!(f, "{}", rate),
+        }
     }
+}
 
-    fn get_command_buffer(&self) -> String {
-        self.command_buffer.clone()
+// Score data received as the proportion of it that is printable text (valid UTF-8 which is
+// not a control character other than line endings and tabs)
+fn score_received_text(data: &[u8]) -> f64 {
+    let text = String::from_utf8_lossy(data);
+    let num_chars = text.chars().count();
+    if num_chars == 0 {
+        return 0.0;
     }
+    let num_printable = text.chars()
+        .filter(|c| *c != char::REPLACEMENT_CHARACTER && (!c.is_control() || matches!(c, '\r' | '\n' | '\t')))
+        .count();
+    num_printable as f64 / num_chars as f64
+}
 
-    fn clear_command_buffer(&mut self) {
-        self.command_buffer.clear();
-        self.print("", false);
+// Detect the baud rate by receiving for a short time at each common rate and choosing the rate
+// at which the data received looks most like text
+fn detect_baud_rate(port: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    println!("Detecting baud rate on {}...", port);
+    let mut best: Option<(u32, f64, usize)> = None;
+    for baud_rate in AUTO_BAUD_RATES {
+        let mut serial_port = new(port, baud_rate).timeout(Duration::from_millis(50)).open()?;
+        let _ = serial_port.clear(serialport_fix_stop_bits::ClearBuffer::Input);
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 256];
+        let start = std::time::Instant::now();
+        while start.elapsed() < AUTO_BAUD_WINDOW {
+            match serial_port.read(&mut buffer) {
+                Ok(n) => received.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        let score = score_received_text(&received);
+        if !received.is_empty() && best.is_none_or(|(_, best_score, _)| score > best_score) {
+            best = Some((baud_rate, score, received.len()));
+        }
+        if received.len() >= AUTO_BAUD_MIN_BYTES && score >= AUTO_BAUD_GOOD_SCORE {
+            break;
+        }
     }
-
-    fn add_to_command_buffer(&mut self, c: char) {
-        self.command_buffer.push(c);
-        self.print("", false);
+    match best {
+        Some((baud_rate, score, num_bytes)) => {
+            println!("Detected baud rate {} ({:.0}% of {} bytes received were text)", baud_rate, score * 100.0, num_bytes);
+            Ok(baud_rate)
+        }
+        None => {
+            println!("No data received so baud rate not detected - using {}", AUTO_BAUD_RATES[0]);
+            Ok(AUTO_BAUD_RATES[0])
+        }
     }
+}
 
-    fn add_str_to_command_buffer(&mut self, s: &str) {
-        self.command_buffer.push_str(s);
-        self.print("", true);
-    }
+// Options controlling the serial monitor
+#[derive(Clone, Debug)]
+pub struct MonitorOptions {
+    pub port: Option<String>,
+    pub baud_rate: MonitorBaud,
+    pub no_reconnect: bool,
+    pub vid: Option<String>,
+    pub split_screen: bool,
+    // Command history file (defaults to MONITOR_HISTORY_FILE_NAME in the app folder)
+    pub history_file: Option<String>,
+    // Remove colours and other escape sequences from the device output (shown and logged)
+    pub no_color: bool,
+    // SysType whose ELF file is used to decode panic backtraces
+    pub sys_type: Option<String>,
+    // Folder where core dumps received from the device are saved
+    pub coredump_dir: Option<String>,
+    // Seconds to wait for a serial port to appear if none is found
+    pub wait_for_port: u64,
+    // Show (and log) the bytes received as a hex dump rather than text
+    pub hexdump: bool,
+    // Commands sent each time the serial port is opened and the delay after each
+    pub on_connect: Vec<String>,
+    pub on_connect_delay_ms: u64,
+    // Delay after each byte of a typed command (for devices that drop characters sent quickly)
+    pub tx_delay_ms: u64,
+}
 
-    fn backspace_command_buffer(&mut self) {
-        if self.command_buffer.len() > 0 {
-            self.command_buffer.pop();
-            self.print("", false);
-        }
-    }
+// Data received from the serial port (bytes are shown as a hex dump)
+enum SerialData {
+    Text(String),
+    Bytes(Vec<u8>),
 }
 
-// Logging to file
-fn open_log_file(log_to_file: bool, log_folder: String) -> Result<SharedLogFile, std::io::Error> {
-    if log_to_file && log_folder.len() > 0 && log_folder != "none" {
-        // Create a log file
-        let name = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
-        let log_file_name = format!("{}/{}.log", log_folder, name);
-        std::fs::create_dir_all(&log_folder)?;
-        // Open the log file
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_file_name)?;
-        return Ok(Arc::new(Mutex::new(Some(LogFileInfo {
-            file,
-            last_write: std::time::Instant::now(),
-        }))));
+// Send the on-connect commands (waiting after each so the device can handle it)
+fn send_on_connect_commands(commands: &[String], delay: Duration, serial_write_tx: &mpsc::Sender<CommandAndTime>,
+            terminal_out: &Mutex<TerminalIO>) {
+    for command in commands {
+        terminal_out.lock().unwrap().log_command(command);
+        let _ = serial_write_tx.send(CommandAndTime {
+            user_input: command.clone(),
+            _time: std::time::Instant::now(),
+            paced: false,
+        });
+        thread::sleep(delay);
     }
-    Ok(Arc::new(Mutex::new(None)))
 }
 
-struct CommandAndTime {
-    user_input: String,
-    _time: std::time::Instant,
+// Open the serial port (reads time out after 100ms)
+pub fn open_serial_port(
+    port: &str,
+    baud_rate: u32,
+) -> Result<Box<dyn SerialPort>, Box<dyn std::error::Error>> {
+    match new(port, baud_rate)
+        .timeout(Duration::from_millis(100))
+        .open()
+    {
+        Ok(serial_port) => Ok(serial_port),
+        Err(e) => {
+            // Give a targeted message if the port is held by another process
+            if e.kind() == serialport_fix_stop_bits::ErrorKind::Io(std::io::ErrorKind::PermissionDenied)
+                || is_serial_port_busy_msg(&e.to_string())
+            {
+                return Err(Box::new(std::io::Error::other(serial_port_busy_hint(port))));
+            }
+            Err(Box::new(e))
+        }
+    }
 }
 
 pub fn start_native(
     app_folder: String,
-    port: Option<String>,
-    baud_rate: u32,
-    no_reconnect: bool,
-    log: bool,
-    log_folder: String,
-    vid: Option<String>
+    monitor_options: MonitorOptions,
+    log_options: ConsoleLogOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
 
-    // Command history in the app folder
-    let mut history_file_path = std::path::PathBuf::from(&app_folder);
-    history_file_path.push("raftcli_history.txt");
-    let history_file_path_str = history_file_path.to_str().unwrap().to_string();
-    let command_history = Arc::new(Mutex::new(CommandHistory::new(&history_file_path_str)));
+    let MonitorOptions { port, baud_rate, no_reconnect, vid, split_screen, history_file, no_color, sys_type, coredump_dir,
+                wait_for_port, hexdump, on_connect, on_connect_delay_ms, tx_delay_ms } = monitor_options;
+    let on_connect_delay = Duration::from_millis(on_connect_delay_ms);
+    let tx_delay = Duration::from_millis(tx_delay_ms);
+
+    // Command history (kept separately from the debug console history)
+    let history_file_path = history_file.unwrap_or_else(|| {
+        std::path::PathBuf::from(&app_folder).join(MONITOR_HISTORY_FILE_NAME).to_string_lossy().to_string()
+    });
+    let mut command_history = CommandHistory::new(&history_file_path);
 
     // Open log file if required
-    let log_file = if log {
-        let file = open_log_file(log, log_folder)?;
-        file
-    } else {
-        Arc::new(Mutex::new(None))
-    };
+    let log_file = open_log_file(&log_options)?;
+    let log_file_to_close = log_file.clone();
+    let hexdump_log_file = log_file.clone();
+    let log_raw = log_options.raw;
 
     // Arc and AtomicBool for controlling the running state
     let running = Arc::new(AtomicBool::new(true));
@@ -229,58 +216,78 @@ pub fn start_native(
     let port = if let Some(port) = port {
         port
     } else {
-        // Use select_most_likely_port if no specific port is provided
+        // Use the most likely port if no specific port is provided
         let port_cmd = PortsCmd::new_with_vid(vid);
-        match select_most_likely_port(&port_cmd, false) {
+        match wait_for_likely_port(&port_cmd, false, wait_for_port) {
             Some(p) => p.port_name,
             None => {
                 println!("Error: No suitable port found");
-                std::process::exit(1);
+                std::process::exit(EXIT_PORT_NOT_FOUND);
             }
         }
     };
     
-    // Function to open the serial port
-    fn open_serial_port(
-        port: &str,
-        baud_rate: u32,
-    ) -> Result<Box<dyn SerialPort>, Box<dyn std::error::Error>> {
-        let port = new(port, baud_rate)
-            .timeout(Duration::from_millis(100))
-            .open()?;
-        Ok(port)
-    }
-
-    // Open the serial port and wrap it in an Arc<Mutex<>>
-    let serial_port = Arc::new(Mutex::new(open_serial_port(&port, baud_rate)?));
+    // Detect the baud rate if required
+    let baud_rate = match baud_rate {
+        MonitorBaud::Auto => detect_baud_rate(&port)?,
+        MonitorBaud::Rate(baud_rate) => baud_rate,
+    };
 
-    // Clone the Arc for the serial communication thread
-    let serial_port_clone = Arc::clone(&serial_port);
+    // Open the serial port - the write thread has its own handle (replaced when the port is
+    // reopened) so writing isn't held up by a read waiting for data
+    let mut serial_port = open_serial_port(&port, baud_rate)?;
+    let serial_writer = Arc::new(Mutex::new(serial_port.try_clone()?));
+    let serial_writer_clone = Arc::clone(&serial_writer);
 
     // Terminal output
-    let terminal_out = Arc::new(Mutex::new(TerminalOut::new()));
+    let terminal_out = Arc::new(Mutex::new(TerminalIO::new(split_screen)));
     terminal_out.lock().unwrap().init().unwrap();
+    terminal_out.lock().unwrap().set_completions(load_command_completions(&app_folder));
 
     // Clone the Arc for the terminal output
     let terminal_out_clone = Arc::clone(&terminal_out);
 
+    // Handler for decoding panic backtraces and saving core dumps in the received data
+    let mut rx_handler = SerialRxHandler::new(&app_folder, find_app_elf(&app_folder, &sys_type),
+                coredump_dir.map(std::path::PathBuf::from));
+
+    // The on-connect commands are sent again when the serial port is reopened
+    let on_connect_clone = on_connect.clone();
+    let serial_write_tx_clone = serial_write_tx.clone();
+
     // Spawn a thread to handle reading from the serial port
     thread::spawn(move || {
+        let mut ansi_stripper = AnsiStripper::default();
         while r.load(Ordering::SeqCst) {
             let mut buffer: Vec<u8> = vec![0; 100];
-            let result = {
-                let mut serial_port_lock = serial_port_clone.lock().unwrap();
-                serial_port_lock.read(&mut buffer)
-            };
+            let result = serial_port.read(&mut buffer);
             match result {
+                Ok(n) if n > 0 && hexdump => {
+                    serial_read_tx.send(SerialData::Bytes(buffer[..n].to_vec()))
+                        .expect("Failed to send data to main thread");
+                    if log_raw {
+                        write_bytes_to_log(&log_file, &buffer[..n]);
+                    }
+                }
                 Ok(n) if n > 0 => {
-                    let received = String::from_utf8_lossy(&buffer[..n]);
-                    serial_read_tx.send(received.to_string())
+                    let mut received = String::from_utf8_lossy(&buffer[..n]).to_string();
+                    if no_color {
+                        received = ansi_stripper.strip(&received);
+                    }
+                    serial_read_tx.send(SerialData::Text(received.clone()))
                         .expect("Failed to send data to main thread");
-                    if let Ok(mut log_file) = log_file.lock() {
-                        if let Some(log_file_info) = log_file.as_mut() {
-                            write!(log_file_info.file, "{}", received).unwrap();
-                            log_file_info.last_write = std::time::Instant::now();
+                    if log_raw {
+                        write_bytes_to_log(&log_file, &buffer[..n]);
+                    } else {
+                        write_to_log(&log_file, &received);
+                    }
+
+                    // Show (and log) any decoded backtrace after the raw one
+                    if let Some(decoded) = rx_handler.handle(&received) {
+                        serial_read_tx.send(SerialData::Text(decoded.clone()))
+                            .expect("Failed to send data to main thread");
+                        if !log_raw {
+                            write_to_log(&log_file, &decoded);
                         }
                     }
                 }
@@ -295,7 +302,13 @@ pub fn start_native(
                     thread::sleep(Duration::from_millis(50));
                     match open_serial_port(&port, baud_rate) {
                         Ok(new_port) => {
-                            *serial_port_clone.lock().unwrap() = new_port;
+                            match new_port.try_clone() {
+                                Ok(writer) => *serial_writer_clone.lock().unwrap() = writer,
+                                Err(_e) => continue,
+                            }
+                            serial_port = new_port;
+                            send_on_connect_commands(&on_connect_clone, on_connect_delay, &serial_write_tx_clone,
+                                        &terminal_out_clone);
                         }
                         Err(_e) => {
                             // eprintln!("Serial port reconnection failed: {:?}\r", e);
@@ -311,11 +324,18 @@ pub fn start_native(
     });
 
     // Spawn a thread to handle writing to the serial port
-    let serial_port_clone = Arc::clone(&serial_port);
     thread::spawn(move || {
         while let Ok(command) = serial_write_rx.recv() {
             // println!("Time to receive command: {:?}", command.time.elapsed());
-            let mut serial_port_lock = serial_port_clone.lock().unwrap();
+            // (the writer is locked for each byte so the port can be reopened while pacing)
+            if command.paced && !tx_delay.is_zero() {
+                for byte in command.user_input.bytes().chain(std::iter::once(b'\n')) {
+                    let _ = serial_writer.lock().unwrap().write(&[byte]);
+                    thread::sleep(tx_delay);
+                }
+                continue;
+            }
+            let mut serial_port_lock = serial_writer.lock().unwrap();
             // println!("Time to lock port: {:?}", command.time.elapsed());
             let _ = serial_port_lock.write(command.user_input.as_bytes());
             let _ = serial_port_lock.write(&[b'\n']);
@@ -323,88 +343,92 @@ pub fn start_native(
         }
     });
 
+    // Send the commands for when the port is opened
+    send_on_connect_commands(&on_connect, on_connect_delay, &serial_write_tx, &terminal_out);
+
     // Print nothing to display the command prompt
     terminal_out.lock().unwrap().print("", false);
 
-    // Main loop to handle terminal events and print received serial data
-    while running.load(Ordering::SeqCst) {
-        // Handle serial data
-        if let Ok(received) = serial_read_rx.try_recv() {
-            terminal_out.lock().unwrap().print(&received, true);
-        }
+    // Main loop to handle terminal events and print received serial data - the terminal is
+    // restored below even if the loop ends with an error
+    let loop_result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        while running.load(Ordering::SeqCst) {
+            // Handle serial data (the hex dump is logged as shown unless the raw bytes are logged)
+            match serial_read_rx.try_recv() {
+                Ok(SerialData::Text(received)) => {
+                    terminal_out.lock().unwrap().show_output(&received);
+                }
+                Ok(SerialData::Bytes(received)) => {
+                    let rows = terminal_out.lock().unwrap().show_hexdump(&received);
+                    if !log_raw {
+                        write_to_log(&hexdump_log_file, &rows);
+                    }
+                }
+                Err(_) if hexdump => {
+                    let row = terminal_out.lock().unwrap().flush_hexdump();
+                    if !log_raw && !row.is_empty() {
+                        write_to_log(&hexdump_log_file, &row);
+                    }
+                }
+                Err(_) => {}
+            }
 
-        // Handle keyboard input
-        if event::poll(Duration::from_millis(0))? {
-            if let Event::Key(key_event) = event::read()? {
-                if key_event.kind == KeyEventKind::Press {
-                    match key_event.code {
-                        KeyCode::Char(c)
-                            if key_event.modifiers == KeyModifiers::CONTROL
-                                && (c == 'c' || c == 'x') =>
-                        {
-                            running.store(false, Ordering::SeqCst);
-                        }
-                        KeyCode::Esc => {
+            // Handle keyboard input
+            if event::poll(Duration::from_millis(0))? {
+                let event = event::read()?;
+                if let Event::Resize(cols, rows) = event {
+                    terminal_out.lock().unwrap().on_resize(cols, rows);
+                }
+                if let Event::Key(key_event) = event {
+                    let action = terminal_out.lock().unwrap().handle_key_event(key_event, &mut command_history);
+                    match action {
+                        TerminalAction::Exit => {
                             running.store(false, Ordering::SeqCst);
                         }
-                        KeyCode::Enter => {
-                            // print!("⏎");
-                            let key_detect_time = std::time::Instant::now();
-                            let user_input = terminal_out.lock().unwrap().get_command_buffer();
+                        TerminalAction::SendCommand(user_input) => {
                             let command: CommandAndTime = CommandAndTime {
-                                user_input: user_input.clone(),
-                                _time: key_detect_time
+                                user_input,
+                                _time: std::time::Instant::now(),
+                                paced: true,
                             };
-                            // println!("Time to get command buffer: {:?}", key_detect_time.elapsed());
                             serial_write_tx.send(command).expect("Failed to send command to write thread");
-                            // Add the command to history
-                            command_history.lock().unwrap().add_command(&user_input);
-                            // println!("Time to send command: {:?}", key_detect_time.elapsed());
-                            terminal_out.lock().unwrap().clear_command_buffer();
                         }
-                        KeyCode::Backspace => {
-                            terminal_out.lock().unwrap().backspace_command_buffer();
+                        TerminalAction::SendFile(file_path) => {
+                            let serial_write_tx = serial_write_tx.clone();
+                            send_file_lines(&file_path, Arc::clone(&terminal_out), move |user_input| {
+                                let _ = serial_write_tx.send(CommandAndTime {
+                                    user_input,
+                                    _time: std::time::Instant::now(),
+                                    paced: false,
+                                });
+                            });
                         }
-                        KeyCode::Char(c) => {
-                            terminal_out.lock().unwrap().add_to_command_buffer(c);
-                        }
-                        KeyCode::Up => {
-                            if let Some(previous_command) = command_history.lock().unwrap().get_previous() {
-                                terminal_out.lock().unwrap().clear_command_buffer();
-                                terminal_out.lock().unwrap().add_str_to_command_buffer(previous_command);
-                            }
-                        }
-                        KeyCode::Down => {
-                            if let Some(next_command) = command_history.lock().unwrap().get_next() {
-                                terminal_out.lock().unwrap().clear_command_buffer();
-                                terminal_out.lock().unwrap().add_str_to_command_buffer(next_command);
-                            } else {
-                                terminal_out.lock().unwrap().clear_command_buffer();
-                            }
-                        }
-                        _ => {}
+                        TerminalAction::None => {}
                     }
                 }
             }
         }
-    }
+        Ok(())
+    })();
+    running.store(false, Ordering::SeqCst);
 
     // Clean up
-    terminal::disable_raw_mode()?;
+    close_log(&log_file_to_close);
+    terminal_out.lock().unwrap().close()?;
     println!("Exiting...\r");
 
-    Ok(())
+    loop_result
 }
 
 pub fn start_non_native(
     app_folder: String,
-    port: Option<String>,
-    baud: u32,
-    no_reconnect: bool,
-    log: bool,
-    log_folder: String,
-    vid: Option<String>
+    monitor_options: MonitorOptions,
+    log_options: ConsoleLogOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
+
+    let MonitorOptions { port, baud_rate: baud, no_reconnect, vid, split_screen, history_file, no_color, sys_type, coredump_dir,
+                wait_for_port, hexdump, on_connect, on_connect_delay_ms, tx_delay_ms } = monitor_options;
+
     // Setup args
     let mut args = vec![
         "monitor".to_string(),
@@ -423,10 +447,74 @@ pub fn start_non_native(
     if no_reconnect {
         args.push("-n".to_string());
     }
-    if log {
+    if split_screen {
+        args.push("--split".to_string());
+    }
+    if let Some(history_file) = history_file {
+        args.push("--history-file".to_string());
+        args.push(history_file);
+    }
+    if no_color {
+        args.push("--no-color".to_string());
+    }
+    if let Some(sys_type) = sys_type {
+        args.push("-s".to_string());
+        args.push(sys_type);
+    }
+    if let Some(coredump_dir) = coredump_dir {
+        args.push("--coredump-dir".to_string());
+        args.push(coredump_dir);
+    }
+    if wait_for_port > 0 {
+        args.push("--wait-for-port".to_string());
+        args.push(wait_for_port.to_string());
+    }
+    if hexdump {
+        args.push("--hexdump".to_string());
+    }
+    for command in on_connect {
+        args.push("--on-connect".to_string());
+        args.push(command);
+    }
+    args.push("--on-connect-delay".to_string());
+    args.push(on_connect_delay_ms.to_string());
+    if tx_delay_ms > 0 {
+        args.push("--tx-delay".to_string());
+        args.push(tx_delay_ms.to_string());
+    }
+    if log_options.log || log_options.log_file.is_some() {
         args.push("-l".to_string());
         args.push("-g".to_string());
-        args.push(log_folder);
+        args.push(log_options.log_folder);
+        if log_options.raw {
+            args.push("--log-raw".to_string());
+        }
+        if let Some(max_size_mb) = log_options.max_size_mb {
+            args.push("--log-max-size".to_string());
+            args.push(max_size_mb.to_string());
+        }
+        if let Some(max_age_mins) = log_options.max_age_mins {
+            args.push("--log-max-age".to_string());
+            args.push(max_age_mins.to_string());
+        }
+        if let Some(keep) = log_options.keep {
+            args.push("--log-keep".to_string());
+            args.push(keep.to_string());
+        }
+        if log_options.line_endings != LogLineEndings::Keep {
+            args.push("--log-line-endings".to_string());
+            args.push(format!("{:?}", log_options.line_endings).to_lowercase());
+        }
+        if log_options.timestamps {
+            args.push("--log-timestamps".to_string());
+        }
+        if log_options.compress {
+            args.push("--log-compress".to_string());
+        }
+    }
+    if let Some(log_file) = log_options.log_file {
+        args.push("--log-file".to_string());
+        args.push(log_file);
     }
 
     // Run the serial monitor
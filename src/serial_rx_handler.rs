@@ -0,0 +1,258 @@
+// RaftCLI: Serial receive handler module
+// Rob Dobson 2024
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::app_build::get_local_idf_env_vars;
+use crate::raft_cli_utils::{get_build_folder_name, utils_get_sys_type};
+
+// Longest partial line kept while waiting for the end of the line
+const MAX_LINE_LEN: usize = 4096;
+
+// Markers around a base64 core dump sent over the serial port by ESP-IDF
+const CORE_DUMP_START_MARKER: &str = "CORE DUMP START";
+const CORE_DUMP_END_MARKER: &str = "CORE DUMP END";
+
+// Largest core dump captured (larger ones are abandoned)
+const MAX_CORE_DUMP_BYTES: usize = 4 * 1024 * 1024;
+
+// ELF file of the built app and the chip it was built for
+#[derive(Clone, Debug)]
+pub struct AppElf {
+    pub elf_file: PathBuf,
+    pub target: Option<String>,
+}
+
+// Find the ELF file of the app built for the SysType using project_description.json in the
+// build folder (falling back to the first .elf file in the build folder)
+pub fn find_app_elf(app_folder: &str, sys_type: &Option<String>) -> Option<AppElf> {
+    if !Path::new(app_folder).join("systypes").exists() {
+        return None;
+    }
+    let sys_type = utils_get_sys_type(sys_type, app_folder.to_string()).ok()?;
+    let build_folder = PathBuf::from(get_build_folder_name(sys_type, app_folder.to_string()));
+    let project_description = std::fs::read_to_string(build_folder.join("project_description.json")).ok()
+        .and_then(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok());
+    let target = project_description.as_ref()
+        .and_then(|description| description["target"].as_str())
+        .map(|target| target.to_string());
+    let elf_file = project_description.as_ref()
+        .and_then(|description| description["app_elf"].as_str())
+        .map(|app_elf| build_folder.join(app_elf))
+        .filter(|elf_file| elf_file.exists())
+        .or_else(|| {
+            std::fs::read_dir(&build_folder).ok()?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .find(|path| path.extension().is_some_and(|ext| ext == "elf"))
+        })?;
+    Some(AppElf { elf_file, target })
+}
+
+// Get the addr2line tool in the ESP-IDF toolchain for a chip
+fn addr2line_tool(target: Option<&str>) -> String {
+    match target {
+        Some(target @ ("esp32" | "esp32s2" | "esp32s3")) => format!("xtensa-{}-elf-addr2line", target),
+        Some(_) => "riscv32-esp-elf-addr2line".to_string(),
+        None => "xtensa-esp32-elf-addr2line".to_string(),
+    }
+}
+
+// Get the program counter addresses from an ESP32 panic backtrace line
+// (e.g. "Backtrace: 0x400d1234:0x3ffb5670 0x400d5678:0x3ffb5690")
+pub fn parse_backtrace_addresses(line: &str) -> Vec<String> {
+    // (compiled once as every line received is checked)
+    static BACKTRACE_RE: OnceLock<Regex> = OnceLock::new();
+    static ADDRESS_RE: OnceLock<Regex> = OnceLock::new();
+    let backtrace_re = BACKTRACE_RE.get_or_init(|| {
+        Regex::new(r"Backtrace:((?:\s*0x[0-9a-fA-F]{8}:0x[0-9a-fA-F]{8})+)").unwrap()
+    });
+    let address_re = ADDRESS_RE.get_or_init(|| Regex::new(r"(0x[0-9a-fA-F]{8}):0x[0-9a-fA-F]{8}").unwrap());
+    match backtrace_re.captures(line) {
+        Some(caps) => address_re.captures_iter(&caps[1]).map(|caps| caps[1].to_string()).collect(),
+        None => Vec::new(),
+    }
+}
+
+// Handles data received from the device - complete lines are checked for panic backtraces
+// (decoded to function, file and line using the app's ELF file) and for core dumps (saved to
+// the core dump folder if there is one and decoded)
+pub struct SerialRxHandler {
+    app_folder: String,
+    app_elf: Option<AppElf>,
+    line_buffer: String,
+    // ESP-IDF environment for running the toolchain (found when first needed)
+    idf_env_vars: Option<HashMap<String, String>>,
+    addr2line_failed: bool,
+    coredump_dir: Option<PathBuf>,
+    // Base64 core dump being captured
+    core_dump: Option<String>,
+}
+
+impl SerialRxHandler {
+    pub fn new(app_folder: &str, app_elf: Option<AppElf>, coredump_dir: Option<PathBuf>) -> SerialRxHandler {
+        SerialRxHandler {
+            app_folder: app_folder.to_string(),
+            app_elf,
+            line_buffer: String::new(),
+            idf_env_vars: None,
+            addr2line_failed: false,
+            coredump_dir,
+            core_dump: None,
+        }
+    }
+
+    // Handle received data returning any extra output to show (and log) after it
+    pub fn handle(&mut self, received: &str) -> Option<String> {
+        self.line_buffer.push_str(received);
+        let Some(last_newline) = self.line_buffer.rfind('\n') else {
+            if self.line_buffer.len() > MAX_LINE_LEN {
+                self.line_buffer.clear();
+            }
+            return None;
+        };
+        let lines: String = self.line_buffer.drain(..=last_newline).collect();
+        let mut extra_output = String::new();
+        for line in lines.lines() {
+            if self.coredump_dir.is_some() {
+                if let Some(core_dump_output) = self.handle_core_dump_line(line) {
+                    extra_output.push_str(&core_dump_output);
+                }
+            }
+            let addresses = parse_backtrace_addresses(line);
+            if !addresses.is_empty() {
+                extra_output.push_str(&self.decode_backtrace(&addresses));
+            }
+        }
+        if extra_output.is_empty() { None } else { Some(extra_output) }
+    }
+
+    // Capture the base64 lines between the core dump markers and save (and decode) the core
+    // dump when the end marker is received
+    fn handle_core_dump_line(&mut self, line: &str) -> Option<String> {
+        if line.contains(CORE_DUMP_START_MARKER) {
+            self.core_dump = Some(String::new());
+            return None;
+        }
+        let core_dump = self.core_dump.as_mut()?;
+        if line.contains(CORE_DUMP_END_MARKER) {
+            let core_dump = self.core_dump.take().unwrap_or_default();
+            return Some(self.save_core_dump(&core_dump));
+        }
+        core_dump.push_str(line.trim());
+        core_dump.push('\n');
+        if core_dump.len() > MAX_CORE_DUMP_BYTES {
+            self.core_dump = None;
+            return Some("Core dump not saved (too large)\r\n".to_string());
+        }
+        None
+    }
+
+    // Save the core dump to a timestamped file in the core dump folder and decode it if possible
+    fn save_core_dump(&mut self, core_dump: &str) -> String {
+        let Some(coredump_dir) = self.coredump_dir.clone() else {
+            return String::new();
+        };
+        let file_name = format!("coredump_{}", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let core_file = coredump_dir.join(format!("{}.b64", file_name));
+        let write_result = std::fs::create_dir_all(&coredump_dir)
+            .and_then(|_| std::fs::write(&core_file, core_dump));
+        if let Err(e) = write_result {
+            return format!("Unable to save core dump to {}: {}\r\n", core_file.display(), e);
+        }
+        let mut output = format!("Core dump saved to {}\r\n", core_file.display());
+
+        // Decoding is best effort as it needs the ELF file and espcoredump from ESP-IDF
+        let report_file = coredump_dir.join(format!("{}.txt", file_name));
+        match self.decode_core_dump(&core_file) {
+            Ok(report) => {
+                let save_result = std::fs::write(&report_file, &report);
+                for line in report.lines() {
+                    output.push_str(&format!("{}\r\n", line));
+                }
+                match save_result {
+                    Ok(()) => output.push_str(&format!("Core dump report saved to {}\r\n", report_file.display())),
+                    Err(e) => output.push_str(&format!("Unable to save core dump report: {}\r\n", e)),
+                }
+            }
+            Err(e) => output.push_str(&format!("Core dump not decoded: {}\r\n", e)),
+        }
+        output
+    }
+
+    // Decode a saved core dump using espcoredump from ESP-IDF
+    fn decode_core_dump(&mut self, core_file: &Path) -> Result<String, String> {
+        let Some(app_elf) = self.app_elf.clone() else {
+            return Err("no ELF file found for the SysType".to_string());
+        };
+        let env_vars = self.idf_env_vars.get_or_insert_with(|| {
+            get_local_idf_env_vars(&self.app_folder, None, false).unwrap_or_default()
+        });
+        let mut command = Command::new("espcoredump.py");
+        if let Some(target) = &app_elf.target {
+            command.args(["--chip", target]);
+        }
+        let output = command
+            .args(["info_corefile", "-t", "b64", "-c"])
+            .arg(core_file)
+            .arg(&app_elf.elf_file)
+            .envs(env_vars.iter())
+            .output()
+            .map_err(|e| format!("unable to run espcoredump.py ({})", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    // Decode backtrace addresses using addr2line from the ESP-IDF toolchain
+    fn decode_backtrace(&mut self, addresses: &[String]) -> String {
+        let Some(app_elf) = &self.app_elf else {
+            return "Backtrace not decoded (no ELF file found for the SysType - build the app first)\r\n".to_string();
+        };
+        if self.addr2line_failed {
+            return String::new();
+        }
+        let env_vars = self.idf_env_vars.get_or_insert_with(|| {
+            get_local_idf_env_vars(&self.app_folder, None, false).unwrap_or_default()
+        });
+        let tool = addr2line_tool(app_elf.target.as_deref());
+        let output = Command::new(&tool)
+            .args(["-pfiaC", "-e"])
+            .arg(&app_elf.elf_file)
+            .args(addresses)
+            .envs(env_vars.iter())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let mut decoded = "Decoded backtrace:\r\n".to_string();
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    decoded.push_str(&format!("  {}\r\n", line));
+                }
+                decoded
+            }
+            Ok(output) => format!("Backtrace not decoded: {}\r\n", String::from_utf8_lossy(&output.stderr).trim()),
+            Err(e) => {
+                // Only report a missing toolchain once
+                self.addr2line_failed = true;
+                format!("Backtrace not decoded: unable to run {} ({}) - is the ESP-IDF toolchain installed?\r\n", tool, e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backtrace_addresses() {
+        let line = "Backtrace: 0x400d1234:0x3ffb5670 0x400D5678:0x3ffb5690 |<-CORRUPTED";
+        assert_eq!(parse_backtrace_addresses(line), vec!["0x400d1234", "0x400D5678"]);
+        assert!(parse_backtrace_addresses("I (123) main: value 0x400d1234:0x3ffb5670").is_empty());
+    }
+}
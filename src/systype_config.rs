@@ -0,0 +1,45 @@
+// RaftCLI: SysType configuration module
+// Rob Dobson 2024
+
+use regex::Regex;
+use std::path::PathBuf;
+
+// Get a value from the SysType's sdkconfig.defaults file (with any quotes removed)
+pub fn read_sdkconfig_value(app_folder: &str, sys_type: &str, key: &str) -> Option<String> {
+    let sdkconfig_path = PathBuf::from(app_folder).join("systypes").join(sys_type).join("sdkconfig.defaults");
+    let sdkconfig = std::fs::read_to_string(sdkconfig_path).ok()?;
+    sdkconfig.lines()
+        .filter_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim().trim_matches('"').to_string())
+        .next()
+}
+
+// Get the path of the SysType's partition table CSV file - this is set in sdkconfig.defaults
+// (relative to the app folder) and defaults to partitions.csv in the SysType folder
+pub fn partition_table_file(app_folder: &str, sys_type: &str) -> Option<PathBuf> {
+    let partition_table_file = match read_sdkconfig_value(app_folder, sys_type, "CONFIG_PARTITION_TABLE_CUSTOM_FILENAME") {
+        Some(file_name) => PathBuf::from(app_folder).join(file_name),
+        None => PathBuf::from(app_folder).join("systypes").join(sys_type).join("partitions.csv"),
+    };
+    if partition_table_file.exists() {
+        Some(partition_table_file)
+    } else {
+        None
+    }
+}
+
+// Get the target chip for the SysType - set by IDF_TARGET in the SysType's features.cmake (or
+// the Common one) or CONFIG_IDF_TARGET in sdkconfig.defaults
+pub fn target_chip(app_folder: &str, sys_type: &str) -> Option<String> {
+    let idf_target_re = Regex::new(r#"(?m)^\s*set\s*\(\s*IDF_TARGET\s+"?([A-Za-z0-9]+)"?\s*\)"#).unwrap();
+    let systypes_folder = PathBuf::from(app_folder).join("systypes");
+    for features_folder in [sys_type, "Common"] {
+        let features_path = systypes_folder.join(features_folder).join("features.cmake");
+        if let Ok(features) = std::fs::read_to_string(features_path) {
+            if let Some(caps) = idf_target_re.captures(&features) {
+                return Some(caps[1].to_lowercase());
+            }
+        }
+    }
+    read_sdkconfig_value(app_folder, sys_type, "CONFIG_IDF_TARGET").map(|chip| chip.to_lowercase())
+}
@@ -0,0 +1,109 @@
+// RaftCLI: Target settings module
+// Rob Dobson 2024
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::raft_cli_utils::{get_sys_type_names, utils_get_sys_type};
+use crate::systype_config::{partition_table_file, target_chip};
+
+// Manifest files (in the systypes folder) describing the SysTypes - the first found is used
+pub const TARGET_MANIFEST_FILE_NAMES: [&str; 2] = ["systypes.json", "targets.json"];
+
+// Settings for a SysType (anything not in the manifest is found from the SysType's folder)
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct SysType {
+    pub name: String,
+    // Target chip (e.g. esp32s3)
+    pub chip: Option<String>,
+    // Default serial monitor baud rate
+    pub baud: Option<u32>,
+    // Partition table CSV file (relative to the app folder)
+    pub partition_file: Option<PathBuf>,
+}
+
+impl SysType {
+    // Settings found from the SysType's folder (features.cmake, sdkconfig.defaults, etc)
+    fn from_folder(app_folder: &str, name: &str) -> SysType {
+        SysType {
+            name: name.to_string(),
+            chip: target_chip(app_folder, name),
+            baud: None,
+            partition_file: partition_table_file(app_folder, name),
+        }
+    }
+
+    // Fill settings missing from the manifest with those found from the SysType's folder
+    fn merge_folder_settings(mut self, app_folder: &str) -> SysType {
+        let from_folder = SysType::from_folder(app_folder, &self.name);
+        self.chip = self.chip.map(|chip| chip.to_lowercase()).or(from_folder.chip);
+        self.partition_file = match self.partition_file {
+            Some(partition_file) => Some(PathBuf::from(app_folder).join(partition_file)),
+            None => from_folder.partition_file,
+        };
+        self
+    }
+}
+
+// Settings for all the SysTypes of an app
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct TargetSettings {
+    pub sys_types: Vec<SysType>,
+}
+
+impl TargetSettings {
+    // Load the SysTypes manifest from the app's systypes folder - if there isn't one the
+    // SysType folders are scanned instead
+    pub fn load(app_folder: &str) -> Result<TargetSettings, Box<dyn std::error::Error>> {
+        let systypes_folder = Path::new(app_folder).join("systypes");
+        let manifest_path = TARGET_MANIFEST_FILE_NAMES.iter()
+            .map(|file_name| systypes_folder.join(file_name))
+            .find(|path| path.exists());
+        let Some(manifest_path) = manifest_path else {
+            let sys_types = get_sys_type_names(app_folder)?.iter()
+                .map(|name| SysType::from_folder(app_folder, name))
+                .collect();
+            return Ok(TargetSettings { sys_types });
+        };
+        let contents = std::fs::read_to_string(&manifest_path)?;
+        let settings: TargetSettings = serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid {}: {}", manifest_path.display(), e))?;
+        if let Some(unnamed) = settings.sys_types.iter().position(|sys_type| sys_type.name.is_empty()) {
+            return Err(format!("Invalid {}: SysType {} has no name", manifest_path.display(), unnamed + 1).into());
+        }
+        let sys_types = settings.sys_types.into_iter()
+            .map(|sys_type| sys_type.merge_folder_settings(app_folder))
+            .collect();
+        Ok(TargetSettings { sys_types })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SysType> {
+        self.sys_types.iter().find(|sys_type| sys_type.name == name)
+    }
+}
+
+// Get the settings for a SysType - if the manifest can't be read (or doesn't list the SysType)
+// the settings are found from the SysType's folder
+pub fn sys_type_settings(app_folder: &str, sys_type: &str) -> SysType {
+    match TargetSettings::load(app_folder) {
+        Ok(settings) => {
+            if let Some(sys_type_settings) = settings.get(sys_type) {
+                return sys_type_settings.clone();
+            }
+        }
+        Err(e) => println!("Warning: {}", e),
+    }
+    SysType::from_folder(app_folder, sys_type)
+}
+
+// Get the settings for the SysType specified (or the default SysType) if the app folder has
+// SysTypes
+pub fn find_sys_type_settings(app_folder: &str, build_sys_type: &Option<String>) -> Option<SysType> {
+    if !Path::new(app_folder).join("systypes").exists() {
+        return None;
+    }
+    let sys_type = utils_get_sys_type(build_sys_type, app_folder.to_string()).ok()?;
+    Some(sys_type_settings(app_folder, &sys_type))
+}
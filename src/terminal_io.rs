@@ -0,0 +1,920 @@
+// RaftCLI: Terminal IO module
+// Rob Dobson 2024
+
+use crossterm::{
+    cursor, event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers}, execute, style::{Color, ResetColor, SetForegroundColor}, terminal,
+};
+use std::io::Write;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cmd_history::CommandHistory;
+
+// Maximum output buffered while the display is paused (the oldest output is dropped beyond this)
+const MAX_PAUSED_OUTPUT_BYTES: usize = 1024 * 1024;
+
+// Delay between lines when sending a file so the device's receive buffer isn't overrun
+const SEND_FILE_LINE_DELAY_MS: u64 = 50;
+
+// File in the app folder listing device commands for tab completion (one per line)
+const COMMANDS_FILE_NAME: &str = ".raftcli_commands";
+
+// Commands completed when the app folder doesn't have a commands file
+const DEFAULT_COMMANDS: &[&str] = &[
+    "v", "reset", "sysmodinfo/", "sysmodstatus/", "devman/", "friendlyname", "serialno",
+    "hwrevno", "wifiscan", "w/", "wifipause", "espfwupdate", "postsettings", "getsettings",
+];
+
+// Removes ANSI escape sequences (e.g. colours) from device output - the state is kept between
+// calls as a sequence may be split across reads from the device
+#[derive(Default)]
+pub struct AnsiStripper {
+    state: AnsiState,
+}
+
+#[derive(Default, PartialEq)]
+enum AnsiState {
+    #[default]
+    Text,
+    // After ESC
+    Escape,
+    // Control sequence (ESC [ ...) which ends with a byte in the range @ to ~
+    Csi,
+    // Operating system command (ESC ] ...) which ends with BEL or ESC backslash
+    Osc,
+    OscEscape,
+}
+
+impl AnsiStripper {
+    pub fn strip(&mut self, data: &str) -> String {
+        let mut text = String::with_capacity(data.len());
+        for c in data.chars() {
+            self.state = match (&self.state, c) {
+                (AnsiState::Text, '\x1b') => AnsiState::Escape,
+                (AnsiState::Text, c) => {
+                    text.push(c);
+                    AnsiState::Text
+                }
+                (AnsiState::Escape, '[') => AnsiState::Csi,
+                (AnsiState::Escape, ']') => AnsiState::Osc,
+                (AnsiState::Escape, _) => AnsiState::Text,
+                (AnsiState::Csi, '@'..='~') => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::Osc, '\x07') => AnsiState::Text,
+                (AnsiState::Osc, '\x1b') => AnsiState::OscEscape,
+                (AnsiState::Osc, _) => AnsiState::Osc,
+                (AnsiState::OscEscape, _) => AnsiState::Text,
+            };
+        }
+        text
+    }
+}
+
+// Bytes shown in each row of a hex dump
+const HEX_DUMP_ROW_BYTES: usize = 16;
+
+// Time without data after which an incomplete hex dump row is shown (so short packets appear)
+const HEX_DUMP_IDLE_FLUSH: Duration = Duration::from_millis(100);
+
+// Formats received bytes as a hex dump with the offset, hex bytes and ASCII (like hexdump -C) -
+// bytes are held until a row is complete or no more have been received for a while
+#[derive(Default)]
+struct HexDump {
+    offset: u64,
+    pending: Vec<u8>,
+    last_received: Option<Instant>,
+}
+
+impl HexDump {
+    fn format_row(offset: u64, bytes: &[u8]) -> String {
+        let mut row = format!("{:08x}  ", offset);
+        for i in 0..HEX_DUMP_ROW_BYTES {
+            match bytes.get(i) {
+                Some(byte) => row.push_str(&format!("{:02x} ", byte)),
+                None => row.push_str("   "),
+            }
+            if i == HEX_DUMP_ROW_BYTES / 2 - 1 {
+                row.push(' ');
+            }
+        }
+        let ascii: String = bytes.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        row.push_str(&format!(" |{}|\r\n", ascii));
+        row
+    }
+
+    // Add received bytes returning the complete rows
+    fn push(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        self.last_received = Some(Instant::now());
+        let mut rows = String::new();
+        while self.pending.len() >= HEX_DUMP_ROW_BYTES {
+            let row_bytes: Vec<u8> = self.pending.drain(..HEX_DUMP_ROW_BYTES).collect();
+            rows.push_str(&Self::format_row(self.offset, &row_bytes));
+            self.offset += HEX_DUMP_ROW_BYTES as u64;
+        }
+        rows
+    }
+
+    // Get the incomplete row if nothing has been received for a while (the next bytes start
+    // a new row)
+    fn flush_if_idle(&mut self) -> String {
+        if self.pending.is_empty() || self.last_received.is_some_and(|time| time.elapsed() < HEX_DUMP_IDLE_FLUSH) {
+            return String::new();
+        }
+        let row = Self::format_row(self.offset, &self.pending);
+        self.offset += self.pending.len() as u64;
+        self.pending.clear();
+        row
+    }
+}
+
+// Result of handling a key press
+pub enum TerminalAction {
+    None,
+    Exit,
+    SendCommand(String),
+    SendFile(String),
+}
+
+pub struct TerminalIO {
+    command_buffer: String,
+    // Insertion point in the command buffer (in chars)
+    insert_index: usize,
+    cursor_col: u16,
+    cursor_row: u16,
+    cols: u16,
+    rows: u16,
+    is_error: bool,
+    // Split screen shows device output in the top pane and the commands sent (with
+    // timestamps) in the bottom pane
+    split_screen: bool,
+    command_log: Vec<String>,
+    // Prompting for the path of a file to send (the command being typed is kept meanwhile)
+    file_prompt: bool,
+    saved_command_buffer: String,
+    // Reverse search of command history (the query is entered in the command buffer)
+    history_search: Option<HistorySearch>,
+    // Known commands for tab completion and the current completion (if cycling through matches)
+    completions: Vec<String>,
+    completion: Option<Completion>,
+    // Raw mode is enabled (between init and close)
+    raw_mode: bool,
+    // Output received while the display is paused (shown when resumed)
+    paused_output: Option<PausedOutput>,
+    // Formatting of received bytes when shown as a hex dump
+    hex_dump: HexDump,
+}
+
+// Output buffered while the display is paused
+#[derive(Default)]
+struct PausedOutput {
+    data: VecDeque<String>,
+    bytes: usize,
+    // Bytes dropped because the buffer was full
+    dropped_bytes: usize,
+}
+
+// Commands matching the text typed before Tab was pressed
+struct Completion {
+    matches: Vec<String>,
+    // Match currently in the command buffer (None until Tab is pressed again)
+    match_index: Option<usize>,
+}
+
+// Current match of a reverse history search
+struct HistorySearch {
+    match_index: usize,
+    matched: String,
+}
+
+const HISTORY_SEARCH_PROMPT: &str = "(reverse-i-search)'";
+
+// Restore the terminal if it was left in raw mode (e.g. when exiting on Ctrl+C) - the scrolling
+// region (set for split screen) is reset and output is flushed
+pub fn restore_terminal() {
+    if terminal::is_raw_mode_enabled().unwrap_or(false) {
+        print!("\x1b[r");
+        let _ = terminal::disable_raw_mode();
+    }
+    let _ = std::io::stdout().flush();
+}
+
+// The terminal is restored however the TerminalIO is dropped (including early returns and panics)
+impl Drop for TerminalIO {
+    fn drop(&mut self) {
+        if self.raw_mode {
+            let _ = self.close();
+        }
+    }
+}
+
+impl TerminalIO {
+    pub fn new(split_screen: bool) -> TerminalIO {
+        TerminalIO {
+            command_buffer: String::new(),
+            insert_index: 0,
+            cursor_col: 0,
+            cursor_row: 0,
+            cols: 0,
+            rows: 0,
+            is_error: false,
+            split_screen,
+            command_log: Vec::new(),
+            file_prompt: false,
+            saved_command_buffer: String::new(),
+            history_search: None,
+            completions: Vec::new(),
+            completion: None,
+            raw_mode: false,
+            paused_output: None,
+            hex_dump: HexDump::default(),
+        }
+    }
+
+    // Set the commands used for tab completion
+    pub fn set_completions(&mut self, completions: Vec<String>) {
+        self.completions = completions;
+    }
+
+    pub fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (cols, rows) = terminal::size()?;
+        self.cols = cols;
+        self.rows = rows;
+
+        // Split screen needs room for both panes, a divider and the prompt
+        if self.split_screen && rows < 10 {
+            self.split_screen = false;
+        }
+
+        // Setup terminal for raw mode
+        terminal::enable_raw_mode()?;
+        self.raw_mode = true;
+        execute!(
+            std::io::stdout(),
+            terminal::Clear(terminal::ClearType::All),
+            cursor::MoveTo(0, 0)
+        )?;
+
+        // Restrict scrolling to the output area (so long lines which wrap never reach the prompt
+        // line) and draw the command log pane
+        self.set_scroll_region();
+        if self.split_screen {
+            self.draw_command_log_pane();
+        }
+        execute!(std::io::stdout(), cursor::MoveTo(0, 0))?;
+        Ok(())
+    }
+
+    // Rows used for device output - the output pane in split screen mode and otherwise all rows
+    // above the prompt
+    fn output_rows(&self) -> u16 {
+        if self.split_screen { self.output_pane_rows() } else { self.rows.saturating_sub(1) }
+    }
+
+    // Set the terminal's scrolling region to the output rows
+    fn set_scroll_region(&self) {
+        print!("\x1b[1;{}r", self.output_rows().max(1));
+    }
+
+    // Update the layout after the terminal has been resized and redraw it
+    pub fn on_resize(&mut self, cols: u16, rows: u16) {
+        self.cols = cols;
+        self.rows = rows;
+
+        // Split screen needs room for both panes, a divider and the prompt
+        if self.split_screen && rows < 10 {
+            self.split_screen = false;
+        }
+
+        // Keep the output position within the output area (above the prompt)
+        self.cursor_row = self.cursor_row.min(self.output_rows().saturating_sub(1));
+        self.cursor_col = self.cursor_col.min(cols.saturating_sub(1));
+
+        // Restrict scrolling to the resized output area and redraw the command log pane
+        self.set_scroll_region();
+        if self.split_screen {
+            self.draw_command_log_pane();
+        }
+        self.print("", true);
+    }
+
+    // Restore the terminal
+    pub fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        print!("\x1b[r");
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, self.rows - 1),
+            terminal::Clear(terminal::ClearType::CurrentLine)
+        )?;
+        terminal::disable_raw_mode()?;
+        self.raw_mode = false;
+        Ok(())
+    }
+
+    // Layout of the split screen - output pane at the top (two thirds of the space), then a
+    // divider, the command log pane and finally the prompt on the last row
+    fn output_pane_rows(&self) -> u16 {
+        (self.rows - 2) * 2 / 3
+    }
+
+    fn command_log_pane_rows(&self) -> u16 {
+        self.rows - 2 - self.output_pane_rows()
+    }
+
+    fn draw_command_log_pane(&mut self) {
+        let divider_row = self.output_pane_rows();
+        let pane_rows = self.command_log_pane_rows() as usize;
+
+        // Divider
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, divider_row),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            SetForegroundColor(Color::DarkGrey),
+        ).unwrap();
+        let title = " Commands sent ";
+        let dashes = (self.cols as usize).saturating_sub(title.len() + 2);
+        print!("--{}{}", title, "-".repeat(dashes));
+
+        // Most recent commands
+        let first = self.command_log.len().saturating_sub(pane_rows);
+        for row in 0..pane_rows {
+            execute!(
+                std::io::stdout(),
+                cursor::MoveTo(0, divider_row + 1 + row as u16),
+                terminal::Clear(terminal::ClearType::CurrentLine)
+            ).unwrap();
+            if let Some(entry) = self.command_log.get(first + row) {
+                print!("{}", entry.chars().take(self.cols as usize).collect::<String>());
+            }
+        }
+        execute!(std::io::stdout(), ResetColor).unwrap();
+    }
+
+    // Record a command sent to the device (shown in the command log pane in split screen mode)
+    pub fn log_command(&mut self, command: &str) {
+        if !self.split_screen {
+            return;
+        }
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f");
+        self.command_log.push(format!("{} > {}", timestamp, command));
+        self.draw_command_log_pane();
+        self.print("", false);
+    }
+
+    pub fn print(&mut self, data: &str, force_show: bool) {
+
+        if !force_show && self.is_error {
+            return;
+        }
+
+        // Clear error flag
+        self.is_error = false;
+
+        // Clear the last line of the terminal (command buffer)
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, self.rows - 1),
+            terminal::Clear(terminal::ClearType::CurrentLine)
+        ).unwrap();
+
+        // Move the cursor to the position of the last output
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(self.cursor_col, self.cursor_row)
+        ).unwrap();
+
+        // Display the received data
+        self.display_serial_data(data);
+
+        // Get the cursor position
+        let (cursor_col, mut cursor_row) = cursor::position().unwrap();
+
+        // Output scrolls within the output rows so the cursor should stay there
+        cursor_row = cursor_row.min(self.output_rows().saturating_sub(1));
+
+        // Save the cursor position
+        self.cursor_col = cursor_col;
+        self.cursor_row = cursor_row;
+
+        // Move the cursor to the bottom line and clear it
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, self.rows - 1),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            SetForegroundColor(Color::Yellow),
+        ).unwrap();
+
+        // Display the command buffer (or the search query and matching command when searching)
+        let prompt = if self.file_prompt { "send file: " } else if self.history_search.is_some() { HISTORY_SEARCH_PROMPT } else { "> " };
+        let prompt = if self.paused_output.is_some() { format!("[PAUSED] {}", prompt) } else { prompt.to_string() };
+        print!("{}{}", prompt, self.command_buffer);
+        if let Some(history_search) = &self.history_search {
+            print!("': {}", history_search.matched);
+        }
+
+        // Show the candidates when completion is ambiguous
+        if let Some(completion) = &self.completion {
+            execute!(std::io::stdout(), SetForegroundColor(Color::DarkGrey)).unwrap();
+            let width = (self.cols as usize).saturating_sub(prompt.len() + self.command_buffer.len() + 3);
+            let candidates = completion.matches.join(" ");
+            print!("  [{}]", candidates.chars().take(width.saturating_sub(1)).collect::<String>());
+        }
+
+        // Reset the text color and place the cursor at the insertion point
+        let cursor_col = (prompt.len() + self.insert_index).min(self.cols.saturating_sub(1) as usize) as u16;
+        execute!(std::io::stdout(), ResetColor, cursor::MoveTo(cursor_col, self.rows - 1)).unwrap();
+
+        // Flush the output
+        std::io::stdout().flush().unwrap();
+    }
+
+    // Show output from the device (buffered instead while the display is paused)
+    pub fn show_output(&mut self, data: &str) {
+        let Some(paused_output) = self.paused_output.as_mut() else {
+            self.print(data, true);
+            return;
+        };
+        paused_output.data.push_back(data.to_string());
+        paused_output.bytes += data.len();
+        let first_overflow = paused_output.dropped_bytes == 0 && paused_output.bytes > MAX_PAUSED_OUTPUT_BYTES;
+        while paused_output.bytes > MAX_PAUSED_OUTPUT_BYTES {
+            let Some(dropped) = paused_output.data.pop_front() else {
+                break;
+            };
+            paused_output.bytes -= dropped.len();
+            paused_output.dropped_bytes += dropped.len();
+        }
+        if first_overflow {
+            self.show_error("[PAUSED] buffer full - the oldest output is being dropped (Ctrl+Q to resume)");
+        }
+    }
+
+    // Show received bytes as a hex dump returning the rows shown (for logging)
+    pub fn show_hexdump(&mut self, data: &[u8]) -> String {
+        let rows = self.hex_dump.push(data);
+        if !rows.is_empty() {
+            self.show_output(&rows);
+        }
+        rows
+    }
+
+    // Show an incomplete hex dump row once no bytes have been received for a while returning the
+    // row shown (if any)
+    pub fn flush_hexdump(&mut self) -> String {
+        let row = self.hex_dump.flush_if_idle();
+        if !row.is_empty() {
+            self.show_output(&row);
+        }
+        row
+    }
+
+    // Pause or resume showing device output - output received while paused is shown on resuming
+    fn set_paused(&mut self, paused: bool) {
+        if paused {
+            if self.paused_output.is_none() {
+                self.paused_output = Some(PausedOutput::default());
+                self.show_info("[PAUSED] output is buffered - Ctrl+Q (or Ctrl+S) to resume");
+            }
+            return;
+        }
+        let Some(paused_output) = self.paused_output.take() else {
+            return;
+        };
+        let mut data: String = paused_output.data.into_iter().collect();
+        if paused_output.dropped_bytes > 0 {
+            data.insert_str(0, &format!("\r\n[{} bytes of output dropped while paused]\r\n", paused_output.dropped_bytes));
+        }
+        self.print(&data, true);
+    }
+
+    pub fn show_error(&mut self, error_msg: &str) {
+
+        // Move the cursor to the bottom line and clear it
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, self.rows - 1),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            SetForegroundColor(Color::Red),
+        ).unwrap();
+
+        // Display the error message
+        print!("! {}", error_msg);
+
+        // Reset the text color
+        execute!(std::io::stdout(), ResetColor).unwrap();
+
+        // Flush the output
+        std::io::stdout().flush().unwrap();
+
+        // Set the error flag
+        self.is_error = true;
+    }
+
+    // Show a status message on the bottom line (until the next output is displayed)
+    pub fn show_info(&mut self, info_msg: &str) {
+
+        // Move the cursor to the bottom line and clear it
+        execute!(
+            std::io::stdout(),
+            cursor::MoveTo(0, self.rows - 1),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            SetForegroundColor(Color::Cyan),
+        ).unwrap();
+
+        // Display the message
+        print!("- {}", info_msg);
+
+        // Reset the text color
+        execute!(std::io::stdout(), ResetColor).unwrap();
+
+        // Flush the output
+        std::io::stdout().flush().unwrap();
+    }
+
+    fn display_serial_data(&mut self, data: &str) {
+        print!("{}", data);
+        std::io::stdout().flush().unwrap();
+    }
+
+    // Byte offset in the command buffer of a char index
+    fn command_buffer_byte_index(&self, char_index: usize) -> usize {
+        self.command_buffer.char_indices()
+            .nth(char_index)
+            .map(|(byte_index, _)| byte_index)
+            .unwrap_or(self.command_buffer.len())
+    }
+
+    // Replace the command buffer (with the insertion point at the end)
+    fn set_command_buffer(&mut self, command: String) -> String {
+        self.insert_index = command.chars().count();
+        std::mem::replace(&mut self.command_buffer, command)
+    }
+
+    fn clear_command_buffer(&mut self) {
+        self.set_command_buffer(String::new());
+        self.print("", false);
+    }
+
+    fn add_to_command_buffer(&mut self, c: char) {
+        let byte_index = self.command_buffer_byte_index(self.insert_index);
+        self.command_buffer.insert(byte_index, c);
+        self.insert_index += 1;
+        self.print("", false);
+    }
+
+    fn add_str_to_command_buffer(&mut self, s: &str) {
+        self.command_buffer.push_str(s);
+        self.insert_index = self.command_buffer.chars().count();
+        self.print("", true);
+    }
+
+    fn backspace_command_buffer(&mut self) {
+        if self.insert_index > 0 {
+            self.insert_index -= 1;
+            let byte_index = self.command_buffer_byte_index(self.insert_index);
+            self.command_buffer.remove(byte_index);
+            self.print("", false);
+        }
+    }
+
+    fn delete_in_command_buffer(&mut self) {
+        if self.insert_index < self.command_buffer.chars().count() {
+            let byte_index = self.command_buffer_byte_index(self.insert_index);
+            self.command_buffer.remove(byte_index);
+            self.print("", false);
+        }
+    }
+
+    // Move the insertion point (clamped to the command buffer)
+    fn move_insert_index(&mut self, new_index: usize) {
+        self.insert_index = new_index.min(self.command_buffer.chars().count());
+        self.print("", false);
+    }
+
+    // Handle a key press - editing the command buffer, navigating history and returning
+    // commands to send or a request to exit
+    pub fn handle_key_event(&mut self, key_event: KeyEvent, command_history: &mut CommandHistory) -> TerminalAction {
+        if key_event.kind != KeyEventKind::Press {
+            return TerminalAction::None;
+        }
+        // Entering the path of a file to send
+        if self.file_prompt {
+            return self.handle_file_prompt_key_event(key_event);
+        }
+        // Searching command history
+        if self.history_search.is_some() {
+            self.handle_history_search_key_event(key_event, command_history);
+            return TerminalAction::None;
+        }
+        // Any key other than Tab ends cycling through completions
+        if key_event.code != KeyCode::Tab && self.completion.take().is_some() {
+            self.print("", true);
+        }
+        match key_event.code {
+            KeyCode::Char(c)
+                if key_event.modifiers == KeyModifiers::CONTROL
+                    && (c == 'c' || c == 'x') =>
+            {
+                return TerminalAction::Exit;
+            }
+            KeyCode::Char('s') if key_event.modifiers == KeyModifiers::CONTROL => {
+                // Toggle pausing the display of device output
+                self.set_paused(self.paused_output.is_none());
+            }
+            KeyCode::Char('q') if key_event.modifiers == KeyModifiers::CONTROL => {
+                self.set_paused(false);
+            }
+            KeyCode::Char('f') if key_event.modifiers == KeyModifiers::CONTROL => {
+                // Prompt for a file to send
+                self.saved_command_buffer = self.set_command_buffer(String::new());
+                self.file_prompt = true;
+                self.print("", true);
+            }
+            KeyCode::Char('r') if key_event.modifiers == KeyModifiers::CONTROL => {
+                // Start a reverse search of command history
+                self.saved_command_buffer = self.set_command_buffer(String::new());
+                self.history_search = Some(HistorySearch { match_index: usize::MAX, matched: String::new() });
+                self.print("", true);
+            }
+            KeyCode::Esc => {
+                return TerminalAction::Exit;
+            }
+            KeyCode::Enter => {
+                let user_input = self.command_buffer.clone();
+                // Add the command to history
+                command_history.add_command(&user_input);
+                self.clear_command_buffer();
+                self.log_command(&user_input);
+                return TerminalAction::SendCommand(user_input);
+            }
+            KeyCode::Backspace => {
+                self.backspace_command_buffer();
+            }
+            KeyCode::Delete => {
+                self.delete_in_command_buffer();
+            }
+            KeyCode::Left => {
+                self.move_insert_index(self.insert_index.saturating_sub(1));
+            }
+            KeyCode::Right => {
+                self.move_insert_index(self.insert_index + 1);
+            }
+            KeyCode::Home => {
+                self.move_insert_index(0);
+            }
+            KeyCode::End => {
+                self.move_insert_index(usize::MAX);
+            }
+            KeyCode::Char(c) => {
+                self.add_to_command_buffer(c);
+            }
+            KeyCode::Tab => {
+                self.complete_command();
+            }
+            KeyCode::Up => {
+                if let Some(previous_command) = command_history.get_previous() {
+                    self.clear_command_buffer();
+                    self.add_str_to_command_buffer(previous_command);
+                }
+            }
+            KeyCode::Down => {
+                self.clear_command_buffer();
+                if let Some(next_command) = command_history.get_next() {
+                    self.add_str_to_command_buffer(next_command);
+                }
+            }
+            _ => {}
+        }
+        TerminalAction::None
+    }
+
+    // Complete the command buffer from the known commands - a single match is completed
+    // directly, otherwise the common prefix is completed and the candidates are shown with
+    // repeated Tab presses cycling through them
+    fn complete_command(&mut self) {
+        if let Some(completion) = self.completion.as_mut() {
+            let match_index = completion.match_index.map_or(0, |index| (index + 1) % completion.matches.len());
+            completion.match_index = Some(match_index);
+            let command = completion.matches[match_index].clone();
+            self.set_command_buffer(command);
+            self.print("", true);
+            return;
+        }
+        let matches: Vec<String> = self.completions.iter()
+            .filter(|command| command.starts_with(&self.command_buffer))
+            .cloned()
+            .collect();
+        match matches.len() {
+            0 => {}
+            1 => {
+                self.set_command_buffer(matches[0].clone());
+                self.print("", true);
+            }
+            _ => {
+                let common_prefix = common_prefix(&matches);
+                if common_prefix.len() > self.command_buffer.len() {
+                    self.set_command_buffer(common_prefix);
+                }
+                self.completion = Some(Completion { matches, match_index: None });
+                self.print("", true);
+            }
+        }
+    }
+
+    // Search command history for the query starting before the index given
+    fn update_history_search(&mut self, command_history: &CommandHistory, from: usize) {
+        let query = self.command_buffer.clone();
+        if let Some(history_search) = self.history_search.as_mut() {
+            match command_history.search_backward(&query, from) {
+                Some((match_index, matched)) => {
+                    history_search.match_index = match_index;
+                    history_search.matched = matched.to_string();
+                }
+                None if from == usize::MAX => {
+                    history_search.match_index = usize::MAX;
+                    history_search.matched.clear();
+                }
+                // Keep the current match if there are no earlier ones
+                None => {}
+            }
+        }
+        self.print("", true);
+    }
+
+    // Handle a key press during reverse search - typing refines the search, Ctrl+R finds an
+    // earlier match, Enter accepts the match into the command buffer and Esc cancels
+    fn handle_history_search_key_event(&mut self, key_event: KeyEvent, command_history: &CommandHistory) {
+        match key_event.code {
+            KeyCode::Char('r') if key_event.modifiers == KeyModifiers::CONTROL => {
+                let from = self.history_search.as_ref().map_or(usize::MAX, |search| search.match_index);
+                self.update_history_search(command_history, from);
+            }
+            KeyCode::Enter | KeyCode::Esc => {
+                let history_search = self.history_search.take();
+                let saved_command_buffer = std::mem::take(&mut self.saved_command_buffer);
+                let command = match history_search {
+                    Some(history_search) if key_event.code == KeyCode::Enter && !history_search.matched.is_empty() => history_search.matched,
+                    _ => saved_command_buffer,
+                };
+                self.set_command_buffer(command);
+                self.print("", true);
+            }
+            KeyCode::Backspace => {
+                if self.insert_index > 0 {
+                    self.insert_index -= 1;
+                    let byte_index = self.command_buffer_byte_index(self.insert_index);
+                    self.command_buffer.remove(byte_index);
+                }
+                self.update_history_search(command_history, usize::MAX);
+            }
+            KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                let byte_index = self.command_buffer_byte_index(self.insert_index);
+                self.command_buffer.insert(byte_index, c);
+                self.insert_index += 1;
+                self.update_history_search(command_history, usize::MAX);
+            }
+            _ => {}
+        }
+    }
+
+    // Handle a key press while entering the path of a file to send
+    fn handle_file_prompt_key_event(&mut self, key_event: KeyEvent) -> TerminalAction {
+        match key_event.code {
+            KeyCode::Enter | KeyCode::Esc => {
+                let saved_command_buffer = std::mem::take(&mut self.saved_command_buffer);
+                let file_path = self.set_command_buffer(saved_command_buffer);
+                self.file_prompt = false;
+                self.print("", true);
+                if key_event.code == KeyCode::Enter && !file_path.trim().is_empty() {
+                    return TerminalAction::SendFile(file_path.trim().to_string());
+                }
+            }
+            KeyCode::Backspace => {
+                self.backspace_command_buffer();
+            }
+            KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.add_to_command_buffer(c);
+            }
+            _ => {}
+        }
+        TerminalAction::None
+    }
+}
+
+// Longest prefix shared by all the strings
+fn common_prefix(strings: &[String]) -> String {
+    let mut prefix = strings.first().cloned().unwrap_or_default();
+    for s in strings.iter().skip(1) {
+        let len = prefix.chars().zip(s.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+// Load the commands for tab completion from the commands file in the app folder (blank
+// lines and lines starting with # are ignored) or use the default commands
+pub fn load_command_completions(app_folder: &str) -> Vec<String> {
+    let commands_file_path = std::path::Path::new(app_folder).join(COMMANDS_FILE_NAME);
+    match std::fs::read_to_string(commands_file_path) {
+        Ok(contents) => contents.lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect(),
+        Err(_) => DEFAULT_COMMANDS.iter().map(|command| command.to_string()).collect(),
+    }
+}
+
+// Send the lines of a file (in a separate thread so that device output continues to be shown)
+// pausing between lines and showing progress on the status line
+pub fn send_file_lines<F>(file_path: &str, terminal_io: Arc<Mutex<TerminalIO>>, send_command: F)
+where
+    F: Fn(String) + Send + 'static,
+{
+    let lines: Vec<String> = match std::fs::read_to_string(file_path) {
+        Ok(contents) => contents.lines()
+            .map(|line| line.trim_end().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        Err(e) => {
+            terminal_io.lock().unwrap().show_error(&format!("Unable to read {}: {}", file_path, e));
+            return;
+        }
+    };
+    let file_path = file_path.to_string();
+    std::thread::spawn(move || {
+        let num_lines = lines.len();
+        for (line_idx, line) in lines.into_iter().enumerate() {
+            {
+                let mut terminal_io = terminal_io.lock().unwrap();
+                terminal_io.log_command(&line);
+                terminal_io.show_info(&format!("Sending {} line {} of {}", file_path, line_idx + 1, num_lines));
+            }
+            send_command(line);
+            std::thread::sleep(Duration::from_millis(SEND_FILE_LINE_DELAY_MS));
+        }
+        terminal_io.lock().unwrap().show_info(&format!("Sent {} lines from {}", num_lines, file_path));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi_stripper() {
+        let mut stripper = AnsiStripper::default();
+        assert_eq!(stripper.strip("\x1b[0;32mI (123) main: ok\x1b[0m\n"), "I (123) main: ok\n");
+        // Sequences split across reads
+        assert_eq!(stripper.strip("before\x1b"), "before");
+        assert_eq!(stripper.strip("[1;3"), "");
+        assert_eq!(stripper.strip("1mafter"), "after");
+        // OSC (e.g. window title) ended by BEL or by ESC backslash
+        assert_eq!(stripper.strip("a\x1b]0;title\x07b"), "ab");
+        assert_eq!(stripper.strip("c\x1b]0;title\x1b"), "c");
+        assert_eq!(stripper.strip("\\d"), "d");
+        // Multi-byte characters are kept
+        assert_eq!(stripper.strip("\x1b[33m25°C ✓\x1b[0m"), "25°C ✓");
+    }
+
+    #[test]
+    fn test_hex_dump_format_row() {
+        // A short row is padded so the ASCII column lines up
+        let row = HexDump::format_row(0x20, b"Hello\x00\x01");
+        assert_eq!(row, format!("00000020  48 65 6c 6c 6f 00 01 {} |Hello..|\r\n", " ".repeat(3 + 1 + 8 * 3)));
+        let row = HexDump::format_row(0, b"0123456789abcdef");
+        assert_eq!(row, "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\r\n");
+    }
+
+    #[test]
+    fn test_hex_dump_push() {
+        let mut hex_dump = HexDump::default();
+        // Nothing is shown until a row is complete
+        assert_eq!(hex_dump.push(&[0x41; 10]), "");
+        let rows = hex_dump.push(&[0x42; 24]);
+        assert_eq!(rows.lines().count(), 2);
+        assert!(rows.starts_with("00000000  41 41"));
+        assert!(rows.contains("\n00000010  42 42"));
+        // The offset runs on from the rows already shown
+        assert!(hex_dump.push(&[0x43; 14]).starts_with("00000020  42 42"));
+        assert_eq!(hex_dump.pending.len(), 0);
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(common_prefix(&strings(&["sysmodinfo/", "sysmodstatus/"])), "sysmod");
+        assert_eq!(common_prefix(&strings(&["reset"])), "reset");
+        assert_eq!(common_prefix(&strings(&["wifiscan", "reset"])), "");
+        assert_eq!(common_prefix(&[]), "");
+        // Multi-byte characters aren't split
+        assert_eq!(common_prefix(&strings(&["temp°C", "temp°F"])), "temp°");
+        assert_eq!(common_prefix(&strings(&["é1", "è2"])), "");
+    }
+}